@@ -0,0 +1,683 @@
+//! MPEG-DASH manifests: each period's video and audio representations with their segments,
+//! addressed by SegmentTemplate (numbers or a timeline), SegmentList, or SegmentBase byte
+//! ranges split along the file's `sidx` index.
+
+use super::{
+  hls::{self, VariantPreference},
+  stream_parts::Segment,
+};
+use anyhow::Context;
+use url::Url;
+
+// Segments one representation may list; a manifest past this is refused rather than planned.
+const MAX_SEGMENTS: u64 = 100_000;
+
+/// Whether the probed `url` is a DASH manifest rather than a file to save as-is.
+pub(super) fn is_manifest(url: &Url, content_type: Option<&str>) -> bool {
+  let by_type = content_type
+    .and_then(|t| t.split(';').next())
+    .is_some_and(|t| t.trim().eq_ignore_ascii_case("application/dash+xml"));
+  by_type || url.path().to_ascii_lowercase().ends_with(".mpd")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Kind {
+  Video,
+  Audio,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Media {
+  /// Every segment spelled out by a template or a list.
+  Segments(Vec<Segment>),
+  /// One file; `index` is the byte range of its `sidx` box, which splits it into segments.
+  Indexed { url: Url, index: Option<(u64, u64)> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Representation {
+  pub id: String,
+  pub kind: Kind,
+  pub mime: String,
+  pub bandwidth: u64,
+  pub height: Option<u32>,
+  pub lang: Option<String>,
+  pub init: Option<Segment>,
+  pub media: Media,
+}
+
+impl Representation {
+  pub(super) fn extension(&self) -> &'static str {
+    match self.mime.as_str() {
+      m if m.ends_with("/webm") => "webm",
+      "audio/mp4" => "m4a",
+      _ => "mp4",
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Period {
+  pub representations: Vec<Representation>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Manifest {
+  /// `type="dynamic"`: a live stream whose segments keep coming.
+  pub dynamic: bool,
+  pub duration_secs: f64,
+  pub periods: Vec<Period>,
+}
+
+/// The representation of `kind` that `pref` picks, among those in `lang` when any are.
+pub(super) fn pick_track<'a>(
+  period: &'a Period,
+  kind: Kind,
+  pref: VariantPreference,
+  lang: Option<&str>,
+) -> Option<&'a Representation> {
+  let of_kind: Vec<&Representation> = period.representations.iter().filter(|r| r.kind == kind).collect();
+  let wanted = lang.map(str::trim).filter(|l| !l.is_empty()).map(str::to_ascii_lowercase);
+  let in_lang: Vec<&Representation> = match &wanted {
+    Some(l) => of_kind
+      .iter()
+      .copied()
+      .filter(|r| r.lang.as_deref().is_some_and(|rl| rl.to_ascii_lowercase().starts_with(l.as_str())))
+      .collect(),
+    None => vec![],
+  };
+  let candidates = if in_lang.is_empty() { of_kind } else { in_lang };
+  let options: Vec<_> = candidates.iter().map(|r| (r.bandwidth, r.height)).collect();
+  hls::pick(&options, pref).map(|i| candidates[i])
+}
+
+/// Parses a manifest fetched from `base`.
+pub(super) fn parse(xml: &str, base: &Url) -> anyhow::Result<Manifest> {
+  let mpd: xml::Mpd = quick_xml::de::from_str(xml).context("not a DASH manifest")?;
+  let dynamic = mpd.kind.as_deref() == Some("dynamic");
+  let total = mpd.duration.as_deref().map(parse_duration).transpose()?;
+  let base = join_base(base, &mpd.base_urls)?;
+  let single = mpd.periods.len() == 1;
+
+  let mut periods = Vec::new();
+  for period in &mpd.periods {
+    let start = period.start.as_deref().map(parse_duration).transpose()?.unwrap_or(0.0);
+    let length = match period.duration.as_deref().map(parse_duration).transpose()? {
+      Some(d) => Some(d),
+      None if single => total.map(|t| t - start),
+      None => None,
+    };
+    let base = join_base(&base, &period.base_urls)?;
+    let mut representations = Vec::new();
+    for set in &period.adaptation_sets {
+      let set_base = join_base(&base, &set.base_urls)?;
+      for rep in &set.representations {
+        let mime = rep.mime_type.clone().or_else(|| set.mime_type.clone()).unwrap_or_default();
+        let content = set.content_type.as_deref().unwrap_or_else(|| mime.split('/').next().unwrap_or_default());
+        let kind = match content {
+          "video" => Kind::Video,
+          "audio" => Kind::Audio,
+          _ => continue,
+        };
+        let rep_base = join_base(&set_base, &rep.base_urls)?;
+        let ctx = Addressing { rep, base: &rep_base, period_secs: length };
+        let template = merge_template(set.segment_template.as_ref(), rep.segment_template.as_ref());
+        let (init, media) = if let Some(t) = template {
+          ctx.template(&t)?
+        } else if let Some(list) = rep.segment_list.as_ref().or(set.segment_list.as_ref()) {
+          ctx.list(list)?
+        } else {
+          ctx.base(rep.segment_base.as_ref().or(set.segment_base.as_ref()))?
+        };
+        representations.push(Representation {
+          id: rep.id.clone().unwrap_or_default(),
+          kind,
+          mime,
+          bandwidth: rep.bandwidth.unwrap_or(0),
+          height: rep.height,
+          lang: set.lang.clone(),
+          init,
+          media,
+        });
+      }
+    }
+    periods.push(Period { representations });
+  }
+  if periods.iter().all(|p| p.representations.is_empty()) {
+    anyhow::bail!("manifest has no audio or video");
+  }
+  Ok(Manifest { dynamic, duration_secs: total.unwrap_or(0.0), periods })
+}
+
+/// Splits a file along its `sidx` box, which was read from `index_start` on: `(offset, length)`
+/// of each referenced segment.
+pub(super) fn sidx_ranges(index: &[u8], index_start: u64) -> anyhow::Result<Vec<(u64, u64)>> {
+  let mut at = 0usize;
+  while index.len().saturating_sub(at) >= 8 {
+    let (size, header) = match u32::from_be_bytes(index[at..at + 4].try_into().unwrap()) {
+      // The box runs to the end of the file, which here is the end of what was read.
+      0 => (index.len() - at, 8),
+      // A 64-bit size follows the type.
+      1 => {
+        let large = index.get(at + 8..at + 16).context("segment index is cut short")?;
+        (usize::try_from(u64::from_be_bytes(large.try_into().unwrap())).unwrap_or(usize::MAX), 16)
+      }
+      n => (n as usize, 8),
+    };
+    anyhow::ensure!(size >= header, "malformed box in segment index");
+    let end = at.saturating_add(size);
+    if &index[at + 4..at + 8] != b"sidx" {
+      at = end;
+      continue;
+    }
+    let sidx = index.get(at..end).context("segment index is cut short")?;
+    let mut r = Reader(&sidx[header..]);
+    let version = r.u32()? >> 24;
+    r.u32()?; // reference_ID
+    r.u32()?; // timescale
+    let first_offset = if version == 0 {
+      r.u32()?;
+      u64::from(r.u32()?)
+    } else {
+      r.u64()?;
+      r.u64()?
+    };
+    let count = r.u32()? & 0xffff;
+    let overflow = || anyhow::anyhow!("segment index points past any file");
+    let mut offset = index_start
+      .checked_add(end as u64)
+      .and_then(|o| o.checked_add(first_offset))
+      .ok_or_else(overflow)?;
+    let mut out = Vec::new();
+    for _ in 0..count {
+      let reference = r.u32()?;
+      anyhow::ensure!(reference >> 31 == 0, "nested segment indexes are not supported");
+      let len = u64::from(reference & 0x7fff_ffff);
+      r.u32()?; // subsegment_duration
+      r.u32()?; // SAP fields
+      out.push((offset, len));
+      offset = offset.checked_add(len).ok_or_else(overflow)?;
+    }
+    return Ok(out);
+  }
+  anyhow::bail!("no sidx box in the segment index")
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+  fn u32(&mut self) -> anyhow::Result<u32> {
+    anyhow::ensure!(self.0.len() >= 4, "segment index is cut short");
+    let (head, rest) = self.0.split_at(4);
+    self.0 = rest;
+    Ok(u32::from_be_bytes(head.try_into().unwrap()))
+  }
+
+  fn u64(&mut self) -> anyhow::Result<u64> {
+    Ok((u64::from(self.u32()?) << 32) | u64::from(self.u32()?))
+  }
+}
+
+/// What segment URLs of one representation are built from.
+struct Addressing<'a> {
+  rep: &'a xml::Representation,
+  base: &'a Url,
+  period_secs: Option<f64>,
+}
+
+impl Addressing<'_> {
+  fn template(&self, t: &xml::SegmentTemplate) -> anyhow::Result<(Option<Segment>, Media)> {
+    let init = match &t.initialization {
+      Some(i) => Some(self.segment(&self.expand(i, 0, 0), None)?),
+      None => None,
+    };
+    let media = t.media.as_deref().context("SegmentTemplate without a media attribute")?;
+    let timescale = t.timescale.unwrap_or(1).max(1);
+    let start_number = t.start_number.unwrap_or(1);
+    let period_ticks = self.period_secs.map(|s| (s * timescale as f64).round() as u64);
+
+    let mut segments = Vec::new();
+    if let Some(timeline) = &t.segment_timeline {
+      let mut time = 0u64;
+      for s in &timeline.segments {
+        time = s.t.unwrap_or(time);
+        let repeats = match s.r.unwrap_or(0) {
+          // -1 repeats until the period ends.
+          r if r < 0 => {
+            let end = period_ticks.context("open-ended SegmentTimeline without a period length")?;
+            end.saturating_sub(time).div_ceil(s.d.max(1)).saturating_sub(1)
+          }
+          r => r as u64,
+        };
+        anyhow::ensure!(
+          repeats < MAX_SEGMENTS - segments.len() as u64,
+          "SegmentTimeline lists more than {MAX_SEGMENTS} segments"
+        );
+        for _ in 0..=repeats {
+          let number = start_number + segments.len() as u64;
+          segments.push(self.segment(&self.expand(media, number, time), None)?);
+          time = time.checked_add(s.d).context("SegmentTimeline runs past the end of time")?;
+        }
+      }
+    } else {
+      let duration = t.duration.context("SegmentTemplate without a duration or timeline")?.max(1);
+      let ticks = period_ticks.context("SegmentTemplate without a period length")?;
+      let count = ticks.div_ceil(duration);
+      anyhow::ensure!(count <= MAX_SEGMENTS, "SegmentTemplate makes {count} segments, more than {MAX_SEGMENTS}");
+      for i in 0..count {
+        segments.push(self.segment(&self.expand(media, start_number + i, i * duration), None)?);
+      }
+    }
+    Ok((init, Media::Segments(segments)))
+  }
+
+  fn list(&self, list: &xml::SegmentList) -> anyhow::Result<(Option<Segment>, Media)> {
+    let init = match &list.initialization {
+      Some(i) => Some(self.segment(i.source_url.as_deref().unwrap_or(""), i.range.as_deref())?),
+      None => None,
+    };
+    let segments = list
+      .segment_urls
+      .iter()
+      .map(|s| self.segment(s.media.as_deref().unwrap_or(""), s.media_range.as_deref()))
+      .collect::<anyhow::Result<_>>()?;
+    Ok((init, Media::Segments(segments)))
+  }
+
+  fn base(&self, base: Option<&xml::SegmentBase>) -> anyhow::Result<(Option<Segment>, Media)> {
+    let init = match base.and_then(|b| b.initialization.as_ref()) {
+      Some(i) => Some(self.segment(i.source_url.as_deref().unwrap_or(""), i.range.as_deref())?),
+      None => None,
+    };
+    let index = base.and_then(|b| b.index_range.as_deref()).map(byte_range).transpose()?;
+    let index = index.map(|(offset, len)| (offset, offset + len - 1));
+    Ok((init, Media::Indexed { url: self.base.clone(), index }))
+  }
+
+  fn segment(&self, uri: &str, range: Option<&str>) -> anyhow::Result<Segment> {
+    let uri = self.base.join(uri).with_context(|| format!("invalid segment URL {uri:?}"))?;
+    Ok(Segment { uri, range: range.map(byte_range).transpose()?, key: None })
+  }
+
+  /// Fills in `$RepresentationID$`, `$Bandwidth$`, `$Number$` and `$Time$` (the last two with
+  /// an optional `%0Nd` width) and `$$`.
+  fn expand(&self, template: &str, number: u64, time: u64) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('$') {
+      out.push_str(&rest[..start]);
+      let Some(len) = rest[start + 1..].find('$') else {
+        rest = &rest[start..];
+        break;
+      };
+      let ident = &rest[start + 1..start + 1 + len];
+      rest = &rest[start + len + 2..];
+      let (name, format) = ident.split_once('%').unwrap_or((ident, ""));
+      let width = format.strip_prefix('0').unwrap_or(format).trim_end_matches('d').parse().unwrap_or(0);
+      match name {
+        "" => out.push('$'),
+        "RepresentationID" => out.push_str(self.rep.id.as_deref().unwrap_or_default()),
+        "Bandwidth" => out.push_str(&format!("{:0width$}", self.rep.bandwidth.unwrap_or(0))),
+        "Number" => out.push_str(&format!("{number:0width$}")),
+        "Time" => out.push_str(&format!("{time:0width$}")),
+        _ => {
+          out.push('$');
+          out.push_str(ident);
+          out.push('$');
+        }
+      }
+    }
+    out.push_str(rest);
+    out
+  }
+}
+
+/// A representation's template filled in from its adaptation set's.
+fn merge_template(
+  set: Option<&xml::SegmentTemplate>,
+  rep: Option<&xml::SegmentTemplate>,
+) -> Option<xml::SegmentTemplate> {
+  match (set, rep) {
+    (None, None) => None,
+    (Some(t), None) | (None, Some(t)) => Some(t.clone()),
+    (Some(s), Some(r)) => Some(xml::SegmentTemplate {
+      media: r.media.clone().or_else(|| s.media.clone()),
+      initialization: r.initialization.clone().or_else(|| s.initialization.clone()),
+      start_number: r.start_number.or(s.start_number),
+      timescale: r.timescale.or(s.timescale),
+      duration: r.duration.or(s.duration),
+      segment_timeline: r.segment_timeline.clone().or_else(|| s.segment_timeline.clone()),
+    }),
+  }
+}
+
+fn join_base(base: &Url, urls: &[String]) -> anyhow::Result<Url> {
+  match urls.first().map(|u| u.trim()).filter(|u| !u.is_empty()) {
+    Some(u) => base.join(u).with_context(|| format!("invalid BaseURL {u:?}")),
+    None => Ok(base.clone()),
+  }
+}
+
+/// `first-last` (inclusive) as `(offset, length)`.
+fn byte_range(value: &str) -> anyhow::Result<(u64, u64)> {
+  let parsed = value
+    .split_once('-')
+    .and_then(|(a, b)| Some((a.trim().parse::<u64>().ok()?, b.trim().parse::<u64>().ok()?)));
+  match parsed {
+    Some((first, last)) if last >= first => Ok((first, last - first + 1)),
+    _ => anyhow::bail!("invalid byte range {value:?}"),
+  }
+}
+
+/// An ISO 8601 duration such as `PT1H2M3.5S` or `P1DT2H`, in seconds.
+fn parse_duration(value: &str) -> anyhow::Result<f64> {
+  let invalid = || anyhow::anyhow!("invalid duration {value:?}");
+  let rest = value.trim().strip_prefix('P').ok_or_else(invalid)?;
+  let (date, time) = rest.split_once('T').unwrap_or((rest, ""));
+  let mut secs = 0.0;
+  for (part, units) in [(date, &[('D', 86_400.0)][..]), (time, &[('H', 3600.0), ('M', 60.0), ('S', 1.0)][..])] {
+    let mut number = String::new();
+    for c in part.chars() {
+      if c.is_ascii_digit() || c == '.' {
+        number.push(c);
+        continue;
+      }
+      let unit = units.iter().find(|(u, _)| *u == c).ok_or_else(invalid)?.1;
+      secs += number.parse::<f64>().map_err(|_| invalid())? * unit;
+      number.clear();
+    }
+    if !number.is_empty() {
+      return Err(invalid());
+    }
+  }
+  Ok(secs)
+}
+
+/// The parts of the MPD schema this reads.
+mod xml {
+  #[derive(Debug, serde::Deserialize)]
+  pub struct Mpd {
+    #[serde(rename = "@type")]
+    pub kind: Option<String>,
+    #[serde(rename = "@mediaPresentationDuration")]
+    pub duration: Option<String>,
+    #[serde(rename = "BaseURL", default)]
+    pub base_urls: Vec<String>,
+    #[serde(rename = "Period", default)]
+    pub periods: Vec<Period>,
+  }
+
+  #[derive(Debug, serde::Deserialize)]
+  pub struct Period {
+    #[serde(rename = "@start")]
+    pub start: Option<String>,
+    #[serde(rename = "@duration")]
+    pub duration: Option<String>,
+    #[serde(rename = "BaseURL", default)]
+    pub base_urls: Vec<String>,
+    #[serde(rename = "AdaptationSet", default)]
+    pub adaptation_sets: Vec<AdaptationSet>,
+  }
+
+  #[derive(Debug, serde::Deserialize)]
+  pub struct AdaptationSet {
+    #[serde(rename = "@mimeType")]
+    pub mime_type: Option<String>,
+    #[serde(rename = "@contentType")]
+    pub content_type: Option<String>,
+    #[serde(rename = "@lang")]
+    pub lang: Option<String>,
+    #[serde(rename = "BaseURL", default)]
+    pub base_urls: Vec<String>,
+    #[serde(rename = "SegmentTemplate")]
+    pub segment_template: Option<SegmentTemplate>,
+    #[serde(rename = "SegmentList")]
+    pub segment_list: Option<SegmentList>,
+    #[serde(rename = "SegmentBase")]
+    pub segment_base: Option<SegmentBase>,
+    #[serde(rename = "Representation", default)]
+    pub representations: Vec<Representation>,
+  }
+
+  #[derive(Debug, serde::Deserialize)]
+  pub struct Representation {
+    #[serde(rename = "@id")]
+    pub id: Option<String>,
+    #[serde(rename = "@bandwidth")]
+    pub bandwidth: Option<u64>,
+    #[serde(rename = "@height")]
+    pub height: Option<u32>,
+    #[serde(rename = "@mimeType")]
+    pub mime_type: Option<String>,
+    #[serde(rename = "BaseURL", default)]
+    pub base_urls: Vec<String>,
+    #[serde(rename = "SegmentTemplate")]
+    pub segment_template: Option<SegmentTemplate>,
+    #[serde(rename = "SegmentList")]
+    pub segment_list: Option<SegmentList>,
+    #[serde(rename = "SegmentBase")]
+    pub segment_base: Option<SegmentBase>,
+  }
+
+  #[derive(Debug, Clone, serde::Deserialize)]
+  pub struct SegmentTemplate {
+    #[serde(rename = "@media")]
+    pub media: Option<String>,
+    #[serde(rename = "@initialization")]
+    pub initialization: Option<String>,
+    #[serde(rename = "@startNumber")]
+    pub start_number: Option<u64>,
+    #[serde(rename = "@timescale")]
+    pub timescale: Option<u64>,
+    #[serde(rename = "@duration")]
+    pub duration: Option<u64>,
+    #[serde(rename = "SegmentTimeline")]
+    pub segment_timeline: Option<SegmentTimeline>,
+  }
+
+  #[derive(Debug, Clone, serde::Deserialize)]
+  pub struct SegmentTimeline {
+    #[serde(rename = "S", default)]
+    pub segments: Vec<S>,
+  }
+
+  #[derive(Debug, Clone, serde::Deserialize)]
+  pub struct S {
+    #[serde(rename = "@t")]
+    pub t: Option<u64>,
+    #[serde(rename = "@d")]
+    pub d: u64,
+    #[serde(rename = "@r")]
+    pub r: Option<i64>,
+  }
+
+  #[derive(Debug, serde::Deserialize)]
+  pub struct SegmentList {
+    #[serde(rename = "Initialization")]
+    pub initialization: Option<Initialization>,
+    #[serde(rename = "SegmentURL", default)]
+    pub segment_urls: Vec<SegmentUrl>,
+  }
+
+  #[derive(Debug, serde::Deserialize)]
+  pub struct SegmentBase {
+    #[serde(rename = "@indexRange")]
+    pub index_range: Option<String>,
+    #[serde(rename = "Initialization")]
+    pub initialization: Option<Initialization>,
+  }
+
+  #[derive(Debug, serde::Deserialize)]
+  pub struct Initialization {
+    #[serde(rename = "@sourceURL")]
+    pub source_url: Option<String>,
+    #[serde(rename = "@range")]
+    pub range: Option<String>,
+  }
+
+  #[derive(Debug, serde::Deserialize)]
+  pub struct SegmentUrl {
+    #[serde(rename = "@media")]
+    pub media: Option<String>,
+    #[serde(rename = "@mediaRange")]
+    pub media_range: Option<String>,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn base() -> Url {
+    Url::parse("https://cdn.example.com/films/tears/manifest.mpd").unwrap()
+  }
+
+  fn paths(media: &Media) -> Vec<String> {
+    let Media::Segments(segments) = media else { panic!("expected segments") };
+    segments.iter().map(|s| s.uri.path().to_string()).collect()
+  }
+
+  #[test]
+  fn templates_by_number_and_timeline() {
+    let xml = r#"<?xml version="1.0"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT9.5S">
+  <Period>
+    <AdaptationSet mimeType="video/mp4" contentType="video">
+      <SegmentTemplate media="v/$RepresentationID$/$Number%03d$.m4s" initialization="v/$RepresentationID$/init.mp4"
+        startNumber="1" timescale="1000" duration="4000"/>
+      <Representation id="720p" bandwidth="3000000" width="1280" height="720"/>
+      <Representation id="1080p" bandwidth="6000000" width="1920" height="1080"/>
+    </AdaptationSet>
+    <AdaptationSet mimeType="audio/mp4" lang="en">
+      <Representation id="a1" bandwidth="128000">
+        <SegmentTemplate media="a/$Time$.m4s" initialization="a/init.mp4" timescale="48000">
+          <SegmentTimeline><S t="0" d="192000" r="1"/><S d="72000"/></SegmentTimeline>
+        </SegmentTemplate>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet mimeType="text/vtt"><Representation id="subs" bandwidth="100"/></AdaptationSet>
+  </Period>
+</MPD>"#;
+    let manifest = parse(xml, &base()).unwrap();
+    assert!(!manifest.dynamic);
+    assert_eq!(manifest.duration_secs, 9.5);
+    let period = &manifest.periods[0];
+    assert_eq!(period.representations.len(), 3);
+
+    let video = pick_track(period, Kind::Video, VariantPreference::parse("720p"), None).unwrap();
+    assert_eq!(video.id, "720p");
+    assert_eq!(video.init.as_ref().unwrap().uri.path(), "/films/tears/v/720p/init.mp4");
+    assert_eq!(
+      paths(&video.media),
+      ["/films/tears/v/720p/001.m4s", "/films/tears/v/720p/002.m4s", "/films/tears/v/720p/003.m4s"]
+    );
+
+    let audio = pick_track(period, Kind::Audio, VariantPreference::Highest, Some("de")).unwrap();
+    assert_eq!((audio.extension(), audio.lang.as_deref()), ("m4a", Some("en")));
+    assert_eq!(paths(&audio.media), ["/films/tears/a/0.m4s", "/films/tears/a/192000.m4s", "/films/tears/a/384000.m4s"]);
+  }
+
+  #[test]
+  fn templates_refuse_runaway_segment_counts() {
+    let mpd = |template: &str| {
+      format!(
+        r#"<MPD type="static" mediaPresentationDuration="PT1M"><Period><AdaptationSet mimeType="video/mp4">
+  <Representation id="v" bandwidth="1">{template}</Representation></AdaptationSet></Period></MPD>"#
+      )
+    };
+    for template in [
+      r#"<SegmentTemplate media="$Number$.m4s"><SegmentTimeline>
+        <S d="1" r="4294967295"/></SegmentTimeline></SegmentTemplate>"#,
+      r#"<SegmentTemplate media="$Time$.m4s"><SegmentTimeline>
+        <S t="18446744073709551615" d="2"/><S d="2"/></SegmentTimeline></SegmentTemplate>"#,
+      r#"<SegmentTemplate media="$Number$.m4s" timescale="1000000" duration="1"/>"#,
+    ] {
+      assert!(parse(&mpd(template), &base()).is_err(), "{template}");
+    }
+  }
+
+  #[test]
+  fn segment_lists_and_bases_with_byte_ranges() {
+    let xml = r#"<MPD type="static" mediaPresentationDuration="PT1M">
+  <BaseURL>https://media.example.com/vod/</BaseURL>
+  <Period>
+    <AdaptationSet mimeType="video/mp4">
+      <Representation id="v" bandwidth="500000" height="360">
+        <BaseURL>video.mp4</BaseURL>
+        <SegmentList>
+          <Initialization range="0-799"/>
+          <SegmentURL mediaRange="800-1799"/>
+          <SegmentURL media="extra.m4s"/>
+        </SegmentList>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet mimeType="audio/webm">
+      <Representation id="a" bandwidth="64000">
+        <BaseURL>audio.webm</BaseURL>
+        <SegmentBase indexRange="600-699"><Initialization range="0-599"/></SegmentBase>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+    let period = &parse(xml, &base()).unwrap().periods[0];
+    let video = &period.representations[0];
+    assert_eq!(video.init.as_ref().unwrap().range, Some((0, 800)));
+    let Media::Segments(segments) = &video.media else { panic!("expected segments") };
+    assert_eq!(segments[0].uri.as_str(), "https://media.example.com/vod/video.mp4");
+    assert_eq!(segments[0].range, Some((800, 1000)));
+    assert_eq!((segments[1].uri.path(), segments[1].range), ("/vod/extra.m4s", None));
+
+    let audio = &period.representations[1];
+    assert_eq!(audio.extension(), "webm");
+    assert_eq!(
+      audio.media,
+      Media::Indexed { url: Url::parse("https://media.example.com/vod/audio.webm").unwrap(), index: Some((600, 699)) }
+    );
+  }
+
+  #[test]
+  fn sidx_splits_a_file_into_ranges() {
+    let mut sidx = Vec::new();
+    sidx.extend_from_slice(&(32u32 + 2 * 12).to_be_bytes());
+    sidx.extend_from_slice(b"sidx");
+    sidx.extend_from_slice(&[0, 0, 0, 0]); // version 0, flags
+    sidx.extend_from_slice(&1u32.to_be_bytes()); // reference_ID
+    sidx.extend_from_slice(&1000u32.to_be_bytes()); // timescale
+    sidx.extend_from_slice(&0u32.to_be_bytes()); // earliest_presentation_time
+    sidx.extend_from_slice(&10u32.to_be_bytes()); // first_offset
+    sidx.extend_from_slice(&2u32.to_be_bytes()); // reserved, reference_count
+    for size in [5000u32, 4200] {
+      sidx.extend_from_slice(&size.to_be_bytes());
+      sidx.extend_from_slice(&4000u32.to_be_bytes());
+      sidx.extend_from_slice(&0x9000_0000u32.to_be_bytes());
+    }
+    // The index starts right after a 600-byte init section and is 56 bytes long.
+    assert_eq!(sidx_ranges(&sidx, 600).unwrap(), [(666, 5000), (5666, 4200)]);
+    assert!(sidx_ranges(&sidx[..30], 600).is_err());
+    // A size of 0 runs to the end of what was read; 1 means a 64-bit size follows.
+    let mut to_end = sidx.clone();
+    to_end[..4].copy_from_slice(&0u32.to_be_bytes());
+    assert_eq!(sidx_ranges(&to_end, 600).unwrap(), [(666, 5000), (5666, 4200)]);
+    let mut large = 1u32.to_be_bytes().to_vec();
+    large.extend_from_slice(b"sidx");
+    large.extend_from_slice(&(sidx.len() as u64 + 8).to_be_bytes());
+    large.extend_from_slice(&sidx[8..]);
+    assert_eq!(sidx_ranges(&large, 600).unwrap(), [(674, 5000), (5674, 4200)]);
+    // Sizes that cannot hold their own header are refused instead of sliced.
+    for size in [2u32, 7] {
+      let mut short = sidx.clone();
+      short[..4].copy_from_slice(&size.to_be_bytes());
+      assert!(sidx_ranges(&short, 600).is_err());
+      short[4..8].copy_from_slice(b"free");
+      assert!(sidx_ranges(&short, 600).is_err());
+    }
+    let mut far = sidx.clone();
+    far[24..28].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert!(sidx_ranges(&far, u64::MAX - 100).is_err());
+
+    assert_eq!(parse_duration("PT1H2M3.5S").unwrap(), 3723.5);
+    assert_eq!(parse_duration("P1DT1S").unwrap(), 86_401.0);
+    assert!(parse_duration("1H").is_err());
+    assert!(is_manifest(&Url::parse("https://a.example/x?f=1").unwrap(), Some("application/dash+xml")));
+  }
+}
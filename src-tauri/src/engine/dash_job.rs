@@ -0,0 +1,412 @@
+//! Downloads a DASH manifest: picks a video and an audio representation of each period, fetches
+//! their segments in parallel, then joins each into its own track file next to the download and
+//! saves both back to back as the download itself.
+
+use super::{
+  bandwidth::BandwidthLimiter,
+  dash::{self, Kind, Media, Representation},
+  hls::VariantPreference,
+  job::{self, JobControl, RuntimeStats},
+  naming,
+  source_job::mark_paused,
+  stream_parts::{self, Fetched, Fetcher, Segment},
+};
+use crate::{
+  model::{DashChoice, DashRepresentation, DownloadRecord, RulesSnapshot, SettingsSnapshot},
+  persistence::Db,
+  transport::{Timeouts, Transport},
+};
+use anyhow::Context;
+use std::{
+  path::{Path, PathBuf},
+  sync::atomic::Ordering,
+};
+use tokio::sync::watch;
+use url::Url;
+
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn attempt_dash_download(
+  db: &Db,
+  settings: &SettingsSnapshot,
+  transport: &Transport,
+  limiter: &BandwidthLimiter,
+  rules: &RulesSnapshot,
+  events: &crate::events::EventHub,
+  download_id: &str,
+  url: &Url,
+  rec: &mut DownloadRecord,
+  clients: Vec<reqwest::Client>,
+  timeouts: Timeouts,
+  mut control_rx: watch::Receiver<JobControl>,
+  stats: RuntimeStats,
+) -> anyhow::Result<()> {
+  // Codes left over from the probe phase must not be mistaken for download failures.
+  *stats.error_code.lock() = None;
+  let mut fetcher = Fetcher::new(db, transport, rules, limiter, timeouts, &stats);
+
+  let choice = DashChoice { video_id: rec.dash_video_id.clone(), audio_id: rec.dash_audio_id.clone() };
+  let planned = tokio::select! {
+    r = plan(&fetcher, &clients[0], url, settings, &choice) => Some(r?),
+    _ = job::wait_for_stop(&mut control_rx) => None,
+  };
+  let Some(tracks) = planned else {
+    return mark_paused(db, download_id, &stats);
+  };
+
+  rec.resolved_url = Some(url.to_string());
+  db.update_resolved_and_mirror(download_id, rec.resolved_url.as_deref(), rec.mirror_used.as_deref())?;
+  if rec.final_filename.is_none() || rec.temp_path.is_none() {
+    rec.supports_ranges = None;
+    rec.content_length = None;
+    rec.etag = None;
+    rec.last_modified = None;
+    let desired = stream_parts::output_name(url, tracks[0].ext);
+    job::record_names(db, events, rec, download_id, &desired)?;
+  }
+  let temp_path = PathBuf::from(rec.temp_path.clone().unwrap());
+  let parts = stream_parts::parts_dir(&temp_path);
+
+  // Video segments first, then audio: one list, so one set of rows tracks both.
+  let segments: Vec<Segment> = tracks.iter().flat_map(|t| t.segments.iter().cloned()).collect();
+  let estimate = tracks.iter().map(|t| t.estimate).sum::<Option<i64>>();
+  let parallel = Transport::multipart_policy(settings, rules, url).max_segments;
+  let fetched = stream_parts::download_segments(
+    &mut fetcher,
+    download_id,
+    &parts,
+    &segments,
+    &clients,
+    parallel,
+    estimate,
+    &mut control_rx,
+  )
+  .await?;
+  if fetched == Fetched::Paused {
+    return mark_paused(db, download_id, &stats);
+  }
+
+  *stats.status_detail.lock() = Some("Joining segments…".to_string());
+  let dest_dir = PathBuf::from(&rec.dest_dir);
+  let final_name = rec.final_filename.clone().unwrap_or_default();
+  let (join_parts, join_temp) = (parts.clone(), temp_path.clone());
+  let join = move || join_tracks(&tracks, &join_parts, &dest_dir, &final_name, &join_temp);
+  let joined = tokio::task::spawn_blocking(join).await.context("join task failed")?;
+  *stats.status_detail.lock() = None;
+  let len = joined.context("failed to join segments")?;
+  let _ = std::fs::remove_dir_all(&parts);
+  stats.total.store(len, Ordering::Relaxed);
+  stats.bytes.store(len, Ordering::Relaxed);
+  db.update_download_bytes(download_id, len)?;
+
+  job::finish_download(db, rec, &temp_path, None, download_id, &control_rx, &stats)
+}
+
+/// One chosen track across every period.
+struct Track {
+  kind: Kind,
+  ext: &'static str,
+  segments: Vec<Segment>,
+  estimate: Option<i64>,
+}
+
+/// The representations the manifest at `url` offers, once each, for choosing them when the
+/// download is added.
+pub(super) async fn representations(
+  fetcher: &Fetcher<'_>,
+  client: &reqwest::Client,
+  url: &Url,
+) -> anyhow::Result<Vec<DashRepresentation>> {
+  let manifest = fetch_manifest(fetcher, client, url).await?;
+  let mut out: Vec<DashRepresentation> = Vec::new();
+  for rep in manifest.periods.iter().flat_map(|p| &p.representations) {
+    let kind = match rep.kind {
+      Kind::Video => "video",
+      Kind::Audio => "audio",
+    };
+    if !out.iter().any(|r| r.kind == kind && r.id == rep.id) {
+      out.push(DashRepresentation {
+        id: rep.id.clone(),
+        kind: kind.to_string(),
+        mime: rep.mime.clone(),
+        bandwidth: rep.bandwidth,
+        height: rep.height,
+        lang: rep.lang.clone(),
+      });
+    }
+  }
+  Ok(out)
+}
+
+async fn fetch_manifest(fetcher: &Fetcher<'_>, client: &reqwest::Client, url: &Url) -> anyhow::Result<dash::Manifest> {
+  let body = fetcher.fetch_small(client, url, None).await?;
+  let manifest =
+    dash::parse(&String::from_utf8_lossy(&body), url).map_err(|e| fetcher.invalid(&format!("{e:#}")))?;
+  if manifest.dynamic {
+    return Err(fetcher.invalid("Live DASH streams are not supported; the manifest is dynamic"));
+  }
+  Ok(manifest)
+}
+
+/// The video and audio tracks from the manifest at `url`, video first: the representations in
+/// `choice`, or what the stream settings pick for a track it leaves open. A period without the
+/// chosen representation falls back to the settings too.
+async fn plan(
+  fetcher: &Fetcher<'_>,
+  client: &reqwest::Client,
+  url: &Url,
+  settings: &SettingsSnapshot,
+  choice: &DashChoice,
+) -> anyhow::Result<Vec<Track>> {
+  let manifest = fetch_manifest(fetcher, client, url).await?;
+
+  let video_pref = VariantPreference::parse(&settings.stream_variant);
+  let audio_pref = Some(settings.stream_audio.trim())
+    .filter(|a| !a.eq_ignore_ascii_case("none"))
+    .map(VariantPreference::parse);
+  let lang = settings.stream_audio_language.as_deref();
+
+  let mut tracks = Vec::new();
+  let tracks_wanted = [
+    (Kind::Video, Some(video_pref), None, choice.video_id.as_deref()),
+    (Kind::Audio, audio_pref, lang, choice.audio_id.as_deref()),
+  ];
+  for (kind, pref, lang, chosen) in tracks_wanted {
+    if pref.is_none() && chosen.is_none() {
+      continue;
+    }
+    // Choosing an audio track overrides settings that turn audio off.
+    let pref = pref.unwrap_or(VariantPreference::Highest);
+    let mut track: Option<Track> = None;
+    let mut last_init = None;
+    let mut found_chosen = false;
+    for period in &manifest.periods {
+      let picked = chosen.and_then(|id| period.representations.iter().find(|r| r.kind == kind && r.id == id));
+      found_chosen |= picked.is_some();
+      let Some(rep) = picked.or_else(|| dash::pick_track(period, kind, pref, lang)) else { continue };
+      tracing::info!(id = %rep.id, bandwidth = rep.bandwidth, ?kind, "selected DASH representation");
+      let track = track.get_or_insert_with(|| Track {
+        kind,
+        ext: rep.extension(),
+        segments: Vec::new(),
+        estimate: Some(rep.bandwidth)
+          .filter(|b| *b > 0)
+          .map(|bps| (bps as f64 * manifest.duration_secs / 8.0) as i64),
+      });
+      // Periods sharing one representation also share its initialization section.
+      let (init, media) = segments_of(fetcher, client, rep).await?;
+      if init.is_some() && init != last_init {
+        track.segments.extend(init.clone());
+        last_init = init;
+      }
+      track.segments.extend(media);
+    }
+    if let (Some(id), false) = (chosen, found_chosen) {
+      return Err(fetcher.invalid(&format!("The DASH manifest no longer offers representation {id}")));
+    }
+    tracks.extend(track);
+  }
+  if tracks.is_empty() {
+    return Err(fetcher.invalid("The DASH manifest has no audio or video track to download"));
+  }
+  Ok(tracks)
+}
+
+/// The initialization section and media segments of `rep`, reading its segment index when the
+/// media is a single indexed file.
+async fn segments_of(
+  fetcher: &Fetcher<'_>,
+  client: &reqwest::Client,
+  rep: &Representation,
+) -> anyhow::Result<(Option<Segment>, Vec<Segment>)> {
+  let (url, (start, end)) = match &rep.media {
+    Media::Segments(segments) => return Ok((rep.init.clone(), segments.clone())),
+    Media::Indexed { url, index: Some(index) } => (url, *index),
+    // Without an index the file is the whole track, initialization included.
+    Media::Indexed { url, index: None } => return Ok((None, vec![whole(url)])),
+  };
+  let body = fetcher.fetch_small(client, url, Some((start, end))).await?;
+  // A server that ignores the range sends the whole file.
+  let wanted = (end - start + 1) as usize;
+  let index = if body.len() > wanted { body.get(start as usize..=end as usize).unwrap_or_default() } else { &body[..] };
+  match dash::sidx_ranges(index, start) {
+    Ok(ranges) => {
+      let media = ranges.into_iter().map(|range| Segment { uri: url.clone(), range: Some(range), key: None }).collect();
+      Ok((rep.init.clone(), media))
+    }
+    Err(e) => {
+      tracing::warn!(error = %format!("{e:#}"), %url, "unreadable segment index; fetching the file whole");
+      Ok((None, vec![whole(url)]))
+    }
+  }
+}
+
+fn whole(url: &Url) -> Segment {
+  Segment { uri: url.clone(), range: None, key: None }
+}
+
+/// Joins each track into its own file next to `final_name` and both back to back into `out`,
+/// or a lone track straight into `out`; returns the size of `out`.
+fn join_tracks(tracks: &[Track], parts: &Path, dest_dir: &Path, final_name: &str, out: &Path) -> anyhow::Result<i64> {
+  if let [track] = tracks {
+    return stream_parts::join_segments(parts, 0..track.segments.len(), out);
+  }
+  let stem = Path::new(final_name).file_stem().and_then(|s| s.to_str()).unwrap_or("stream");
+  let mut files = Vec::new();
+  let mut first = 0;
+  for track in tracks {
+    let label = match track.kind {
+      Kind::Video => "video",
+      Kind::Audio => "audio",
+    };
+    let name = naming::choose_non_colliding_filename(dest_dir, &format!("{stem}.{label}.{}", track.ext))?;
+    let path = dest_dir.join(name);
+    stream_parts::join_segments(parts, first..first + track.segments.len(), &path)?;
+    first += track.segments.len();
+    files.push(path);
+  }
+  stream_parts::join_files(files, out)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use axum::{
+    http::{HeaderMap as Headers, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+  };
+
+  /// The `bytes=a-b` or `bytes=a-` part of `file` as a 206, or all of it.
+  fn ranged(headers: &Headers, file: &'static [u8]) -> axum::response::Response {
+    let range = headers
+      .get("range")
+      .and_then(|v| v.to_str().ok())
+      .and_then(|v| v.strip_prefix("bytes="))
+      .and_then(|v| v.split_once('-'))
+      .and_then(|(a, b)| Some((a.parse::<usize>().ok()?, b.parse::<usize>().ok())));
+    match range {
+      Some((from, to)) => {
+        let to = to.unwrap_or(file.len() - 1);
+        let content_range = format!("bytes {from}-{to}/{}", file.len());
+        (StatusCode::PARTIAL_CONTENT, [("content-range", content_range)], file[from..=to].to_vec()).into_response()
+      }
+      None => (StatusCode::OK, file.to_vec()).into_response(),
+    }
+  }
+
+  #[tokio::test]
+  async fn chosen_representations_override_the_stream_settings() {
+    let manifest = r#"<MPD type="static" mediaPresentationDuration="PT8S">
+  <Period>
+    <AdaptationSet mimeType="video/mp4">
+      <SegmentTemplate media="v/$RepresentationID$-$Number$.m4s" initialization="v/$RepresentationID$-init.mp4"
+        duration="4"/>
+      <Representation id="lo" bandwidth="400000" height="360"/>
+      <Representation id="hi" bandwidth="2000000" height="1080"/>
+    </AdaptationSet>
+    <AdaptationSet mimeType="audio/mp4" lang="en">
+      <SegmentTemplate media="a/$RepresentationID$-$Number$.m4s" duration="4"/>
+      <Representation id="en" bandwidth="96000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+    let app = Router::new().route("/film/manifest.mpd", get(move || async move { manifest }));
+    let url = Url::parse(&format!("{}/film/manifest.mpd", crate::transport::tests::serve(app).await)).unwrap();
+
+    let (db, _dir) = crate::transport::tests::test_db();
+    let rules = db.list_rules().unwrap();
+    let transport = Transport::new().unwrap();
+    let client = transport.client_for(&Default::default()).unwrap();
+    let (limiter, stats) = (BandwidthLimiter::new(0), RuntimeStats::new("d1".to_string()));
+    let fetcher = Fetcher::new(&db, &transport, &rules, &limiter, Timeouts::default(), &stats);
+
+    let offered = representations(&fetcher, &client, &url).await.unwrap();
+    let ids: Vec<_> = offered.iter().map(|r| (r.kind.as_str(), r.id.as_str(), r.height)).collect();
+    assert_eq!(ids, [("video", "lo", Some(360)), ("video", "hi", Some(1080)), ("audio", "en", None)]);
+
+    // The settings want 480p video and no audio; the choice asks for 1080p and the English track.
+    let mut settings = crate::transport::tests::settings();
+    settings.stream_variant = "480p".to_string();
+    settings.stream_audio = "none".to_string();
+    let choice = DashChoice { video_id: Some("hi".to_string()), audio_id: Some("en".to_string()) };
+    let tracks = plan(&fetcher, &client, &url, &settings, &choice).await.unwrap();
+    let first: Vec<_> = tracks.iter().map(|t| t.segments[0].uri.path().to_string()).collect();
+    assert_eq!(first, ["/film/v/hi-init.mp4", "/film/a/en-1.m4s"]);
+
+    let tracks = plan(&fetcher, &client, &url, &settings, &DashChoice::default()).await.unwrap();
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0].segments[0].uri.path(), "/film/v/lo-init.mp4");
+
+    let gone = DashChoice { video_id: Some("4k".to_string()), audio_id: None };
+    assert!(plan(&fetcher, &client, &url, &settings, &gone).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn saves_both_tracks_and_their_concatenation() {
+    const AUDIO: &[u8] = b"AINIT|audio one|audio two";
+    let manifest = r#"<MPD type="static" mediaPresentationDuration="PT8S">
+  <Period>
+    <AdaptationSet mimeType="video/mp4">
+      <SegmentTemplate media="v/$RepresentationID$-$Number$.m4s" initialization="v/$RepresentationID$-init.mp4"
+        duration="4"/>
+      <Representation id="lo" bandwidth="400000" height="360"/>
+      <Representation id="hi" bandwidth="2000000" height="1080"/>
+    </AdaptationSet>
+    <AdaptationSet mimeType="audio/mp4" lang="en">
+      <Representation id="en" bandwidth="96000">
+        <BaseURL>audio.m4a</BaseURL>
+        <SegmentList>
+          <Initialization range="0-5"/><SegmentURL mediaRange="6-15"/><SegmentURL mediaRange="16-24"/>
+        </SegmentList>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+    let app = Router::new()
+      .route("/film/manifest.mpd", get(move || async move { manifest }))
+      .route("/film/v/lo-init.mp4", get(|| async { "VINIT|" }))
+      .route("/film/v/lo-1.m4s", get(|| async { "video one|" }))
+      .route("/film/v/lo-2.m4s", get(|| async { "video two|" }))
+      .route("/film/audio.m4a", get(|h: Headers| async move { ranged(&h, AUDIO) }));
+    let base = crate::transport::tests::serve(app).await;
+
+    let (db, dir) = crate::transport::tests::test_db();
+    let url = Url::parse(&format!("{base}/film/manifest.mpd")).unwrap();
+    db.insert_download_skeleton("d1", url.as_str(), &dir.display().to_string(), false, None).unwrap();
+    let mut rec = db.get_download("d1").unwrap().unwrap();
+
+    let mut settings = crate::transport::tests::settings();
+    settings.stream_variant = "480p".to_string();
+    settings.stream_audio_language = Some("en".to_string());
+    let rules = crate::transport::tests::rules_with(crate::transport::tests::local_rule());
+    let transport = Transport::new().unwrap();
+    let client = transport.client_for(&Default::default()).unwrap();
+    let (_control_tx, control_rx) = watch::channel(JobControl::Run);
+    let stats = RuntimeStats::new("d1".to_string());
+
+    attempt_dash_download(
+      &db,
+      &settings,
+      &transport,
+      &BandwidthLimiter::new(0),
+      &rules,
+      &crate::events::EventHub::new(),
+      "d1",
+      &url,
+      &mut rec,
+      vec![client],
+      Timeouts::default(),
+      control_rx,
+      stats.clone(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(std::fs::read(dir.join("film.video.mp4")).unwrap(), b"VINIT|video one|video two|");
+    assert_eq!(std::fs::read(dir.join("film.audio.m4a")).unwrap(), AUDIO);
+    assert_eq!(std::fs::read(dir.join("film.mp4")).unwrap(), b"VINIT|video one|video two|AINIT|audio one|audio two");
+    assert!(!stream_parts::parts_dir(&dir.join(".zdmr-d1.part")).exists());
+    assert_eq!(*stats.status.lock(), crate::model::DownloadStatus::Completed);
+  }
+}
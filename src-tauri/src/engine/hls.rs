@@ -0,0 +1,359 @@
+//! HTTP Live Streaming playlists: a master playlist's variants, a media playlist's segments
+//! with their keys and byte ranges, all resolved against the playlist's URL.
+
+use super::stream_parts::{Key, Segment};
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+use url::Url;
+
+/// Types servers announce `.m3u8` playlists with.
+const PLAYLIST_TYPES: [&str; 2] = ["application/vnd.apple.mpegurl", "application/x-mpegurl"];
+
+/// Whether the probed `url` is an HLS playlist rather than a file to save as-is.
+pub(super) fn is_playlist(url: &Url, content_type: Option<&str>) -> bool {
+  let by_type = content_type
+    .and_then(|t| t.split(';').next())
+    .is_some_and(|t| PLAYLIST_TYPES.iter().any(|p| t.trim().eq_ignore_ascii_case(p)));
+  by_type || url.path().to_ascii_lowercase().ends_with(".m3u8")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Variant {
+  pub uri: Url,
+  pub bandwidth: u64,
+  /// Width and height from RESOLUTION, when the playlist gives it.
+  pub resolution: Option<(u32, u32)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct MediaPlaylist {
+  /// In playback order; an EXT-X-MAP initialization section comes before the segments it
+  /// applies to, so concatenating them in order gives a playable file.
+  pub segments: Vec<Segment>,
+  pub duration_secs: f64,
+  /// EXT-X-ENDLIST or a VOD playlist type: the list will not grow.
+  pub ended: bool,
+  /// Has an EXT-X-MAP, so the segments are fragmented MP4 rather than MPEG-TS.
+  pub fragmented_mp4: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Playlist {
+  Master(Vec<Variant>),
+  Media(MediaPlaylist),
+}
+
+/// Which variant of a master playlist to download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum VariantPreference {
+  Highest,
+  Lowest,
+  /// The best variant at most this many lines tall.
+  MaxHeight(u32),
+  /// The best variant within this many bits per second.
+  MaxBandwidth(u64),
+}
+
+impl VariantPreference {
+  /// "highest", "lowest", a height like "720p" or a bitrate like "3000k"; anything else is
+  /// "highest".
+  pub(super) fn parse(s: &str) -> Self {
+    let s = s.trim().to_ascii_lowercase();
+    if s == "lowest" {
+      return Self::Lowest;
+    }
+    if let Some(h) = s.strip_suffix('p').and_then(|v| v.parse().ok()) {
+      return Self::MaxHeight(h);
+    }
+    if let Some(k) = s.strip_suffix('k').and_then(|v| v.parse::<u64>().ok()) {
+      return Self::MaxBandwidth(k.saturating_mul(1000));
+    }
+    Self::Highest
+  }
+}
+
+/// The variant `pref` picks.
+pub(super) fn select_variant(variants: &[Variant], pref: VariantPreference) -> Option<&Variant> {
+  let options: Vec<_> = variants.iter().map(|v| (v.bandwidth, v.resolution.map(|(_, h)| h))).collect();
+  pick(&options, pref).map(|i| &variants[i])
+}
+
+/// Which of `(bandwidth, height)` options `pref` picks. With a cap, that is the best one within
+/// it, or the smallest when none fits.
+pub(super) fn pick(options: &[(u64, Option<u32>)], pref: VariantPreference) -> Option<usize> {
+  let by_bandwidth = |(_, o): &(usize, &(u64, Option<u32>))| (o.0, o.1.unwrap_or(0));
+  let all = || options.iter().enumerate();
+  let lowest = || all().min_by_key(by_bandwidth);
+  let picked = match pref {
+    VariantPreference::Highest => all().max_by_key(by_bandwidth),
+    VariantPreference::Lowest => lowest(),
+    VariantPreference::MaxHeight(max) => all()
+      .filter(|(_, o)| o.1.is_some_and(|h| h <= max))
+      .max_by_key(|(_, o)| (o.1, o.0))
+      .or_else(lowest),
+    VariantPreference::MaxBandwidth(max) => all().filter(|(_, o)| o.0 <= max).max_by_key(by_bandwidth).or_else(lowest),
+  };
+  picked.map(|(i, _)| i)
+}
+
+/// Parses a master or media playlist fetched from `base`.
+pub(super) fn parse(text: &str, base: &Url) -> anyhow::Result<Playlist> {
+  let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+  if lines.next().map(|l| l.trim_start_matches('\u{feff}')) != Some("#EXTM3U") {
+    anyhow::bail!("not an HLS playlist (missing #EXTM3U)");
+  }
+  let resolve = |uri: &str| base.join(uri).map_err(|e| anyhow::anyhow!("invalid URI {uri:?} in playlist: {e}"));
+
+  let mut variants = Vec::new();
+  let mut pending_variant: Option<(u64, Option<(u32, u32)>)> = None;
+  let mut segments = Vec::new();
+  let mut sequence: u64 = 0;
+  let mut index: u64 = 0;
+  let mut duration_secs = 0.0;
+  let mut ended = false;
+  let mut fragmented_mp4 = false;
+  // The current key: its URI and the IV when the tag gives one (else the sequence number).
+  let mut key: Option<(Url, Option<[u8; 16]>)> = None;
+  let mut pending_range: Option<(u64, Option<u64>)> = None;
+  // Where the previous sub-range ended, for EXT-X-BYTERANGE without an offset.
+  let mut range_end: Option<(Url, u64)> = None;
+  let mut last_map: Option<Segment> = None;
+
+  for line in lines {
+    let Some(tag) = line.strip_prefix('#') else {
+      let uri = resolve(line)?;
+      if let Some((bandwidth, resolution)) = pending_variant.take() {
+        variants.push(Variant { uri, bandwidth, resolution });
+        continue;
+      }
+      let range = match pending_range.take() {
+        Some((len, offset)) => {
+          let start = offset.or_else(|| range_end.as_ref().filter(|(u, _)| *u == uri).map(|(_, end)| *end));
+          let start = start.ok_or_else(|| anyhow::anyhow!("EXT-X-BYTERANGE without an offset for {uri}"))?;
+          range_end = Some((uri.clone(), start + len));
+          Some((start, len))
+        }
+        None => None,
+      };
+      let key = key.as_ref().map(|(uri, iv)| Key {
+        uri: uri.clone(),
+        iv: iv.unwrap_or_else(|| u128::from(sequence + index).to_be_bytes()),
+      });
+      segments.push(Segment { uri, range, key });
+      index += 1;
+      continue;
+    };
+    let (name, value) = tag.split_once(':').unwrap_or((tag, ""));
+    match name {
+      "EXT-X-STREAM-INF" => {
+        let attrs = attributes(value);
+        let bandwidth = attr(&attrs, "BANDWIDTH").and_then(|b| b.parse().ok()).unwrap_or(0);
+        let resolution = attr(&attrs, "RESOLUTION").and_then(|r| {
+          let (w, h) = r.split_once(['x', 'X'])?;
+          Some((w.parse().ok()?, h.parse().ok()?))
+        });
+        pending_variant = Some((bandwidth, resolution));
+      }
+      "EXT-X-MEDIA-SEQUENCE" => sequence = value.trim().parse().unwrap_or(0),
+      "EXTINF" => {
+        let secs = value.split(',').next().unwrap_or_default();
+        duration_secs += secs.trim().parse::<f64>().unwrap_or(0.0);
+      }
+      "EXT-X-BYTERANGE" => pending_range = Some(byte_range(value)?),
+      "EXT-X-KEY" => {
+        let attrs = attributes(value);
+        key = match attr(&attrs, "METHOD").unwrap_or("NONE") {
+          "NONE" => None,
+          "AES-128" => {
+            let uri = attr(&attrs, "URI").ok_or_else(|| anyhow::anyhow!("AES-128 key without a URI"))?;
+            Some((resolve(uri)?, attr(&attrs, "IV").map(parse_iv).transpose()?))
+          }
+          other => anyhow::bail!("{other} encryption is not supported"),
+        };
+      }
+      "EXT-X-MAP" => {
+        let attrs = attributes(value);
+        let uri = resolve(attr(&attrs, "URI").ok_or_else(|| anyhow::anyhow!("EXT-X-MAP without a URI"))?)?;
+        let range = attr(&attrs, "BYTERANGE").map(byte_range).transpose()?;
+        let range = range.map(|(len, offset)| (offset.unwrap_or(0), len));
+        let key = key.as_ref().map(|(uri, iv)| Key {
+          uri: uri.clone(),
+          iv: iv.unwrap_or_else(|| u128::from(sequence + index).to_be_bytes()),
+        });
+        let map = Segment { uri, range, key };
+        if last_map.as_ref() != Some(&map) {
+          segments.push(map.clone());
+          last_map = Some(map);
+        }
+        fragmented_mp4 = true;
+      }
+      "EXT-X-PLAYLIST-TYPE" if value.trim() == "VOD" => ended = true,
+      "EXT-X-ENDLIST" => ended = true,
+      _ => {}
+    }
+  }
+
+  if !variants.is_empty() {
+    return Ok(Playlist::Master(variants));
+  }
+  if segments.is_empty() {
+    anyhow::bail!("playlist has no segments");
+  }
+  Ok(Playlist::Media(MediaPlaylist { segments, duration_secs, ended, fragmented_mp4 }))
+}
+
+/// Decrypts one AES-128-CBC segment in place, dropping its PKCS#7 padding.
+pub(super) fn decrypt(data: &mut Vec<u8>, key: &[u8; 16], iv: &[u8; 16]) -> anyhow::Result<()> {
+  let len = cbc::Decryptor::<aes::Aes128>::new(key.into(), iv.into())
+    .decrypt_padded_mut::<Pkcs7>(data)
+    .map_err(|_| anyhow::anyhow!("segment did not decrypt (wrong key or IV?)"))?
+    .len();
+  data.truncate(len);
+  Ok(())
+}
+
+/// `<length>[@<offset>]`.
+fn byte_range(value: &str) -> anyhow::Result<(u64, Option<u64>)> {
+  let value = value.trim().trim_matches('"');
+  let (len, offset) = match value.split_once('@') {
+    Some((len, offset)) => (len, Some(offset)),
+    None => (value, None),
+  };
+  let parse = |v: &str| v.trim().parse::<u64>().map_err(|_| anyhow::anyhow!("invalid byte range {value:?}"));
+  Ok((parse(len)?, offset.map(parse).transpose()?))
+}
+
+fn parse_iv(value: &str) -> anyhow::Result<[u8; 16]> {
+  let hex = value.trim_start_matches("0x").trim_start_matches("0X");
+  let iv = u128::from_str_radix(hex, 16).map_err(|_| anyhow::anyhow!("invalid IV {value:?}"))?;
+  Ok(iv.to_be_bytes())
+}
+
+/// An attribute list (`NAME=value,NAME="quoted, value"`), names upper-cased.
+fn attributes(list: &str) -> Vec<(String, String)> {
+  let mut out = Vec::new();
+  let mut rest = list.trim();
+  while let Some((name, after)) = rest.split_once('=') {
+    let (value, next) = match after.strip_prefix('"') {
+      Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+      None => after.split_once(',').unwrap_or((after, "")),
+    };
+    out.push((name.trim().to_ascii_uppercase(), value.trim().to_string()));
+    rest = next.trim_start_matches(',').trim_start();
+  }
+  out
+}
+
+fn attr<'a>(attrs: &'a [(String, String)], name: &str) -> Option<&'a str> {
+  attrs.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use aes::cipher::BlockEncryptMut;
+
+  fn base() -> Url {
+    Url::parse("https://cdn.example.com/show/master.m3u8?token=abc").unwrap()
+  }
+
+  const MASTER: &str = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=800000,RESOLUTION=640x360,CODECS=\"avc1.4d401e,mp4a.40.2\"
+360p/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2800000,RESOLUTION=1280x720,CODECS=\"avc1.4d401f,mp4a.40.2\"
+720p/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=5000000,RESOLUTION=1920x1080
+https://other.example.com/1080p.m3u8
+";
+
+  #[test]
+  fn master_variants_resolve_and_select() {
+    let Playlist::Master(variants) = parse(MASTER, &base()).unwrap() else { panic!("expected a master playlist") };
+    assert_eq!(variants.len(), 3);
+    assert_eq!(variants[0].uri.as_str(), "https://cdn.example.com/show/360p/index.m3u8");
+    assert_eq!(variants[1].resolution, Some((1280, 720)));
+    assert_eq!(variants[2].bandwidth, 5_000_000);
+
+    let pick = |pref: &str| select_variant(&variants, VariantPreference::parse(pref)).unwrap().bandwidth;
+    assert_eq!(pick("highest"), 5_000_000);
+    assert_eq!(pick(""), 5_000_000);
+    assert_eq!(pick("lowest"), 800_000);
+    assert_eq!(pick("720p"), 2_800_000);
+    assert_eq!(pick("3000k"), 2_800_000);
+    // Nothing fits the cap: fall back to the smallest.
+    assert_eq!(pick("240p"), 800_000);
+    assert_eq!(pick("100k"), 800_000);
+  }
+
+  #[test]
+  fn media_playlist_keys_ranges_and_maps() {
+    let text = "#EXTM3U
+#EXT-X-VERSION:7
+#EXT-X-MEDIA-SEQUENCE:5
+#EXT-X-MAP:URI=\"init.mp4\",BYTERANGE=\"720@0\"
+#EXTINF:4.0,
+#EXT-X-BYTERANGE:1000@720
+media.mp4
+#EXTINF:4.0,
+#EXT-X-BYTERANGE:500
+media.mp4
+#EXT-X-KEY:METHOD=AES-128,URI=\"https://keys.example.com/k?id=1\"
+#EXTINF:2.5,
+seg7.m4s
+#EXT-X-KEY:METHOD=AES-128,URI=\"k2\",IV=0x000102030405060708090A0B0C0D0E0F
+#EXTINF:2.5,
+seg8.m4s
+#EXT-X-KEY:METHOD=NONE
+#EXTINF:1,
+seg9.m4s
+#EXT-X-ENDLIST
+";
+    let Playlist::Media(media) = parse(text, &base()).unwrap() else { panic!("expected a media playlist") };
+    assert!(media.ended && media.fragmented_mp4);
+    assert_eq!(media.duration_secs, 14.0);
+    let s = &media.segments;
+    assert_eq!(s.len(), 6);
+    assert_eq!((s[0].uri.path(), s[0].range), ("/show/init.mp4", Some((0, 720))));
+    assert_eq!(s[1].range, Some((720, 1000)));
+    assert_eq!(s[2].range, Some((1720, 500)));
+    let key = s[3].key.as_ref().unwrap();
+    assert_eq!(key.uri.as_str(), "https://keys.example.com/k?id=1");
+    // Media sequence 5 plus two segments before it.
+    assert_eq!(key.iv, 7u128.to_be_bytes());
+    assert_eq!(s[4].key.as_ref().unwrap().iv, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+    assert_eq!(s[5].key, None);
+  }
+
+  #[test]
+  fn live_and_unsupported_playlists() {
+    let live = "#EXTM3U\n#EXTINF:6,\na.ts\n#EXTINF:6,\nb.ts\n";
+    let Playlist::Media(media) = parse(live, &base()).unwrap() else { panic!("expected a media playlist") };
+    assert!(!media.ended);
+    assert!(!media.fragmented_mp4);
+
+    let sample_aes = "#EXTM3U\n#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"k\"\n#EXTINF:6,\na.ts\n";
+    assert!(parse(sample_aes, &base()).unwrap_err().to_string().contains("SAMPLE-AES"));
+    assert!(parse("<html></html>", &base()).is_err());
+
+    let url = |u: &str| Url::parse(u).unwrap();
+    assert!(is_playlist(&url("https://a.example/live/INDEX.M3U8"), None));
+    assert!(is_playlist(&url("https://a.example/play?id=1"), Some("application/vnd.apple.mpegurl; charset=utf-8")));
+    assert!(!is_playlist(&url("https://a.example/movie.ts"), Some("video/mp2t")));
+  }
+
+  #[test]
+  fn decrypts_aes_128_segments() {
+    let (key, iv) = ([7u8; 16], 3u128.to_be_bytes());
+    let plain = b"0123456789abcdef-and-a-tail".to_vec();
+    let mut data = vec![0u8; 48];
+    let len = cbc::Encryptor::<aes::Aes128>::new(&key.into(), &iv.into())
+      .encrypt_padded_b2b_mut::<Pkcs7>(&plain, &mut data)
+      .unwrap()
+      .len();
+    data.truncate(len);
+
+    let mut wrong = data.clone();
+    assert!(decrypt(&mut wrong, &[8u8; 16], &iv).is_err() || wrong != plain);
+    decrypt(&mut data, &key, &iv).unwrap();
+    assert_eq!(data, plain);
+  }
+}
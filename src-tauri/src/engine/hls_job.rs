@@ -0,0 +1,266 @@
+//! Downloads an HLS stream: picks a variant of a master playlist, fetches the media playlist's
+//! segments in parallel (decrypting AES-128 ones), then joins them into one `.ts` (or `.mp4`
+//! for fragmented MP4 streams).
+
+use super::{
+  bandwidth::BandwidthLimiter,
+  hls::{self, MediaPlaylist, Playlist, VariantPreference},
+  job::{self, JobControl, RuntimeStats},
+  source_job::mark_paused,
+  stream_parts::{self, Fetched, Fetcher},
+};
+use crate::{
+  error::ErrorCode,
+  model::{DownloadRecord, RulesSnapshot, SettingsSnapshot},
+  persistence::Db,
+  transport::{Timeouts, Transport},
+};
+use anyhow::Context;
+use std::{path::PathBuf, sync::atomic::Ordering};
+use tokio::sync::watch;
+use url::Url;
+
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn attempt_hls_download(
+  db: &Db,
+  settings: &SettingsSnapshot,
+  transport: &Transport,
+  limiter: &BandwidthLimiter,
+  rules: &RulesSnapshot,
+  events: &crate::events::EventHub,
+  download_id: &str,
+  url: &Url,
+  rec: &mut DownloadRecord,
+  clients: Vec<reqwest::Client>,
+  timeouts: Timeouts,
+  mut control_rx: watch::Receiver<JobControl>,
+  stats: RuntimeStats,
+) -> anyhow::Result<()> {
+  // Codes left over from the probe phase must not be mistaken for download failures.
+  *stats.error_code.lock() = None;
+  let mut fetcher = Fetcher::new(db, transport, rules, limiter, timeouts, &stats);
+
+  let loaded = tokio::select! {
+    r = load_media_playlist(&fetcher, &clients[0], url, VariantPreference::parse(&settings.stream_variant)) => Some(r?),
+    _ = job::wait_for_stop(&mut control_rx) => None,
+  };
+  let Some((media_url, media, bandwidth)) = loaded else {
+    return mark_paused(db, download_id, &stats);
+  };
+  if !media.ended {
+    *stats.error_code.lock() = Some(ErrorCode::InvalidUrl);
+    *stats.error_message.lock() = Some("Live HLS streams are not supported; the playlist has no end".to_string());
+    anyhow::bail!("live playlist");
+  }
+
+  // The variant's playlist is what the segments come from, so that is the URL to show.
+  rec.resolved_url = Some(media_url.to_string());
+  db.update_resolved_and_mirror(download_id, rec.resolved_url.as_deref(), rec.mirror_used.as_deref())?;
+  if rec.final_filename.is_none() || rec.temp_path.is_none() {
+    rec.supports_ranges = None;
+    rec.content_length = None;
+    rec.etag = None;
+    rec.last_modified = None;
+    let desired = stream_parts::output_name(url, if media.fragmented_mp4 { "mp4" } else { "ts" });
+    job::record_names(db, events, rec, download_id, &desired)?;
+  }
+  let temp_path = PathBuf::from(rec.temp_path.clone().unwrap());
+  let parts = stream_parts::parts_dir(&temp_path);
+
+  // Sizes are unknown until segments arrive; the advertised bitrate gives a first guess.
+  let estimate = bandwidth.map(|bps| (bps as f64 * media.duration_secs / 8.0) as i64);
+  let parallel = Transport::multipart_policy(settings, rules, url).max_segments;
+  let fetched = stream_parts::download_segments(
+    &mut fetcher,
+    download_id,
+    &parts,
+    &media.segments,
+    &clients,
+    parallel,
+    estimate,
+    &mut control_rx,
+  )
+  .await?;
+  if fetched == Fetched::Paused {
+    return mark_paused(db, download_id, &stats);
+  }
+
+  *stats.status_detail.lock() = Some("Joining segments…".to_string());
+  let (count, join_parts, join_temp) = (media.segments.len(), parts.clone(), temp_path.clone());
+  let joined = tokio::task::spawn_blocking(move || stream_parts::join_segments(&join_parts, 0..count, &join_temp))
+    .await
+    .context("join task failed")?;
+  *stats.status_detail.lock() = None;
+  let len = joined.context("failed to join segments")?;
+  let _ = std::fs::remove_dir_all(&parts);
+  stats.total.store(len, Ordering::Relaxed);
+  stats.bytes.store(len, Ordering::Relaxed);
+  db.update_download_bytes(download_id, len)?;
+
+  job::finish_download(db, rec, &temp_path, None, download_id, &control_rx, &stats)
+}
+
+/// The media playlist at `url`, or at the variant of it `pref` picks when `url` is a master
+/// playlist, with that variant's bitrate.
+async fn load_media_playlist(
+  fetcher: &Fetcher<'_>,
+  client: &reqwest::Client,
+  url: &Url,
+  pref: VariantPreference,
+) -> anyhow::Result<(Url, MediaPlaylist, Option<u64>)> {
+  let variants = match parse_playlist(fetcher, client, url).await? {
+    Playlist::Media(media) => return Ok((url.clone(), media, None)),
+    Playlist::Master(variants) => variants,
+  };
+  let Some(variant) = hls::select_variant(&variants, pref) else {
+    return Err(fetcher.invalid("master playlist has no variants"));
+  };
+  tracing::info!(variant = %variant.uri, bandwidth = variant.bandwidth, "selected HLS variant");
+  match parse_playlist(fetcher, client, &variant.uri).await? {
+    Playlist::Media(media) => Ok((variant.uri.clone(), media, Some(variant.bandwidth).filter(|b| *b > 0))),
+    Playlist::Master(_) => Err(fetcher.invalid("variant playlist is another master playlist")),
+  }
+}
+
+async fn parse_playlist(fetcher: &Fetcher<'_>, client: &reqwest::Client, url: &Url) -> anyhow::Result<Playlist> {
+  let body = fetcher.fetch_small(client, url, None).await?;
+  let text = String::from_utf8_lossy(&body);
+  hls::parse(&text, url).map_err(|e| fetcher.invalid(&format!("{e:#}")))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::persistence::SegmentRow;
+  use stream_parts::parts_dir;
+  use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
+  use axum::{
+    extract::State,
+    http::{HeaderMap as Headers, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+  };
+  use std::sync::{atomic::AtomicUsize, Arc};
+
+  const KEY: [u8; 16] = [9; 16];
+
+  fn encrypt(plain: &[u8], sequence: u128) -> Vec<u8> {
+    let mut out = vec![0u8; plain.len() + 16];
+    let len = cbc::Encryptor::<aes::Aes128>::new(&KEY.into(), &sequence.to_be_bytes().into())
+      .encrypt_padded_b2b_mut::<Pkcs7>(plain, &mut out)
+      .unwrap()
+      .len();
+    out.truncate(len);
+    out
+  }
+
+  /// `body` tagged `etag`, or the `bytes=N-` part of it as a 206 while `If-Range` names that tag.
+  fn ranged(headers: &Headers, body: Vec<u8>, etag: &str) -> axum::response::Response {
+    let from = headers
+      .get("range")
+      .and_then(|v| v.to_str().ok())
+      .and_then(|v| v.strip_prefix("bytes="))
+      .and_then(|v| v.trim_end_matches('-').parse::<usize>().ok());
+    let current = headers.get("if-range").map_or(true, |v| v == etag);
+    match from.filter(|_| current) {
+      Some(from) => {
+        let content_range = format!("bytes {from}-{}/{}", body.len() - 1, body.len());
+        let headers = [("etag", etag.to_string()), ("content-range", content_range)];
+        (StatusCode::PARTIAL_CONTENT, headers, body[from..].to_vec()).into_response()
+      }
+      None => (StatusCode::OK, [("etag", etag.to_string())], body).into_response(),
+    }
+  }
+
+  #[tokio::test]
+  async fn resumes_segments_decrypts_and_joins_the_chosen_variant() {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let app = Router::new()
+      .route(
+        "/show/master.m3u8",
+        get(|| async {
+          "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=900000,RESOLUTION=640x360\nlo/index.m3u8\n\
+           #EXT-X-STREAM-INF:BANDWIDTH=3000000,RESOLUTION=1280x720\nhi/index.m3u8\n"
+        }),
+      )
+      .route(
+        "/show/hi/index.m3u8",
+        get(|| async {
+          "#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:1\n#EXTINF:4,\nseg0.ts\n\
+           #EXT-X-KEY:METHOD=AES-128,URI=\"/keys/k\"\n#EXTINF:4,\nseg1.ts\n#EXTINF:4,\nseg2.ts\n#EXT-X-ENDLIST\n"
+        }),
+      )
+      .route("/keys/k", get(|| async { KEY.to_vec() }))
+      .route(
+        "/show/hi/seg0.ts",
+        get(|State(hits): State<Arc<AtomicUsize>>| async move {
+          hits.fetch_add(1, Ordering::Relaxed);
+          b"plain zero|".to_vec()
+        }),
+      )
+      .route("/show/hi/seg1.ts", get(|h: Headers| async move { ranged(&h, encrypt(b"secret one|", 2), "\"one\"") }))
+      .route("/show/hi/seg2.ts", get(|h: Headers| async move { ranged(&h, encrypt(b"secret two", 3), "\"two\"") }))
+      .with_state(hits.clone());
+    let base = crate::transport::tests::serve(app).await;
+
+    let (db, dir) = crate::transport::tests::test_db();
+    let url = Url::parse(&format!("{base}/show/master.m3u8")).unwrap();
+    db.insert_download_skeleton("d1", url.as_str(), &dir.display().to_string(), false, None).unwrap();
+    let mut rec = db.get_download("d1").unwrap().unwrap();
+
+    // An earlier run finished segment 0, got five bytes into segment 1, and five into a version
+    // of segment 2 the server no longer has.
+    let temp_path = dir.join(".zdmr-d1.part");
+    rec.temp_path = Some(temp_path.display().to_string());
+    rec.final_filename = Some("show.ts".to_string());
+    let parts = parts_dir(&temp_path);
+    std::fs::create_dir_all(&parts).unwrap();
+    std::fs::write(parts.join("00000.seg"), b"plain zero|").unwrap();
+    std::fs::write(parts.join("00001.part"), &encrypt(b"secret one|", 2)[..5]).unwrap();
+    std::fs::write(parts.join("00001.validator"), "\"one\"").unwrap();
+    std::fs::write(parts.join("00002.part"), b"stale").unwrap();
+    std::fs::write(parts.join("00002.validator"), "\"old\"").unwrap();
+    let row = |i: i64, bytes_done: i64, status: &str| SegmentRow {
+      range_start: i,
+      range_end: i,
+      bytes_done,
+      status: status.to_string(),
+      last_error: None,
+    };
+    db.replace_segments("d1", vec![row(0, 11, "COMPLETED"), row(1, 5, "ACTIVE"), row(2, 5, "ACTIVE")]).unwrap();
+
+    let mut settings = crate::transport::tests::settings();
+    settings.stream_variant = "720p".to_string();
+    let rules = crate::transport::tests::rules_with(crate::transport::tests::local_rule());
+    let transport = Transport::new().unwrap();
+    let client = transport.client_for(&Default::default()).unwrap();
+    let (_control_tx, control_rx) = watch::channel(JobControl::Run);
+    let stats = RuntimeStats::new("d1".to_string());
+
+    attempt_hls_download(
+      &db,
+      &settings,
+      &transport,
+      &BandwidthLimiter::new(0),
+      &rules,
+      &crate::events::EventHub::new(),
+      "d1",
+      &url,
+      &mut rec,
+      vec![client],
+      Timeouts::default(),
+      control_rx,
+      stats.clone(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(std::fs::read(dir.join("show.ts")).unwrap(), b"plain zero|secret one|secret two");
+    assert_eq!(hits.load(Ordering::Relaxed), 0);
+    assert!(!parts.exists());
+    assert_eq!(*stats.status.lock(), crate::model::DownloadStatus::Completed);
+    let rec = db.get_download("d1").unwrap().unwrap();
+    assert_eq!(rec.resolved_url.as_deref(), Some(format!("{base}/show/hi/index.m3u8").as_str()));
+  }
+}
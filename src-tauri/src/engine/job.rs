@@ -1,19 +1,30 @@
 use crate::{
-  engine::{bandwidth::BandwidthLimiter, file_writer::write_at_all, naming},
+  engine::{
+    bandwidth::BandwidthLimiter,
+    file_writer::write_at_all,
+    dash,
+    dash_job,
+    hls,
+    hls_job,
+    naming,
+    range,
+    rate_limit::{self, HostThrottle, SegmentGate},
+    source_job,
+  },
   error::ErrorCode,
   model::{DownloadRecord, DownloadStatus},
   persistence::{Db, SegmentRow, SegmentRowWithId, SettingsStore},
-  transport::Transport,
+  transport::{pool, proxy, s3, source, MultipartPolicy, ProbeMethod, ProxyConfig, ProxyRoute, SendError, Timeouts, Transport},
 };
 use anyhow::Context;
 use futures_util::StreamExt;
-use reqwest::header::{HeaderMap, HeaderValue, RANGE};
+use reqwest::header::{HeaderMap, HeaderValue, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
 use std::{
   fs::OpenOptions,
   path::{Path, PathBuf},
   sync::atomic::{AtomicI64, Ordering},
   sync::Arc,
-  time::Duration,
+  time::{Duration, SystemTime},
 };
 use tokio::sync::watch;
 use tokio::time::Instant;
@@ -59,7 +70,7 @@ impl RuntimeStats {
   }
 }
 
-fn now_unix_ms() -> i64 {
+pub(super) fn now_unix_ms() -> i64 {
   use std::time::{SystemTime, UNIX_EPOCH};
   SystemTime::now()
     .duration_since(UNIX_EPOCH)
@@ -67,7 +78,7 @@ fn now_unix_ms() -> i64 {
     .unwrap_or(0)
 }
 
-fn compute_backoff_delay_ms(attempt: usize) -> u64 {
+pub(super) fn compute_backoff_delay_ms(attempt: usize) -> u64 {
   // 1s, 2s, 4s, ... capped at 60s
   let base: u64 = 1_000;
   let max: u64 = 60_000;
@@ -76,11 +87,52 @@ fn compute_backoff_delay_ms(attempt: usize) -> u64 {
   exp.min(max)
 }
 
+// Consecutive 429/503 answers we sit out before giving up on the current source.
+const MAX_RATE_LIMIT_RETRIES: usize = 10;
+
+fn rate_limit_delay(headers: &HeaderMap, attempt: usize) -> Duration {
+  rate_limit::server_backoff(headers, SystemTime::now())
+    .unwrap_or_else(|| Duration::from_millis(compute_backoff_delay_ms(attempt)))
+}
+
+pub(super) async fn wait_for_stop(control_rx: &mut watch::Receiver<JobControl>) {
+  loop {
+    if matches!(*control_rx.borrow(), JobControl::Pause | JobControl::Cancel) {
+      return;
+    }
+    if control_rx.changed().await.is_err() {
+      // Controller is gone; nobody can pause us anymore.
+      std::future::pending::<()>().await;
+    }
+  }
+}
+
+/// Sit out a server-requested backoff, surfaced through `backoff_until_ms`.
+/// Returns false if the job was paused/cancelled while waiting.
+async fn wait_out_rate_limit(
+  stats: &RuntimeStats,
+  control_rx: &mut watch::Receiver<JobControl>,
+  status: u16,
+  delay: Duration,
+) -> bool {
+  stats
+    .backoff_until_ms
+    .store(now_unix_ms() + delay.as_millis() as i64, Ordering::Relaxed);
+  *stats.status_detail.lock() = Some(format!("Rate limited by server (HTTP {status}). Retrying…"));
+  let completed = tokio::select! {
+    _ = tokio::time::sleep(delay) => true,
+    _ = wait_for_stop(control_rx) => false,
+  };
+  stats.backoff_until_ms.store(0, Ordering::Relaxed);
+  completed
+}
+
 pub async fn run_download_job(
   db: Db,
   settings: SettingsStore,
   transport: Transport,
   limiter: BandwidthLimiter,
+  throttle: HostThrottle,
   rules: crate::model::RulesSnapshot,
   events: crate::events::EventHub,
   download_id: String,
@@ -112,6 +164,7 @@ pub async fn run_download_job(
       &settings,
       &transport,
       &limiter,
+      &throttle,
       &rules,
       &events,
       &download_id,
@@ -158,11 +211,77 @@ fn build_attempt_urls(rules: &crate::model::RulesSnapshot, rec: &DownloadRecord)
   Ok(out)
 }
 
+/// The forced proxy (`forced_proxy_url`, else the global one) when `forced`, otherwise whatever
+/// the proxy rules pick for `url`.
+pub(super) async fn choose_route(
+  transport: &Transport,
+  settings: &crate::model::SettingsSnapshot,
+  rules: &crate::model::RulesSnapshot,
+  forced: bool,
+  forced_proxy_url: Option<&str>,
+  url: &Url,
+) -> ProxyRoute {
+  if !forced {
+    return transport.proxy_route(settings, rules, url).await;
+  }
+  match forced_proxy_url
+    .map(str::to_string)
+    .or_else(|| settings.global_proxy_url.clone())
+    .filter(|v| !v.trim().is_empty())
+  {
+    Some(url) => ProxyRoute::proxy(url, "forced for this download"),
+    None => ProxyRoute::direct("forced proxy has no address"),
+  }
+}
+
+/// Where `route` sends requests, in failover order (`None` is DIRECT): a `pool://` route's
+/// members, or its proxy followed by any PAC fallbacks. The pool comes back too, for health.
+pub(super) fn route_candidates<'a>(
+  transport: &Transport,
+  rules: &'a crate::model::RulesSnapshot,
+  route: &ProxyRoute,
+) -> anyhow::Result<(Option<&'a crate::model::ProxyPool>, Vec<Option<String>>)> {
+  let pool = match route.proxy_url.as_deref().and_then(pool::pool_name) {
+    Some(name) => Some(
+      rules
+        .proxy_pools
+        .iter()
+        .find(|p| p.enabled && p.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| anyhow::anyhow!("unknown or disabled proxy pool {name}"))?,
+    ),
+    None => None,
+  };
+  let candidates = match pool {
+    Some(p) => transport.pools().candidates(p),
+    None => std::iter::once(route.proxy_url.clone()).chain(route.fallbacks.iter().cloned()).collect(),
+  };
+  if candidates.is_empty() {
+    anyhow::bail!("proxy pool has no members");
+  }
+  Ok((pool, candidates))
+}
+
+/// The profile and client for requests to `url` through `candidate` (`None` is DIRECT).
+pub(super) fn candidate_client(
+  db: &Db,
+  transport: &Transport,
+  settings: &crate::model::SettingsSnapshot,
+  rules: &crate::model::RulesSnapshot,
+  url: &Url,
+  candidate: Option<&str>,
+) -> anyhow::Result<(crate::transport::ClientProfile, reqwest::Client)> {
+  let proxy = candidate.map(|u| proxy_config(db, u)).transpose()?;
+  let profile = Transport::client_profile(settings, rules, url).with_proxy(proxy);
+  let client = transport.client_for(&profile)?;
+  Ok((profile, client))
+}
+
 async fn attempt_download_once(
   db: &Db,
   settings: &SettingsStore,
   transport: &Transport,
   limiter: &BandwidthLimiter,
+  throttle: &HostThrottle,
   rules: &crate::model::RulesSnapshot,
   events: &crate::events::EventHub,
   download_id: &str,
@@ -174,18 +293,62 @@ async fn attempt_download_once(
 ) -> anyhow::Result<()> {
   let url_parsed = Url::parse(url).context("invalid url")?;
 
-  let proxy_url = Transport::effective_proxy_url(&settings.get_snapshot()?, rules, &url_parsed);
-  let forced = rec.forced_proxy;
-  let forced_url = rec
-    .forced_proxy_url
-    .clone()
-    .or_else(|| settings.get_snapshot().ok().and_then(|s| s.global_proxy_url));
-  let proxy_url = if forced {
-    forced_url.filter(|v| !v.trim().is_empty())
+  let snapshot = settings.get_snapshot()?;
+  if source::handles(&url_parsed) {
+    return source_job::attempt_source_download(
+      db,
+      &snapshot,
+      transport,
+      limiter,
+      throttle,
+      rules,
+      events,
+      download_id,
+      &url_parsed,
+      attempt_idx,
+      rec,
+      control_rx,
+      stats,
+    )
+    .await;
+  }
+  // `s3://` is plain HTTP to the bucket's endpoint; `send_with_auth` signs every request, so
+  // probes, segments and resumes each carry a fresh signature.
+  let url_parsed = if url_parsed.scheme() == "s3" {
+    match s3::object_url(&rules.http_credentials, &url_parsed) {
+      Ok(u) => u,
+      Err(e) => {
+        *stats.error_code.lock() = Some(ErrorCode::InvalidUrl);
+        *stats.error_message.lock() = Some(format!("{e:#}"));
+        return Err(e);
+      }
+    }
   } else {
-    proxy_url
+    url_parsed
+  };
+  let url = url_parsed.as_str();
+  if let Some(expired) = s3::expired_presign(&rules.http_credentials, &url_parsed, time::OffsetDateTime::now_utc()) {
+    let at = expired.format(&time::format_description::well_known::Rfc3339).unwrap_or_default();
+    *stats.error_code.lock() = Some(ErrorCode::Http4xx);
+    *stats.error_message.lock() =
+      Some(format!("Presigned URL expired at {at}; add an AWS SigV4 login for its endpoint to re-sign it"));
+    anyhow::bail!("presigned URL expired");
+  }
+  let forced_url = rec.forced_proxy_url.as_deref();
+  let route = choose_route(transport, &snapshot, rules, rec.forced_proxy, forced_url, &url_parsed).await;
+  rec.proxy_route = Some(route.explanation.clone());
+  db.update_proxy_route(download_id, &route.explanation)?;
+
+  // A `pool://` route expands to the pool's members in failover order; a PAC answer listing
+  // several entries fails over between them the same way.
+  let (pool, candidates) = match route_candidates(transport, rules, &route) {
+    Ok(c) => c,
+    Err(e) => {
+      *stats.error_code.lock() = Some(ErrorCode::InvalidUrl);
+      *stats.error_message.lock() = Some(format!("{e:#}"));
+      return Err(e);
+    }
   };
-  let client = transport.client_for(proxy_url.as_deref())?;
 
   // Record which source URL (and which mirror, if any) we are currently attempting.
   let mirror_used = if attempt_idx == 0 {
@@ -197,53 +360,169 @@ async fn attempt_download_once(
   rec.mirror_used = mirror_used.clone();
   db.update_resolved_and_mirror(download_id, rec.resolved_url.as_deref(), mirror_used.as_deref())?;
 
-  // Probe via HEAD
-  let mut headers = HeaderMap::new();
-  Transport::apply_header_rules(rules, &mut headers, &url_parsed);
-
-  let head = client
-    .head(url_parsed.clone())
-    .headers(headers.clone())
-    .send()
-    .await;
-
-  let (supports_ranges, content_length, etag, last_modified, content_disposition, content_type) =
-    match head {
-      Ok(resp) => {
-        let status = resp.status();
-        if status.is_client_error() || status.is_server_error() {
-          set_http_error(&stats, status.as_u16(), resp.text().await.ok());
-          anyhow::bail!("http {}", status.as_u16());
+  // Probe: HEAD first, falling back to a one-byte ranged GET for hosts that reject HEAD.
+  // Pool members that cannot be reached are marked down and the next one is tried.
+  let limits = ProbeLimits {
+    throttle,
+    host: url_parsed.host_str().unwrap_or_default(),
+    segments: Transport::multipart_policy(&snapshot, rules, &url_parsed).max_segments,
+  };
+  let mut failed = Vec::new();
+  let mut chosen = None;
+  for (idx, candidate) in candidates.iter().enumerate() {
+    let (profile, client) = match candidate_client(db, transport, &snapshot, rules, &url_parsed, candidate.as_deref()) {
+      Ok(c) => c,
+      Err(e) => {
+        *stats.error_code.lock() = Some(ErrorCode::InvalidUrl);
+        *stats.error_message.lock() = Some(format!("{e:#}"));
+        return Err(e);
+      }
+    };
+    match probe_remote(transport, rules, &client, profile.timeouts, &url_parsed, limits, &stats, &mut control_rx).await {
+      Ok(Some(probe)) => {
+        if let (Some(_), Some(member)) = (pool, candidate) {
+          transport.pools().mark_up(member, None);
         }
-        let headers = resp.headers();
-        let supports = headers
-          .get("accept-ranges")
-          .and_then(|v| v.to_str().ok())
-          .map(|s| s.to_ascii_lowercase().contains("bytes"));
-        let len = headers
-          .get("content-length")
-          .and_then(|v| v.to_str().ok())
-          .and_then(|s| s.parse::<i64>().ok());
-        let etag = headers.get("etag").and_then(|v| v.to_str().ok()).map(|s| s.to_string());
-        let lm = headers
-          .get("last-modified")
-          .and_then(|v| v.to_str().ok())
-          .map(|s| s.to_string());
-        let cd = headers
-          .get("content-disposition")
-          .and_then(|v| v.to_str().ok())
-          .map(|s| s.to_string());
-        let ct = headers
-          .get("content-type")
-          .and_then(|v| v.to_str().ok())
-          .map(|s| s.to_string());
-        (supports, len, etag, lm, cd, ct)
+        chosen = Some((idx, profile, client, probe));
+        break;
+      }
+      Ok(None) => {
+        *stats.status.lock() = DownloadStatus::Paused;
+        db.update_download_status(download_id, DownloadStatus::Paused, None, None)?;
+        return Ok(());
       }
       Err(e) => {
-        set_reqwest_error(&stats, &e);
-        anyhow::bail!("probe failed: {e}");
+        let connect_failed = matches!(*stats.error_code.lock(), Some(ErrorCode::ConnectFail));
+        if candidates.len() == 1 || !connect_failed {
+          return Err(e);
+        }
+        if let (Some(_), Some(member)) = (pool, candidate) {
+          transport.pools().mark_down(member, &format!("{e:#}"));
+        }
+        if idx + 1 == candidates.len() {
+          return Err(e);
+        }
+        let label = candidate.as_deref().map_or_else(|| "DIRECT".to_string(), proxy::display_url);
+        tracing::info!(download_id = %download_id, proxy = %label, error = %e, "proxy candidate failed; trying next");
+        failed.push(label);
+        *stats.error_code.lock() = None;
+        *stats.error_message.lock() = None;
       }
+    }
+  }
+  let Some((chosen_idx, profile, client, (probe, probe_body))) = chosen else {
+    anyhow::bail!("no proxy candidate succeeded");
+  };
+  let timeouts = profile.timeouts;
+
+  if pool.is_some() || !failed.is_empty() {
+    let via = candidates[chosen_idx].as_deref().map_or_else(|| "DIRECT".to_string(), proxy::display_url);
+    let mut explanation = match pool {
+      Some(p) => format!("{}; pool {} chose {via}", route.explanation, p.name),
+      None => format!("{}; used {via}", route.explanation),
     };
+    if !failed.is_empty() {
+      explanation.push_str(&format!(" after {} failed", failed.join(", ")));
+    }
+    rec.proxy_route = Some(explanation.clone());
+    db.update_proxy_route(download_id, &explanation)?;
+  }
+
+  // With per-segment rotation, segments take turns over the pool members still in play, and
+  // over the transport rule's local addresses when it lists several.
+  let segment_proxies: Vec<Option<ProxyConfig>> = match pool {
+    Some(p) if p.rotate_per_segment && candidates[chosen_idx].is_some() => candidates[chosen_idx..]
+      .iter()
+      .flatten()
+      .map(|member| proxy_config(db, member).map(Some))
+      .collect::<anyhow::Result<_>>()?,
+    _ => vec![],
+  };
+  let local_addresses = Transport::local_addresses(rules, &url_parsed);
+  let segment_clients: Vec<reqwest::Client> = Transport::segment_profiles(&profile, &segment_proxies, &local_addresses)
+    .iter()
+    .map(|p| transport.client_for(p))
+    .collect::<anyhow::Result<_>>()?;
+  // A segment whose proxy stops accepting connections moves on to the candidates after the
+  // chosen one, as the probe would have.
+  let members: Vec<Option<String>> = if segment_proxies.is_empty() {
+    vec![candidates[chosen_idx].clone()]
+  } else {
+    candidates[chosen_idx..].iter().flatten().cloned().map(Some).collect()
+  };
+  let segment_routes = SegmentRoutes {
+    rotation: segment_clients.iter().enumerate().map(|(i, c)| (members[i % members.len()].clone(), c.clone())).collect(),
+    fallbacks: candidates[chosen_idx + 1..]
+      .iter()
+      .map(|member| {
+        let proxy = member.as_deref().map(|u| proxy_config(db, u)).transpose()?;
+        Ok((member.clone(), transport.client_for(&profile.clone().with_proxy(proxy))?))
+      })
+      .collect::<anyhow::Result<_>>()?,
+    pooled: pool.is_some(),
+  };
+  let ProbeInfo {
+    supports_ranges,
+    content_length,
+    etag,
+    last_modified,
+    content_disposition,
+    content_type,
+    content_encoding,
+  } = probe;
+
+  // A playlist is not the file itself: its segments are.
+  if hls::is_playlist(&url_parsed, content_type.as_deref()) {
+    drop(probe_body);
+    return hls_job::attempt_hls_download(
+      db,
+      &snapshot,
+      transport,
+      limiter,
+      rules,
+      events,
+      download_id,
+      &url_parsed,
+      rec,
+      segment_clients,
+      timeouts,
+      control_rx,
+      stats,
+    )
+    .await;
+  }
+  // Nor is a DASH manifest.
+  if dash::is_manifest(&url_parsed, content_type.as_deref()) {
+    drop(probe_body);
+    return dash_job::attempt_dash_download(
+      db,
+      &snapshot,
+      transport,
+      limiter,
+      rules,
+      events,
+      download_id,
+      &url_parsed,
+      rec,
+      segment_clients,
+      timeouts,
+      control_rx,
+      stats,
+    )
+    .await;
+  }
+
+  // When a rule opts into decoding, the server's length and offsets describe the encoded
+  // bytes, not what we write: treat the size as unknown and stream it in one piece.
+  let decoded = profile.decode_content
+    && content_encoding
+      .as_deref()
+      .is_some_and(|e| !e.trim().eq_ignore_ascii_case("identity"));
+  let (supports_ranges, content_length) = if decoded {
+    (Some(false), None)
+  } else {
+    (supports_ranges, content_length)
+  };
 
   stats
     .total
@@ -286,67 +565,45 @@ async fn attempt_download_once(
       content_disposition.as_deref(),
       content_type.as_deref(),
     );
-    let chosen = naming::choose_non_colliding_filename(Path::new(&rec.dest_dir), &desired)?;
-    let temp_path = Path::new(&rec.dest_dir).join(format!(".zdmr-{download_id}.part"));
-
-    rec.final_filename = Some(chosen.clone());
-    rec.temp_path = Some(temp_path.display().to_string());
     rec.resolved_url = Some(url.to_string());
     rec.supports_ranges = supports_ranges;
     rec.content_length = content_length;
     rec.etag = etag.clone();
     rec.last_modified = last_modified.clone();
     rec.mirror_used = mirror_used;
-
-    db.set_download_finalization(
-      download_id,
-      rec.resolved_url.as_deref(),
-      rec.temp_path.as_deref(),
-      rec.final_filename.as_deref(),
-      rec.content_length,
-      rec.etag.as_deref(),
-      rec.last_modified.as_deref(),
-      rec.supports_ranges,
-      rec.mirror_used.as_deref(),
-    )?;
-    // Notify UI immediately so "(resolving...)" flips to the real name without waiting for another event.
-    events.emit_downloads_changed();
+    record_names(db, events, rec, download_id, &desired)?;
   }
 
   let temp_path = PathBuf::from(rec.temp_path.clone().unwrap());
   let total = content_length.or(rec.content_length);
+  // Resumed ranges are conditional on the validators recorded at first probe, so a changed
+  // remote file comes back as a full 200 instead of being spliced into the old bytes.
+  let if_range = range::if_range_value(rec.etag.as_deref(), rec.last_modified.as_deref());
   stats.bytes.store(rec.bytes_downloaded, Ordering::Relaxed);
   stats.last_bytes.store(rec.bytes_downloaded, Ordering::Relaxed);
 
-  // Create/prepare temp file.
-  {
-    let mut opts = OpenOptions::new();
-    opts.create(true).write(true).read(true);
-    let f = opts.open(&temp_path).context("failed to open temp file")?;
-    if let Some(len) = total {
-      if len > 0 {
-        f.set_len(len as u64).ok();
-      }
-    }
-  }
+  prepare_temp_file(&temp_path, total)?;
 
   // Decide multipart vs single.
-  let do_multipart = total
-    .filter(|l| *l >= 32 * 1024 * 1024) // 32MiB+
-    .is_some()
-    && supports_ranges.unwrap_or(false);
+  let policy = Transport::multipart_policy(&settings.get_snapshot()?, rules, &url_parsed);
+  let do_multipart = policy.applies_to(total) && supports_ranges.unwrap_or(false);
 
   if do_multipart {
     // Lightweight warmup probe to adapt initial segment concurrency based on observed throughput.
-    let warmup_bps = warmup_probe_bps(&client, rules, &url_parsed).await.unwrap_or(0.0);
+    let warmup_bps = warmup_probe_bps(transport, &client, timeouts, rules, &url_parsed).await.unwrap_or(0.0);
     if let Err(e) = download_multipart(
       db,
-      client,
+      transport,
+      segment_routes,
+      timeouts,
+      throttle,
       rules,
       &url_parsed,
       &temp_path,
       download_id,
       total.unwrap(),
+      if_range.clone(),
+      &policy,
       warmup_bps,
       limiter,
       control_rx.clone(),
@@ -363,13 +620,17 @@ async fn attempt_download_once(
         // Re-run as single stream without ranges/resume.
         download_single(
           db,
-          transport.client_for(proxy_url.as_deref())?,
+          transport,
+          transport.client_for(&profile)?,
+          timeouts,
           rules,
           &url_parsed,
           &temp_path,
           download_id,
           total,
           false,
+          None,
+          None,
           limiter,
           control_rx.clone(),
           stats.clone(),
@@ -382,13 +643,17 @@ async fn attempt_download_once(
   } else {
     download_single(
       db,
+      transport,
       client,
+      timeouts,
       rules,
       &url_parsed,
       &temp_path,
       download_id,
       total,
       supports_ranges.unwrap_or(false),
+      if_range,
+      probe_body,
       limiter,
       control_rx.clone(),
       stats.clone(),
@@ -396,7 +661,62 @@ async fn attempt_download_once(
     .await?;
   }
 
-  // Finalize: rename temp to final.
+  finish_download(db, rec, &temp_path, total, download_id, &control_rx, &stats)
+}
+
+/// Picks the final name (and the temp file next to it) on the first successful probe, and
+/// records them together with what the probe learned, which the caller has put on `rec`.
+pub(super) fn record_names(
+  db: &Db,
+  events: &crate::events::EventHub,
+  rec: &mut DownloadRecord,
+  download_id: &str,
+  desired: &str,
+) -> anyhow::Result<()> {
+  let chosen = naming::choose_non_colliding_filename(Path::new(&rec.dest_dir), desired)?;
+  let temp_path = Path::new(&rec.dest_dir).join(format!(".zdmr-{download_id}.part"));
+  rec.final_filename = Some(chosen);
+  rec.temp_path = Some(temp_path.display().to_string());
+
+  db.set_download_finalization(
+    download_id,
+    rec.resolved_url.as_deref(),
+    rec.temp_path.as_deref(),
+    rec.final_filename.as_deref(),
+    rec.content_length,
+    rec.etag.as_deref(),
+    rec.last_modified.as_deref(),
+    rec.supports_ranges,
+    rec.mirror_used.as_deref(),
+  )?;
+  // Notify UI immediately so "(resolving...)" flips to the real name without waiting for another event.
+  events.emit_downloads_changed();
+  Ok(())
+}
+
+/// Creates the temp file, preallocated when the size is known.
+pub(super) fn prepare_temp_file(temp_path: &Path, total: Option<i64>) -> anyhow::Result<()> {
+  let mut opts = OpenOptions::new();
+  opts.create(true).write(true).read(true);
+  let f = opts.open(temp_path).context("failed to open temp file")?;
+  if let Some(len) = total {
+    if len > 0 {
+      f.set_len(len as u64).ok();
+    }
+  }
+  Ok(())
+}
+
+/// Renames the temp file to the final name and checks its size, unless the job was stopped.
+pub(super) fn finish_download(
+  db: &Db,
+  rec: &DownloadRecord,
+  temp_path: &Path,
+  total: Option<i64>,
+  download_id: &str,
+  control_rx: &watch::Receiver<JobControl>,
+  stats: &RuntimeStats,
+) -> anyhow::Result<()> {
   if matches!(*control_rx.borrow(), JobControl::Pause | JobControl::Cancel) {
     *stats.status.lock() = DownloadStatus::Paused;
     db.update_download_status(download_id, DownloadStatus::Paused, None, None)?;
@@ -405,7 +725,7 @@ async fn attempt_download_once(
 
   let final_name = rec.final_filename.clone().unwrap();
   let final_path = Path::new(&rec.dest_dir).join(final_name);
-  std::fs::rename(&temp_path, &final_path).context("failed to move temp file to final path")?;
+  std::fs::rename(temp_path, &final_path).context("failed to move temp file to final path")?;
 
   // Basic integrity: size matches expected when known.
   if let Some(len) = total {
@@ -428,26 +748,245 @@ async fn attempt_download_once(
   Ok(())
 }
 
+struct ProbeInfo {
+  supports_ranges: Option<bool>,
+  content_length: Option<i64>,
+  etag: Option<String>,
+  last_modified: Option<String>,
+  content_disposition: Option<String>,
+  content_type: Option<String>,
+  content_encoding: Option<String>,
+}
+
+/// A proxy URL with its stored credentials attached.
+pub(super) fn proxy_config(db: &Db, url: &str) -> anyhow::Result<ProxyConfig> {
+  let proxy = ProxyConfig::parse(url)?;
+  let stored = db.get_proxy_credentials(&proxy.key())?;
+  Ok(proxy.with_credentials(stored))
+}
+
+fn header_string(headers: &HeaderMap, name: &str) -> Option<String> {
+  headers.get(name).and_then(|v| v.to_str().ok()).map(|s| s.to_string())
+}
+
+fn probe_info(headers: &HeaderMap, supports_ranges: Option<bool>, content_length: Option<i64>) -> ProbeInfo {
+  ProbeInfo {
+    supports_ranges,
+    content_length,
+    etag: header_string(headers, "etag"),
+    last_modified: header_string(headers, "last-modified"),
+    content_disposition: header_string(headers, "content-disposition"),
+    content_type: header_string(headers, "content-type"),
+    content_encoding: header_string(headers, "content-encoding"),
+  }
+}
+
+/// Learns size/validators/range support for `url`. Tries the method that last worked for the
+/// host, then the other one if the server rejects it with an HTTP error (many CDNs and
+/// signed-URL hosts answer 403/405 to HEAD but serve GET fine).
+///
+/// A ranged GET answered with 200 carries the whole body; it is returned so the single stream
+/// consumes it instead of asking again. `Ok(None)` means the job was paused while probing.
+#[allow(clippy::too_many_arguments)]
+async fn probe_remote(
+  transport: &Transport,
+  rules: &crate::model::RulesSnapshot,
+  client: &reqwest::Client,
+  timeouts: Timeouts,
+  url: &Url,
+  limits: ProbeLimits<'_>,
+  stats: &RuntimeStats,
+  control_rx: &mut watch::Receiver<JobControl>,
+) -> anyhow::Result<Option<(ProbeInfo, Option<reqwest::Response>)>> {
+  let headers = transport.request_headers(rules, url);
+  let host = url.host_str().unwrap_or_default();
+  let order = match transport.probe_method(host) {
+    ProbeMethod::Head => [ProbeMethod::Head, ProbeMethod::RangedGet],
+    ProbeMethod::RangedGet => [ProbeMethod::RangedGet, ProbeMethod::Head],
+  };
+
+  let mut last_status = 0;
+  for method in order {
+    let request = || match method {
+      ProbeMethod::Head => client.head(url.clone()).headers(headers.clone()),
+      ProbeMethod::RangedGet => client
+        .get(url.clone())
+        .headers(headers.clone())
+        .header(RANGE, HeaderValue::from_static("bytes=0-0")),
+    };
+    let resp = match send_respecting_rate_limit(transport, rules, request, timeouts, limits, stats, control_rx).await? {
+      Sent::Response(Ok(r)) => r,
+      Sent::Response(Err(e)) => {
+        // Network-level failures would hit the fallback just the same.
+        set_send_error(stats, &e);
+        anyhow::bail!("probe failed: {e}");
+      }
+      Sent::Paused => return Ok(None),
+    };
+
+    let status = resp.status();
+    let h = resp.headers();
+    let outcome = match method {
+      ProbeMethod::Head if status.is_success() => {
+        let supports = header_string(h, "accept-ranges").map(|s| s.to_ascii_lowercase().contains("bytes"));
+        let len = header_string(h, "content-length").and_then(|s| s.parse::<i64>().ok());
+        Some((probe_info(h, supports, len), None))
+      }
+      ProbeMethod::RangedGet if status.as_u16() == 206 => {
+        let total = header_string(h, "content-range")
+          .and_then(|v| range::parse_content_range(&v))
+          .and_then(|cr| cr.total);
+        Some((probe_info(h, Some(true), total), None))
+      }
+      ProbeMethod::RangedGet if status.as_u16() == 416 => {
+        // `bytes */0`: the resource exists but is empty.
+        let total = header_string(h, "content-range").and_then(|v| range::unsatisfied_length(&v));
+        total.map(|t| (probe_info(h, Some(true), Some(t)), None))
+      }
+      ProbeMethod::RangedGet if status.is_success() => {
+        // Range ignored: this *is* the download, so keep the response open.
+        let info = probe_info(h, Some(false), resp.content_length().map(|l| l as i64));
+        Some((info, Some(resp)))
+      }
+      _ => None,
+    };
+    if let Some(probe) = outcome {
+      transport.remember_probe_method(host, method);
+      if probe.0.supports_ranges.is_none() || (probe.0.supports_ranges == Some(true) && probe.0.content_length.is_none()) {
+        let ranged = || {
+          client
+            .get(url.clone())
+            .headers(headers.clone())
+            .header(RANGE, HeaderValue::from_static("bytes=0-0"))
+        };
+        return confirm_ranges(transport, rules, ranged, timeouts, limits, probe.0, stats, control_rx).await;
+      }
+      return Ok(Some(probe));
+    }
+    last_status = status.as_u16();
+    tracing::info!(host = %host, ?method, status = last_status, "probe rejected");
+  }
+
+  set_http_error(stats, last_status, None);
+  anyhow::bail!("http {last_status}")
+}
+
+/// HEAD left range support or the total size open (no `Accept-Ranges`, or no `Content-Length`):
+/// ask for the first byte and trust what the server actually does.
+#[allow(clippy::too_many_arguments)]
+async fn confirm_ranges(
+  transport: &Transport,
+  rules: &crate::model::RulesSnapshot,
+  ranged_request: impl Fn() -> reqwest::RequestBuilder,
+  timeouts: Timeouts,
+  limits: ProbeLimits<'_>,
+  mut info: ProbeInfo,
+  stats: &RuntimeStats,
+  control_rx: &mut watch::Receiver<JobControl>,
+) -> anyhow::Result<Option<(ProbeInfo, Option<reqwest::Response>)>> {
+  let resp = match send_respecting_rate_limit(transport, rules, ranged_request, timeouts, limits, stats, control_rx).await? {
+    Sent::Response(Ok(r)) => r,
+    // HEAD already succeeded; an inconclusive answer here just leaves its result as is.
+    Sent::Response(Err(_)) => return Ok(Some((info, None))),
+    Sent::Paused => return Ok(None),
+  };
+  match resp.status().as_u16() {
+    206 => {
+      let total = header_string(resp.headers(), "content-range")
+        .and_then(|v| range::parse_content_range(&v))
+        .and_then(|cr| cr.total);
+      info.supports_ranges = Some(true);
+      info.content_length = info.content_length.or(total);
+      Ok(Some((info, None)))
+    }
+    200 => {
+      // Range ignored: keep the full response as the download body.
+      info.supports_ranges = Some(false);
+      info.content_length = info.content_length.or(resp.content_length().map(|l| l as i64));
+      Ok(Some((info, Some(resp))))
+    }
+    _ => Ok(Some((info, None))),
+  }
+}
+
+enum Sent {
+  Response(Result<reqwest::Response, SendError>),
+  Paused,
+}
+
+/// Where a probe's 429/503 answers count: they lower the host's segment cap just as a
+/// segment's would, so the download that follows does not open the full segment count.
+#[derive(Clone, Copy)]
+struct ProbeLimits<'a> {
+  throttle: &'a HostThrottle,
+  host: &'a str,
+  /// Segments the download would open without a cap.
+  segments: usize,
+}
+
+/// Sends `request()` again for as long as the server answers 429/503, honoring its backoff.
+async fn send_respecting_rate_limit(
+  transport: &Transport,
+  rules: &crate::model::RulesSnapshot,
+  request: impl Fn() -> reqwest::RequestBuilder,
+  timeouts: Timeouts,
+  limits: ProbeLimits<'_>,
+  stats: &RuntimeStats,
+  control_rx: &mut watch::Receiver<JobControl>,
+) -> anyhow::Result<Sent> {
+  let mut attempt: usize = 0;
+  loop {
+    let resp = transport.send_with_auth(rules, request(), &timeouts).await;
+    let limited = match &resp {
+      Ok(r) if rate_limit::is_rate_limited(r.status().as_u16()) => {
+        Some((r.status().as_u16(), rate_limit_delay(r.headers(), attempt)))
+      }
+      _ => None,
+    };
+    let Some((status, delay)) = limited else { return Ok(Sent::Response(resp)) };
+    if attempt == 0 {
+      let active = limits.throttle.segment_cap(limits.host).unwrap_or(limits.segments);
+      let cap = limits.throttle.record_rate_limited(limits.host, active);
+      tracing::info!(host = %limits.host, status, cap, "probe rate limited; lowering segment cap");
+    }
+    if attempt >= MAX_RATE_LIMIT_RETRIES {
+      set_http_error(stats, status, None);
+      anyhow::bail!("http {status} (rate limited)");
+    }
+    attempt += 1;
+    if !wait_out_rate_limit(stats, control_rx, status, delay).await {
+      return Ok(Sent::Paused);
+    }
+  }
+}
+
 async fn download_single(
   db: &Db,
+  transport: &Transport,
   client: reqwest::Client,
+  timeouts: Timeouts,
   rules: &crate::model::RulesSnapshot,
   url: &Url,
   temp_path: &Path,
   download_id: &str,
   content_length: Option<i64>,
-  supports_ranges: bool,
+  mut supports_ranges: bool,
+  mut if_range: Option<HeaderValue>,
+  mut probe_body: Option<reqwest::Response>,
   limiter: &BandwidthLimiter,
   mut control_rx: watch::Receiver<JobControl>,
   stats: RuntimeStats,
 ) -> anyhow::Result<()> {
-  // If we receive no bytes for this long while "DOWNLOADING", assume a stall (e.g. rate limit)
-  // and retry the request with exponential backoff starting at the last written offset.
-  let stall_timeout = Duration::from_secs(20);
+  // If we receive no bytes for the read idle timeout while "DOWNLOADING", assume a stall (e.g. rate
+  // limit) and retry the request with exponential backoff starting at the last written offset.
+  let stall_timeout = timeouts.read_idle;
   let mut stall_attempt: usize = 0;
-
-  let mut headers = HeaderMap::new();
-  Transport::apply_header_rules(rules, &mut headers, url);
+  let mut rate_limit_attempt: usize = 0;
+  // Bodies that end short of the known length are re-requested from where they stopped, with
+  // backoff. Progress does not refill this budget: a server that keeps truncating (or answers
+  // with empty ranges) would otherwise be asked forever.
+  let max_retries: usize = 10;
+  let mut early_eof_attempt: usize = 0;
 
   let mut start = db
     .get_download(download_id)?
@@ -463,13 +1002,6 @@ async fn download_single(
     db.update_download_bytes(download_id, 0)?;
   }
 
-  if start > 0 && supports_ranges {
-    headers.insert(
-      RANGE,
-      HeaderValue::from_str(&format!("bytes={start}-")).unwrap(),
-    );
-  }
-
   let file = OpenOptions::new().write(true).open(temp_path)?;
   let mut offset = start as u64;
   let mut bytes_total = start;
@@ -480,26 +1012,87 @@ async fn download_single(
 
   loop {
     // (Re)issue request from current offset.
-    let mut headers = HeaderMap::new();
-    Transport::apply_header_rules(rules, &mut headers, url);
-    if bytes_total > 0 && supports_ranges {
+    let mut headers = transport.request_headers(rules, url);
+    let ranged = bytes_total > 0 && supports_ranges;
+    if ranged {
       headers.insert(
         RANGE,
         HeaderValue::from_str(&format!("bytes={bytes_total}-")).unwrap(),
       );
+      if let Some(v) = if_range.clone() {
+        headers.insert(IF_RANGE, v);
+      }
     }
 
-    let resp = client.get(url.clone()).headers(headers).send().await;
+    // A probe that already returned the full body is consumed instead of asking again.
+    let resp = match probe_body.take().filter(|_| !ranged) {
+      Some(body) => Ok(body),
+      None => transport.send_with_auth(rules, client.get(url.clone()).headers(headers), &timeouts).await,
+    };
     let resp = match resp {
       Ok(r) => r,
       Err(e) => {
-        set_reqwest_error(&stats, &e);
+        set_send_error(&stats, &e);
         anyhow::bail!(e);
       }
     };
+    let status = resp.status().as_u16();
+    if rate_limit::is_rate_limited(status) && rate_limit_attempt < MAX_RATE_LIMIT_RETRIES {
+      let delay = rate_limit_delay(resp.headers(), rate_limit_attempt);
+      rate_limit_attempt += 1;
+      if !wait_out_rate_limit(&stats, &mut control_rx, status, delay).await {
+        db.update_download_bytes(download_id, bytes_total)?;
+        db.update_download_status(download_id, DownloadStatus::Paused, None, None)?;
+        *stats.status.lock() = DownloadStatus::Paused;
+        return Ok(());
+      }
+      last_progress = Instant::now();
+      continue;
+    }
     if resp.status().is_client_error() || resp.status().is_server_error() {
-      set_http_error(&stats, resp.status().as_u16(), None);
-      anyhow::bail!("http {}", resp.status().as_u16());
+      set_http_error(&stats, status, None);
+      anyhow::bail!("http {status}");
+    }
+
+    if ranged && status == 206 {
+      match range::check_partial(resp.headers(), bytes_total, None, content_length) {
+        Ok(_) => {}
+        Err(m) if m.is_remote_change() => {
+          set_remote_changed(&stats, &m.to_string());
+          anyhow::bail!("remote changed: {m}");
+        }
+        Err(m) => {
+          // Never write a range we cannot place; stop trusting ranges for this download.
+          tracing::warn!(download_id=%download_id, error=%m, "invalid ranged response; restarting from zero");
+          supports_ranges = false;
+          restart_from_zero(db, download_id, &file, content_length, &stats)?;
+          offset = 0;
+          bytes_total = 0;
+          continue;
+        }
+      }
+    } else if status != 206 {
+      // Full body: either we did not ask for a range, the server ignored it, or If-Range
+      // decided the file changed. In every case the body starts at byte 0.
+      if let (Some(expected), Some(got)) = (content_length, resp.content_length()) {
+        if expected != got as i64 {
+          let msg = format!("remote size changed ({expected} -> {got} bytes)");
+          set_remote_changed(&stats, &msg);
+          anyhow::bail!("remote changed: {msg}");
+        }
+      }
+      if ranged {
+        tracing::info!(download_id=%download_id, "server answered ranged request with full body; restarting from zero");
+        restart_from_zero(db, download_id, &file, content_length, &stats)?;
+        offset = 0;
+        bytes_total = 0;
+        let etag = resp.headers().get(ETAG).and_then(|v| v.to_str().ok());
+        let last_modified = resp.headers().get(LAST_MODIFIED).and_then(|v| v.to_str().ok());
+        if etag.is_some() || last_modified.is_some() {
+          db.update_download_validators(download_id, etag, last_modified)?;
+          if_range = range::if_range_value(etag, last_modified);
+        }
+      }
     }
 
     let mut stream = resp.bytes_stream();
@@ -524,7 +1117,8 @@ async fn download_single(
           stall_attempt = stall_attempt.saturating_add(1);
           let until_ms = now_unix_ms() + delay_ms as i64;
           stats.backoff_until_ms.store(until_ms, Ordering::Relaxed);
-          *stats.status_detail.lock() = Some("Stalled (possible rate limit). Retrying…".to_string());
+          *stats.status_detail.lock() =
+            Some(format!("No data for {}s (possible rate limit). Retrying…", stall_timeout.as_secs()));
           // Sleep, but allow pause/cancel.
           tokio::select! {
             _ = tokio::time::sleep(Duration::from_millis(delay_ms)) => {}
@@ -546,6 +1140,31 @@ async fn download_single(
             // End of stream: persist final bytes and do basic sanity.
             db.update_download_bytes(download_id, bytes_total)?;
             if let Some(len) = content_length {
+              if bytes_total < len && supports_ranges {
+                if early_eof_attempt >= max_retries {
+                  *stats.error_code.lock() = Some(ErrorCode::Unknown);
+                  *stats.error_message.lock() =
+                    Some(format!("Server kept ending the body early ({bytes_total} of {len} bytes)"));
+                  anyhow::bail!("body ended early {max_retries} times");
+                }
+                // Body ended early; pick up the rest with a validated ranged request.
+                tracing::warn!(download_id=%download_id, bytes_total, len, "single stream ended early; resuming");
+                let delay_ms = compute_backoff_delay_ms(early_eof_attempt);
+                early_eof_attempt += 1;
+                stats.backoff_until_ms.store(now_unix_ms() + delay_ms as i64, Ordering::Relaxed);
+                *stats.status_detail.lock() = Some("Download ended early. Resuming…".to_string());
+                tokio::select! {
+                  _ = tokio::time::sleep(Duration::from_millis(delay_ms)) => {}
+                  _ = wait_for_stop(&mut control_rx) => {
+                    db.update_download_status(download_id, DownloadStatus::Paused, None, None)?;
+                    *stats.status.lock() = DownloadStatus::Paused;
+                    return Ok(());
+                  }
+                }
+                stats.backoff_until_ms.store(0, Ordering::Relaxed);
+                last_progress = Instant::now();
+                break;
+              }
               if bytes_total != len {
                 tracing::warn!(download_id=%download_id, bytes_total, len, "single download length mismatch");
               }
@@ -571,6 +1190,7 @@ async fn download_single(
           stats.backoff_until_ms.store(0, Ordering::Relaxed);
           *stats.status_detail.lock() = None;
           stall_attempt = 0; // successful progress resets backoff
+          rate_limit_attempt = 0;
           if matches!(*control_rx.borrow(), JobControl::Pause | JobControl::Cancel) {
             db.update_download_bytes(download_id, bytes_total)?;
             db.update_download_status(download_id, DownloadStatus::Paused, None, None)?;
@@ -590,23 +1210,59 @@ async fn download_single(
   // unreachable
 }
 
+/// The clients the segments of one download use. Segments take turns over `rotation` (`None`
+/// is DIRECT) and fall back to the members after theirs when a proxy refuses connections.
+struct SegmentRoutes {
+  rotation: Vec<(Option<String>, reqwest::Client)>,
+  fallbacks: Vec<(Option<String>, reqwest::Client)>,
+  /// Pool members that fail are marked down for every download, not just this one.
+  pooled: bool,
+}
+
+impl SegmentRoutes {
+  fn for_segment(&self, idx: usize) -> SegmentRoute {
+    let own = self.rotation[idx % self.rotation.len()].clone();
+    let rest = self.fallbacks.iter().filter(|(member, _)| *member != own.0).cloned();
+    SegmentRoute {
+      clients: std::iter::once(own.clone()).chain(rest).collect(),
+      pooled: self.pooled,
+    }
+  }
+}
+
+/// One segment's clients in the order it tries them.
+struct SegmentRoute {
+  clients: Vec<(Option<String>, reqwest::Client)>,
+  pooled: bool,
+}
+
 async fn download_multipart(
   db: &Db,
-  client: reqwest::Client,
+  transport: &Transport,
+  routes: SegmentRoutes,
+  timeouts: Timeouts,
+  throttle: &HostThrottle,
   rules: &crate::model::RulesSnapshot,
   url: &Url,
   temp_path: &Path,
   download_id: &str,
   content_length: i64,
+  if_range: Option<HeaderValue>,
+  policy: &MultipartPolicy,
   warmup_bps: f64,
   limiter: &BandwidthLimiter,
   control_rx: watch::Receiver<JobControl>,
   stats: RuntimeStats,
 ) -> anyhow::Result<()> {
+  // Codes left over from the probe phase must not be mistaken for segment failures.
+  *stats.error_code.lock() = None;
+
   // Create or load segments.
+  let host = url.host_str().unwrap_or_default().to_string();
+  let segment_cap = throttle.segment_cap(&host);
   let existing = db.list_segments(download_id)?;
   let segments = if existing.is_empty() {
-    let planned = plan_segments(content_length, warmup_bps);
+    let planned = plan_segments(content_length, policy, warmup_bps, segment_cap);
     db.replace_segments(download_id, planned)?;
     db.list_segments(download_id)?
   } else {
@@ -620,12 +1276,20 @@ async fn download_multipart(
   stats.bytes.store(initial, Ordering::Relaxed);
   let total_bytes = stats.bytes.clone();
 
+  // Segments resumed from an earlier plan may outnumber what the host currently tolerates.
+  let pending = segments.iter().filter(|s| s.status != "COMPLETED").count();
+  let gate = SegmentGate::new(segment_cap.map_or(pending, |cap| pending.min(cap)));
+
   let mut join_handles = Vec::new();
-  for seg in segments.clone() {
-    let seg_client = client.clone();
+  for (idx, seg) in segments.clone().into_iter().enumerate() {
+    let seg_route = routes.for_segment(idx);
+    let seg_throttle = throttle.clone();
+    let seg_gate = gate.clone();
+    let seg_if_range = if_range.clone();
     let seg_url = url.clone();
     let seg_rules = rules.clone();
     let seg_db = db.clone();
+    let seg_transport = transport.clone();
     let seg_temp = temp_path.to_path_buf();
     let seg_download_id = download_id.to_string();
     let seg_limiter = limiter.clone();
@@ -636,12 +1300,18 @@ async fn download_multipart(
     join_handles.push(tauri::async_runtime::spawn(async move {
       if let Err(e) = download_segment(
         &seg_db,
-        seg_client,
+        &seg_transport,
+        seg_route,
+        timeouts,
+        &seg_throttle,
+        &seg_gate,
         &seg_rules,
         &seg_url,
         &seg_temp,
         &seg_download_id,
         seg,
+        content_length,
+        seg_if_range,
         &seg_limiter,
         seg_control,
         total_bytes,
@@ -674,9 +1344,13 @@ async fn download_multipart(
         }
         let segs = db.list_segments(download_id)?;
         if segs.iter().any(|s| s.status == "ERROR") {
-          // A segment errored: treat as range failure and let caller downgrade.
-          *stats.error_code.lock() = Some(ErrorCode::RangeUnsupported);
-          *stats.error_message.lock() = Some("Segmented download failed (range unsupported)".to_string());
+          // A segment errored: treat as range failure and let caller downgrade, unless the
+          // segment already classified it as something a single stream would not fix.
+          let mut code = stats.error_code.lock();
+          if !matches!(*code, Some(ErrorCode::RemoteChanged | ErrorCode::RateLimited)) {
+            *code = Some(ErrorCode::RangeUnsupported);
+            *stats.error_message.lock() = Some("Segmented download failed (range unsupported)".to_string());
+          }
           anyhow::bail!("segment error");
         }
         let all_done = segs.iter().all(|s| s.status == "COMPLETED");
@@ -693,37 +1367,52 @@ async fn download_multipart(
     let _ = h.await;
   }
 
+  if !gate.was_rate_limited() {
+    throttle.record_clean_run(&host);
+  }
+
   Ok(())
 }
 
-fn plan_segments(content_length: i64, warmup_bps: f64) -> Vec<SegmentRow> {
-  // Default segment size 16MiB; cap concurrency 16. Adjust concurrency based on observed warmup throughput.
-  let seg_size: i64 = 16 * 1024 * 1024;
-  let base = ((content_length + seg_size - 1) / seg_size).clamp(2, 16) as i64;
+pub(super) fn plan_segments(
+  content_length: i64,
+  policy: &MultipartPolicy,
+  warmup_bps: f64,
+  segment_cap: Option<usize>,
+) -> Vec<SegmentRow> {
+  // Start from the configured segment size, then adjust the count to observed warmup throughput.
+  let max = policy.max_segments.max(1) as i64;
+  let seg_size = policy.segment_size_bytes.max(1);
+  let base = ((content_length + seg_size - 1) / seg_size).clamp(2.min(max), max);
 
   let desired = if warmup_bps <= 0.0 {
     base
   } else if warmup_bps > 20.0 * 1024.0 * 1024.0 {
-    base.max(8).min(16)
+    base.max(8).min(max)
   } else if warmup_bps > 8.0 * 1024.0 * 1024.0 {
-    base.max(6).min(12)
+    base.max(6).min(max.min(12))
   } else if warmup_bps > 3.0 * 1024.0 * 1024.0 {
-    base.max(4).min(8)
+    base.max(4).min(max.min(8))
   } else {
     base.min(4)
   };
-  let count = desired as i64;
-
+  // Hosts that recently answered 429/503 get fewer segments.
+  let count = segment_cap
+    .map_or(desired, |cap| desired.min(cap.max(1) as i64))
+    .min(content_length)
+    .max(1);
+
+  // Split evenly so no segment starts past the end of a file smaller than count * seg_size.
+  let span = (content_length + count - 1) / count;
   let mut segs = Vec::new();
   for i in 0..count {
-    let start = i * seg_size;
-    let mut end = ((i + 1) * seg_size) - 1;
-    if i == count - 1 {
-      end = content_length - 1;
+    let start = i * span;
+    if start >= content_length {
+      break;
     }
     segs.push(SegmentRow {
       range_start: start,
-      range_end: end,
+      range_end: ((i + 1) * span).min(content_length) - 1,
       bytes_done: 0,
       status: "ACTIVE".to_string(),
       last_error: None,
@@ -733,15 +1422,16 @@ fn plan_segments(content_length: i64, warmup_bps: f64) -> Vec<SegmentRow> {
 }
 
 async fn warmup_probe_bps(
+  transport: &Transport,
   client: &reqwest::Client,
+  timeouts: Timeouts,
   rules: &crate::model::RulesSnapshot,
   url: &Url,
 ) -> Option<f64> {
-  let mut headers = HeaderMap::new();
-  Transport::apply_header_rules(rules, &mut headers, url);
+  let mut headers = transport.request_headers(rules, url);
   headers.insert(RANGE, HeaderValue::from_static("bytes=0-1048575"));
   let start = Instant::now();
-  let resp = client.get(url.clone()).headers(headers).send().await.ok()?;
+  let resp = transport.send_with_auth(rules, client.get(url.clone()).headers(headers), &timeouts).await.ok()?;
   if resp.status().as_u16() != 206 {
     return None;
   }
@@ -763,12 +1453,18 @@ async fn warmup_probe_bps(
 
 async fn download_segment(
   db: &Db,
-  client: reqwest::Client,
+  transport: &Transport,
+  route: SegmentRoute,
+  timeouts: Timeouts,
+  throttle: &HostThrottle,
+  gate: &SegmentGate,
   rules: &crate::model::RulesSnapshot,
   url: &Url,
   temp_path: &Path,
   _download_id: &str,
   seg: SegmentRowWithId,
+  content_length: i64,
+  if_range: Option<HeaderValue>,
   limiter: &BandwidthLimiter,
   mut control_rx: watch::Receiver<JobControl>,
   total_bytes: Arc<AtomicI64>,
@@ -778,12 +1474,19 @@ async fn download_segment(
     return Ok(());
   }
 
-  // Similar to single stream: if a segment receives no bytes for a while, treat it as a stall
-  // and retry with exponential backoff from the current offset.
-  let stall_timeout = Duration::from_secs(20);
+  // Similar to single stream: if a segment receives no bytes for the read idle timeout, treat it
+  // as a stall and retry with exponential backoff from the current offset.
+  let stall_timeout = timeouts.read_idle;
   let max_retries: usize = 10;
   let mut stall_attempt: usize = 0;
+  let mut rate_limit_attempt: usize = 0;
+  // Ranges that end short are re-requested with backoff; like the single stream, progress does
+  // not refill this budget.
+  let mut early_eof_attempt: usize = 0;
   let mut last_progress = Instant::now();
+  let host = url.host_str().unwrap_or_default().to_string();
+  let mut permit = None;
+  let mut current = 0;
 
   let file = OpenOptions::new().write(true).open(temp_path)?;
   let mut bytes_done = seg.bytes_done;
@@ -803,19 +1506,43 @@ async fn download_segment(
       return Ok(());
     }
 
-    let mut headers = HeaderMap::new();
-    Transport::apply_header_rules(rules, &mut headers, url);
+    if permit.is_none() {
+      tokio::select! {
+        p = gate.acquire() => permit = Some(p),
+        _ = wait_for_stop(&mut control_rx) => {
+          db.update_segment_bytes(seg.id, bytes_done, "ACTIVE", None)?;
+          return Ok(());
+        }
+      }
+      last_progress = Instant::now();
+    }
+
+    let mut headers = transport.request_headers(rules, url);
     headers.insert(
       RANGE,
       HeaderValue::from_str(&format!("bytes={start}-{}", seg.range_end)).unwrap(),
     );
+    if let Some(v) = if_range.clone() {
+      headers.insert(IF_RANGE, v);
+    }
 
-    let resp = match client.get(url.clone()).headers(headers).send().await {
+    let client = &route.clients[current].1;
+    let resp = match transport.send_with_auth(rules, client.get(url.clone()).headers(headers), &timeouts).await {
       Ok(r) => r,
+      Err(e) if e.is_connect() && current + 1 < route.clients.len() => {
+        let member = route.clients[current].0.as_deref();
+        if let (true, Some(m)) = (route.pooled, member) {
+          transport.pools().mark_down(m, &e.to_string());
+        }
+        let label = member.map_or_else(|| "DIRECT".to_string(), proxy::display_url);
+        tracing::info!(segment_id=%seg.id, proxy=%label, error=%e, "segment proxy failed; trying next");
+        current += 1;
+        continue;
+      }
       Err(e) => {
         tracing::warn!(segment_id=%seg.id, error=%e, "segment request failed; retrying");
         if stall_attempt >= max_retries {
-          set_reqwest_error(&stats, &e);
+          set_send_error(&stats, &e);
           db.update_segment_bytes(seg.id, bytes_done, "ERROR", Some(&e.to_string()))?;
           anyhow::bail!(e);
         }
@@ -830,7 +1557,29 @@ async fn download_segment(
       }
     };
 
-    if resp.status().as_u16() != 206 {
+    let status = resp.status().as_u16();
+    if rate_limit::is_rate_limited(status) {
+      if rate_limit_attempt >= MAX_RATE_LIMIT_RETRIES {
+        set_http_error(&stats, status, None);
+        db.update_segment_bytes(seg.id, bytes_done, "ERROR", Some("rate limited (max retries)"))?;
+        anyhow::bail!("segment rate limited");
+      }
+      let delay = rate_limit_delay(resp.headers(), rate_limit_attempt);
+      rate_limit_attempt = rate_limit_attempt.saturating_add(1);
+      // Back off for this host: fewer segments now and for the next multipart plan.
+      let cap = throttle.record_rate_limited(&host, gate.capacity());
+      if let Some(p) = permit.take() {
+        gate.shrink_to(cap, p);
+      }
+      tracing::info!(segment_id=%seg.id, status, delay_ms = delay.as_millis() as u64, cap, "segment rate limited");
+      if !wait_out_rate_limit(&stats, &mut control_rx, status, delay).await {
+        db.update_segment_bytes(seg.id, bytes_done, "ACTIVE", None)?;
+        return Ok(());
+      }
+      continue;
+    }
+
+    if status != 206 {
       // Range not supported or server downgraded. Let caller downgrade.
       *stats.error_code.lock() = Some(ErrorCode::RangeUnsupported);
       *stats.error_message.lock() = Some("Server does not support ranged requests".to_string());
@@ -838,6 +1587,17 @@ async fn download_segment(
       anyhow::bail!("range unsupported");
     }
 
+    if let Err(m) = range::check_partial(resp.headers(), start, Some(seg.range_end), Some(content_length)) {
+      if m.is_remote_change() {
+        set_remote_changed(&stats, &m.to_string());
+      } else {
+        *stats.error_code.lock() = Some(ErrorCode::RangeUnsupported);
+        *stats.error_message.lock() = Some(format!("Invalid ranged response: {m}"));
+      }
+      db.update_segment_bytes(seg.id, bytes_done, "ERROR", Some(&m.to_string()))?;
+      anyhow::bail!("invalid ranged response: {m}");
+    }
+
     let mut stream = resp.bytes_stream();
     let mut offset = start as u64;
 
@@ -855,14 +1615,17 @@ async fn download_segment(
         }
         _ = tokio::time::sleep_until(stall_deadline) => {
           if stall_attempt >= max_retries {
+            let e = SendError::ReadIdleTimeout(stall_timeout);
+            set_send_error(&stats, &e);
             db.update_segment_bytes(seg.id, bytes_done, "ERROR", Some("segment stalled (max retries)"))?;
-            anyhow::bail!("segment stalled");
+            anyhow::bail!("segment stalled: {e}");
           }
           let delay_ms = compute_backoff_delay_ms(stall_attempt);
           stall_attempt = stall_attempt.saturating_add(1);
           let until_ms = now_unix_ms() + delay_ms as i64;
           stats.backoff_until_ms.store(until_ms, Ordering::Relaxed);
-          *stats.status_detail.lock() = Some("Stalled (possible rate limit). Retrying…".to_string());
+          *stats.status_detail.lock() =
+            Some(format!("No data for {}s (possible rate limit). Retrying…", stall_timeout.as_secs()));
           tokio::time::sleep(Duration::from_millis(delay_ms)).await;
           stats.backoff_until_ms.store(0, Ordering::Relaxed);
           // Break inner loop to re-issue the request at the next outer iteration.
@@ -876,6 +1639,27 @@ async fn download_segment(
               db.update_segment_bytes(seg.id, bytes_done, "COMPLETED", None)?;
               return Ok(());
             }
+            if early_eof_attempt >= max_retries {
+              *stats.error_code.lock() = Some(ErrorCode::Unknown);
+              *stats.error_message.lock() =
+                Some(format!("Server kept ending a segment early ({bytes_done} of {expected} bytes)"));
+              db.update_segment_bytes(seg.id, bytes_done, "ERROR", Some("segment ended early (max retries)"))?;
+              anyhow::bail!("segment body ended early {max_retries} times");
+            }
+            tracing::warn!(segment_id=%seg.id, bytes_done, expected, "segment stream ended early; resuming");
+            let delay_ms = compute_backoff_delay_ms(early_eof_attempt);
+            early_eof_attempt += 1;
+            stats.backoff_until_ms.store(now_unix_ms() + delay_ms as i64, Ordering::Relaxed);
+            *stats.status_detail.lock() = Some("Segment ended early. Resuming…".to_string());
+            tokio::select! {
+              _ = tokio::time::sleep(Duration::from_millis(delay_ms)) => {}
+              _ = wait_for_stop(&mut control_rx) => {
+                db.update_segment_bytes(seg.id, bytes_done, "ACTIVE", None)?;
+                return Ok(());
+              }
+            }
+            stats.backoff_until_ms.store(0, Ordering::Relaxed);
+            last_progress = Instant::now();
             break;
           };
 
@@ -891,11 +1675,19 @@ async fn download_segment(
           stats.backoff_until_ms.store(0, Ordering::Relaxed);
           *stats.status_detail.lock() = None;
           stall_attempt = 0;
+          rate_limit_attempt = 0;
 
           if matches!(*control_rx.borrow(), JobControl::Pause | JobControl::Cancel) {
             db.update_segment_bytes(seg.id, bytes_done, "ACTIVE", None)?;
             return Ok(());
           }
+          // Never write past the segment, even if the server sends more than it announced.
+          let remaining = (seg.range_end - seg.range_start + 1 - bytes_done).max(0) as usize;
+          let chunk = chunk.slice(..chunk.len().min(remaining));
+          if chunk.is_empty() {
+            db.update_segment_bytes(seg.id, bytes_done, "COMPLETED", None)?;
+            return Ok(());
+          }
           limiter.acquire(chunk.len()).await;
           write_at_all(&file, offset, &chunk)?;
           offset += chunk.len() as u64;
@@ -908,8 +1700,10 @@ async fn download_segment(
   }
 }
 
-fn set_http_error(stats: &RuntimeStats, status: u16, body: Option<String>) {
-  let code = if (400..500).contains(&status) {
+pub(super) fn set_http_error(stats: &RuntimeStats, status: u16, body: Option<String>) {
+  let code = if rate_limit::is_rate_limited(status) {
+    ErrorCode::RateLimited
+  } else if (400..500).contains(&status) {
     ErrorCode::Http4xx
   } else if (500..600).contains(&status) {
     ErrorCode::Http5xx
@@ -920,13 +1714,38 @@ fn set_http_error(stats: &RuntimeStats, status: u16, body: Option<String>) {
   *stats.error_message.lock() = Some(body.unwrap_or_else(|| format!("HTTP {status}")));
 }
 
-fn set_reqwest_error(stats: &RuntimeStats, err: &reqwest::Error) {
+pub(super) fn set_remote_changed(stats: &RuntimeStats, detail: &str) {
+  *stats.error_code.lock() = Some(ErrorCode::RemoteChanged);
+  *stats.error_message.lock() = Some(format!("Remote changed ({detail})"));
+}
+
+/// Drop everything written so far; the next bytes written belong at offset 0.
+fn restart_from_zero(
+  db: &Db,
+  download_id: &str,
+  file: &std::fs::File,
+  content_length: Option<i64>,
+  stats: &RuntimeStats,
+) -> anyhow::Result<()> {
+  file.set_len(0).context("failed to truncate temp file")?;
+  if let Some(len) = content_length.filter(|l| *l > 0) {
+    file.set_len(len as u64).ok();
+  }
+  stats.bytes.store(0, Ordering::Relaxed);
+  stats.last_bytes.store(0, Ordering::Relaxed);
+  db.update_download_bytes(download_id, 0)
+}
+
+pub(super) fn set_send_error(stats: &RuntimeStats, err: &SendError) {
+  if let Some(reason) = err.tls_reason() {
+    *stats.error_code.lock() = Some(ErrorCode::TlsFail);
+    *stats.error_message.lock() = Some(format!("TLS handshake failed: {reason}"));
+    return;
+  }
   let code = if err.is_timeout() {
     ErrorCode::Timeout
   } else if err.is_connect() {
     ErrorCode::ConnectFail
-  } else if err.is_request() {
-    ErrorCode::Unknown
   } else {
     ErrorCode::Unknown
   };
@@ -934,7 +1753,7 @@ fn set_reqwest_error(stats: &RuntimeStats, err: &reqwest::Error) {
   *stats.error_message.lock() = Some(err.to_string());
 }
 
-fn format_code(code: ErrorCode) -> &'static str {
+pub(super) fn format_code(code: ErrorCode) -> &'static str {
   use ErrorCode::*;
   match code {
     DnsFail => "DNS_FAIL",
@@ -943,6 +1762,7 @@ fn format_code(code: ErrorCode) -> &'static str {
     Http4xx => "HTTP_4XX",
     Http5xx => "HTTP_5XX",
     Timeout => "TIMEOUT",
+    RateLimited => "RATE_LIMITED",
     RangeUnsupported => "RANGE_UNSUPPORTED",
     DiskFull => "DISK_FULL",
     RemoteChanged => "REMOTE_CHANGED",
@@ -954,3 +1774,250 @@ fn format_code(code: ErrorCode) -> &'static str {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MIB: i64 = 1024 * 1024;
+
+  fn policy(segment_size_bytes: i64, max_segments: usize) -> MultipartPolicy {
+    MultipartPolicy { min_size_bytes: 0, segment_size_bytes, max_segments }
+  }
+
+  fn assert_contiguous(segs: &[SegmentRow], len: i64) {
+    assert_eq!(segs.first().unwrap().range_start, 0);
+    assert_eq!(segs.last().unwrap().range_end, len - 1);
+    for w in segs.windows(2) {
+      assert_eq!(w[0].range_end + 1, w[1].range_start);
+    }
+  }
+
+  #[test]
+  fn plan_covers_small_files_without_empty_segments() {
+    let segs = plan_segments(3 * MIB, &policy(16 * MIB, 16), 0.0, None);
+    assert_eq!(segs.len(), 2);
+    assert_contiguous(&segs, 3 * MIB);
+
+    let segs = plan_segments(3, &policy(16 * MIB, 16), 50.0 * MIB as f64, None);
+    assert_eq!(segs.len(), 3);
+    assert_contiguous(&segs, 3);
+  }
+
+  #[test]
+  fn plan_respects_segment_size_max_and_host_cap() {
+    let segs = plan_segments(100 * MIB, &policy(10 * MIB, 16), 0.0, None);
+    assert_eq!(segs.len(), 10);
+    assert_contiguous(&segs, 100 * MIB);
+
+    assert_eq!(plan_segments(100 * MIB, &policy(10 * MIB, 4), 0.0, None).len(), 4);
+    assert_eq!(plan_segments(100 * MIB, &policy(10 * MIB, 16), 0.0, Some(3)).len(), 3);
+  }
+
+  #[tokio::test]
+  async fn rate_limited_probe_lowers_the_segment_cap() {
+    use axum::{response::IntoResponse, routing::head, Router};
+    use std::sync::atomic::AtomicUsize;
+
+    let hits = Arc::new(AtomicUsize::new(0));
+    let app = Router::new().route(
+      "/file.bin",
+      head(move || {
+        let first = hits.fetch_add(1, Ordering::SeqCst) == 0;
+        async move {
+          if first {
+            (axum::http::StatusCode::TOO_MANY_REQUESTS, [("retry-after", "0")]).into_response()
+          } else {
+            ([("accept-ranges", "bytes"), ("content-length", "1048576")]).into_response()
+          }
+        }
+      }),
+    );
+    let url = Url::parse(&format!("{}/file.bin", crate::transport::tests::serve(app).await)).unwrap();
+    let transport = Transport::new().unwrap();
+    let client = transport.client_for(&Default::default()).unwrap();
+    let rules = crate::transport::tests::rules_with(crate::transport::tests::local_rule());
+    let throttle = HostThrottle::new();
+    let limits = ProbeLimits { throttle: &throttle, host: "127.0.0.1", segments: 8 };
+    let (_control_tx, mut control_rx) = watch::channel(JobControl::Run);
+    let stats = RuntimeStats::new("d1".to_string());
+
+    let (info, _) = probe_remote(&transport, &rules, &client, Timeouts::default(), &url, limits, &stats, &mut control_rx)
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!((info.supports_ranges, info.content_length), (Some(true), Some(1048576)));
+    assert_eq!(throttle.segment_cap("127.0.0.1"), Some(4));
+  }
+
+  #[tokio::test]
+  async fn rejected_head_falls_back_to_a_ranged_get() {
+    use axum::{http::HeaderMap as Headers, routing::get, Router};
+
+    let app = Router::new().route(
+      "/file.bin",
+      get(|h: Headers| async move {
+        assert_eq!(h.get("range").and_then(|v| v.to_str().ok()), Some("bytes=0-0"));
+        (axum::http::StatusCode::PARTIAL_CONTENT, [("content-range", "bytes 0-0/5000"), ("etag", "\"v1\"")], "x")
+      })
+      .head(|| async { axum::http::StatusCode::METHOD_NOT_ALLOWED }),
+    );
+    let url = Url::parse(&format!("{}/file.bin", crate::transport::tests::serve(app).await)).unwrap();
+    let transport = Transport::new().unwrap();
+    let client = transport.client_for(&Default::default()).unwrap();
+    let rules = crate::transport::tests::rules_with(crate::transport::tests::local_rule());
+    let throttle = HostThrottle::new();
+    let limits = ProbeLimits { throttle: &throttle, host: "127.0.0.1", segments: 8 };
+    let (_control_tx, mut control_rx) = watch::channel(JobControl::Run);
+    let stats = RuntimeStats::new("d1".to_string());
+
+    let (info, body) =
+      probe_remote(&transport, &rules, &client, Timeouts::default(), &url, limits, &stats, &mut control_rx)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!((info.supports_ranges, info.content_length), (Some(true), Some(5000)));
+    assert_eq!(info.etag.as_deref(), Some("\"v1\""));
+    assert!(body.is_none());
+    // The next probe of this host starts with the method that worked.
+    assert_eq!(transport.probe_method("127.0.0.1"), ProbeMethod::RangedGet);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn a_server_that_keeps_truncating_fails_the_job() {
+    use axum::{http::HeaderMap as Headers, routing::get, Router};
+    use std::sync::atomic::AtomicUsize;
+
+    // Every ranged request for the rest of a 100-byte file comes back empty.
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = hits.clone();
+    let app = Router::new().route(
+      "/file.bin",
+      get(move |h: Headers| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        async move {
+          match h.get("range").and_then(|v| v.to_str().ok()).and_then(|v| v.strip_prefix("bytes=")) {
+            Some(from) => {
+              let from = from.trim_end_matches('-');
+              let range = format!("bytes {from}-99/100");
+              (axum::http::StatusCode::PARTIAL_CONTENT, [("content-range", range)], Vec::new())
+            }
+            None => {
+              (axum::http::StatusCode::OK, [("content-type", "application/octet-stream".to_string())], vec![7u8; 100])
+            }
+          }
+        }
+      }),
+    );
+    let url = Url::parse(&format!("{}/file.bin", crate::transport::tests::serve(app).await)).unwrap();
+
+    let (db, dir) = crate::transport::tests::test_db();
+    db.insert_download_skeleton("d1", url.as_str(), &dir.display().to_string(), false, None).unwrap();
+    let temp_path = dir.join(".zdmr-d1.part");
+    prepare_temp_file(&temp_path, Some(100)).unwrap();
+    // An earlier run got the first ten bytes.
+    db.update_download_bytes("d1", 10).unwrap();
+    let transport = Transport::new().unwrap();
+    let client = transport.client_for(&Default::default()).unwrap();
+    let rules = crate::transport::tests::rules_with(crate::transport::tests::local_rule());
+    let (_control_tx, control_rx) = watch::channel(JobControl::Run);
+    let stats = RuntimeStats::new("d1".to_string());
+
+    let result = download_single(
+      &db,
+      &transport,
+      client,
+      Timeouts::default(),
+      &rules,
+      &url,
+      &temp_path,
+      "d1",
+      Some(100),
+      true,
+      None,
+      None,
+      &BandwidthLimiter::new(0),
+      control_rx,
+      stats.clone(),
+    )
+    .await;
+    assert!(result.is_err());
+    assert!(matches!(*stats.error_code.lock(), Some(ErrorCode::Unknown)));
+    // The resume plus ten re-requests for the missing bytes, then it gives up.
+    assert_eq!(hits.load(Ordering::SeqCst), 11);
+  }
+
+  #[tokio::test]
+  async fn a_segment_moves_past_a_pool_member_that_refuses_connections() {
+    use axum::{http::HeaderMap as Headers, routing::get, Router};
+
+    let app = Router::new().route(
+      "/file.bin",
+      get(|h: Headers| async move {
+        let range = h.get("range").and_then(|v| v.to_str().ok()).unwrap_or_default().to_string();
+        assert_eq!(range, "bytes=0-99");
+        (axum::http::StatusCode::PARTIAL_CONTENT, [("content-range", "bytes 0-99/100")], vec![7u8; 100])
+      }),
+    );
+    let url = Url::parse(&format!("{}/file.bin", crate::transport::tests::serve(app).await)).unwrap();
+    // Nothing listens on this port once the listener is dropped.
+    let dead = format!("http://{}", std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap());
+
+    let (db, dir) = crate::transport::tests::test_db();
+    db.insert_download_skeleton("d1", url.as_str(), &dir.display().to_string(), false, None).unwrap();
+    db.replace_segments("d1", plan_segments(100, &policy(100, 1), 0.0, None)).unwrap();
+    let seg = db.list_segments("d1").unwrap().remove(0);
+    let temp_path = dir.join(".zdmr-d1.part");
+    prepare_temp_file(&temp_path, Some(100)).unwrap();
+
+    let transport = Transport::new().unwrap();
+    let via_dead = crate::transport::ClientProfile::default().with_proxy(Some(ProxyConfig::parse(&dead).unwrap()));
+    let routes = SegmentRoutes {
+      rotation: vec![(Some(dead.clone()), transport.client_for(&via_dead).unwrap())],
+      fallbacks: vec![(None, transport.client_for(&Default::default()).unwrap())],
+      pooled: true,
+    };
+    let rules = crate::transport::tests::rules_with(crate::transport::tests::local_rule());
+    let (_control_tx, control_rx) = watch::channel(JobControl::Run);
+    let stats = RuntimeStats::new("d1".to_string());
+
+    download_segment(
+      &db,
+      &transport,
+      routes.for_segment(0),
+      Timeouts::default(),
+      &HostThrottle::new(),
+      &SegmentGate::new(1),
+      &rules,
+      &url,
+      &temp_path,
+      "d1",
+      seg,
+      100,
+      None,
+      &BandwidthLimiter::new(0),
+      control_rx,
+      Arc::new(AtomicI64::new(0)),
+      stats,
+    )
+    .await
+    .unwrap();
+    assert_eq!(db.list_segments("d1").unwrap()[0].status, "COMPLETED");
+    assert_eq!(std::fs::read(&temp_path).unwrap(), vec![7u8; 100]);
+    let pool: crate::model::ProxyPool = serde_json::from_value(serde_json::json!({
+      "id": 1, "name": "p", "enabled": true, "strategy": "round_robin", "allow_direct": false,
+      "rotate_per_segment": false, "health_check_url": null, "health_check_interval_secs": 0, "members": [dead]
+    }))
+    .unwrap();
+    assert_eq!(transport.pools().status(&pool)[0].healthy, Some(false));
+  }
+
+  #[test]
+  fn multipart_policy_thresholds() {
+    let p = MultipartPolicy { min_size_bytes: 32 * MIB, segment_size_bytes: 16 * MIB, max_segments: 16 };
+    assert!(p.applies_to(Some(32 * MIB)));
+    assert!(!p.applies_to(Some(MIB)));
+    assert!(!p.applies_to(None));
+    assert!(!MultipartPolicy { max_segments: 1, ..p }.applies_to(Some(64 * MIB)));
+  }
+}
@@ -1,30 +1,45 @@
 pub mod bandwidth;
 pub mod file_writer;
 pub mod naming;
+pub mod range;
+pub mod rate_limit;
+mod dash;
+mod dash_job;
+mod hls;
+mod hls_job;
 mod job;
+mod source_job;
+mod stream_parts;
 
 use crate::{
   events::{EventHub, ServerEvent, EVENT_DOWNLOADS_CHANGED, EVENT_PROGRESS_BATCH},
-  model::{DownloadProgressUpdate, DownloadStatus},
+  model::{DashChoice, DashRepresentation, DownloadProgressUpdate, DownloadStatus},
   persistence::{Db, SettingsStore},
-  transport::Transport,
+  transport::{s3, SendError, Transport},
 };
 use anyhow::Context;
 use dashmap::DashMap;
 use std::sync::Arc;
 use tauri::{AppHandle, Emitter};
 use tokio::sync::{mpsc, Mutex, watch};
+use url::Url;
 use uuid::Uuid;
 
 #[derive(Clone)]
 pub struct DownloadEngineHandle {
   tx: mpsc::Sender<EngineCommand>,
+  transport: Transport,
 }
 
 impl DownloadEngineHandle {
   pub async fn send(&self, cmd: EngineCommand) -> anyhow::Result<()> {
     self.tx.send(cmd).await.context("engine channel closed")
   }
+
+  /// The engine's transport, for commands that inspect routing or proxy health.
+  pub fn transport(&self) -> &Transport {
+    &self.transport
+  }
 }
 
 pub struct DownloadEngine {
@@ -35,7 +50,15 @@ pub struct DownloadEngine {
 
 #[derive(Debug)]
 pub enum EngineCommand {
-  AddDownloads { urls: Vec<String>, dest_dir: String, batch_id: Option<String>, forced_proxy: bool, forced_proxy_url: Option<String> },
+  AddDownloads {
+    urls: Vec<String>,
+    dest_dir: String,
+    batch_id: Option<String>,
+    forced_proxy: bool,
+    forced_proxy_url: Option<String>,
+    /// Applies to the URLs that turn out to be DASH manifests.
+    dash: DashChoice,
+  },
   Pause { id: String },
   Resume { id: String },
   Retry { id: String },
@@ -51,13 +74,18 @@ impl DownloadEngine {
     let limiter = bandwidth::BandwidthLimiter::new(
       settings.get_snapshot().ok().and_then(|s| s.bandwidth_limit_bps).unwrap_or(0),
     );
-    let transport = Transport::new().expect("transport init");
+    let transport = Transport::new().expect("transport init").with_secrets(db.secrets().clone());
+    match db.list_cookies() {
+      Ok(cookies) => transport.cookies().load(cookies),
+      Err(e) => tracing::error!(error = %e, "failed to load cookies"),
+    }
     let inner = Arc::new(EngineInner {
       db,
       settings,
       events,
       limiter,
       transport,
+      throttle: rate_limit::HostThrottle::new(),
       jobs: Arc::new(DashMap::new()),
       stats: Arc::new(DashMap::new()),
     });
@@ -69,7 +97,10 @@ impl DownloadEngine {
   }
 
   pub fn handle(&self) -> DownloadEngineHandle {
-    DownloadEngineHandle { tx: self.tx.clone() }
+    DownloadEngineHandle {
+      tx: self.tx.clone(),
+      transport: self.inner.transport.clone(),
+    }
   }
 
   pub fn start_background_tasks(&self, app: AppHandle, _paths: crate::app_state::AppPaths) {
@@ -87,6 +118,12 @@ impl DownloadEngine {
     // Throttled progress batch producer (30Hz).
     spawn_progress_flusher(inner.clone());
 
+    // Periodic proxy pool health checks.
+    spawn_pool_health_checks(inner.clone());
+
+    // Cookies picked up from Set-Cookie are written back to SQLite.
+    spawn_cookie_persister(inner.clone());
+
     tauri::async_runtime::spawn(async move {
       while let Some(cmd) = rx.recv().await {
         if let Err(e) = handle_cmd(inner.clone(), cmd).await {
@@ -103,6 +140,7 @@ struct EngineInner {
   events: EventHub,
   limiter: bandwidth::BandwidthLimiter,
   transport: Transport,
+  throttle: rate_limit::HostThrottle,
   jobs: Arc<DashMap<String, JobEntry>>,
   stats: Arc<DashMap<String, job::RuntimeStats>>,
 }
@@ -113,15 +151,20 @@ struct JobEntry {
 
 async fn handle_cmd(inner: Arc<EngineInner>, cmd: EngineCommand) -> anyhow::Result<()> {
   match cmd {
-    EngineCommand::AddDownloads { urls, dest_dir, batch_id, forced_proxy, forced_proxy_url } => {
+    EngineCommand::AddDownloads { urls, dest_dir, batch_id, forced_proxy, forced_proxy_url, dash } => {
       for url in urls {
-        let id = Uuid::new_v4().to_string();
-        inner.db.insert_download_skeleton(&id, &url, &dest_dir, forced_proxy, forced_proxy_url.as_deref())?;
-        if let Some(batch_id) = batch_id.as_deref() {
-          inner.db.attach_download_to_batch(&id, batch_id)?;
+        let add = NewDownload {
+          url: &url,
+          dest_dir: &dest_dir,
+          batch_id: batch_id.as_deref(),
+          forced_proxy,
+          forced_proxy_url: forced_proxy_url.as_deref(),
+          dash: &dash,
+        };
+        match Url::parse(&url) {
+          Ok(parsed) if s3::is_prefix(&parsed) => spawn_s3_prefix(&inner, parsed, add),
+          _ => add_download(&inner, add).await?,
         }
-        inner.db.update_download_status(&id, DownloadStatus::Queued, None, None)?;
-        start_or_resume(inner.clone(), id).await?;
       }
       inner.events.emit_downloads_changed();
       Ok(())
@@ -147,6 +190,7 @@ async fn handle_cmd(inner: Arc<EngineInner>, cmd: EngineCommand) -> anyhow::Resu
       }
       if let Some(r) = inner.db.get_download(&id)? {
         if let Some(p) = r.temp_path {
+          let _ = std::fs::remove_dir_all(stream_parts::parts_dir(std::path::Path::new(&p)));
           let _ = std::fs::remove_file(p);
         }
       }
@@ -161,6 +205,7 @@ async fn handle_cmd(inner: Arc<EngineInner>, cmd: EngineCommand) -> anyhow::Resu
       }
       if let Some(r) = inner.db.get_download(&id)? {
         if let Some(p) = r.temp_path {
+          let _ = std::fs::remove_dir_all(stream_parts::parts_dir(std::path::Path::new(&p)));
           let _ = std::fs::remove_file(p);
         }
         if let Some(name) = r.final_filename {
@@ -195,6 +240,155 @@ async fn handle_cmd(inner: Arc<EngineInner>, cmd: EngineCommand) -> anyhow::Resu
   }
 }
 
+#[derive(Clone, Copy)]
+struct NewDownload<'a> {
+  url: &'a str,
+  dest_dir: &'a str,
+  batch_id: Option<&'a str>,
+  forced_proxy: bool,
+  forced_proxy_url: Option<&'a str>,
+  dash: &'a DashChoice,
+}
+
+async fn add_download(inner: &Arc<EngineInner>, add: NewDownload<'_>) -> anyhow::Result<()> {
+  let id = Uuid::new_v4().to_string();
+  inner
+    .db
+    .insert_download_skeleton(&id, add.url, add.dest_dir, add.forced_proxy, add.forced_proxy_url)?;
+  if let Some(batch_id) = add.batch_id {
+    inner.db.attach_download_to_batch(&id, batch_id)?;
+  }
+  if *add.dash != DashChoice::default() {
+    inner.db.update_dash_choice(&id, add.dash)?;
+  }
+  inner.db.update_download_status(&id, DownloadStatus::Queued, None, None)?;
+  start_or_resume(inner.clone(), id).await
+}
+
+/// Lists and queues an S3 prefix on its own task: a big bucket can take many requests to list,
+/// and the command loop must keep handling pauses and other adds meanwhile.
+fn spawn_s3_prefix(inner: &Arc<EngineInner>, prefix: Url, add: NewDownload<'_>) {
+  let inner = inner.clone();
+  let (url, dest_dir) = (add.url.to_string(), add.dest_dir.to_string());
+  let (batch_id, forced_proxy_url) = (add.batch_id.map(str::to_string), add.forced_proxy_url.map(str::to_string));
+  let (forced_proxy, dash) = (add.forced_proxy, add.dash.clone());
+  tauri::async_runtime::spawn(async move {
+    let add = NewDownload {
+      url: &url,
+      dest_dir: &dest_dir,
+      batch_id: batch_id.as_deref(),
+      forced_proxy,
+      forced_proxy_url: forced_proxy_url.as_deref(),
+      dash: &dash,
+    };
+    if let Err(e) = add_s3_prefix(&inner, &prefix, add).await {
+      tracing::error!(prefix = %prefix, error = %e, "failed to queue S3 prefix");
+    }
+    inner.events.emit_downloads_changed();
+  });
+}
+
+/// Queues every object under an `s3://bucket/prefix/` URL, keeping the folders below the prefix
+/// under `dest_dir`. Without a batch of its own, the prefix becomes one. A listing that fails
+/// shows up as a failed download of the prefix itself.
+async fn add_s3_prefix(inner: &Arc<EngineInner>, url: &Url, add: NewDownload<'_>) -> anyhow::Result<()> {
+  let rules = inner.db.list_rules()?;
+  let listed = list_s3_prefix(inner, &rules, url, add).await.and_then(|objects| {
+    if objects.is_empty() {
+      anyhow::bail!("no objects under {url}");
+    }
+    Ok(objects)
+  });
+  let objects = match listed {
+    Ok(objects) => objects,
+    Err(e) => {
+      let id = Uuid::new_v4().to_string();
+      inner
+        .db
+        .insert_download_skeleton(&id, add.url, add.dest_dir, add.forced_proxy, add.forced_proxy_url)?;
+      if let Some(batch_id) = add.batch_id {
+        inner.db.attach_download_to_batch(&id, batch_id)?;
+      }
+      let code = job::format_code(crate::error::ErrorCode::InvalidUrl);
+      inner
+        .db
+        .update_download_status(&id, DownloadStatus::Error, Some(code), Some(&format!("{e:#}")))?;
+      return Ok(());
+    }
+  };
+  let batch_id = match add.batch_id {
+    Some(b) => b.to_string(),
+    None => inner.db.insert_batch(add.dest_dir, Some(add.url), None)?,
+  };
+  for obj in &objects {
+    let mut dest = std::path::PathBuf::from(add.dest_dir);
+    let folders: Vec<&str> = obj.relative.split('/').collect();
+    for folder in &folders[..folders.len() - 1] {
+      let clean = sanitize_filename::sanitize(folder);
+      if !clean.is_empty() && clean != "." && clean != ".." {
+        dest.push(clean);
+      }
+    }
+    let dest = dest.to_string_lossy();
+    add_download(inner, NewDownload { url: obj.url.as_str(), dest_dir: &dest, batch_id: Some(&batch_id), ..add }).await?;
+  }
+  tracing::info!(prefix = %url, objects = objects.len(), batch_id = %batch_id, "queued S3 prefix");
+  Ok(())
+}
+
+/// Lists `url` through the proxies a download from its endpoint would use, moving past ones
+/// that refuse connections the way a download's probe does.
+async fn list_s3_prefix(
+  inner: &Arc<EngineInner>,
+  rules: &crate::model::RulesSnapshot,
+  url: &Url,
+  add: NewDownload<'_>,
+) -> anyhow::Result<Vec<s3::S3Object>> {
+  let settings = inner.settings.get_snapshot()?;
+  let endpoint = s3::object_url(&rules.http_credentials, url)?;
+  let transport = &inner.transport;
+  let route = job::choose_route(transport, &settings, rules, add.forced_proxy, add.forced_proxy_url, &endpoint).await;
+  let (pool, candidates) = job::route_candidates(transport, rules, &route)?;
+  let mut last_err = None;
+  for candidate in &candidates {
+    let (profile, client) = job::candidate_client(&inner.db, transport, &settings, rules, &endpoint, candidate.as_deref())?;
+    match transport.list_s3_prefix(rules, &client, &profile.timeouts, url).await {
+      Ok(objects) => {
+        if let (Some(_), Some(member)) = (pool, candidate) {
+          transport.pools().mark_up(member, None);
+        }
+        return Ok(objects);
+      }
+      Err(e) if e.downcast_ref::<SendError>().is_some_and(SendError::is_connect) => {
+        if let (Some(_), Some(member)) = (pool, candidate) {
+          transport.pools().mark_down(member, &format!("{e:#}"));
+        }
+        last_err = Some(e);
+      }
+      Err(e) => return Err(e),
+    }
+  }
+  Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no proxy candidate succeeded")))
+}
+
+/// The representations the DASH manifest at `url` offers, fetched the way its download would
+/// fetch it (same route, headers and logins).
+pub async fn probe_dash(
+  db: &Db,
+  settings: &SettingsStore,
+  transport: &Transport,
+  url: &Url,
+) -> anyhow::Result<Vec<DashRepresentation>> {
+  let settings = settings.get_snapshot()?;
+  let rules = db.list_rules()?;
+  let route = job::choose_route(transport, &settings, &rules, false, None, url).await;
+  let (_, candidates) = job::route_candidates(transport, &rules, &route)?;
+  let (profile, client) = job::candidate_client(db, transport, &settings, &rules, url, candidates[0].as_deref())?;
+  let (limiter, stats) = (bandwidth::BandwidthLimiter::new(0), job::RuntimeStats::new(String::new()));
+  let fetcher = stream_parts::Fetcher::new(db, transport, &rules, &limiter, profile.timeouts, &stats);
+  dash_job::representations(&fetcher, &client, url).await
+}
+
 async fn start_or_resume(inner: Arc<EngineInner>, id: String) -> anyhow::Result<()> {
   // Already active?
   if inner.jobs.contains_key(&id) {
@@ -214,6 +408,7 @@ async fn start_or_resume(inner: Arc<EngineInner>, id: String) -> anyhow::Result<
   let settings = inner.settings.clone();
   let transport = inner.transport.clone();
   let limiter = inner.limiter.clone();
+  let throttle = inner.throttle.clone();
   let events = inner.events.clone();
   let jobs = inner.jobs.clone();
   let stats_map = inner.stats.clone();
@@ -224,6 +419,7 @@ async fn start_or_resume(inner: Arc<EngineInner>, id: String) -> anyhow::Result<
       settings.clone(),
       transport.clone(),
       limiter.clone(),
+      throttle,
       rules,
       events.clone(),
       id.clone(),
@@ -245,6 +441,55 @@ async fn start_or_resume(inner: Arc<EngineInner>, id: String) -> anyhow::Result<
   Ok(())
 }
 
+fn spawn_pool_health_checks(inner: Arc<EngineInner>) {
+  tauri::async_runtime::spawn(async move {
+    // Pools carry their own intervals; this only decides how often we look.
+    let mut tick = tokio::time::interval(std::time::Duration::from_secs(5));
+    tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+      tick.tick().await;
+      let pools = match inner.transport.pools().configured() {
+        Some(pools) => pools,
+        None => {
+          let Ok(rules) = inner.db.list_rules() else { continue };
+          inner.transport.pools().set_configured(rules.proxy_pools.clone());
+          rules.proxy_pools
+        }
+      };
+      if pools.is_empty() {
+        continue;
+      }
+      let creds = inner.db.list_proxy_credentials().unwrap_or_default();
+      inner.transport.check_due_pools(&pools, &creds).await;
+    }
+  });
+}
+
+fn spawn_cookie_persister(inner: Arc<EngineInner>) {
+  tauri::async_runtime::spawn(async move {
+    let mut tick = tokio::time::interval(std::time::Duration::from_secs(5));
+    tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+      tick.tick().await;
+      if let Err(e) = save_cookies(&inner.db, &inner.transport) {
+        tracing::warn!(error = %e, "failed to save cookies");
+      }
+    }
+  });
+}
+
+/// Writes the transport's cookie jar to the database if it changed. Values are sealed by the
+/// secrets vault, so while it is locked the changes wait.
+pub fn save_cookies(db: &Db, transport: &Transport) -> anyhow::Result<()> {
+  if !db.secrets().is_unlocked() {
+    return Ok(());
+  }
+  match transport.cookies().take_dirty() {
+    Some(cookies) => db.replace_cookies(&cookies),
+    None => Ok(()),
+  }
+}
+
 fn spawn_progress_flusher(inner: Arc<EngineInner>) {
   tauri::async_runtime::spawn(async move {
     let mut tick = tokio::time::interval(std::time::Duration::from_millis(33));
@@ -0,0 +1,159 @@
+//! `Content-Range` / `If-Range` helpers: every 206 is checked against what we asked for
+//! before a single byte is written at an offset.
+
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_RANGE};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentRange {
+  pub start: i64,
+  pub end: i64,
+  // None when the server answers `bytes a-b/*`
+  pub total: Option<i64>,
+}
+
+/// Parses `bytes <start>-<end>/<total|*>`. Unsatisfied ranges (`bytes */N`) yield None.
+pub fn parse_content_range(v: &str) -> Option<ContentRange> {
+  let rest = v.trim().strip_prefix("bytes")?.trim_start();
+  let (range, total) = rest.split_once('/')?;
+  let (start, end) = range.trim().split_once('-')?;
+  let start = start.trim().parse::<i64>().ok()?;
+  let end = end.trim().parse::<i64>().ok()?;
+  let total = match total.trim() {
+    "*" => None,
+    t => Some(t.parse::<i64>().ok()?),
+  };
+  if start < 0 || end < start || total.is_some_and(|t| end >= t) {
+    return None;
+  }
+  Some(ContentRange { start, end, total })
+}
+
+/// Complete length from an unsatisfied-range answer (`416` with `bytes */N`).
+pub fn unsatisfied_length(v: &str) -> Option<i64> {
+  let rest = v.trim().strip_prefix("bytes")?.trim_start();
+  rest.strip_prefix("*/")?.trim().parse::<i64>().ok().filter(|n| *n >= 0)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeMismatch {
+  Missing,
+  WrongStart { requested: i64, got: i64 },
+  PastEnd { requested: i64, got: i64 },
+  TotalChanged { expected: i64, got: i64 },
+}
+
+impl RangeMismatch {
+  /// A different total length means a different file, not a misbehaving range implementation.
+  pub fn is_remote_change(&self) -> bool {
+    matches!(self, RangeMismatch::TotalChanged { .. })
+  }
+}
+
+impl fmt::Display for RangeMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RangeMismatch::Missing => write!(f, "206 response without a valid Content-Range"),
+      RangeMismatch::WrongStart { requested, got } => {
+        write!(f, "Content-Range starts at {got}, requested {requested}")
+      }
+      RangeMismatch::PastEnd { requested, got } => {
+        write!(f, "Content-Range ends at {got}, requested up to {requested}")
+      }
+      RangeMismatch::TotalChanged { expected, got } => {
+        write!(f, "remote size changed ({expected} -> {got} bytes)")
+      }
+    }
+  }
+}
+
+/// Validates a 206 against the requested `start`..=`end` (open-ended when `end` is None)
+/// and the total length we recorded at probe time.
+pub fn check_partial(
+  headers: &HeaderMap,
+  start: i64,
+  end: Option<i64>,
+  expected_total: Option<i64>,
+) -> Result<ContentRange, RangeMismatch> {
+  let cr = headers
+    .get(CONTENT_RANGE)
+    .and_then(|v| v.to_str().ok())
+    .and_then(parse_content_range)
+    .ok_or(RangeMismatch::Missing)?;
+  if cr.start != start {
+    return Err(RangeMismatch::WrongStart { requested: start, got: cr.start });
+  }
+  if let Some(end) = end {
+    // A shorter range is fine (we re-request the rest); a longer one would spill into the next segment.
+    if cr.end > end {
+      return Err(RangeMismatch::PastEnd { requested: end, got: cr.end });
+    }
+  }
+  if let (Some(expected), Some(got)) = (expected_total, cr.total) {
+    if expected != got {
+      return Err(RangeMismatch::TotalChanged { expected, got });
+    }
+  }
+  Ok(cr)
+}
+
+/// Validator for `If-Range`: a strong ETag if we have one, else Last-Modified.
+/// Weak ETags are not allowed in `If-Range` (RFC 9110 §13.1.5).
+pub fn if_range_value(etag: Option<&str>, last_modified: Option<&str>) -> Option<HeaderValue> {
+  let strong_etag = etag.map(str::trim).filter(|e| !e.is_empty() && !e.starts_with("W/"));
+  strong_etag
+    .or_else(|| last_modified.map(str::trim).filter(|v| !v.is_empty()))
+    .and_then(|v| HeaderValue::from_str(v).ok())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn with_content_range(v: &str) -> HeaderMap {
+    let mut h = HeaderMap::new();
+    h.insert(CONTENT_RANGE, HeaderValue::from_str(v).unwrap());
+    h
+  }
+
+  #[test]
+  fn parses_content_range_forms() {
+    assert_eq!(
+      parse_content_range("bytes 100-199/1000"),
+      Some(ContentRange { start: 100, end: 199, total: Some(1000) })
+    );
+    assert_eq!(
+      parse_content_range("bytes 0-0/*"),
+      Some(ContentRange { start: 0, end: 0, total: None })
+    );
+    assert_eq!(parse_content_range("bytes */1000"), None);
+    assert_eq!(unsatisfied_length("bytes */0"), Some(0));
+    assert_eq!(parse_content_range("bytes 10-5/100"), None);
+    assert_eq!(parse_content_range("bytes 0-100/100"), None);
+  }
+
+  #[test]
+  fn check_partial_rejects_mismatches() {
+    let h = with_content_range("bytes 100-199/1000");
+    assert!(check_partial(&h, 100, Some(199), Some(1000)).is_ok());
+    assert!(check_partial(&h, 100, None, None).is_ok());
+    assert_eq!(
+      check_partial(&h, 0, None, Some(1000)),
+      Err(RangeMismatch::WrongStart { requested: 0, got: 100 })
+    );
+    assert_eq!(
+      check_partial(&h, 100, Some(150), Some(1000)),
+      Err(RangeMismatch::PastEnd { requested: 150, got: 199 })
+    );
+    assert!(check_partial(&h, 100, None, Some(2000)).unwrap_err().is_remote_change());
+    assert_eq!(check_partial(&HeaderMap::new(), 0, None, None), Err(RangeMismatch::Missing));
+  }
+
+  #[test]
+  fn if_range_prefers_strong_etag() {
+    let lm = "Wed, 21 Oct 2015 07:28:00 GMT";
+    assert_eq!(if_range_value(Some("\"abc\""), Some(lm)).unwrap(), "\"abc\"");
+    assert_eq!(if_range_value(Some("W/\"abc\""), Some(lm)).unwrap(), lm);
+    assert!(if_range_value(Some("W/\"abc\""), None).is_none());
+  }
+}
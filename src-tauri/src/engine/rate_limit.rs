@@ -0,0 +1,219 @@
+//! Server-directed rate limiting: `Retry-After` / `RateLimit-*` parsing and per-host segment caps.
+
+use dashmap::DashMap;
+use reqwest::header::HeaderMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Upper bound on any server-provided wait, so a bogus header cannot park a download forever.
+const MAX_SERVER_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Segment ceiling a throttled host climbs back to before its cap is forgotten.
+const SEGMENT_CEILING: usize = 16;
+
+/// 429 Too Many Requests and 503 Service Unavailable are treated as "slow down", not as failures.
+pub fn is_rate_limited(status: u16) -> bool {
+  matches!(status, 429 | 503)
+}
+
+/// Wait requested by the server, taken from (in order of preference) `Retry-After`,
+/// `RateLimit-Reset`, the structured `RateLimit` header, `X-RateLimit-Reset` and
+/// `X-RateLimit-Reset-After`.
+pub fn server_backoff(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+  let get = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim);
+  let delay = get("retry-after")
+    .and_then(|v| parse_retry_after(v, now))
+    .or_else(|| get("ratelimit-reset").and_then(parse_delta_seconds))
+    .or_else(|| get("ratelimit").and_then(parse_structured_reset))
+    .or_else(|| get("x-ratelimit-reset").and_then(|v| parse_reset_value(v, now)))
+    .or_else(|| get("x-ratelimit-reset-after").and_then(parse_delta_seconds))?;
+  Some(delay.min(MAX_SERVER_BACKOFF))
+}
+
+fn parse_retry_after(v: &str, now: SystemTime) -> Option<Duration> {
+  // Either delay-seconds or an HTTP-date.
+  if let Some(d) = parse_delta_seconds(v) {
+    return Some(d);
+  }
+  let at = httpdate::parse_http_date(v).ok()?;
+  Some(at.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+fn parse_delta_seconds(v: &str) -> Option<Duration> {
+  let secs = v.parse::<f64>().ok()?;
+  if !secs.is_finite() || secs < 0.0 {
+    return None;
+  }
+  Some(Duration::from_secs_f64(secs.min(MAX_SERVER_BACKOFF.as_secs_f64())))
+}
+
+fn parse_reset_value(v: &str, now: SystemTime) -> Option<Duration> {
+  // X-RateLimit-Reset is a delta on some hosts and a unix timestamp (s or ms) on others
+  // (GitHub, Twitter). Anything that looks like a timestamp is converted to a delta.
+  let raw = v.parse::<f64>().ok()?;
+  if !raw.is_finite() || raw < 0.0 {
+    return None;
+  }
+  let epoch_secs = if raw >= 1e12 {
+    raw / 1000.0
+  } else if raw >= 1e9 {
+    raw
+  } else {
+    return parse_delta_seconds(v);
+  };
+  let now_secs = now.duration_since(UNIX_EPOCH).ok()?.as_secs_f64();
+  Some(Duration::from_secs_f64((epoch_secs - now_secs).clamp(0.0, MAX_SERVER_BACKOFF.as_secs_f64())))
+}
+
+fn parse_structured_reset(v: &str) -> Option<Duration> {
+  // Draft IETF forms:
+  // - RateLimit: limit=100, remaining=0, reset=30
+  // - RateLimit: "default";r=0;t=30
+  v.split([',', ';'])
+    .filter_map(|param| param.split_once('='))
+    .find(|(k, _)| matches!(k.trim(), "reset" | "t"))
+    .and_then(|(_, val)| parse_delta_seconds(val.trim().trim_matches('"')))
+}
+
+/// Per-host cap on concurrent segments, lowered whenever a host answers 429/503 and
+/// raised again one step per clean multipart run.
+#[derive(Clone, Default)]
+pub struct HostThrottle {
+  caps: Arc<DashMap<String, usize>>,
+}
+
+impl HostThrottle {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn segment_cap(&self, host: &str) -> Option<usize> {
+    self.caps.get(&host.to_ascii_lowercase()).map(|c| *c)
+  }
+
+  /// Halve the segment count for `host` relative to the concurrency that got limited.
+  /// Returns the new cap (never below 1).
+  pub fn record_rate_limited(&self, host: &str, active_segments: usize) -> usize {
+    let mut entry = self
+      .caps
+      .entry(host.to_ascii_lowercase())
+      .or_insert(active_segments.max(1));
+    *entry = (active_segments.min(*entry) / 2).max(1);
+    *entry
+  }
+
+  pub fn record_clean_run(&self, host: &str) {
+    let key = host.to_ascii_lowercase();
+    let forget = match self.caps.get_mut(&key) {
+      Some(mut cap) => {
+        *cap += 1;
+        *cap >= SEGMENT_CEILING
+      }
+      None => false,
+    };
+    if forget {
+      self.caps.remove(&key);
+    }
+  }
+}
+
+/// Concurrency gate for the segments of one multipart download. It only ever shrinks:
+/// a rate-limited segment retires its permit instead of returning it.
+#[derive(Clone)]
+pub struct SegmentGate {
+  sem: Arc<Semaphore>,
+  capacity: Arc<AtomicUsize>,
+  limited: Arc<AtomicBool>,
+}
+
+impl SegmentGate {
+  pub fn new(capacity: usize) -> Self {
+    let capacity = capacity.max(1);
+    Self {
+      sem: Arc::new(Semaphore::new(capacity)),
+      capacity: Arc::new(AtomicUsize::new(capacity)),
+      limited: Arc::new(AtomicBool::new(false)),
+    }
+  }
+
+  pub async fn acquire(&self) -> OwnedSemaphorePermit {
+    self
+      .sem
+      .clone()
+      .acquire_owned()
+      .await
+      .expect("segment gate is never closed")
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.capacity.load(Ordering::Relaxed)
+  }
+
+  pub fn was_rate_limited(&self) -> bool {
+    self.limited.load(Ordering::Relaxed)
+  }
+
+  /// Give `permit` back, or retire it if the gate is still wider than `cap`.
+  pub fn shrink_to(&self, cap: usize, permit: OwnedSemaphorePermit) {
+    self.limited.store(true, Ordering::Relaxed);
+    let retired = self
+      .capacity
+      .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| (c > cap.max(1)).then(|| c - 1))
+      .is_ok();
+    if retired {
+      permit.forget();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use reqwest::header::HeaderValue;
+
+  fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+    let mut h = HeaderMap::new();
+    for (k, v) in pairs {
+      h.insert(*k, HeaderValue::from_str(v).unwrap());
+    }
+    h
+  }
+
+  #[test]
+  fn retry_after_seconds_and_http_date() {
+    let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let got = server_backoff(&headers(&[("retry-after", "120")]), now).unwrap();
+    assert_eq!(got, Duration::from_secs(120));
+
+    let at = httpdate::fmt_http_date(now + Duration::from_secs(30));
+    let got = server_backoff(&headers(&[("retry-after", &at)]), now).unwrap();
+    assert_eq!(got, Duration::from_secs(30));
+  }
+
+  #[test]
+  fn ratelimit_headers_as_delta_and_epoch() {
+    let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let got = server_backoff(&headers(&[("ratelimit-reset", "7")]), now).unwrap();
+    assert_eq!(got, Duration::from_secs(7));
+
+    let got = server_backoff(&headers(&[("ratelimit", "limit=10, remaining=0, reset=12")]), now).unwrap();
+    assert_eq!(got, Duration::from_secs(12));
+
+    let got = server_backoff(&headers(&[("x-ratelimit-reset", "1700000045")]), now).unwrap();
+    assert_eq!(got, Duration::from_secs(45));
+
+    assert!(server_backoff(&headers(&[("retry-after", "soon")]), now).is_none());
+  }
+
+  #[test]
+  fn host_throttle_halves_and_recovers() {
+    let t = HostThrottle::new();
+    assert_eq!(t.record_rate_limited("Example.com", 8), 4);
+    assert_eq!(t.record_rate_limited("example.com", 8), 2);
+    assert_eq!(t.segment_cap("example.com"), Some(2));
+    t.record_clean_run("example.com");
+    assert_eq!(t.segment_cap("example.com"), Some(3));
+  }
+}
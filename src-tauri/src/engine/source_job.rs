@@ -0,0 +1,635 @@
+//! Downloads through a transport [`Source`] (FTP and the other non-HTTP protocols): the same
+//! naming, temp file, resume, segments, pause and progress as the HTTP path in `job`, over the
+//! simpler probe/open interface.
+
+use super::{
+  bandwidth::BandwidthLimiter,
+  file_writer::write_at_all,
+  job::{self, JobControl, RuntimeStats},
+  naming,
+  rate_limit::{HostThrottle, SegmentGate},
+};
+use crate::{
+  error::ErrorCode,
+  model::{DownloadRecord, DownloadStatus, RulesSnapshot, SettingsSnapshot},
+  persistence::{Db, SegmentRowWithId},
+  transport::{
+    source::{ByteStream, Source, SourceError},
+    MultipartPolicy, Transport,
+  },
+};
+use anyhow::Context;
+use futures_util::StreamExt;
+use std::{
+  fs::{File, OpenOptions},
+  io,
+  path::{Path, PathBuf},
+  sync::{atomic::Ordering, Arc},
+  time::Duration,
+};
+use tokio::sync::watch;
+use tokio::time::Instant;
+use url::Url;
+
+// Reopens after transient failures (drops, stalls, busy servers) before a transfer gives up.
+const MAX_REOPENS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fetched {
+  Complete,
+  Paused,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn attempt_source_download(
+  db: &Db,
+  settings: &SettingsSnapshot,
+  transport: &Transport,
+  limiter: &BandwidthLimiter,
+  throttle: &HostThrottle,
+  rules: &RulesSnapshot,
+  events: &crate::events::EventHub,
+  download_id: &str,
+  url: &Url,
+  attempt_idx: usize,
+  rec: &mut DownloadRecord,
+  mut control_rx: watch::Receiver<JobControl>,
+  stats: RuntimeStats,
+) -> anyhow::Result<()> {
+  // These protocols have no proxy support, so a route that wants one must not quietly go direct;
+  // only a PAC answer that lists DIRECT as a fallback allows it.
+  let forced_url = rec.forced_proxy_url.as_deref();
+  let mut route = job::choose_route(transport, settings, rules, rec.forced_proxy, forced_url, url).await;
+  if route.proxy_url.is_some() && route.fallbacks.contains(&None) {
+    route.explanation = format!("{}; DIRECT fallback used, {}:// cannot use proxies", route.explanation, url.scheme());
+    route.proxy_url = None;
+  }
+  rec.proxy_route = Some(route.explanation.clone());
+  db.update_proxy_route(download_id, &route.explanation)?;
+  if route.proxy_url.is_some() {
+    let message = format!(
+      "{}:// downloads cannot go through a proxy, but this one is routed to {}; add a DIRECT proxy rule for its host",
+      url.scheme(),
+      route.explanation,
+    );
+    *stats.error_code.lock() = Some(ErrorCode::InvalidUrl);
+    *stats.error_message.lock() = Some(message.clone());
+    anyhow::bail!(message);
+  }
+
+  let mirror_used = (attempt_idx > 0).then(|| {
+    let port = url.port().map(|p| format!(":{p}")).unwrap_or_default();
+    format!("{}://{}{port}", url.scheme(), url.host_str().unwrap_or_default())
+  });
+  rec.resolved_url = Some(url.to_string());
+  rec.mirror_used = mirror_used.clone();
+  db.update_resolved_and_mirror(download_id, rec.resolved_url.as_deref(), mirror_used.as_deref())?;
+
+  let source = match transport.source_for(settings, rules, url) {
+    Ok(s) => s,
+    Err(e) => {
+      *stats.error_code.lock() = Some(ErrorCode::InvalidUrl);
+      *stats.error_message.lock() = Some(format!("{e:#}"));
+      return Err(e);
+    }
+  };
+  let stall_timeout = Transport::client_profile(settings, rules, url).timeouts.read_idle;
+
+  let probed = tokio::select! {
+    r = source.probe() => Some(r),
+    _ = job::wait_for_stop(&mut control_rx) => None,
+  };
+  let info = match probed {
+    Some(Ok(info)) => info,
+    Some(Err(e)) => {
+      set_source_error(&stats, &e);
+      anyhow::bail!("probe failed: {e}");
+    }
+    None => return mark_paused(db, download_id, &stats),
+  };
+  stats.total.store(info.size.unwrap_or(-1), Ordering::Relaxed);
+
+  // Without ETags, the modification stamp and size are what tell a resumed file apart.
+  if rec.final_filename.is_some() {
+    let stamp_changed = matches!((rec.last_modified.as_deref(), info.modified.as_deref()), (Some(a), Some(b)) if a != b);
+    let size_changed = matches!((rec.content_length, info.size), (Some(a), Some(b)) if a != b);
+    if stamp_changed || size_changed {
+      job::set_remote_changed(&stats, if stamp_changed { "modification time changed" } else { "size changed" });
+      anyhow::bail!("remote changed");
+    }
+  }
+
+  if rec.final_filename.is_none() || rec.temp_path.is_none() {
+    let desired = naming::filename_from_headers_and_url(url, None, None);
+    rec.supports_ranges = Some(info.ranges);
+    rec.content_length = info.size;
+    rec.etag = None;
+    rec.last_modified = info.modified.clone();
+    job::record_names(db, events, rec, download_id, &desired)?;
+  }
+
+  let temp_path = PathBuf::from(rec.temp_path.clone().unwrap());
+  let total = info.size.or(rec.content_length);
+  job::prepare_temp_file(&temp_path, total)?;
+
+  let policy = Transport::multipart_policy(settings, rules, url);
+  let fetched = match total {
+    Some(total) if policy.applies_to(Some(total)) && info.ranges && info.parallel => {
+      download_segments(
+        db,
+        source,
+        &temp_path,
+        download_id,
+        total,
+        &policy,
+        throttle,
+        url.host_str().unwrap_or_default(),
+        limiter,
+        &mut control_rx,
+        &stats,
+        stall_timeout,
+      )
+      .await?
+    }
+    _ => {
+      download_whole(
+        db,
+        source.as_ref(),
+        &temp_path,
+        download_id,
+        total,
+        info.ranges,
+        limiter,
+        &mut control_rx,
+        &stats,
+        stall_timeout,
+      )
+      .await?
+    }
+  };
+  if fetched == Fetched::Paused {
+    return mark_paused(db, download_id, &stats);
+  }
+
+  job::finish_download(db, rec, &temp_path, total, download_id, &control_rx, &stats)
+}
+
+pub(super) fn mark_paused(db: &Db, download_id: &str, stats: &RuntimeStats) -> anyhow::Result<()> {
+  *stats.status.lock() = DownloadStatus::Paused;
+  db.update_download_status(download_id, DownloadStatus::Paused, None, None)
+}
+
+/// One transfer from the start (or the resume offset) to the end of the file.
+#[allow(clippy::too_many_arguments)]
+async fn download_whole(
+  db: &Db,
+  source: &dyn Source,
+  temp_path: &Path,
+  download_id: &str,
+  total: Option<i64>,
+  ranges: bool,
+  limiter: &BandwidthLimiter,
+  control_rx: &mut watch::Receiver<JobControl>,
+  stats: &RuntimeStats,
+  stall_timeout: Duration,
+) -> anyhow::Result<Fetched> {
+  let file = OpenOptions::new().write(true).open(temp_path).context("failed to open temp file")?;
+  let mut done = db.get_download(download_id)?.map_or(0, |r| r.bytes_downloaded.max(0));
+  if !db.list_segments(download_id)?.is_empty() {
+    // An earlier run split the file; its byte count is not a prefix we can resume from.
+    db.replace_segments(download_id, Vec::new())?;
+    done = 0;
+  }
+  stats.bytes.store(done, Ordering::Relaxed);
+  stats.last_bytes.store(done, Ordering::Relaxed);
+  let mut persist = |bytes: i64| db.update_download_bytes(download_id, bytes);
+  let piece = Piece { start: 0, end: None, len: total };
+  fetch(source, &file, piece, ranges, &mut done, limiter, control_rx, stats, stall_timeout, false, &mut persist).await
+}
+
+/// Parallel ranged transfers, one connection per segment, persisted like HTTP segments so a
+/// resume picks each one up where it stopped. Like HTTP segments they share the host's
+/// [`HostThrottle`] cap, and a server that refuses another connection gets fewer of them.
+#[allow(clippy::too_many_arguments)]
+async fn download_segments(
+  db: &Db,
+  source: Arc<dyn Source>,
+  temp_path: &Path,
+  download_id: &str,
+  total: i64,
+  policy: &MultipartPolicy,
+  throttle: &HostThrottle,
+  host: &str,
+  limiter: &BandwidthLimiter,
+  control_rx: &mut watch::Receiver<JobControl>,
+  stats: &RuntimeStats,
+  stall_timeout: Duration,
+) -> anyhow::Result<Fetched> {
+  // Codes left over from the probe phase must not be mistaken for segment failures.
+  *stats.error_code.lock() = None;
+
+  let segment_cap = throttle.segment_cap(host);
+  let mut segments = db.list_segments(download_id)?;
+  if segments.is_empty() {
+    db.replace_segments(download_id, job::plan_segments(total, policy, 0.0, segment_cap))?;
+    segments = db.list_segments(download_id)?;
+  }
+  stats.bytes.store(segments.iter().map(|s| s.bytes_done).sum(), Ordering::Relaxed);
+  let pending = segments.iter().filter(|s| s.status != "COMPLETED").count();
+  let gate = SegmentGate::new(segment_cap.map_or(pending, |cap| pending.min(cap)));
+
+  // Segments follow this instead of the job's control, so one failing stops its siblings.
+  let (seg_tx, seg_rx) = watch::channel(JobControl::Run);
+  let seg_tx = Arc::new(seg_tx);
+  let mut handles = Vec::new();
+  for seg in segments.into_iter().filter(|s| s.status != "COMPLETED") {
+    let (db, source, seg_tx, mut seg_rx) = (db.clone(), source.clone(), seg_tx.clone(), seg_rx.clone());
+    let (limiter, stats, temp_path) = (limiter.clone(), stats.clone(), temp_path.to_path_buf());
+    let (gate, throttle, host) = (gate.clone(), throttle.clone(), host.to_string());
+    handles.push(tauri::async_runtime::spawn(async move {
+      let slot = Slot { gate: &gate, throttle: &throttle, host: &host };
+      let result =
+        fetch_segment(&db, source.as_ref(), &temp_path, &seg, slot, &limiter, &mut seg_rx, &stats, stall_timeout).await;
+      if result.is_err() {
+        seg_tx.send_replace(JobControl::Cancel);
+      }
+      result
+    }));
+  }
+
+  let all = futures_util::future::join_all(handles);
+  tokio::pin!(all);
+  let mut persist_tick = tokio::time::interval(Duration::from_secs(1));
+  persist_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+  let mut stopping = false;
+  let results = loop {
+    tokio::select! {
+      results = &mut all => break results,
+      _ = persist_tick.tick() => {
+        db.update_download_bytes(download_id, stats.bytes.load(Ordering::Relaxed))?;
+      }
+      _ = job::wait_for_stop(control_rx), if !stopping => {
+        stopping = true;
+        seg_tx.send_replace(JobControl::Pause);
+      }
+    }
+  };
+  db.update_download_bytes(download_id, stats.bytes.load(Ordering::Relaxed))?;
+
+  let mut fetched = Fetched::Complete;
+  for result in results {
+    match result {
+      Ok(Ok(Fetched::Complete)) => {}
+      Ok(Ok(Fetched::Paused)) => fetched = Fetched::Paused,
+      Ok(Err(e)) => return Err(e),
+      Err(e) => anyhow::bail!("segment task failed: {e}"),
+    }
+  }
+  if fetched == Fetched::Complete && !gate.was_rate_limited() {
+    throttle.record_clean_run(host);
+  }
+  Ok(fetched)
+}
+
+/// Where a segment gets its connection from.
+#[derive(Clone, Copy)]
+struct Slot<'a> {
+  gate: &'a SegmentGate,
+  throttle: &'a HostThrottle,
+  host: &'a str,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn fetch_segment(
+  db: &Db,
+  source: &dyn Source,
+  temp_path: &Path,
+  seg: &SegmentRowWithId,
+  slot: Slot<'_>,
+  limiter: &BandwidthLimiter,
+  control_rx: &mut watch::Receiver<JobControl>,
+  stats: &RuntimeStats,
+  stall_timeout: Duration,
+) -> anyhow::Result<Fetched> {
+  let file = OpenOptions::new().write(true).open(temp_path).context("failed to open temp file")?;
+  let mut done = seg.bytes_done;
+  let piece = Piece {
+    start: seg.range_start as u64,
+    end: Some(seg.range_end as u64),
+    len: Some(seg.range_end - seg.range_start + 1),
+  };
+  let result = loop {
+    let permit = tokio::select! {
+      p = slot.gate.acquire() => p,
+      _ = job::wait_for_stop(control_rx) => return Ok(Fetched::Paused),
+    };
+    // The last connection left keeps retrying instead: the server has to allow one.
+    let give_way = slot.gate.capacity() > 1;
+    let mut persist = |bytes: i64| db.update_segment_bytes(seg.id, bytes, "ACTIVE", None);
+    let result =
+      fetch(source, &file, piece, true, &mut done, limiter, control_rx, stats, stall_timeout, give_way, &mut persist).await;
+    let refused = result.as_ref().err().and_then(|e| e.downcast_ref::<SourceError>());
+    if give_way && matches!(refused, Some(SourceError::TooManyConnections(_))) {
+      // Retiring the permit means this segment waits for a sibling to finish before reopening.
+      let cap = slot.throttle.record_rate_limited(slot.host, slot.gate.capacity());
+      slot.gate.shrink_to(cap, permit);
+      tracing::info!(segment_id = seg.id, cap, "server refused another connection; running fewer segments");
+      continue;
+    }
+    break result;
+  };
+  match &result {
+    Ok(Fetched::Complete) => db.update_segment_bytes(seg.id, done, "COMPLETED", None)?,
+    Ok(Fetched::Paused) => {}
+    Err(e) => db.update_segment_bytes(seg.id, done, "ERROR", Some(&format!("{e:#}")))?,
+  }
+  result
+}
+
+/// Which bytes of the file a transfer covers: `start..=end` (or to EOF), `len` of them if known.
+#[derive(Debug, Clone, Copy)]
+struct Piece {
+  start: u64,
+  end: Option<u64>,
+  len: Option<i64>,
+}
+
+/// Fetches `piece` into `file`, `done` bytes of it already there. Drops, stalls and busy
+/// replies reopen from where it stopped (or from zero without `ranges`) with backoff; anything
+/// else, or too many reopens in a row, is recorded on `stats` and returned. With `give_way`, a
+/// refusal for too many connections is returned at once so the caller can close one instead.
+#[allow(clippy::too_many_arguments)]
+async fn fetch(
+  source: &dyn Source,
+  file: &File,
+  piece: Piece,
+  mut ranges: bool,
+  done: &mut i64,
+  limiter: &BandwidthLimiter,
+  control_rx: &mut watch::Receiver<JobControl>,
+  stats: &RuntimeStats,
+  stall_timeout: Duration,
+  give_way: bool,
+  persist: &mut (dyn FnMut(i64) -> anyhow::Result<()> + Send),
+) -> anyhow::Result<Fetched> {
+  let mut failures: usize = 0;
+  loop {
+    if piece.len.is_some_and(|len| *done >= len) {
+      persist(*done)?;
+      return Ok(Fetched::Complete);
+    }
+    if matches!(*control_rx.borrow(), JobControl::Pause | JobControl::Cancel) {
+      persist(*done)?;
+      return Ok(Fetched::Paused);
+    }
+    if *done > 0 && !ranges {
+      // No way to skip what we have: start over, dropping it.
+      file.set_len(0).context("failed to truncate temp file")?;
+      if let Some(len) = piece.len.filter(|l| *l > 0) {
+        file.set_len(len as u64).ok();
+      }
+      stats.bytes.fetch_sub(*done, Ordering::Relaxed);
+      *done = 0;
+      persist(0)?;
+    }
+
+    let before = *done;
+    let from = piece.start + *done as u64;
+    let opened = tokio::select! {
+      r = source.open(from, piece.end) => r,
+      _ = job::wait_for_stop(control_rx) => {
+        persist(*done)?;
+        return Ok(Fetched::Paused);
+      }
+    };
+    let err = match opened {
+      Ok(stream) => match pump(stream, file, piece, done, limiter, control_rx, stats, stall_timeout, persist).await? {
+        Pumped::Paused => return Ok(Fetched::Paused),
+        Pumped::Ended if piece.len.map_or(true, |len| *done >= len) => {
+          persist(*done)?;
+          return Ok(Fetched::Complete);
+        }
+        Pumped::Ended => SourceError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "transfer ended early")),
+        Pumped::Failed(e) => e,
+      },
+      Err(e) => e,
+    };
+    persist(*done)?;
+
+    if matches!(err, SourceError::NoResume(_)) && piece.end.is_none() && ranges {
+      tracing::info!(error = %err, "server refused to resume; restarting from zero");
+      ranges = false;
+      continue;
+    }
+    if *done > before {
+      failures = 0;
+    }
+    if give_way && matches!(err, SourceError::TooManyConnections(_)) {
+      return Err(err.into());
+    }
+    if !err.is_transient() || failures >= MAX_REOPENS {
+      set_source_error(stats, &err);
+      return Err(err.into());
+    }
+    let delay_ms = job::compute_backoff_delay_ms(failures);
+    failures += 1;
+    tracing::warn!(error = %err, delay_ms, "transfer interrupted; reopening");
+    stats
+      .backoff_until_ms
+      .store(job::now_unix_ms() + delay_ms as i64, Ordering::Relaxed);
+    *stats.status_detail.lock() = Some(format!("{err}. Retrying…"));
+    let resumed = tokio::select! {
+      _ = tokio::time::sleep(Duration::from_millis(delay_ms)) => true,
+      _ = job::wait_for_stop(control_rx) => false,
+    };
+    stats.backoff_until_ms.store(0, Ordering::Relaxed);
+    if !resumed {
+      return Ok(Fetched::Paused);
+    }
+  }
+}
+
+enum Pumped {
+  /// The stream ended, or delivered all of `piece.len`.
+  Ended,
+  Paused,
+  Failed(SourceError),
+}
+
+/// Writes `stream` into `file` at `piece.start + done`, through the bandwidth limiter, saving
+/// progress every second and giving up on it after `stall_timeout` without data.
+#[allow(clippy::too_many_arguments)]
+async fn pump(
+  mut stream: ByteStream,
+  file: &File,
+  piece: Piece,
+  done: &mut i64,
+  limiter: &BandwidthLimiter,
+  control_rx: &mut watch::Receiver<JobControl>,
+  stats: &RuntimeStats,
+  stall_timeout: Duration,
+  persist: &mut (dyn FnMut(i64) -> anyhow::Result<()> + Send),
+) -> anyhow::Result<Pumped> {
+  let mut persist_tick = tokio::time::interval(Duration::from_secs(1));
+  persist_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+  let mut last_progress = Instant::now();
+  loop {
+    tokio::select! {
+      _ = persist_tick.tick() => persist(*done)?,
+      _ = job::wait_for_stop(control_rx) => {
+        persist(*done)?;
+        return Ok(Pumped::Paused);
+      }
+      _ = tokio::time::sleep_until(last_progress + stall_timeout) => {
+        return Ok(Pumped::Failed(SourceError::ReadIdleTimeout(stall_timeout)));
+      }
+      next = stream.next() => {
+        let chunk = match next {
+          None => return Ok(Pumped::Ended),
+          Some(Err(e)) => return Ok(Pumped::Failed(e)),
+          Some(Ok(chunk)) => chunk,
+        };
+        last_progress = Instant::now();
+        stats.backoff_until_ms.store(0, Ordering::Relaxed);
+        *stats.status_detail.lock() = None;
+        // Never write past the piece, even if the server sends more than asked for.
+        let chunk = match piece.len {
+          Some(len) => chunk.slice(..chunk.len().min((len - *done).max(0) as usize)),
+          None => chunk,
+        };
+        limiter.acquire(chunk.len()).await;
+        write_at_all(file, piece.start + *done as u64, &chunk)?;
+        *done += chunk.len() as i64;
+        stats.bytes.fetch_add(chunk.len() as i64, Ordering::Relaxed);
+        if piece.len.is_some_and(|len| *done >= len) {
+          return Ok(Pumped::Ended);
+        }
+      }
+    }
+  }
+}
+
+fn set_source_error(stats: &RuntimeStats, err: &SourceError) {
+  let code = match err {
+    SourceError::Connect(_) | SourceError::Io(_) => ErrorCode::ConnectFail,
+    SourceError::ConnectTimeout(_) | SourceError::ResponseTimeout(_) | SourceError::ReadIdleTimeout(_) => {
+      ErrorCode::Timeout
+    }
+    SourceError::Tls(_) | SourceError::HostKey(_) => ErrorCode::TlsFail,
+    SourceError::Refused(_) => ErrorCode::Http4xx,
+    SourceError::Busy(_) => ErrorCode::Http5xx,
+    SourceError::TooManyConnections(_) => ErrorCode::RateLimited,
+    SourceError::NoResume(_) => ErrorCode::RangeUnsupported,
+    SourceError::Protocol(_) => ErrorCode::Unknown,
+  };
+  *stats.error_code.lock() = Some(code);
+  *stats.error_message.lock() = Some(err.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transport::source::SourceInfo;
+  use futures_util::future::BoxFuture;
+  use std::sync::atomic::AtomicUsize;
+
+  /// Serves `data` but refuses a connection beyond `max` open at once, like an FTP 421.
+  struct Crowded {
+    data: bytes::Bytes,
+    open: Arc<AtomicUsize>,
+    max: usize,
+  }
+
+  impl Source for Crowded {
+    fn probe(&self) -> BoxFuture<'_, Result<SourceInfo, SourceError>> {
+      unimplemented!()
+    }
+
+    fn open(&self, start: u64, end: Option<u64>) -> BoxFuture<'_, Result<ByteStream, SourceError>> {
+      Box::pin(async move {
+        if self.open.fetch_add(1, Ordering::SeqCst) >= self.max {
+          self.open.fetch_sub(1, Ordering::SeqCst);
+          return Err(SourceError::TooManyConnections("421 too many connections".into()));
+        }
+        let body = self.data.slice(start as usize..=end.unwrap() as usize);
+        let open = self.open.clone();
+        let stream = futures_util::stream::once(async move {
+          tokio::time::sleep(Duration::from_millis(20)).await;
+          open.fetch_sub(1, Ordering::SeqCst);
+          Ok(body)
+        });
+        Ok(stream.boxed())
+      })
+    }
+  }
+
+  #[tokio::test]
+  async fn segments_close_connections_the_server_refuses() {
+    let (db, dir) = crate::transport::tests::test_db();
+    db.insert_download_skeleton("d1", "ftp://127.0.0.1/file.bin", &dir.display().to_string(), false, None).unwrap();
+    let temp_path = dir.join(".zdmr-d1.part");
+    job::prepare_temp_file(&temp_path, Some(100)).unwrap();
+    let data: Vec<u8> = (0..100).collect();
+    let source = Crowded { data: data.clone().into(), open: Arc::new(AtomicUsize::new(0)), max: 2 };
+    let policy = MultipartPolicy { min_size_bytes: 0, segment_size_bytes: 25, max_segments: 4 };
+    let throttle = HostThrottle::new();
+    let (_control_tx, mut control_rx) = watch::channel(JobControl::Run);
+    let stats = RuntimeStats::new("d1".to_string());
+
+    let fetched = download_segments(
+      &db,
+      Arc::new(source),
+      &temp_path,
+      "d1",
+      100,
+      &policy,
+      &throttle,
+      "127.0.0.1",
+      &BandwidthLimiter::new(0),
+      &mut control_rx,
+      &stats,
+      Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+    assert_eq!(fetched, Fetched::Complete);
+    assert_eq!(std::fs::read(&temp_path).unwrap(), data);
+    assert!(db.list_segments("d1").unwrap().iter().all(|s| s.status == "COMPLETED"));
+    assert!(throttle.segment_cap("127.0.0.1").is_some_and(|cap| cap <= 2));
+    assert!(stats.error_code.lock().is_none());
+  }
+
+  #[tokio::test]
+  async fn a_route_that_names_a_proxy_fails_instead_of_going_direct() {
+    let (db, dir) = crate::transport::tests::test_db();
+    let url = Url::parse("ftp://127.0.0.1:1/file.bin").unwrap();
+    db.insert_download_skeleton("d1", url.as_str(), &dir.display().to_string(), true, Some("http://127.0.0.1:3128"))
+      .unwrap();
+    let mut rec = db.get_download("d1").unwrap().unwrap();
+    let rules = crate::transport::tests::rules_with(crate::transport::tests::local_rule());
+    let (_control_tx, control_rx) = watch::channel(JobControl::Run);
+    let stats = RuntimeStats::new("d1".to_string());
+
+    let result = attempt_source_download(
+      &db,
+      &crate::transport::tests::settings(),
+      &Transport::new().unwrap(),
+      &BandwidthLimiter::new(0),
+      &HostThrottle::new(),
+      &rules,
+      &crate::events::EventHub::new(),
+      "d1",
+      &url,
+      0,
+      &mut rec,
+      control_rx,
+      stats.clone(),
+    )
+    .await;
+    assert!(result.is_err());
+    assert!(matches!(*stats.error_code.lock(), Some(ErrorCode::InvalidUrl)));
+    assert!(stats.error_message.lock().as_deref().unwrap().contains("cannot go through a proxy"));
+    let stored = db.get_download("d1").unwrap().unwrap().proxy_route;
+    assert_eq!(stored.as_deref(), Some("PROXY http://127.0.0.1:3128: forced for this download"));
+  }
+}
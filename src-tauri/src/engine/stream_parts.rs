@@ -0,0 +1,565 @@
+//! Media segments of the stream jobs: fetched in parallel into part files under
+//! [`parts_dir`], tracked in `download_segments`, then joined into the output.
+//!
+//! A segment's row holds its position in the job's segment list in `range_start`/`range_end`
+//! rather than byte offsets; `bytes_done` and `COMPLETED` let a resume skip finished segments
+//! and continue partial ones with a ranged request. A whole-resource segment keeps the
+//! validator of its first response next to its part file, so it only resumes through `If-Range`.
+
+use super::{
+  bandwidth::BandwidthLimiter,
+  hls,
+  job::{self, JobControl, RuntimeStats},
+  range, rate_limit,
+};
+use crate::{
+  error::ErrorCode,
+  model::RulesSnapshot,
+  persistence::{Db, SegmentRow, SegmentRowWithId},
+  transport::{SendError, Timeouts, Transport},
+};
+use anyhow::Context;
+use futures_util::StreamExt;
+use reqwest::header::{HeaderValue, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use std::{
+  collections::HashMap,
+  fs::OpenOptions,
+  io::Write,
+  ops::Range,
+  path::{Path, PathBuf},
+  sync::atomic::Ordering,
+  time::{Duration, SystemTime},
+};
+use tokio::sync::watch;
+use tokio::time::Instant;
+use url::Url;
+
+// Retries of one request after drops, stalls and 5xx/429 answers before the download fails.
+const MAX_RETRIES: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Key {
+  pub uri: Url,
+  pub iv: [u8; 16],
+}
+
+/// One media segment (or initialization section) and where its bytes are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Segment {
+  pub uri: Url,
+  /// `(offset, length)` within `uri`; the whole resource when absent.
+  pub range: Option<(u64, u64)>,
+  /// The AES-128 key it is encrypted with.
+  pub key: Option<Key>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Fetched {
+  Complete,
+  Paused,
+}
+
+/// Where the segments of the download saving to `temp_path` are kept until they are joined.
+pub(super) fn parts_dir(temp_path: &Path) -> PathBuf {
+  temp_path.with_extension("parts")
+}
+
+/// The playlist's or manifest's name with `ext`, or its folder's name when it has a generic
+/// name like `index.m3u8`.
+pub(super) fn output_name(url: &Url, ext: &str) -> String {
+  const GENERIC: [&str; 7] = ["index", "master", "playlist", "prog_index", "chunklist", "manifest", "stream"];
+  let mut names = url.path_segments().into_iter().flatten().filter(|s| !s.is_empty()).rev();
+  let file = names.next().unwrap_or_default();
+  let stem = file.rsplit_once('.').map_or(file, |(stem, _)| stem);
+  let stem = match names.next() {
+    Some(dir) if stem.is_empty() || GENERIC.iter().any(|g| stem.to_ascii_lowercase().starts_with(g)) => dir,
+    _ => stem,
+  };
+  let stem = urlencoding::decode(stem).map_or_else(|_| stem.to_string(), |s| s.into_owned());
+  let stem = sanitize_filename::sanitize(stem);
+  if stem.is_empty() {
+    format!("stream.{ext}")
+  } else {
+    format!("{stem}.{ext}")
+  }
+}
+
+/// Fetches the segments not finished yet, up to `parallel` at a time, taking turns over
+/// `clients` so rotating proxies and local addresses apply. `estimate` is the expected total
+/// size, if known, until finished segments give a better one.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn download_segments(
+  fetcher: &mut Fetcher<'_>,
+  download_id: &str,
+  parts: &Path,
+  segments: &[Segment],
+  clients: &[reqwest::Client],
+  parallel: usize,
+  estimate: Option<i64>,
+  control_rx: &mut watch::Receiver<JobControl>,
+) -> anyhow::Result<Fetched> {
+  let (db, stats) = (fetcher.db, fetcher.stats);
+  let rows = segment_rows(db, download_id, parts, segments.len())?;
+  let done_bytes: i64 = rows
+    .iter()
+    .map(|r| if r.status == "COMPLETED" { r.bytes_done } else { part_len(parts, r) as i64 })
+    .sum();
+  stats.bytes.store(done_bytes, Ordering::Relaxed);
+  stats.last_bytes.store(done_bytes, Ordering::Relaxed);
+
+  let count = segments.len() as i64;
+  let mut finished: Vec<i64> = rows.iter().filter(|r| r.status == "COMPLETED").map(|r| r.bytes_done).collect();
+  let total = |finished: &[i64]| match finished.len() {
+    0 => estimate.unwrap_or(-1),
+    n => finished.iter().sum::<i64>() / n as i64 * count,
+  };
+  stats.total.store(total(&finished), Ordering::Relaxed);
+
+  let pending: Vec<(SegmentRowWithId, &Segment)> = rows
+    .into_iter()
+    .filter(|r| r.status != "COMPLETED")
+    .map(|r| {
+      let seg = &segments[r.range_start as usize];
+      (r, seg)
+    })
+    .collect();
+  let loaded = tokio::select! {
+    r = fetcher.load_keys(&clients[0], pending.iter().map(|(_, s)| *s)) => Some(r),
+    _ = job::wait_for_stop(control_rx) => None,
+  };
+  match loaded {
+    Some(r) => r?,
+    None => return Ok(Fetched::Paused),
+  }
+
+  let fetcher = &*fetcher;
+  let mut fetches = Vec::new();
+  for (i, (row, seg)) in pending.into_iter().enumerate() {
+    let client = &clients[i % clients.len()];
+    fetches.push(async move { fetcher.fetch_segment(client, parts, &row, seg).await });
+  }
+  let mut pieces = futures_util::stream::iter(fetches).buffer_unordered(parallel.max(1));
+
+  let mut persist_tick = tokio::time::interval(Duration::from_secs(1));
+  persist_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+  let fetched = loop {
+    tokio::select! {
+      next = pieces.next() => match next {
+        None => break Fetched::Complete,
+        Some(Ok(len)) => {
+          finished.push(len);
+          stats.total.store(total(&finished), Ordering::Relaxed);
+        }
+        Some(Err(e)) => {
+          db.update_download_bytes(download_id, stats.bytes.load(Ordering::Relaxed))?;
+          return Err(e);
+        }
+      },
+      _ = persist_tick.tick() => {
+        db.update_download_bytes(download_id, stats.bytes.load(Ordering::Relaxed))?;
+      }
+      // Dropping the in-flight requests is safe: their part files are where a resume starts.
+      _ = job::wait_for_stop(control_rx) => break Fetched::Paused,
+    }
+  };
+  db.update_download_bytes(download_id, stats.bytes.load(Ordering::Relaxed))?;
+  Ok(fetched)
+}
+
+/// Joins the finished segments `indices` into `out` in order; returns its size.
+pub(super) fn join_segments(parts: &Path, indices: Range<usize>, out: &Path) -> anyhow::Result<i64> {
+  join_files(indices.map(|i| parts.join(format!("{i:05}.seg"))), out)
+}
+
+/// Writes `inputs` back to back into `out`; returns its size.
+pub(super) fn join_files(inputs: impl IntoIterator<Item = PathBuf>, out: &Path) -> anyhow::Result<i64> {
+  let mut writer = std::io::BufWriter::new(std::fs::File::create(out).context("failed to create output file")?);
+  let mut len = 0;
+  for path in inputs {
+    let mut input = std::fs::File::open(&path).with_context(|| format!("missing {}", path.display()))?;
+    len += std::io::copy(&mut input, &mut writer)?;
+  }
+  writer.flush()?;
+  Ok(len as i64)
+}
+
+/// The rows for `count` segments, reusing those of an earlier run of the same list and starting
+/// over (parts included) when it changed.
+fn segment_rows(db: &Db, download_id: &str, parts: &Path, count: usize) -> anyhow::Result<Vec<SegmentRowWithId>> {
+  let rows = db.list_segments(download_id)?;
+  let matches = rows.len() == count && rows.iter().enumerate().all(|(i, r)| r.range_start == i as i64);
+  if matches {
+    // A finished segment whose file went missing is fetched again.
+    for r in rows.iter().filter(|r| r.status == "COMPLETED" && !segment_path(parts, r).exists()) {
+      db.update_segment_bytes(r.id, 0, "ACTIVE", None)?;
+    }
+  } else {
+    let _ = std::fs::remove_dir_all(parts);
+    let fresh = (0..count as i64)
+      .map(|i| SegmentRow {
+        range_start: i,
+        range_end: i,
+        bytes_done: 0,
+        status: "ACTIVE".to_string(),
+        last_error: None,
+      })
+      .collect();
+    db.replace_segments(download_id, fresh)?;
+  }
+  std::fs::create_dir_all(parts).context("failed to create segment folder")?;
+  db.list_segments(download_id)
+}
+
+fn segment_path(parts: &Path, row: &SegmentRowWithId) -> PathBuf {
+  parts.join(format!("{:05}.seg", row.range_start))
+}
+
+fn partial_path(parts: &Path, row: &SegmentRowWithId) -> PathBuf {
+  parts.join(format!("{:05}.part", row.range_start))
+}
+
+/// The `If-Range` validator of the response `partial` started from.
+fn validator_path(partial: &Path) -> PathBuf {
+  partial.with_extension("validator")
+}
+
+fn part_len(parts: &Path, row: &SegmentRowWithId) -> u64 {
+  std::fs::metadata(partial_path(parts, row)).map_or(0, |m| m.len())
+}
+
+/// What every request of one download shares.
+pub(super) struct Fetcher<'a> {
+  db: &'a Db,
+  transport: &'a Transport,
+  rules: &'a RulesSnapshot,
+  limiter: &'a BandwidthLimiter,
+  timeouts: Timeouts,
+  stats: &'a RuntimeStats,
+  keys: HashMap<Url, [u8; 16]>,
+}
+
+/// Why a request did not deliver; `Retry` ones are worth another try after a backoff.
+enum Failure {
+  Retry(String, Option<Duration>),
+  Fatal(anyhow::Error),
+}
+
+impl<'a> Fetcher<'a> {
+  pub(super) fn new(
+    db: &'a Db,
+    transport: &'a Transport,
+    rules: &'a RulesSnapshot,
+    limiter: &'a BandwidthLimiter,
+    timeouts: Timeouts,
+    stats: &'a RuntimeStats,
+  ) -> Self {
+    Self { db, transport, rules, limiter, timeouts, stats, keys: HashMap::new() }
+  }
+}
+
+impl Fetcher<'_> {
+  /// Fetches the AES-128 keys `segments` need that are not loaded yet.
+  async fn load_keys<'s>(
+    &mut self,
+    client: &reqwest::Client,
+    segments: impl Iterator<Item = &'s Segment>,
+  ) -> anyhow::Result<()> {
+    let mut wanted: Vec<Url> = segments.filter_map(|s| s.key.as_ref()).map(|k| k.uri.clone()).collect();
+    wanted.dedup();
+    for uri in wanted {
+      if self.keys.contains_key(&uri) {
+        continue;
+      }
+      let body = self.fetch_small(client, &uri, None).await?;
+      let key: [u8; 16] = body.as_ref().try_into().map_err(|_| {
+        self.invalid(&format!("HLS key {uri} is {} bytes, expected 16", body.len()))
+      })?;
+      self.keys.insert(uri, key);
+    }
+    Ok(())
+  }
+
+  /// A playlist, manifest, key or index: small enough to read whole, and not counted as
+  /// download progress. `range` is `(start, end)`, both inclusive; a server that answers it
+  /// with anything but exactly that range is an error, never a read of the whole resource.
+  pub(super) async fn fetch_small(
+    &self,
+    client: &reqwest::Client,
+    url: &Url,
+    range: Option<(u64, u64)>,
+  ) -> anyhow::Result<bytes::Bytes> {
+    let mut failures = 0;
+    loop {
+      let failure = match self.send(client, url, range.map(|(start, end)| (start, Some(end))), None).await {
+        Ok(resp) => {
+          if let Some((start, end)) = range {
+            self.check_partial(&resp, url, start, Some(end))?;
+          }
+          match resp.bytes().await {
+            Ok(body) if range.is_some_and(|(start, end)| body.len() as u64 != end - start + 1) => {
+              Failure::Retry(format!("byte range of {url} ended early"), None)
+            }
+            Ok(body) => return Ok(body),
+            Err(e) => Failure::Retry(e.to_string(), None),
+          }
+        }
+        Err(f) => f,
+      };
+      self.back_off(failure, &mut failures).await?;
+    }
+  }
+
+  /// Fails unless `resp` is a 206 for `start..=end` (or to the end) of `url`.
+  fn check_partial(&self, resp: &reqwest::Response, url: &Url, start: u64, end: Option<u64>) -> anyhow::Result<()> {
+    if resp.status().as_u16() != 206 {
+      *self.stats.error_code.lock() = Some(ErrorCode::RangeUnsupported);
+      *self.stats.error_message.lock() = Some(format!("Server ignored the byte range asked of {url}"));
+      anyhow::bail!("byte range ignored for {url}");
+    }
+    if let Err(m) = range::check_partial(resp.headers(), start as i64, end.map(|e| e as i64), None) {
+      *self.stats.error_code.lock() = Some(ErrorCode::RangeUnsupported);
+      *self.stats.error_message.lock() = Some(format!("Invalid ranged response for {url}: {m}"));
+      anyhow::bail!("invalid ranged response for {url}: {m}");
+    }
+    Ok(())
+  }
+
+  /// Downloads one segment into its part file, resuming what an earlier run left, and
+  /// decrypts it; returns its downloaded size.
+  async fn fetch_segment(
+    &self,
+    client: &reqwest::Client,
+    parts: &Path,
+    row: &SegmentRowWithId,
+    seg: &Segment,
+  ) -> anyhow::Result<i64> {
+    let partial = partial_path(parts, row);
+    let mut failures = 0;
+    let len = loop {
+      match self.fetch_part(client, &partial, row, seg).await {
+        Ok(len) => break len,
+        Err(failure) => {
+          if let Err(e) = self.back_off(failure, &mut failures).await {
+            let done = std::fs::metadata(&partial).map_or(0, |m| m.len() as i64);
+            self.db.update_segment_bytes(row.id, done, "ERROR", Some(&format!("{e:#}")))?;
+            return Err(e);
+          }
+        }
+      }
+    };
+
+    if let Some(key) = &seg.key {
+      let mut data = std::fs::read(&partial).context("failed to read segment")?;
+      if let Err(e) = hls::decrypt(&mut data, &self.keys[&key.uri], &key.iv) {
+        *self.stats.error_code.lock() = Some(ErrorCode::Unknown);
+        *self.stats.error_message.lock() = Some(format!("Segment {}: {e:#}", row.range_start));
+        self.db.update_segment_bytes(row.id, len, "ERROR", Some(&format!("{e:#}")))?;
+        return Err(e);
+      }
+      std::fs::write(&partial, data).context("failed to write segment")?;
+    }
+    std::fs::rename(&partial, segment_path(parts, row)).context("failed to save segment")?;
+    self.db.update_segment_bytes(row.id, len, "COMPLETED", None)?;
+    Ok(len)
+  }
+
+  /// One request for the rest of a segment, appended to `partial`.
+  async fn fetch_part(
+    &self,
+    client: &reqwest::Client,
+    partial: &Path,
+    row: &SegmentRowWithId,
+    seg: &Segment,
+  ) -> Result<i64, Failure> {
+    let fatal = |e: std::io::Error| Failure::Fatal(anyhow::Error::new(e).context("failed to write segment"));
+    let mut done = std::fs::metadata(partial).map_or(0, |m| m.len());
+    let validator = validator_path(partial);
+    let if_range = match seg.range {
+      None if done > 0 => {
+        let stored = std::fs::read_to_string(&validator).ok();
+        let value = stored.and_then(|v| HeaderValue::from_str(v.trim()).ok());
+        if value.is_none() {
+          // Nothing tells a changed segment apart from the one we have: start it over.
+          self.stats.bytes.fetch_sub(done as i64, Ordering::Relaxed);
+          done = 0;
+        }
+        value
+      }
+      _ => None,
+    };
+    let wanted = match seg.range {
+      Some((_, len)) if done >= len => return Ok(done as i64),
+      Some((offset, len)) => Some((offset + done, Some(offset + len - 1))),
+      None if done > 0 => Some((done, None)),
+      None => None,
+    };
+    let resp = self.send(client, &seg.uri, wanted, if_range).await?;
+    match wanted {
+      Some((start, end)) if seg.range.is_some() || resp.status().as_u16() == 206 => {
+        self.check_partial(&resp, &seg.uri, start, end).map_err(Failure::Fatal)?;
+      }
+      Some(_) => {
+        // The segment changed (or ranges are not supported): what we had is replaced.
+        self.stats.bytes.fetch_sub(done as i64, Ordering::Relaxed);
+        done = 0;
+      }
+      None => {}
+    }
+    if seg.range.is_none() && done == 0 {
+      let etag = resp.headers().get(ETAG).and_then(|v| v.to_str().ok());
+      let last_modified = resp.headers().get(LAST_MODIFIED).and_then(|v| v.to_str().ok());
+      match range::if_range_value(etag, last_modified) {
+        Some(v) => std::fs::write(&validator, v.as_bytes()).map_err(fatal)?,
+        None => {
+          let _ = std::fs::remove_file(&validator);
+        }
+      }
+    }
+    let expected = resp.content_length().map(|l| done + l);
+    let mut file = OpenOptions::new()
+      .create(true)
+      .write(true)
+      .append(done > 0)
+      .truncate(done == 0)
+      .open(partial)
+      .map_err(fatal)?;
+
+    let mut stream = resp.bytes_stream();
+    let mut persisted = Instant::now();
+    loop {
+      let next = tokio::time::timeout(self.timeouts.read_idle, stream.next()).await;
+      let chunk = match next {
+        Err(_) => return Err(Failure::Retry(SendError::ReadIdleTimeout(self.timeouts.read_idle).to_string(), None)),
+        Ok(None) => break,
+        Ok(Some(Err(e))) => return Err(Failure::Retry(e.to_string(), None)),
+        Ok(Some(Ok(chunk))) => chunk,
+      };
+      *self.stats.status_detail.lock() = None;
+      self.limiter.acquire(chunk.len()).await;
+      file.write_all(&chunk).map_err(fatal)?;
+      done += chunk.len() as u64;
+      self.stats.bytes.fetch_add(chunk.len() as i64, Ordering::Relaxed);
+      if persisted.elapsed() >= Duration::from_secs(1) {
+        persisted = Instant::now();
+        self.db.update_segment_bytes(row.id, done as i64, "ACTIVE", None).ok();
+      }
+    }
+    if expected.is_some_and(|e| done < e) {
+      return Err(Failure::Retry("segment ended early".to_string(), None));
+    }
+    Ok(done as i64)
+  }
+
+  /// GETs `url`, or `start..=end` of it (only if it still matches `if_range`), with the
+  /// transport's headers and logins.
+  async fn send(
+    &self,
+    client: &reqwest::Client,
+    url: &Url,
+    range: Option<(u64, Option<u64>)>,
+    if_range: Option<HeaderValue>,
+  ) -> Result<reqwest::Response, Failure> {
+    let mut headers = self.transport.request_headers(self.rules, url);
+    if let Some((start, end)) = range {
+      let end = end.map(|e| e.to_string()).unwrap_or_default();
+      headers.insert(RANGE, HeaderValue::from_str(&format!("bytes={start}-{end}")).unwrap());
+      if let Some(v) = if_range {
+        headers.insert(IF_RANGE, v);
+      }
+    }
+    let request = client.get(url.clone()).headers(headers);
+    let resp = match self.transport.send_with_auth(self.rules, request, &self.timeouts).await {
+      Ok(r) => r,
+      Err(e) => {
+        job::set_send_error(self.stats, &e);
+        return Err(match e.tls_reason() {
+          Some(_) => Failure::Fatal(e.into()),
+          None => Failure::Retry(e.to_string(), None),
+        });
+      }
+    };
+    let status = resp.status();
+    if status.is_success() {
+      return Ok(resp);
+    }
+    let code = status.as_u16();
+    job::set_http_error(self.stats, code, Some(format!("HTTP {code} for {url}")));
+    if rate_limit::is_rate_limited(code) {
+      Err(Failure::Retry(format!("HTTP {code}"), rate_limit::server_backoff(resp.headers(), SystemTime::now())))
+    } else if code == 408 || code >= 500 {
+      Err(Failure::Retry(format!("HTTP {code}"), None))
+    } else {
+      Err(Failure::Fatal(anyhow::anyhow!("http {code} for {url}")))
+    }
+  }
+
+  /// Waits before the next try of a retryable failure; the failure itself once it is fatal or
+  /// has happened too often in a row.
+  async fn back_off(&self, failure: Failure, failures: &mut usize) -> anyhow::Result<()> {
+    let (reason, delay) = match failure {
+      Failure::Fatal(e) => return Err(e),
+      Failure::Retry(reason, _) if *failures >= MAX_RETRIES => anyhow::bail!("{reason} (gave up after {MAX_RETRIES} retries)"),
+      Failure::Retry(reason, delay) => (reason, delay),
+    };
+    let delay = delay.unwrap_or_else(|| Duration::from_millis(job::compute_backoff_delay_ms(*failures)));
+    *failures += 1;
+    tracing::warn!(error = %reason, delay_ms = delay.as_millis() as u64, "segment request failed; retrying");
+    self
+      .stats
+      .backoff_until_ms
+      .store(job::now_unix_ms() + delay.as_millis() as i64, Ordering::Relaxed);
+    *self.stats.status_detail.lock() = Some(format!("{reason}. Retrying…"));
+    tokio::time::sleep(delay).await;
+    self.stats.backoff_until_ms.store(0, Ordering::Relaxed);
+    Ok(())
+  }
+
+  /// Records `message` as an invalid-input failure and returns it as an error.
+  pub(super) fn invalid(&self, message: &str) -> anyhow::Error {
+    *self.stats.error_code.lock() = Some(ErrorCode::InvalidUrl);
+    *self.stats.error_message.lock() = Some(message.to_string());
+    anyhow::anyhow!("{message}")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn output_names_skip_generic_names() {
+    let name = |u: &str| output_name(&Url::parse(u).unwrap(), "ts");
+    assert_eq!(name("https://cdn.example.com/films/big%20buck/master.m3u8?t=1"), "big buck.ts");
+    assert_eq!(name("https://cdn.example.com/films/trailer.m3u8"), "trailer.ts");
+    assert_eq!(name("https://cdn.example.com/hls/index_720.m3u8"), "hls.ts");
+    assert_eq!(name("https://cdn.example.com/master.m3u8"), "master.ts");
+  }
+
+  #[tokio::test]
+  async fn ranged_small_fetches_need_the_range_they_asked_for() {
+    use axum::{http::HeaderMap as Headers, routing::get, Router};
+
+    let app = Router::new()
+      .route("/whole.mp4", get(|| async { vec![1u8; 64] }))
+      .route(
+        "/ranged.mp4",
+        get(|h: Headers| async move {
+          assert_eq!(h.get("range").unwrap(), "bytes=8-15");
+          (axum::http::StatusCode::PARTIAL_CONTENT, [("content-range", "bytes 8-15/64")], vec![2u8; 8])
+        }),
+      );
+    let base = crate::transport::tests::serve(app).await;
+    let (db, _dir) = crate::transport::tests::test_db();
+    let transport = Transport::new().unwrap();
+    let rules = crate::transport::tests::rules_with(crate::transport::tests::local_rule());
+    let limiter = BandwidthLimiter::new(0);
+    let stats = RuntimeStats::new("d1".to_string());
+    let fetcher = Fetcher::new(&db, &transport, &rules, &limiter, Timeouts::default(), &stats);
+    let client = transport.client_for(&Default::default()).unwrap();
+
+    let url = |path: &str| Url::parse(&format!("{base}{path}")).unwrap();
+    assert_eq!(fetcher.fetch_small(&client, &url("/ranged.mp4"), Some((8, 15))).await.unwrap().as_ref(), [2u8; 8]);
+    assert!(fetcher.fetch_small(&client, &url("/whole.mp4"), Some((8, 15))).await.is_err());
+    assert!(matches!(*stats.error_code.lock(), Some(ErrorCode::RangeUnsupported)));
+  }
+}
@@ -13,6 +13,7 @@ pub enum ErrorCode {
   Http4xx,
   Http5xx,
   Timeout,
+  RateLimited,
   RangeUnsupported,
   DiskFull,
   RemoteChanged,
@@ -31,6 +32,7 @@ impl ErrorCode {
         | ErrorCode::TlsFail
         | ErrorCode::Http5xx
         | ErrorCode::Timeout
+        | ErrorCode::RateLimited
         | ErrorCode::RangeUnsupported
     )
   }
@@ -31,6 +31,13 @@ pub fn run() {
 
       let settings = persistence::SettingsStore::new(db.clone());
       let os_download_dir = app.handle().path().download_dir().unwrap_or_default();
+      // Secrets vault (proxy passwords, header tokens, local API token). With a key file it
+      // unlocks now; with a master passphrase it stays locked until the user unlocks it.
+      if db.secrets_status()?.mode == "key_file" {
+        if let Err(e) = db.unlock_secrets(None) {
+          tracing::error!(error = %e, "failed to unlock secrets");
+        }
+      }
       settings.ensure_bootstrap_defaults(&paths, os_download_dir)?;
 
       // Shared event hub + download engine
@@ -96,10 +103,35 @@ pub fn run() {
       ui_bridge::cmd_list_rules,
       ui_bridge::cmd_upsert_proxy_rule,
       ui_bridge::cmd_delete_proxy_rule,
+      ui_bridge::cmd_test_proxy_route,
+      ui_bridge::cmd_probe_dash,
+      ui_bridge::cmd_upsert_proxy_pool,
+      ui_bridge::cmd_delete_proxy_pool,
+      ui_bridge::cmd_check_proxy_pool,
+      ui_bridge::cmd_list_proxy_credentials,
+      ui_bridge::cmd_set_proxy_credentials,
+      ui_bridge::cmd_delete_proxy_credentials,
+      ui_bridge::cmd_import_cookies,
+      ui_bridge::cmd_upsert_http_credential,
+      ui_bridge::cmd_delete_http_credential,
+      ui_bridge::cmd_import_netrc,
+      ui_bridge::cmd_export_cookies,
       ui_bridge::cmd_upsert_header_rule,
       ui_bridge::cmd_delete_header_rule,
+      ui_bridge::cmd_preview_headers,
+      ui_bridge::cmd_list_secrets,
+      ui_bridge::cmd_set_secret,
+      ui_bridge::cmd_delete_secret,
+      ui_bridge::cmd_secrets_status,
+      ui_bridge::cmd_unlock_secrets,
+      ui_bridge::cmd_lock_secrets,
+      ui_bridge::cmd_set_master_passphrase,
       ui_bridge::cmd_upsert_mirror_rule,
       ui_bridge::cmd_delete_mirror_rule,
+      ui_bridge::cmd_upsert_segment_rule,
+      ui_bridge::cmd_delete_segment_rule,
+      ui_bridge::cmd_upsert_transport_rule,
+      ui_bridge::cmd_delete_transport_rule,
       ui_bridge::cmd_add_domain_to_proxy_and_retry,
       ui_bridge::cmd_clear_completed_downloads,
       ui_bridge::cmd_check_for_updates,
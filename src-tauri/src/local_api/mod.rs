@@ -21,7 +21,14 @@ struct ApiState {
   settings: SettingsStore,
   engine: DownloadEngineHandle,
   events: EventHub,
-  token: String,
+}
+
+impl ApiState {
+  /// The token lives in the secrets vault, so it is read per request: while the vault is
+  /// locked every request is rejected.
+  fn authorized(&self, headers: &HeaderMap) -> bool {
+    self.db.get_local_api_token().is_ok_and(|token| check_auth(headers, &token))
+  }
 }
 
 pub fn spawn_local_api(
@@ -31,7 +38,6 @@ pub fn spawn_local_api(
   engine: DownloadEngineHandle,
   events: EventHub,
 ) -> anyhow::Result<()> {
-  let token = db.get_local_api_token()?;
   let port = settings.get_snapshot()?.local_api_port as u16;
 
   let state = ApiState {
@@ -39,7 +45,6 @@ pub fn spawn_local_api(
     settings,
     engine,
     events,
-    token,
   };
 
   let app = Router::new()
@@ -50,6 +55,7 @@ pub fn spawn_local_api(
     .route("/downloads/:id/retry", post(post_retry))
     .route("/downloads/:id", delete(delete_download))
     .route("/events", get(get_events))
+    .route("/cookies", get(get_cookies).post(post_cookies))
     .with_state(state);
 
   let addr = SocketAddr::from(([127, 0, 0, 1], port));
@@ -81,7 +87,7 @@ async fn post_download(
   headers: HeaderMap,
   Json(req): Json<AddDownloadsRequest>,
 ) -> impl IntoResponse {
-  if !check_auth(&headers, &st.token) {
+  if !st.authorized(&headers) {
     return StatusCode::UNAUTHORIZED.into_response();
   }
   let dest_dir = match req.dest_dir {
@@ -96,6 +102,7 @@ async fn post_download(
       batch_id: None,
       forced_proxy: false,
       forced_proxy_url: None,
+      dash: req.dash,
     })
     .await;
   StatusCode::ACCEPTED.into_response()
@@ -106,7 +113,7 @@ async fn post_batch(
   headers: HeaderMap,
   Json(req): Json<NewBatchRequest>,
 ) -> impl IntoResponse {
-  if !check_auth(&headers, &st.token) {
+  if !st.authorized(&headers) {
     return StatusCode::UNAUTHORIZED.into_response();
   }
   let batch_id = match st
@@ -128,13 +135,14 @@ async fn post_batch(
         .get_snapshot()
         .ok()
         .and_then(|s| s.global_proxy_url),
+      dash: req.dash,
     })
     .await;
   StatusCode::ACCEPTED.into_response()
 }
 
 async fn post_pause(State(st): State<ApiState>, headers: HeaderMap, Path(id): Path<String>) -> impl IntoResponse {
-  if !check_auth(&headers, &st.token) {
+  if !st.authorized(&headers) {
     return StatusCode::UNAUTHORIZED.into_response();
   }
   let _ = st.engine.send(EngineCommand::Pause { id }).await;
@@ -142,7 +150,7 @@ async fn post_pause(State(st): State<ApiState>, headers: HeaderMap, Path(id): Pa
 }
 
 async fn post_resume(State(st): State<ApiState>, headers: HeaderMap, Path(id): Path<String>) -> impl IntoResponse {
-  if !check_auth(&headers, &st.token) {
+  if !st.authorized(&headers) {
     return StatusCode::UNAUTHORIZED.into_response();
   }
   let _ = st.engine.send(EngineCommand::Resume { id }).await;
@@ -150,7 +158,7 @@ async fn post_resume(State(st): State<ApiState>, headers: HeaderMap, Path(id): P
 }
 
 async fn post_retry(State(st): State<ApiState>, headers: HeaderMap, Path(id): Path<String>) -> impl IntoResponse {
-  if !check_auth(&headers, &st.token) {
+  if !st.authorized(&headers) {
     return StatusCode::UNAUTHORIZED.into_response();
   }
   let _ = st.engine.send(EngineCommand::Retry { id }).await;
@@ -158,15 +166,40 @@ async fn post_retry(State(st): State<ApiState>, headers: HeaderMap, Path(id): Pa
 }
 
 async fn delete_download(State(st): State<ApiState>, headers: HeaderMap, Path(id): Path<String>) -> impl IntoResponse {
-  if !check_auth(&headers, &st.token) {
+  if !st.authorized(&headers) {
     return StatusCode::UNAUTHORIZED.into_response();
   }
   let _ = st.engine.send(EngineCommand::Delete { id }).await;
   StatusCode::NO_CONTENT.into_response()
 }
 
+/// Exports the cookie jar as a Netscape `cookies.txt`.
+async fn get_cookies(State(st): State<ApiState>, headers: HeaderMap) -> Response {
+  if !st.authorized(&headers) {
+    return StatusCode::UNAUTHORIZED.into_response();
+  }
+  let text = st.engine.transport().cookies().export_netscape();
+  ([(axum::http::header::CONTENT_TYPE, "text/plain; charset=utf-8")], text).into_response()
+}
+
+/// Imports a Netscape `cookies.txt` body (e.g. from the browser extension).
+async fn post_cookies(State(st): State<ApiState>, headers: HeaderMap, body: String) -> Response {
+  if !st.authorized(&headers) {
+    return StatusCode::UNAUTHORIZED.into_response();
+  }
+  let transport = st.engine.transport();
+  let n = match transport.cookies().import_netscape(&body) {
+    Ok(n) => n,
+    Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+  };
+  if crate::engine::save_cookies(&st.db, transport).is_err() {
+    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+  }
+  Json(serde_json::json!({ "imported": n })).into_response()
+}
+
 async fn get_events(State(st): State<ApiState>, headers: HeaderMap) -> Response {
-  if !check_auth(&headers, &st.token) {
+  if !st.authorized(&headers) {
     return StatusCode::UNAUTHORIZED.into_response();
   }
 
@@ -34,6 +34,14 @@ pub struct DownloadRecord {
   pub supports_ranges: Option<bool>,
   pub mirror_used: Option<String>,
   pub batch_id: Option<String>,
+  /// Why the last attempt went DIRECT or through a particular proxy.
+  #[serde(default)]
+  pub proxy_route: Option<String>,
+  /// DASH representations picked when the download was added; `None` follows the stream settings.
+  #[serde(default)]
+  pub dash_video_id: Option<String>,
+  #[serde(default)]
+  pub dash_audio_id: Option<String>,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -62,6 +70,83 @@ pub struct SettingsSnapshot {
   pub global_proxy_enabled: bool,
   pub global_proxy_url: Option<String>,
   pub local_api_port: i64,
+  #[serde(default = "default_multipart_min_size_bytes")]
+  pub multipart_min_size_bytes: i64,
+  #[serde(default = "default_multipart_segment_size_bytes")]
+  pub multipart_segment_size_bytes: i64,
+  #[serde(default = "default_multipart_max_segments")]
+  pub multipart_max_segments: i64,
+  #[serde(default = "default_connect_timeout_secs")]
+  pub connect_timeout_secs: i64,
+  #[serde(default = "default_response_timeout_secs")]
+  pub response_timeout_secs: i64,
+  #[serde(default = "default_read_idle_timeout_secs")]
+  pub read_idle_timeout_secs: i64,
+  /// "allowlist" (proxy rules pick proxied hosts), "denylist" (proxy rules pick direct hosts),
+  /// "all", "none", or "pac" (ask `pac_source`).
+  #[serde(default = "default_proxy_mode")]
+  pub proxy_mode: String,
+  /// PAC file as a path, `file://` or `http(s)://` URL.
+  #[serde(default)]
+  pub pac_source: Option<String>,
+  /// Which variant of an HLS master playlist, or video track of a DASH manifest, to download:
+  /// "highest", "lowest", a height cap like "720p" or a bitrate cap like "3000k".
+  #[serde(default = "default_stream_variant")]
+  pub stream_variant: String,
+  /// Which audio track of a DASH manifest to download alongside the video: "highest", "lowest",
+  /// a bitrate cap like "128k", or "none".
+  #[serde(default = "default_stream_audio")]
+  pub stream_audio: String,
+  /// Preferred audio language of a DASH manifest, as a tag prefix like "en"; any language when
+  /// no track matches.
+  #[serde(default)]
+  pub stream_audio_language: Option<String>,
+}
+
+pub const DEFAULT_MULTIPART_MIN_SIZE_BYTES: i64 = 32 * 1024 * 1024;
+pub const DEFAULT_MULTIPART_SEGMENT_SIZE_BYTES: i64 = 16 * 1024 * 1024;
+pub const DEFAULT_MULTIPART_MAX_SEGMENTS: i64 = 16;
+pub const DEFAULT_CONNECT_TIMEOUT_SECS: i64 = 15;
+pub const DEFAULT_RESPONSE_TIMEOUT_SECS: i64 = 60;
+pub const DEFAULT_READ_IDLE_TIMEOUT_SECS: i64 = 20;
+pub const DEFAULT_PROXY_MODE: &str = "allowlist";
+pub const DEFAULT_STREAM_VARIANT: &str = "highest";
+pub const DEFAULT_STREAM_AUDIO: &str = "highest";
+
+fn default_multipart_min_size_bytes() -> i64 {
+  DEFAULT_MULTIPART_MIN_SIZE_BYTES
+}
+
+fn default_multipart_segment_size_bytes() -> i64 {
+  DEFAULT_MULTIPART_SEGMENT_SIZE_BYTES
+}
+
+fn default_multipart_max_segments() -> i64 {
+  DEFAULT_MULTIPART_MAX_SEGMENTS
+}
+
+fn default_connect_timeout_secs() -> i64 {
+  DEFAULT_CONNECT_TIMEOUT_SECS
+}
+
+fn default_response_timeout_secs() -> i64 {
+  DEFAULT_RESPONSE_TIMEOUT_SECS
+}
+
+fn default_read_idle_timeout_secs() -> i64 {
+  DEFAULT_READ_IDLE_TIMEOUT_SECS
+}
+
+fn default_proxy_mode() -> String {
+  DEFAULT_PROXY_MODE.to_string()
+}
+
+fn default_stream_variant() -> String {
+  DEFAULT_STREAM_VARIANT.to_string()
+}
+
+fn default_stream_audio() -> String {
+  DEFAULT_STREAM_AUDIO.to_string()
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -78,6 +163,9 @@ pub struct HeaderRule {
   pub id: i64,
   pub pattern: String,
   pub enabled: bool,
+  /// Every matching rule applies, lowest priority first, so higher priorities win conflicts.
+  #[serde(default)]
+  pub priority: i64,
   pub headers_json: serde_json::Value,
 }
 
@@ -89,11 +177,156 @@ pub struct MirrorRule {
   pub candidates_json: serde_json::Value,
 }
 
+/// Login for one proxy endpoint, keyed by `scheme://host:port` (see `transport::proxy::credential_key`).
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ProxyCredentials {
+  pub proxy_url: String,
+  pub username: String,
+  /// Never sent back to the UI.
+  #[serde(default, skip_serializing)]
+  pub password: Option<String>,
+}
+
+/// Login for servers that answer `401` (see `transport::auth`), or a bearer token sent up front.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct HttpCredential {
+  pub id: i64,
+  pub pattern: String,
+  /// Only answer challenges for this realm; `None` or empty for any realm. For "aws-sigv4" it is
+  /// the region (us-east-1 when empty).
+  #[serde(default)]
+  pub realm: Option<String>,
+  /// "auto" (Basic or Digest, whichever the server asks for), "basic", "digest", "bearer",
+  /// "ssh-key" for `sftp://` and `scp://` logins, or "aws-sigv4" for S3 (access key id as the
+  /// user, secret key as the password).
+  pub scheme: String,
+  pub username: String,
+  pub enabled: bool,
+  /// Password or bearer token; kept in the secrets vault and never sent back to the UI. For
+  /// "ssh-key" it is the key file's passphrase, or the private key itself when there is no file.
+  #[serde(default, skip_serializing)]
+  pub password: Option<String>,
+  /// Private key file for "ssh-key" logins.
+  #[serde(default)]
+  pub key_path: Option<String>,
+  /// S3-compatible endpoint for "aws-sigv4" logins, e.g. `http://localhost:9000` for MinIO;
+  /// empty for AWS itself.
+  #[serde(default)]
+  pub endpoint: Option<String>,
+}
+
+/// One cookie in the persistent jar (see `transport::cookies`).
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct StoredCookie {
+  /// Lowercase, without a leading dot.
+  pub domain: String,
+  /// Sent only to `domain` itself, not its subdomains.
+  pub host_only: bool,
+  pub path: String,
+  pub secure: bool,
+  pub http_only: bool,
+  /// Unix seconds; `None` for session cookies, which are kept until replaced.
+  pub expires: Option<i64>,
+  pub name: String,
+  pub value: String,
+}
+
+/// Per-host multipart overrides; `None` fields fall back to the global settings.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SegmentRule {
+  pub id: i64,
+  pub pattern: String,
+  pub enabled: bool,
+  pub min_size_bytes: Option<i64>,
+  pub segment_size_bytes: Option<i64>,
+  pub max_segments: Option<i64>,
+}
+
+/// A named set of proxies, used wherever a proxy URL is accepted as `pool://<name>`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ProxyPool {
+  pub id: i64,
+  pub name: String,
+  pub enabled: bool,
+  /// "round_robin" or "least_latency".
+  pub strategy: String,
+  /// Go DIRECT once every member has failed.
+  pub allow_direct: bool,
+  /// Spread the segments of one multipart download across members.
+  pub rotate_per_segment: bool,
+  /// Fetched through each member by health checks; without it a check is a TCP connect.
+  pub health_check_url: Option<String>,
+  /// 0 disables periodic checks.
+  pub health_check_interval_secs: i64,
+  /// Proxy URLs, in order.
+  pub members: Vec<String>,
+}
+
+/// Per-host client behavior (see `transport::ClientProfile`); `None` timeouts use the global settings.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct TransportRule {
+  pub id: i64,
+  pub pattern: String,
+  pub enabled: bool,
+  pub decode_content: bool,
+  #[serde(default)]
+  pub connect_timeout_secs: Option<i64>,
+  #[serde(default)]
+  pub response_timeout_secs: Option<i64>,
+  #[serde(default)]
+  pub read_idle_timeout_secs: Option<i64>,
+  #[serde(default)]
+  pub user_agent: Option<String>,
+  /// "http1" (default) or "http2".
+  #[serde(default)]
+  pub http_version: Option<String>,
+  #[serde(default)]
+  pub max_redirects: Option<i64>,
+  #[serde(default)]
+  pub block_cross_host_redirects: bool,
+  /// "ipv4", "ipv6" or null for the resolver's order.
+  #[serde(default)]
+  pub ip_preference: Option<String>,
+  /// Pinned addresses, curl `--resolve` style: `host[:port]:addr[,addr]`, one per line.
+  #[serde(default)]
+  pub dns_overrides: Option<String>,
+  /// DNS-over-HTTPS endpoint used instead of the system resolver.
+  #[serde(default)]
+  pub doh_url: Option<String>,
+  /// PEM file of extra CA certificates trusted alongside the built-in roots.
+  #[serde(default)]
+  pub ca_bundle_path: Option<String>,
+  /// PEM client certificate chain for mTLS; the key may be in the same file.
+  #[serde(default)]
+  pub client_cert_path: Option<String>,
+  #[serde(default)]
+  pub client_key_path: Option<String>,
+  /// Skip certificate and hostname validation. Only allowed on host-specific patterns.
+  #[serde(default)]
+  pub accept_invalid_certs: bool,
+  /// SSH hosts missing from `known_hosts` are trusted on first use and added to it (OpenSSH's
+  /// `accept-new`); a changed key is still refused.
+  #[serde(default)]
+  pub ssh_accept_new_host_keys: bool,
+  /// Source IPs or interface names to connect from, comma-separated; several spread the
+  /// segments of a multipart download across them.
+  #[serde(default)]
+  pub local_address: Option<String>,
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct RulesSnapshot {
   pub proxy_rules: Vec<ProxyRule>,
   pub header_rules: Vec<HeaderRule>,
   pub mirror_rules: Vec<MirrorRule>,
+  #[serde(default)]
+  pub segment_rules: Vec<SegmentRule>,
+  #[serde(default)]
+  pub transport_rules: Vec<TransportRule>,
+  #[serde(default)]
+  pub proxy_pools: Vec<ProxyPool>,
+  #[serde(default)]
+  pub http_credentials: Vec<HttpCredential>,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -103,12 +336,39 @@ pub struct NewBatchRequest {
   pub raw_url_list: Option<String>,
   pub urls: Vec<String>,
   pub download_through_proxy: Option<bool>,
+  /// Representations to fetch when a URL is a DASH manifest (see `cmd_probe_dash`).
+  #[serde(default)]
+  pub dash: DashChoice,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct AddDownloadsRequest {
   pub urls: Vec<String>,
   pub dest_dir: Option<String>,
+  /// Representations to fetch when a URL is a DASH manifest (see `cmd_probe_dash`).
+  #[serde(default)]
+  pub dash: DashChoice,
+}
+
+/// DASH representation ids chosen for a download; a `None` track follows the stream settings.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DashChoice {
+  #[serde(default)]
+  pub video_id: Option<String>,
+  #[serde(default)]
+  pub audio_id: Option<String>,
+}
+
+/// One representation a DASH manifest offers, as listed for the add dialog.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DashRepresentation {
+  pub id: String,
+  /// "video" or "audio".
+  pub kind: String,
+  pub mime: String,
+  pub bandwidth: u64,
+  pub height: Option<u32>,
+  pub lang: Option<String>,
 }
 
 
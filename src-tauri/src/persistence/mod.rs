@@ -1,13 +1,21 @@
+pub mod secrets;
+
 use crate::{
   app_state::AppPaths,
   model::{
-    DownloadRecord, DownloadStatus, HeaderRule, MirrorRule, ProxyRule, RulesSnapshot,
-    SettingsSnapshot,
+    DashChoice, DownloadRecord, DownloadStatus, HeaderRule, HttpCredential, StoredCookie, MirrorRule, ProxyCredentials, ProxyPool, ProxyRule, RulesSnapshot,
+    SegmentRule, SettingsSnapshot, TransportRule, DEFAULT_CONNECT_TIMEOUT_SECS, DEFAULT_MULTIPART_MAX_SEGMENTS,
+    DEFAULT_MULTIPART_MIN_SIZE_BYTES, DEFAULT_MULTIPART_SEGMENT_SIZE_BYTES, DEFAULT_PROXY_MODE,
+    DEFAULT_READ_IDLE_TIMEOUT_SECS, DEFAULT_RESPONSE_TIMEOUT_SECS, DEFAULT_STREAM_AUDIO, DEFAULT_STREAM_VARIANT,
   },
+  transport::{pool, proxy},
 };
 use anyhow::Context;
 use parking_lot::Mutex;
 use rusqlite::{params, Connection, OptionalExtension};
+use secrets::{
+  cookie_secret_name, header_rule_secret_prefix, http_credential_name, proxy_password_name, write_cookies, SecretRow, SecretVault, LOCAL_API_TOKEN,
+};
 use std::{path::PathBuf, sync::Arc};
 use uuid::Uuid;
 
@@ -17,6 +25,7 @@ pub struct Db {
   // We keep a single connection behind a mutex; DB work is small and we do hot-path updates
   // via in-memory state + periodic persistence (implemented in the engine).
   conn: Arc<Mutex<Connection>>,
+  secrets: Arc<SecretVault>,
 }
 
 impl Db {
@@ -31,6 +40,7 @@ impl Db {
     Ok(Self {
       path: Arc::new(path),
       conn: Arc::new(Mutex::new(conn)),
+      secrets: Arc::new(SecretVault::default()),
     })
   }
 
@@ -60,6 +70,9 @@ impl Db {
         use_proxy_mode TEXT,
         mirror_used TEXT,
         batch_id TEXT,
+        proxy_route TEXT,
+        dash_video_id TEXT,
+        dash_audio_id TEXT,
         FOREIGN KEY(batch_id) REFERENCES batches(id)
       );
 
@@ -100,7 +113,8 @@ impl Db {
         id INTEGER PRIMARY KEY AUTOINCREMENT,
         pattern TEXT NOT NULL,
         enabled INTEGER NOT NULL,
-        headers_json TEXT NOT NULL
+        headers_json TEXT NOT NULL,
+        priority INTEGER NOT NULL DEFAULT 0
       );
 
       CREATE TABLE IF NOT EXISTS mirror_rules (
@@ -110,6 +124,99 @@ impl Db {
         candidates_json TEXT NOT NULL
       );
 
+      CREATE TABLE IF NOT EXISTS segment_rules (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        pattern TEXT NOT NULL,
+        enabled INTEGER NOT NULL,
+        min_size_bytes INTEGER,
+        segment_size_bytes INTEGER,
+        max_segments INTEGER
+      );
+
+      CREATE TABLE IF NOT EXISTS proxy_credentials (
+        proxy_url TEXT PRIMARY KEY,
+        username TEXT NOT NULL,
+        password TEXT
+      );
+
+      -- Encrypted vault entries; see persistence/secrets.rs.
+      CREATE TABLE IF NOT EXISTS secrets (
+        name TEXT PRIMARY KEY,
+        nonce BLOB NOT NULL,
+        ciphertext BLOB NOT NULL,
+        updated_at TEXT NOT NULL
+      );
+
+      -- Passwords and tokens live in the secrets vault as @http_auth:<id>.
+      CREATE TABLE IF NOT EXISTS http_credentials (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        pattern TEXT NOT NULL,
+        realm TEXT,
+        scheme TEXT NOT NULL DEFAULT 'auto',
+        username TEXT NOT NULL,
+        enabled INTEGER NOT NULL DEFAULT 1,
+        key_path TEXT,
+        endpoint TEXT
+      );
+
+      -- Persistent cookie jar; see transport/cookies.rs.
+      CREATE TABLE IF NOT EXISTS cookies (
+        domain TEXT NOT NULL,
+        path TEXT NOT NULL,
+        name TEXT NOT NULL,
+        value TEXT NOT NULL,
+        host_only INTEGER NOT NULL,
+        secure INTEGER NOT NULL,
+        http_only INTEGER NOT NULL,
+        expires INTEGER,
+        -- The value sealed by the secrets vault; `value` is empty once it is.
+        nonce BLOB,
+        ciphertext BLOB,
+        PRIMARY KEY (domain, path, name)
+      );
+
+      CREATE TABLE IF NOT EXISTS proxy_pools (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL UNIQUE,
+        enabled INTEGER NOT NULL DEFAULT 1,
+        strategy TEXT NOT NULL DEFAULT 'round_robin',
+        allow_direct INTEGER NOT NULL DEFAULT 0,
+        rotate_per_segment INTEGER NOT NULL DEFAULT 0,
+        health_check_url TEXT,
+        health_check_interval_secs INTEGER NOT NULL DEFAULT 60
+      );
+
+      CREATE TABLE IF NOT EXISTS proxy_pool_members (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        pool_id INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        proxy_url TEXT NOT NULL,
+        FOREIGN KEY(pool_id) REFERENCES proxy_pools(id)
+      );
+
+      CREATE TABLE IF NOT EXISTS transport_rules (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        pattern TEXT NOT NULL,
+        enabled INTEGER NOT NULL,
+        decode_content INTEGER NOT NULL DEFAULT 0,
+        connect_timeout_secs INTEGER,
+        response_timeout_secs INTEGER,
+        read_idle_timeout_secs INTEGER,
+        user_agent TEXT,
+        http_version TEXT,
+        max_redirects INTEGER,
+        block_cross_host_redirects INTEGER NOT NULL DEFAULT 0,
+        ip_preference TEXT,
+        dns_overrides TEXT,
+        doh_url TEXT,
+        local_address TEXT,
+        ca_bundle_path TEXT,
+        client_cert_path TEXT,
+        client_key_path TEXT,
+        accept_invalid_certs INTEGER NOT NULL DEFAULT 0,
+        ssh_accept_new_host_keys INTEGER NOT NULL DEFAULT 0
+      );
+
       CREATE INDEX IF NOT EXISTS idx_downloads_status_updated ON downloads(status, updated_at);
       CREATE INDEX IF NOT EXISTS idx_segments_by_download ON download_segments(download_id);
     "#;
@@ -121,6 +228,39 @@ impl Db {
     let _ = conn.execute(r#"ALTER TABLE downloads ADD COLUMN completed_at TEXT"#, []);
     let _ = conn.execute(r#"ALTER TABLE downloads ADD COLUMN forced_proxy INTEGER NOT NULL DEFAULT 0"#, []);
     let _ = conn.execute(r#"ALTER TABLE downloads ADD COLUMN forced_proxy_url TEXT"#, []);
+    let _ = conn.execute(r#"ALTER TABLE downloads ADD COLUMN proxy_route TEXT"#, []);
+    let _ = conn.execute(r#"ALTER TABLE downloads ADD COLUMN dash_video_id TEXT"#, []);
+    let _ = conn.execute(r#"ALTER TABLE downloads ADD COLUMN dash_audio_id TEXT"#, []);
+    let _ = conn.execute(r#"ALTER TABLE cookies ADD COLUMN nonce BLOB"#, []);
+    let _ = conn.execute(r#"ALTER TABLE cookies ADD COLUMN ciphertext BLOB"#, []);
+    let _ = conn.execute(r#"ALTER TABLE transport_rules ADD COLUMN connect_timeout_secs INTEGER"#, []);
+    let _ = conn.execute(r#"ALTER TABLE transport_rules ADD COLUMN response_timeout_secs INTEGER"#, []);
+    let _ = conn.execute(r#"ALTER TABLE transport_rules ADD COLUMN read_idle_timeout_secs INTEGER"#, []);
+    let _ = conn.execute(r#"ALTER TABLE transport_rules ADD COLUMN user_agent TEXT"#, []);
+    let _ = conn.execute(r#"ALTER TABLE transport_rules ADD COLUMN http_version TEXT"#, []);
+    let _ = conn.execute(r#"ALTER TABLE transport_rules ADD COLUMN max_redirects INTEGER"#, []);
+    let _ = conn.execute(
+      r#"ALTER TABLE transport_rules ADD COLUMN block_cross_host_redirects INTEGER NOT NULL DEFAULT 0"#,
+      [],
+    );
+    let _ = conn.execute(r#"ALTER TABLE transport_rules ADD COLUMN ip_preference TEXT"#, []);
+    let _ = conn.execute(r#"ALTER TABLE transport_rules ADD COLUMN ca_bundle_path TEXT"#, []);
+    let _ = conn.execute(r#"ALTER TABLE transport_rules ADD COLUMN dns_overrides TEXT"#, []);
+    let _ = conn.execute(r#"ALTER TABLE transport_rules ADD COLUMN doh_url TEXT"#, []);
+    let _ = conn.execute(r#"ALTER TABLE transport_rules ADD COLUMN local_address TEXT"#, []);
+    let _ = conn.execute(r#"ALTER TABLE transport_rules ADD COLUMN client_cert_path TEXT"#, []);
+    let _ = conn.execute(r#"ALTER TABLE transport_rules ADD COLUMN client_key_path TEXT"#, []);
+    let _ = conn.execute(
+      r#"ALTER TABLE transport_rules ADD COLUMN accept_invalid_certs INTEGER NOT NULL DEFAULT 0"#,
+      [],
+    );
+    let _ = conn.execute(
+      r#"ALTER TABLE transport_rules ADD COLUMN ssh_accept_new_host_keys INTEGER NOT NULL DEFAULT 0"#,
+      [],
+    );
+    let _ = conn.execute(r#"ALTER TABLE header_rules ADD COLUMN priority INTEGER NOT NULL DEFAULT 0"#, []);
+    let _ = conn.execute(r#"ALTER TABLE http_credentials ADD COLUMN key_path TEXT"#, []);
+    let _ = conn.execute(r#"ALTER TABLE http_credentials ADD COLUMN endpoint TEXT"#, []);
     Ok(())
   }
 
@@ -138,7 +278,7 @@ impl Db {
           id, created_at, updated_at, started_at, completed_at, forced_proxy, forced_proxy_url,
           original_url, resolved_url, dest_dir, final_filename,
           temp_path, status, error_code, error_message, content_length, etag, last_modified,
-          bytes_downloaded, supports_ranges, mirror_used, batch_id
+          bytes_downloaded, supports_ranges, mirror_used, batch_id, proxy_route, dash_video_id, dash_audio_id
         FROM downloads
         ORDER BY created_at DESC
       "#,
@@ -173,6 +313,9 @@ impl Db {
           .map(|v| v != 0),
         mirror_used: row.get(20)?,
         batch_id: row.get(21)?,
+        proxy_route: row.get(22)?,
+        dash_video_id: row.get(23)?,
+        dash_audio_id: row.get(24)?,
       })
     })?;
 
@@ -192,7 +335,7 @@ impl Db {
             id, created_at, updated_at, started_at, completed_at, forced_proxy, forced_proxy_url,
             original_url, resolved_url, dest_dir, final_filename,
             temp_path, status, error_code, error_message, content_length, etag, last_modified,
-            bytes_downloaded, supports_ranges, mirror_used, batch_id
+            bytes_downloaded, supports_ranges, mirror_used, batch_id, proxy_route, dash_video_id, dash_audio_id
           FROM downloads
           WHERE id=?1
         "#,
@@ -226,6 +369,9 @@ impl Db {
               .map(|v| v != 0),
             mirror_used: row.get(20)?,
             batch_id: row.get(21)?,
+            proxy_route: row.get(22)?,
+            dash_video_id: row.get(23)?,
+            dash_audio_id: row.get(24)?,
           })
         },
       )
@@ -387,6 +533,16 @@ impl Db {
     Ok(())
   }
 
+  pub fn update_download_validators(&self, id: &str, etag: Option<&str>, last_modified: Option<&str>) -> anyhow::Result<()> {
+    let now = Self::now_rfc3339();
+    let conn = self.conn.lock();
+    conn.execute(
+      r#"UPDATE downloads SET updated_at=?2, etag=?3, last_modified=?4 WHERE id=?1"#,
+      params![id, now, etag, last_modified],
+    )?;
+    Ok(())
+  }
+
   pub fn update_resolved_and_mirror(&self, id: &str, resolved_url: Option<&str>, mirror_used: Option<&str>) -> anyhow::Result<()> {
     let now = Self::now_rfc3339();
     let conn = self.conn.lock();
@@ -397,6 +553,25 @@ impl Db {
     Ok(())
   }
 
+  pub fn update_dash_choice(&self, id: &str, choice: &DashChoice) -> anyhow::Result<()> {
+    let conn = self.conn.lock();
+    conn.execute(
+      r#"UPDATE downloads SET dash_video_id=?2, dash_audio_id=?3 WHERE id=?1"#,
+      params![id, choice.video_id, choice.audio_id],
+    )?;
+    Ok(())
+  }
+
+  pub fn update_proxy_route(&self, id: &str, proxy_route: &str) -> anyhow::Result<()> {
+    let now = Self::now_rfc3339();
+    let conn = self.conn.lock();
+    conn.execute(
+      r#"UPDATE downloads SET updated_at=?2, proxy_route=?3 WHERE id=?1"#,
+      params![id, now, proxy_route],
+    )?;
+    Ok(())
+  }
+
   pub fn reset_download_for_retry(&self, id: &str) -> anyhow::Result<()> {
     let now = Self::now_rfc3339();
     let conn = self.conn.lock();
@@ -557,6 +732,54 @@ impl Db {
         .get_setting_raw("local_api_port")?
         .and_then(|s| s.parse::<i64>().ok())
         .unwrap_or(17777),
+      multipart_min_size_bytes: self
+        .get_setting_raw("multipart_min_size_bytes")?
+        .and_then(|s| s.parse::<i64>().ok())
+        .filter(|v| *v >= 0)
+        .unwrap_or(DEFAULT_MULTIPART_MIN_SIZE_BYTES),
+      multipart_segment_size_bytes: self
+        .get_setting_raw("multipart_segment_size_bytes")?
+        .and_then(|s| s.parse::<i64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_MULTIPART_SEGMENT_SIZE_BYTES),
+      multipart_max_segments: self
+        .get_setting_raw("multipart_max_segments")?
+        .and_then(|s| s.parse::<i64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_MULTIPART_MAX_SEGMENTS),
+      connect_timeout_secs: self
+        .get_setting_raw("connect_timeout_secs")?
+        .and_then(|s| s.parse::<i64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS),
+      response_timeout_secs: self
+        .get_setting_raw("response_timeout_secs")?
+        .and_then(|s| s.parse::<i64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_RESPONSE_TIMEOUT_SECS),
+      read_idle_timeout_secs: self
+        .get_setting_raw("read_idle_timeout_secs")?
+        .and_then(|s| s.parse::<i64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_READ_IDLE_TIMEOUT_SECS),
+      proxy_mode: self
+        .get_setting_raw("proxy_mode")?
+        .filter(|s| !s.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_PROXY_MODE.to_string()),
+      pac_source: self
+        .get_setting_raw("pac_source")?
+        .and_then(|s| if s.trim().is_empty() { None } else { Some(s) }),
+      stream_variant: self
+        .get_setting_raw("stream_variant")?
+        .filter(|s| !s.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_STREAM_VARIANT.to_string()),
+      stream_audio: self
+        .get_setting_raw("stream_audio")?
+        .filter(|s| !s.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_STREAM_AUDIO.to_string()),
+      stream_audio_language: self
+        .get_setting_raw("stream_audio_language")?
+        .and_then(|s| if s.trim().is_empty() { None } else { Some(s) }),
     })
   }
 
@@ -573,17 +796,42 @@ impl Db {
     self.set_setting_raw("global_proxy_enabled", if s.global_proxy_enabled { "1" } else { "0" })?;
     self.set_setting_raw("global_proxy_url", s.global_proxy_url.as_deref().unwrap_or(""))?;
     self.set_setting_raw("local_api_port", &s.local_api_port.to_string())?;
+    self.set_setting_raw("multipart_min_size_bytes", &s.multipart_min_size_bytes.to_string())?;
+    self.set_setting_raw("multipart_segment_size_bytes", &s.multipart_segment_size_bytes.to_string())?;
+    self.set_setting_raw("multipart_max_segments", &s.multipart_max_segments.to_string())?;
+    self.set_setting_raw("connect_timeout_secs", &s.connect_timeout_secs.to_string())?;
+    self.set_setting_raw("response_timeout_secs", &s.response_timeout_secs.to_string())?;
+    self.set_setting_raw("read_idle_timeout_secs", &s.read_idle_timeout_secs.to_string())?;
+    self.set_setting_raw("proxy_mode", &s.proxy_mode)?;
+    self.set_setting_raw("pac_source", s.pac_source.as_deref().unwrap_or(""))?;
+    self.set_setting_raw("stream_variant", &s.stream_variant)?;
+    self.set_setting_raw("stream_audio", &s.stream_audio)?;
+    self.set_setting_raw("stream_audio_language", s.stream_audio_language.as_deref().unwrap_or(""))?;
+    Ok(())
+  }
+
+  fn delete_setting_raw(&self, key: &str) -> anyhow::Result<()> {
+    let conn = self.conn.lock();
+    conn.execute(r#"DELETE FROM settings WHERE key=?1"#, params![key])?;
     Ok(())
   }
 
+  /// Kept in the secrets vault; a token from an older plaintext setting is moved there once the
+  /// vault is unlocked. Fails while locked rather than handing out a different token.
   pub fn get_local_api_token(&self) -> anyhow::Result<String> {
-    if let Some(v) = self.get_setting_raw("local_api_token")? {
-      if !v.is_empty() {
-        return Ok(v);
-      }
+    if let Some(v) = self.secrets.get(LOCAL_API_TOKEN) {
+      return Ok(v);
+    }
+    let plaintext = self.get_setting_raw("local_api_token")?.filter(|v| !v.is_empty());
+    if !self.secrets.is_unlocked() {
+      return match plaintext {
+        Some(v) if !self.has_secret_row(LOCAL_API_TOKEN)? => Ok(v),
+        _ => Err(anyhow::anyhow!("secrets are locked")),
+      };
     }
-    let token = Uuid::new_v4().to_string();
-    self.set_setting_raw("local_api_token", &token)?;
+    let token = plaintext.unwrap_or_else(|| Uuid::new_v4().to_string());
+    self.put_secret(LOCAL_API_TOKEN, &token)?;
+    self.delete_setting_raw("local_api_token")?;
     Ok(token)
   }
 
@@ -608,13 +856,14 @@ impl Db {
     }
 
     let mut header_stmt =
-      conn.prepare(r#"SELECT id, pattern, enabled, headers_json FROM header_rules ORDER BY id DESC"#)?;
+      conn.prepare(r#"SELECT id, pattern, enabled, headers_json, priority FROM header_rules ORDER BY id DESC"#)?;
     let header_rows = header_stmt.query_map([], |r| {
       let raw: String = r.get(3)?;
       Ok(HeaderRule {
         id: r.get(0)?,
         pattern: r.get(1)?,
         enabled: r.get::<_, i64>(2)? != 0,
+        priority: r.get(4)?,
         headers_json: serde_json::from_str(&raw).unwrap_or(serde_json::json!({})),
       })
     })?;
@@ -639,10 +888,121 @@ impl Db {
       mirror_rules.push(r?);
     }
 
+    let mut segment_stmt = conn.prepare(
+      r#"SELECT id, pattern, enabled, min_size_bytes, segment_size_bytes, max_segments FROM segment_rules ORDER BY id DESC"#,
+    )?;
+    let segment_rows = segment_stmt.query_map([], |r| {
+      Ok(SegmentRule {
+        id: r.get(0)?,
+        pattern: r.get(1)?,
+        enabled: r.get::<_, i64>(2)? != 0,
+        min_size_bytes: r.get(3)?,
+        segment_size_bytes: r.get(4)?,
+        max_segments: r.get(5)?,
+      })
+    })?;
+    let mut segment_rules = Vec::new();
+    for r in segment_rows {
+      segment_rules.push(r?);
+    }
+
+    let mut transport_stmt = conn.prepare(
+      r#"SELECT id, pattern, enabled, decode_content, connect_timeout_secs, response_timeout_secs, read_idle_timeout_secs,
+                user_agent, http_version, max_redirects, block_cross_host_redirects, ip_preference,
+                ca_bundle_path, client_cert_path, client_key_path, accept_invalid_certs, dns_overrides, doh_url,
+                local_address, ssh_accept_new_host_keys
+         FROM transport_rules ORDER BY id DESC"#,
+    )?;
+    let transport_rows = transport_stmt.query_map([], |r| {
+      Ok(TransportRule {
+        id: r.get(0)?,
+        pattern: r.get(1)?,
+        enabled: r.get::<_, i64>(2)? != 0,
+        decode_content: r.get::<_, i64>(3)? != 0,
+        connect_timeout_secs: r.get(4)?,
+        response_timeout_secs: r.get(5)?,
+        read_idle_timeout_secs: r.get(6)?,
+        user_agent: r.get(7)?,
+        http_version: r.get(8)?,
+        max_redirects: r.get(9)?,
+        block_cross_host_redirects: r.get::<_, i64>(10)? != 0,
+        ip_preference: r.get(11)?,
+        ca_bundle_path: r.get(12)?,
+        client_cert_path: r.get(13)?,
+        client_key_path: r.get(14)?,
+        accept_invalid_certs: r.get::<_, i64>(15)? != 0,
+        dns_overrides: r.get(16)?,
+        doh_url: r.get(17)?,
+        local_address: r.get(18)?,
+        ssh_accept_new_host_keys: r.get::<_, i64>(19)? != 0,
+      })
+    })?;
+    let mut transport_rules = Vec::new();
+    for r in transport_rows {
+      transport_rules.push(r?);
+    }
+
+    let mut pool_stmt = conn.prepare(
+      r#"SELECT id, name, enabled, strategy, allow_direct, rotate_per_segment, health_check_url, health_check_interval_secs
+         FROM proxy_pools ORDER BY name"#,
+    )?;
+    let pool_rows = pool_stmt.query_map([], |r| {
+      Ok(ProxyPool {
+        id: r.get(0)?,
+        name: r.get(1)?,
+        enabled: r.get::<_, i64>(2)? != 0,
+        strategy: r.get(3)?,
+        allow_direct: r.get::<_, i64>(4)? != 0,
+        rotate_per_segment: r.get::<_, i64>(5)? != 0,
+        health_check_url: r.get(6)?,
+        health_check_interval_secs: r.get(7)?,
+        members: Vec::new(),
+      })
+    })?;
+    let mut proxy_pools = Vec::new();
+    for r in pool_rows {
+      proxy_pools.push(r?);
+    }
+    let mut member_stmt =
+      conn.prepare(r#"SELECT proxy_url FROM proxy_pool_members WHERE pool_id=?1 ORDER BY position"#)?;
+    for pool in &mut proxy_pools {
+      let rows = member_stmt.query_map(params![pool.id], |r| r.get::<_, String>(0))?;
+      for r in rows {
+        pool.members.push(r?);
+      }
+    }
+
+    let mut cred_stmt = conn.prepare(
+      r#"SELECT id, pattern, realm, scheme, username, enabled, key_path, endpoint FROM http_credentials ORDER BY id DESC"#,
+    )?;
+    let cred_rows = cred_stmt.query_map([], |r| {
+      Ok(HttpCredential {
+        id: r.get(0)?,
+        pattern: r.get(1)?,
+        realm: r.get(2)?,
+        scheme: r.get(3)?,
+        username: r.get(4)?,
+        enabled: r.get::<_, i64>(5)? != 0,
+        password: None,
+        key_path: r.get(6)?,
+        endpoint: r.get(7)?,
+      })
+    })?;
+    let mut http_credentials = Vec::new();
+    for r in cred_rows {
+      let mut c = r?;
+      c.password = self.secrets.get(&http_credential_name(c.id));
+      http_credentials.push(c);
+    }
+
     Ok(RulesSnapshot {
       proxy_rules,
       header_rules,
       mirror_rules,
+      segment_rules,
+      transport_rules,
+      proxy_pools,
+      http_credentials,
     })
   }
 
@@ -683,6 +1043,7 @@ impl Db {
     id: Option<i64>,
     pattern: &str,
     enabled: bool,
+    priority: i64,
     headers_json: &serde_json::Value,
   ) -> anyhow::Result<i64> {
     let conn = self.conn.lock();
@@ -690,22 +1051,38 @@ impl Db {
     let raw = serde_json::to_string(headers_json)?;
     if let Some(id) = id {
       conn.execute(
-        r#"UPDATE header_rules SET pattern=?2, enabled=?3, headers_json=?4 WHERE id=?1"#,
-        params![id, pattern, enabled_i, raw],
+        r#"UPDATE header_rules SET pattern=?2, enabled=?3, headers_json=?4, priority=?5 WHERE id=?1"#,
+        params![id, pattern, enabled_i, raw, priority],
       )?;
       Ok(id)
     } else {
       conn.execute(
-        r#"INSERT INTO header_rules(pattern, enabled, headers_json) VALUES(?1, ?2, ?3)"#,
-        params![pattern, enabled_i, raw],
+        r#"INSERT INTO header_rules(pattern, enabled, headers_json, priority) VALUES(?1, ?2, ?3, ?4)"#,
+        params![pattern, enabled_i, raw, priority],
       )?;
       Ok(conn.last_insert_rowid())
     }
   }
 
+  /// Deletes the rule together with the header values it sealed into the vault.
   pub fn delete_header_rule(&self, id: i64) -> anyhow::Result<()> {
-    let conn = self.conn.lock();
-    conn.execute(r#"DELETE FROM header_rules WHERE id=?1"#, params![id])?;
+    let prefix = header_rule_secret_prefix(id);
+    let names: Vec<String> = {
+      let mut conn = self.conn.lock();
+      let tx = conn.transaction()?;
+      tx.execute(r#"DELETE FROM header_rules WHERE id=?1"#, params![id])?;
+      let names = {
+        let mut stmt = tx.prepare(r#"SELECT name FROM secrets WHERE substr(name, 1, length(?1)) = ?1"#)?;
+        let rows = stmt.query_map(params![prefix], |r| r.get(0))?;
+        rows.collect::<Result<_, _>>()?
+      };
+      tx.execute(r#"DELETE FROM secrets WHERE substr(name, 1, length(?1)) = ?1"#, params![prefix])?;
+      tx.commit()?;
+      names
+    };
+    for name in names {
+      self.secrets.forget(&name);
+    }
     Ok(())
   }
 
@@ -739,6 +1116,329 @@ impl Db {
     conn.execute(r#"DELETE FROM mirror_rules WHERE id=?1"#, params![id])?;
     Ok(())
   }
+
+  /// A `None` password keeps the stored one; new passwords go to the secrets vault, which must
+  /// be unlocked.
+  pub fn upsert_http_credential(&self, cred: &HttpCredential) -> anyhow::Result<i64> {
+    if cred.password.is_some() && !self.secrets.is_unlocked() {
+      anyhow::bail!("unlock secrets to store a password");
+    }
+    let id = {
+      let conn = self.conn.lock();
+      let enabled_i = if cred.enabled { 1 } else { 0 };
+      let realm = cred.realm.as_deref().filter(|r| !r.is_empty());
+      let key_path = cred.key_path.as_deref().filter(|p| !p.is_empty());
+      let endpoint = cred.endpoint.as_deref().filter(|e| !e.is_empty());
+      if cred.id > 0 {
+        conn.execute(
+          r#"UPDATE http_credentials SET pattern=?2, realm=?3, scheme=?4, username=?5, enabled=?6, key_path=?7, endpoint=?8
+             WHERE id=?1"#,
+          params![cred.id, cred.pattern, realm, cred.scheme, cred.username, enabled_i, key_path, endpoint],
+        )?;
+        cred.id
+      } else {
+        conn.execute(
+          r#"INSERT INTO http_credentials(pattern, realm, scheme, username, enabled, key_path, endpoint)
+             VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
+          params![cred.pattern, realm, cred.scheme, cred.username, enabled_i, key_path, endpoint],
+        )?;
+        conn.last_insert_rowid()
+      }
+    };
+    if let Some(password) = &cred.password {
+      self.put_secret(&http_credential_name(id), password)?;
+    }
+    Ok(id)
+  }
+
+  pub fn delete_http_credential(&self, id: i64) -> anyhow::Result<()> {
+    {
+      let conn = self.conn.lock();
+      conn.execute(r#"DELETE FROM http_credentials WHERE id=?1"#, params![id])?;
+    }
+    self.delete_secret(&http_credential_name(id))
+  }
+
+  pub fn upsert_segment_rule(
+    &self,
+    id: Option<i64>,
+    pattern: &str,
+    enabled: bool,
+    min_size_bytes: Option<i64>,
+    segment_size_bytes: Option<i64>,
+    max_segments: Option<i64>,
+  ) -> anyhow::Result<i64> {
+    let conn = self.conn.lock();
+    let enabled_i = if enabled { 1 } else { 0 };
+    if let Some(id) = id {
+      conn.execute(
+        r#"UPDATE segment_rules SET pattern=?2, enabled=?3, min_size_bytes=?4, segment_size_bytes=?5, max_segments=?6 WHERE id=?1"#,
+        params![id, pattern, enabled_i, min_size_bytes, segment_size_bytes, max_segments],
+      )?;
+      Ok(id)
+    } else {
+      conn.execute(
+        r#"INSERT INTO segment_rules(pattern, enabled, min_size_bytes, segment_size_bytes, max_segments) VALUES(?1, ?2, ?3, ?4, ?5)"#,
+        params![pattern, enabled_i, min_size_bytes, segment_size_bytes, max_segments],
+      )?;
+      Ok(conn.last_insert_rowid())
+    }
+  }
+
+  pub fn delete_segment_rule(&self, id: i64) -> anyhow::Result<()> {
+    let conn = self.conn.lock();
+    conn.execute(r#"DELETE FROM segment_rules WHERE id=?1"#, params![id])?;
+    Ok(())
+  }
+
+  /// Passwords moved to the vault are stored as NULL here and filled back in while unlocked.
+  fn with_vault_password(&self, mut creds: ProxyCredentials) -> ProxyCredentials {
+    if creds.password.is_none() {
+      creds.password = self.secrets.get(&proxy_password_name(&creds.proxy_url));
+    }
+    creds
+  }
+
+  /// Fails while the vault is locked over a password it holds, rather than connecting without it.
+  pub fn get_proxy_credentials(&self, proxy_url: &str) -> anyhow::Result<Option<ProxyCredentials>> {
+    let v = self
+      .conn
+      .lock()
+      .query_row(
+        r#"SELECT proxy_url, username, password FROM proxy_credentials WHERE proxy_url=?1"#,
+        params![proxy_url],
+        |r| {
+          Ok(ProxyCredentials {
+            proxy_url: r.get(0)?,
+            username: r.get(1)?,
+            password: r.get(2)?,
+          })
+        },
+      )
+      .optional()?;
+    let Some(creds) = v.map(|c| self.with_vault_password(c)) else {
+      return Ok(None);
+    };
+    if creds.password.is_none() && !self.secrets.is_unlocked() && self.has_secret_row(&proxy_password_name(proxy_url))? {
+      anyhow::bail!("secrets are locked; unlock them to use the stored login for {proxy_url}");
+    }
+    Ok(Some(creds))
+  }
+
+  pub fn list_proxy_credentials(&self) -> anyhow::Result<Vec<ProxyCredentials>> {
+    let conn = self.conn.lock();
+    let mut stmt = conn.prepare(r#"SELECT proxy_url, username, password FROM proxy_credentials ORDER BY proxy_url"#)?;
+    let rows = stmt.query_map([], |r| {
+      Ok(ProxyCredentials {
+        proxy_url: r.get(0)?,
+        username: r.get(1)?,
+        password: r.get(2)?,
+      })
+    })?;
+    let mut out = Vec::new();
+    for r in rows {
+      out.push(self.with_vault_password(r?));
+    }
+    Ok(out)
+  }
+
+  /// A `None` password keeps whatever is stored, so the UI can rename a user without re-typing it.
+  /// New passwords go to the secrets vault, which must be unlocked.
+  pub fn set_proxy_credentials(&self, creds: &ProxyCredentials) -> anyhow::Result<()> {
+    let sealed = creds.password.is_some();
+    if let Some(password) = &creds.password {
+      self
+        .put_secret(&proxy_password_name(&creds.proxy_url), password)
+        .context("cannot store the proxy password")?;
+    }
+    let conn = self.conn.lock();
+    conn.execute(
+      r#"INSERT INTO proxy_credentials(proxy_url, username, password) VALUES(?1, ?2, NULL)
+         ON CONFLICT(proxy_url) DO UPDATE SET username=excluded.username,
+           password=CASE WHEN ?3 THEN NULL ELSE proxy_credentials.password END"#,
+      params![creds.proxy_url, creds.username, sealed],
+    )?;
+    Ok(())
+  }
+
+  /// Moves `user:pass@` out of a proxy URL into the credentials table and returns the bare URL,
+  /// so passwords never end up in settings, rules or logs. `pool://` references pass through.
+  pub fn store_inline_proxy_credentials(&self, raw: &str) -> anyhow::Result<String> {
+    if pool::pool_name(raw).is_some() {
+      return Ok(raw.trim().to_string());
+    }
+    let (bare, creds) = proxy::split_credentials(raw)?;
+    if let Some(creds) = creds {
+      self.set_proxy_credentials(&creds)?;
+    }
+    Ok(bare)
+  }
+
+  pub fn delete_proxy_credentials(&self, proxy_url: &str) -> anyhow::Result<()> {
+    {
+      let conn = self.conn.lock();
+      conn.execute(r#"DELETE FROM proxy_credentials WHERE proxy_url=?1"#, params![proxy_url])?;
+    }
+    self.delete_secret(&proxy_password_name(proxy_url))
+  }
+
+  /// Stored cookies. Sealed values are opened through the secrets vault; while it is locked,
+  /// those cookies are left out.
+  pub fn list_cookies(&self) -> anyhow::Result<Vec<StoredCookie>> {
+    let conn = self.conn.lock();
+    let mut stmt = conn.prepare(
+      r#"SELECT domain, host_only, path, secure, http_only, expires, name, value, nonce, ciphertext
+         FROM cookies ORDER BY domain, path, name"#,
+    )?;
+    let rows = stmt.query_map([], |r| {
+      let cookie = StoredCookie {
+        domain: r.get(0)?,
+        host_only: r.get::<_, i64>(1)? != 0,
+        path: r.get(2)?,
+        secure: r.get::<_, i64>(3)? != 0,
+        http_only: r.get::<_, i64>(4)? != 0,
+        expires: r.get(5)?,
+        name: r.get(6)?,
+        value: r.get(7)?,
+      };
+      let sealed: Option<(Vec<u8>, Vec<u8>)> = r.get::<_, Option<Vec<u8>>>(8)?.zip(r.get(9)?);
+      Ok((cookie, sealed))
+    })?;
+    let mut out = Vec::new();
+    for r in rows {
+      let (mut cookie, sealed) = r?;
+      if let Some((nonce, ciphertext)) = sealed {
+        let row = SecretRow { name: cookie_secret_name(&cookie), nonce, ciphertext };
+        let Some(value) = self.secrets.decrypt(&row) else { continue };
+        cookie.value = value;
+      }
+      out.push(cookie);
+    }
+    Ok(out)
+  }
+
+  /// Replaces the stored jar with `cookies` (the jar is small, so it is written whole). Values
+  /// are sealed by the secrets vault, which must be unlocked.
+  pub fn replace_cookies(&self, cookies: &[StoredCookie]) -> anyhow::Result<()> {
+    let sealed = cookies
+      .iter()
+      .map(|c| Ok((c.clone(), self.secrets.encrypt(&cookie_secret_name(c), &c.value)?)))
+      .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut conn = self.conn.lock();
+    let tx = conn.transaction()?;
+    write_cookies(&tx, &sealed)?;
+    tx.commit()?;
+    Ok(())
+  }
+
+  pub fn upsert_transport_rule(&self, rule: &TransportRule) -> anyhow::Result<i64> {
+    let conn = self.conn.lock();
+    let enabled_i = if rule.enabled { 1 } else { 0 };
+    let decode_i = if rule.decode_content { 1 } else { 0 };
+    let block_i = if rule.block_cross_host_redirects { 1 } else { 0 };
+    let insecure_i = if rule.accept_invalid_certs { 1 } else { 0 };
+    let accept_new_i = if rule.ssh_accept_new_host_keys { 1 } else { 0 };
+    let trimmed = |p: &Option<String>| p.as_deref().map(str::trim).filter(|p| !p.is_empty()).map(str::to_string);
+    let values = params![
+      rule.pattern,
+      enabled_i,
+      decode_i,
+      rule.connect_timeout_secs,
+      rule.response_timeout_secs,
+      rule.read_idle_timeout_secs,
+      rule.user_agent,
+      rule.http_version,
+      rule.max_redirects,
+      block_i,
+      rule.ip_preference,
+      trimmed(&rule.ca_bundle_path),
+      trimmed(&rule.client_cert_path),
+      trimmed(&rule.client_key_path),
+      insecure_i,
+      trimmed(&rule.dns_overrides),
+      trimmed(&rule.doh_url),
+      trimmed(&rule.local_address),
+      accept_new_i,
+      rule.id,
+    ];
+    if rule.id > 0 {
+      conn.execute(
+        r#"UPDATE transport_rules SET pattern=?1, enabled=?2, decode_content=?3, connect_timeout_secs=?4,
+             response_timeout_secs=?5, read_idle_timeout_secs=?6, user_agent=?7, http_version=?8,
+             max_redirects=?9, block_cross_host_redirects=?10, ip_preference=?11, ca_bundle_path=?12,
+             client_cert_path=?13, client_key_path=?14, accept_invalid_certs=?15, dns_overrides=?16, doh_url=?17,
+             local_address=?18, ssh_accept_new_host_keys=?19
+           WHERE id=?20"#,
+        values,
+      )?;
+      Ok(rule.id)
+    } else {
+      conn.execute(
+        r#"INSERT INTO transport_rules(pattern, enabled, decode_content, connect_timeout_secs, response_timeout_secs,
+             read_idle_timeout_secs, user_agent, http_version, max_redirects, block_cross_host_redirects, ip_preference,
+             ca_bundle_path, client_cert_path, client_key_path, accept_invalid_certs, dns_overrides, doh_url,
+             local_address, ssh_accept_new_host_keys)
+           VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19)"#,
+        &values[..19],
+      )?;
+      Ok(conn.last_insert_rowid())
+    }
+  }
+
+  pub fn delete_transport_rule(&self, id: i64) -> anyhow::Result<()> {
+    let conn = self.conn.lock();
+    conn.execute(r#"DELETE FROM transport_rules WHERE id=?1"#, params![id])?;
+    Ok(())
+  }
+
+  /// Saves the pool and replaces its member list.
+  pub fn upsert_proxy_pool(&self, pool: &ProxyPool) -> anyhow::Result<i64> {
+    let mut conn = self.conn.lock();
+    let tx = conn.transaction()?;
+    let values = params![
+      pool.name,
+      if pool.enabled { 1 } else { 0 },
+      pool.strategy,
+      if pool.allow_direct { 1 } else { 0 },
+      if pool.rotate_per_segment { 1 } else { 0 },
+      pool.health_check_url,
+      pool.health_check_interval_secs,
+      pool.id,
+    ];
+    let id = if pool.id > 0 {
+      tx.execute(
+        r#"UPDATE proxy_pools SET name=?1, enabled=?2, strategy=?3, allow_direct=?4, rotate_per_segment=?5,
+             health_check_url=?6, health_check_interval_secs=?7
+           WHERE id=?8"#,
+        values,
+      )?;
+      pool.id
+    } else {
+      tx.execute(
+        r#"INSERT INTO proxy_pools(name, enabled, strategy, allow_direct, rotate_per_segment, health_check_url,
+             health_check_interval_secs)
+           VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
+        &values[..7],
+      )?;
+      tx.last_insert_rowid()
+    };
+    tx.execute(r#"DELETE FROM proxy_pool_members WHERE pool_id=?1"#, params![id])?;
+    for (position, url) in pool.members.iter().enumerate() {
+      tx.execute(
+        r#"INSERT INTO proxy_pool_members(pool_id, position, proxy_url) VALUES(?1, ?2, ?3)"#,
+        params![id, position as i64, url],
+      )?;
+    }
+    tx.commit()?;
+    Ok(id)
+  }
+
+  pub fn delete_proxy_pool(&self, id: i64) -> anyhow::Result<()> {
+    let conn = self.conn.lock();
+    conn.execute(r#"DELETE FROM proxy_pool_members WHERE pool_id=?1"#, params![id])?;
+    conn.execute(r#"DELETE FROM proxy_pools WHERE id=?1"#, params![id])?;
+    Ok(())
+  }
 }
 
 fn parse_status(s: &str) -> DownloadStatus {
@@ -771,6 +1471,7 @@ fn parse_error_code(s: String) -> Option<crate::error::ErrorCode> {
     "HTTP_4XX" => Http4xx,
     "HTTP_5XX" => Http5xx,
     "TIMEOUT" => Timeout,
+    "RATE_LIMITED" => RateLimited,
     "RANGE_UNSUPPORTED" => RangeUnsupported,
     "DISK_FULL" => DiskFull,
     "REMOTE_CHANGED" => RemoteChanged,
@@ -846,7 +1547,9 @@ impl SettingsStore {
       snap.local_api_port = 17777;
     }
     self.db.set_settings_snapshot(&snap)?;
-    let _token = self.db.get_local_api_token()?;
+    if self.db.secrets().is_unlocked() {
+      let _token = self.db.get_local_api_token()?;
+    }
     Ok(())
   }
 }
@@ -0,0 +1,814 @@
+//! Secrets vault: tokens and passwords encrypted at rest in the `secrets` table.
+//!
+//! Values are encrypted with ChaCha20-Poly1305 under a key that is either random and kept in
+//! `secrets.key` next to the database (unlocked automatically), or derived from a master
+//! passphrase with PBKDF2 (locked until the user unlocks it). While unlocked, decrypted values
+//! are held in memory; `Db` and `Transport` read them from there transparently.
+//!
+//! User secrets are referenced from header rules as `${secret:name}`. Internal entries start
+//! with `@`: proxy passwords (`@proxy:<url>`), HTTP credentials (`@http_auth:<id>`), the local
+//! API token and the passphrase check.
+//!
+//! Cookie values are sealed under the same key but stay in the `cookies` table, one per row.
+
+use super::Db;
+use crate::model::{HeaderRule, StoredCookie};
+use crate::transport::proxy::split_credentials;
+use anyhow::{anyhow, bail, Context};
+use parking_lot::RwLock;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use rusqlite::{params, OptionalExtension};
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+pub const KEY_LEN: usize = 32;
+pub const LOCAL_API_TOKEN: &str = "@local_api_token";
+/// Sealed with every passphrase-derived key, so a wrong passphrase fails even with no secrets.
+const PASSPHRASE_CHECK: &str = "@check";
+const PASSPHRASE_CHECK_VALUE: &str = "zdmr";
+const KDF_SALT_SETTING: &str = "secrets_kdf_salt";
+const KDF_ITERATIONS_SETTING: &str = "secrets_kdf_iterations";
+const DEFAULT_KDF_ITERATIONS: u32 = 600_000;
+
+/// One encrypted row of the `secrets` table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecretRow {
+  pub name: String,
+  pub nonce: Vec<u8>,
+  pub ciphertext: Vec<u8>,
+}
+
+/// Where the vault key comes from, and whether it is currently unlocked.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct SecretsStatus {
+  /// "key_file" or "passphrase".
+  pub mode: String,
+  pub unlocked: bool,
+}
+
+pub fn proxy_password_name(proxy_url: &str) -> String {
+  format!("@proxy:{proxy_url}")
+}
+
+pub fn http_credential_name(id: i64) -> String {
+  format!("@http_auth:{id}")
+}
+
+/// Every sealed value of header rule `id` is named with this prefix and the header's slug.
+pub fn header_rule_secret_prefix(id: i64) -> String {
+  format!("header_rule_{id}_")
+}
+
+/// Binds a sealed cookie value to the cookie it belongs to.
+pub fn cookie_secret_name(c: &StoredCookie) -> String {
+  format!("@cookie:{}\t{}\t{}", c.domain, c.path, c.name)
+}
+
+/// Rewrites the `cookies` table with values already sealed.
+pub(super) fn write_cookies(tx: &rusqlite::Transaction<'_>, cookies: &[(StoredCookie, SecretRow)]) -> rusqlite::Result<()> {
+  tx.execute(r#"DELETE FROM cookies"#, [])?;
+  for (c, row) in cookies {
+    tx.execute(
+      r#"INSERT OR REPLACE INTO cookies(domain, path, name, value, host_only, secure, http_only, expires, nonce, ciphertext)
+         VALUES(?1, ?2, ?3, '', ?4, ?5, ?6, ?7, ?8, ?9)"#,
+      params![c.domain, c.path, c.name, c.host_only as i64, c.secure as i64, c.http_only as i64, c.expires, row.nonce, row.ciphertext],
+    )?;
+  }
+  Ok(())
+}
+
+#[derive(Default)]
+struct VaultState {
+  key: Option<[u8; KEY_LEN]>,
+  values: HashMap<String, String>,
+}
+
+#[derive(Default)]
+pub struct SecretVault {
+  state: RwLock<VaultState>,
+}
+
+impl SecretVault {
+  /// Decrypts every row with `key`; on any failure (usually the wrong key) the vault stays as
+  /// it was.
+  pub fn unlock(&self, key: [u8; KEY_LEN], rows: &[SecretRow]) -> anyhow::Result<()> {
+    let mut values = HashMap::new();
+    for row in rows {
+      values.insert(row.name.clone(), open(&key, row)?);
+    }
+    *self.state.write() = VaultState { key: Some(key), values };
+    Ok(())
+  }
+
+  /// Forgets the key and every decrypted value.
+  pub fn lock(&self) {
+    *self.state.write() = VaultState::default();
+  }
+
+  pub fn is_unlocked(&self) -> bool {
+    self.state.read().key.is_some()
+  }
+
+  pub fn get(&self, name: &str) -> Option<String> {
+    self.state.read().values.get(name).cloned()
+  }
+
+  pub fn names(&self) -> Vec<String> {
+    let mut names: Vec<String> = self.state.read().values.keys().cloned().collect();
+    names.sort();
+    names
+  }
+
+  /// Encrypts `value` under `name` and remembers it; the caller stores the returned row.
+  pub fn seal(&self, name: &str, value: &str) -> anyhow::Result<SecretRow> {
+    let mut state = self.state.write();
+    let key = state.key.ok_or_else(|| anyhow!("secrets are locked"))?;
+    let row = seal(&key, name, value)?;
+    state.values.insert(name.to_string(), value.to_string());
+    Ok(row)
+  }
+
+  pub fn forget(&self, name: &str) {
+    self.state.write().values.remove(name);
+  }
+
+  /// Encrypts `value` for storage outside the `secrets` table, without remembering it.
+  pub fn encrypt(&self, name: &str, value: &str) -> anyhow::Result<SecretRow> {
+    let key = self.state.read().key.ok_or_else(|| anyhow!("secrets are locked"))?;
+    seal(&key, name, value)
+  }
+
+  /// Opens a row from `encrypt`; `None` while locked or if it was sealed under another key.
+  pub fn decrypt(&self, row: &SecretRow) -> Option<String> {
+    let key = self.state.read().key?;
+    open(&key, row).ok()
+  }
+
+  /// Every value encrypted under `key`, with the passphrase check sealed (`Some`) or left out.
+  /// The vault itself is unchanged: the caller stores the rows, then calls `adopt_key`.
+  fn reseal(&self, key: &[u8; KEY_LEN], check: Option<&str>) -> anyhow::Result<Vec<SecretRow>> {
+    let state = self.state.read();
+    if state.key.is_none() {
+      bail!("secrets are locked");
+    }
+    state
+      .values
+      .iter()
+      .filter(|(name, _)| name.as_str() != PASSPHRASE_CHECK)
+      .map(|(name, value)| seal(key, name, value))
+      .chain(check.map(|value| seal(key, PASSPHRASE_CHECK, value)))
+      .collect()
+  }
+
+  /// Switches to `key` once the rows from `reseal` are stored.
+  fn adopt_key(&self, key: [u8; KEY_LEN], check: Option<&str>) {
+    let mut state = self.state.write();
+    state.key = Some(key);
+    match check {
+      Some(value) => state.values.insert(PASSPHRASE_CHECK.to_string(), value.to_string()),
+      None => state.values.remove(PASSPHRASE_CHECK),
+    };
+  }
+}
+
+/// Names are referenced inside `${secret:...}`, so they stay simple (and never start with `@`).
+fn validate_name(name: &str) -> anyhow::Result<()> {
+  let ok = !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'-' | b'.'));
+  if !ok {
+    bail!("secret names may only contain letters, digits, '_', '-' and '.'");
+  }
+  Ok(())
+}
+
+/// Header values worth keeping out of `headers_json`.
+fn is_sensitive_header(name: &str) -> bool {
+  let n = name.to_ascii_lowercase();
+  matches!(n.as_str(), "authorization" | "proxy-authorization" | "cookie")
+    || ["token", "secret", "api-key", "apikey", "password"].iter().any(|s| n.contains(s))
+}
+
+/// Reads the key file, creating it with a random key (readable only by the user) if missing.
+fn load_or_create_key_file(path: &Path) -> anyhow::Result<[u8; KEY_LEN]> {
+  match std::fs::read(path) {
+    Ok(bytes) => bytes
+      .try_into()
+      .map_err(|_| anyhow!("{} is not a {KEY_LEN}-byte key", path.display())),
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+      let key = random_bytes::<KEY_LEN>()?;
+      write_key_file(path, &key)?;
+      Ok(key)
+    }
+    Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+  }
+}
+
+fn write_key_file(path: &Path, key: &[u8; KEY_LEN]) -> anyhow::Result<()> {
+  let mut opts = std::fs::OpenOptions::new();
+  opts.write(true).create(true).truncate(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+  let mut f = opts.open(path).with_context(|| format!("failed to create {}", path.display()))?;
+  std::io::Write::write_all(&mut f, key)?;
+  f.sync_all()?;
+  Ok(())
+}
+
+pub fn random_bytes<const N: usize>() -> anyhow::Result<[u8; N]> {
+  let mut out = [0u8; N];
+  SystemRandom::new().fill(&mut out).map_err(|_| anyhow!("no system randomness"))?;
+  Ok(out)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> [u8; KEY_LEN] {
+  let mut key = [0u8; KEY_LEN];
+  let iterations = NonZeroU32::new(iterations.max(1)).expect("non-zero");
+  pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+  key
+}
+
+fn aead_key(key: &[u8; KEY_LEN]) -> LessSafeKey {
+  LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).expect("key length"))
+}
+
+/// The name is authenticated too, so a row copied under another name fails to open.
+fn seal(key: &[u8; KEY_LEN], name: &str, value: &str) -> anyhow::Result<SecretRow> {
+  let nonce = random_bytes::<NONCE_LEN>()?;
+  let mut ciphertext = value.as_bytes().to_vec();
+  aead_key(key)
+    .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(name.as_bytes()), &mut ciphertext)
+    .map_err(|_| anyhow!("encryption failed"))?;
+  Ok(SecretRow {
+    name: name.to_string(),
+    nonce: nonce.to_vec(),
+    ciphertext,
+  })
+}
+
+fn open(key: &[u8; KEY_LEN], row: &SecretRow) -> anyhow::Result<String> {
+  let nonce = Nonce::try_assume_unique_for_key(&row.nonce).map_err(|_| anyhow!("secret {} is corrupt", row.name))?;
+  let mut buf = row.ciphertext.clone();
+  let plain = aead_key(key)
+    .open_in_place(nonce, Aad::from(row.name.as_bytes()), &mut buf)
+    .map_err(|_| anyhow!("cannot decrypt secret {} (wrong key?)", row.name))?;
+  String::from_utf8(plain.to_vec()).context("secret is not UTF-8")
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+  if s.len() % 2 != 0 {
+    return None;
+  }
+  (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+impl Db {
+  pub fn secrets(&self) -> &std::sync::Arc<SecretVault> {
+    &self.secrets
+  }
+
+  fn key_file_path(&self) -> PathBuf {
+    self.path.with_file_name("secrets.key")
+  }
+
+  pub fn secrets_status(&self) -> anyhow::Result<SecretsStatus> {
+    let mode = if self.get_setting_raw(KDF_SALT_SETTING)?.is_some() { "passphrase" } else { "key_file" };
+    Ok(SecretsStatus {
+      mode: mode.to_string(),
+      unlocked: self.secrets.is_unlocked(),
+    })
+  }
+
+  /// Unlocks with the key file, or with `passphrase` in passphrase mode, then moves any
+  /// plaintext credentials left in the database into the vault.
+  pub fn unlock_secrets(&self, passphrase: Option<&str>) -> anyhow::Result<()> {
+    let key = match self.get_setting_raw(KDF_SALT_SETTING)? {
+      Some(salt) => {
+        let passphrase = passphrase.filter(|p| !p.is_empty()).ok_or_else(|| anyhow!("a passphrase is required"))?;
+        let salt = from_hex(&salt).ok_or_else(|| anyhow!("stored passphrase salt is corrupt"))?;
+        let iterations = self
+          .get_setting_raw(KDF_ITERATIONS_SETTING)?
+          .and_then(|v| v.parse().ok())
+          .unwrap_or(DEFAULT_KDF_ITERATIONS);
+        derive_key(passphrase, &salt, iterations)
+      }
+      None => self.key_file_key()?,
+    };
+    let rows = self.list_secret_rows()?;
+    let is_passphrase = self.secrets_status()?.mode == "passphrase";
+    self
+      .secrets
+      .unlock(key, &rows)
+      .map_err(|e| if is_passphrase { anyhow!("wrong passphrase") } else { e })?;
+    self.migrate_plaintext_secrets()?;
+    Ok(())
+  }
+
+  /// The key file's key. A `secrets.key.new` left behind by a switch back from a passphrase
+  /// that committed its rows but never moved the key into place takes over when it opens the
+  /// stored rows and the key file does not; otherwise it is stale and removed.
+  fn key_file_key(&self) -> anyhow::Result<[u8; KEY_LEN]> {
+    let key_file = self.key_file_path();
+    let pending = key_file.with_extension("key.new");
+    let read = |path: &Path| std::fs::read(path).ok().and_then(|b| <[u8; KEY_LEN]>::try_from(b).ok());
+    if let Some(key) = read(&pending) {
+      let rows = self.list_secret_rows()?;
+      let opens = |key: [u8; KEY_LEN]| SecretVault::default().unlock(key, &rows).is_ok();
+      if opens(key) && !read(&key_file).is_some_and(opens) {
+        std::fs::rename(&pending, &key_file).with_context(|| format!("failed to restore {}", key_file.display()))?;
+        return Ok(key);
+      }
+      let _ = std::fs::remove_file(&pending);
+    }
+    load_or_create_key_file(&key_file)
+  }
+
+  pub fn lock_secrets(&self) {
+    self.secrets.lock();
+  }
+
+  /// Switches to passphrase mode (`Some`) or back to a key file (`None`), re-encrypting every
+  /// secret. The vault must be unlocked.
+  pub fn set_master_passphrase(&self, passphrase: Option<&str>) -> anyhow::Result<()> {
+    self.set_master_passphrase_with(passphrase, DEFAULT_KDF_ITERATIONS)
+  }
+
+  fn set_master_passphrase_with(&self, passphrase: Option<&str>, iterations: u32) -> anyhow::Result<()> {
+    if !self.secrets.is_unlocked() {
+      bail!("unlock secrets first");
+    }
+    let key_file = self.key_file_path();
+    match passphrase {
+      Some(p) => {
+        if p.chars().count() < 8 {
+          bail!("the passphrase must be at least 8 characters");
+        }
+        let salt = random_bytes::<16>()?;
+        let key = derive_key(p, &salt, iterations);
+        let rows = self.secrets.reseal(&key, Some(PASSPHRASE_CHECK_VALUE))?;
+        let cookies = self.reseal_cookies(&key)?;
+        let settings = [(KDF_SALT_SETTING, Some(to_hex(&salt))), (KDF_ITERATIONS_SETTING, Some(iterations.to_string()))];
+        self.replace_secret_rows(&rows, &cookies, &settings)?;
+        self.secrets.adopt_key(key, Some(PASSPHRASE_CHECK_VALUE));
+        let _ = std::fs::remove_file(&key_file);
+      }
+      None => {
+        let key = random_bytes::<KEY_LEN>()?;
+        let rows = self.secrets.reseal(&key, None)?;
+        let cookies = self.reseal_cookies(&key)?;
+        // Written aside first: until the rows are committed, the old key must keep working.
+        let pending = key_file.with_extension("key.new");
+        write_key_file(&pending, &key)?;
+        let settings = [(KDF_SALT_SETTING, None), (KDF_ITERATIONS_SETTING, None)];
+        if let Err(e) = self.replace_secret_rows(&rows, &cookies, &settings) {
+          let _ = std::fs::remove_file(&pending);
+          return Err(e);
+        }
+        // The rows are sealed under the new key now, so the vault follows even if the move fails;
+        // the next unlock picks the key up from `pending`.
+        let moved = std::fs::rename(&pending, &key_file);
+        self.secrets.adopt_key(key, None);
+        moved.with_context(|| format!("failed to move the new key to {}", key_file.display()))?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Stored cookies sealed under `key`, for `replace_secret_rows`.
+  fn reseal_cookies(&self, key: &[u8; KEY_LEN]) -> anyhow::Result<Vec<(StoredCookie, SecretRow)>> {
+    self
+      .list_cookies()?
+      .into_iter()
+      .map(|c| {
+        let row = seal(key, &cookie_secret_name(&c), &c.value)?;
+        Ok((c, row))
+      })
+      .collect()
+  }
+
+  fn replace_secret_rows(
+    &self,
+    rows: &[SecretRow],
+    cookies: &[(StoredCookie, SecretRow)],
+    settings: &[(&str, Option<String>)],
+  ) -> anyhow::Result<()> {
+    let mut conn = self.conn.lock();
+    let tx = conn.transaction()?;
+    write_cookies(&tx, cookies)?;
+    tx.execute(r#"DELETE FROM secrets"#, [])?;
+    for row in rows {
+      tx.execute(
+        r#"INSERT INTO secrets(name, nonce, ciphertext, updated_at) VALUES(?1, ?2, ?3, ?4)"#,
+        params![row.name, row.nonce, row.ciphertext, Self::now_rfc3339()],
+      )?;
+    }
+    for (key, value) in settings {
+      match value {
+        Some(v) => tx.execute(
+          r#"INSERT INTO settings(key, value) VALUES(?1, ?2) ON CONFLICT(key) DO UPDATE SET value=excluded.value"#,
+          params![key, v],
+        )?,
+        None => tx.execute(r#"DELETE FROM settings WHERE key=?1"#, params![key])?,
+      };
+    }
+    tx.commit()?;
+    Ok(())
+  }
+
+  /// User-visible secret names (internal `@` entries are hidden).
+  pub fn secret_names(&self) -> Vec<String> {
+    self.secrets.names().into_iter().filter(|n| !n.starts_with('@')).collect()
+  }
+
+  pub fn set_secret(&self, name: &str, value: &str) -> anyhow::Result<()> {
+    validate_name(name)?;
+    self.put_secret(name, value)
+  }
+
+  pub(super) fn put_secret(&self, name: &str, value: &str) -> anyhow::Result<()> {
+    let row = self.secrets.seal(name, value)?;
+    let conn = self.conn.lock();
+    conn.execute(
+      r#"INSERT INTO secrets(name, nonce, ciphertext, updated_at) VALUES(?1, ?2, ?3, ?4)
+         ON CONFLICT(name) DO UPDATE SET nonce=excluded.nonce, ciphertext=excluded.ciphertext, updated_at=excluded.updated_at"#,
+      params![row.name, row.nonce, row.ciphertext, Self::now_rfc3339()],
+    )?;
+    Ok(())
+  }
+
+  pub fn delete_secret(&self, name: &str) -> anyhow::Result<()> {
+    let conn = self.conn.lock();
+    conn.execute(r#"DELETE FROM secrets WHERE name=?1"#, params![name])?;
+    self.secrets.forget(name);
+    Ok(())
+  }
+
+  pub(super) fn has_secret_row(&self, name: &str) -> anyhow::Result<bool> {
+    let conn = self.conn.lock();
+    let found: Option<i64> =
+      conn.query_row(r#"SELECT 1 FROM secrets WHERE name=?1"#, params![name], |r| r.get(0)).optional()?;
+    Ok(found.is_some())
+  }
+
+  fn list_secret_rows(&self) -> anyhow::Result<Vec<SecretRow>> {
+    let conn = self.conn.lock();
+    let mut stmt = conn.prepare(r#"SELECT name, nonce, ciphertext FROM secrets ORDER BY name"#)?;
+    let rows = stmt.query_map([], |r| {
+      Ok(SecretRow {
+        name: r.get(0)?,
+        nonce: r.get(1)?,
+        ciphertext: r.get(2)?,
+      })
+    })?;
+    let mut out = Vec::new();
+    for r in rows {
+      out.push(r?);
+    }
+    Ok(out)
+  }
+
+  /// Moves plaintext proxy passwords (including ones inline in proxy URLs), sensitive header rule
+  /// values, cookie values and the local API token into the vault. Runs on every unlock and after saving rules; a no-op once nothing is left.
+  pub fn migrate_plaintext_secrets(&self) -> anyhow::Result<()> {
+    if !self.secrets.is_unlocked() {
+      return Ok(());
+    }
+    let plaintext: Vec<(String, String)> = {
+      let conn = self.conn.lock();
+      let mut stmt = conn.prepare(r#"SELECT proxy_url, password FROM proxy_credentials WHERE password IS NOT NULL"#)?;
+      let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+      let mut out = Vec::new();
+      for r in rows {
+        out.push(r?);
+      }
+      out
+    };
+    for (proxy_url, password) in plaintext {
+      self.put_secret(&proxy_password_name(&proxy_url), &password)?;
+      let conn = self.conn.lock();
+      conn.execute(r#"UPDATE proxy_credentials SET password=NULL WHERE proxy_url=?1"#, params![proxy_url])?;
+    }
+
+    for rule in self.list_rules()?.header_rules {
+      if let Some(headers_json) = self.seal_header_values(&rule)? {
+        let conn = self.conn.lock();
+        conn.execute(
+          r#"UPDATE header_rules SET headers_json=?2 WHERE id=?1"#,
+          params![rule.id, serde_json::to_string(&headers_json)?],
+        )?;
+      }
+    }
+
+    self.migrate_inline_proxy_credentials()?;
+
+    // Cookies saved before their values were sealed.
+    let plaintext_cookies: i64 =
+      self.conn.lock().query_row(r#"SELECT COUNT(*) FROM cookies WHERE ciphertext IS NULL"#, [], |r| r.get(0))?;
+    if plaintext_cookies > 0 {
+      self.replace_cookies(&self.list_cookies()?)?;
+    }
+
+    // Creates the token in the vault if needed and drops the plaintext setting.
+    self.get_local_api_token()?;
+    Ok(())
+  }
+
+  /// Proxy URLs saved with `user:pass@` before logins were kept apart: the login moves to the
+  /// credentials table and the URL is stored bare. URLs that do not parse are left alone.
+  fn migrate_inline_proxy_credentials(&self) -> anyhow::Result<()> {
+    let strip = |raw: &str| -> anyhow::Result<Option<String>> {
+      match split_credentials(raw) {
+        Ok((bare, Some(creds))) => self.set_proxy_credentials(&creds).map(|_| Some(bare)),
+        _ => Ok(None),
+      }
+    };
+    if let Some(bare) = self.get_setting_raw("global_proxy_url")?.as_deref().map(strip).transpose()?.flatten() {
+      self.set_setting_raw("global_proxy_url", &bare)?;
+    }
+    const COLUMNS: [(&str, &str); 3] =
+      [("proxy_rules", "proxy_url_override"), ("proxy_pool_members", "proxy_url"), ("downloads", "forced_proxy_url")];
+    for (table, column) in COLUMNS {
+      let inline: Vec<String> = {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare(&format!("SELECT DISTINCT {column} FROM {table} WHERE {column} LIKE '%@%'"))?;
+        let rows = stmt.query_map([], |r| r.get(0))?;
+        rows.collect::<Result<_, _>>()?
+      };
+      for raw in inline {
+        if let Some(bare) = strip(&raw)? {
+          let conn = self.conn.lock();
+          conn.execute(&format!("UPDATE {table} SET {column}=?2 WHERE {column}=?1"), params![raw, bare])?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Replaces literal sensitive values in `rule` with `${secret:header_rule_<id>_<name>}`;
+  /// `None` if there was nothing to move.
+  fn seal_header_values(&self, rule: &HeaderRule) -> anyhow::Result<Option<serde_json::Value>> {
+    let mut json = rule.headers_json.clone();
+    let nested = json.get("headers").is_some();
+    let obj = if nested { json.get_mut("headers") } else { Some(&mut json) };
+    let Some(map) = obj.and_then(|o| o.as_object_mut()) else { return Ok(None) };
+    let mut changed = false;
+    for (header, v) in map.iter_mut() {
+      if !is_sensitive_header(header) {
+        continue;
+      }
+      let slot = if v.is_string() { Some(v) } else { v.get_mut("value") };
+      let Some(slot) = slot else { continue };
+      let Some(value) = slot.as_str().filter(|s| !s.is_empty() && !s.contains("${")) else { continue };
+      let slug: String = header.to_ascii_lowercase().chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+      let name = format!("{}{slug}", header_rule_secret_prefix(rule.id));
+      self.put_secret(&name, value)?;
+      *slot = serde_json::Value::String(format!("${{secret:{name}}}"));
+      changed = true;
+    }
+    Ok(changed.then_some(json))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::{ProxyCredentials, ProxyPool};
+  use crate::transport::tests::test_db;
+  use serde_json::json;
+
+  #[test]
+  fn sealed_rows_open_only_with_the_right_key_and_name() {
+    let key = random_bytes::<KEY_LEN>().unwrap();
+    let vault = SecretVault::default();
+    assert!(vault.seal("hf_token", "x").is_err(), "locked vault must not seal");
+    vault.unlock(key, &[]).unwrap();
+    let row = vault.seal("hf_token", "hf_abc").unwrap();
+    assert!(!row.ciphertext.windows(6).any(|w| w == b"hf_abc"));
+
+    let reopened = SecretVault::default();
+    reopened.unlock(key, std::slice::from_ref(&row)).unwrap();
+    assert_eq!(reopened.get("hf_token").as_deref(), Some("hf_abc"));
+
+    let wrong = SecretVault::default();
+    assert!(wrong.unlock(random_bytes::<KEY_LEN>().unwrap(), std::slice::from_ref(&row)).is_err());
+    assert!(!wrong.is_unlocked());
+    let renamed = SecretRow { name: "other".into(), ..row };
+    assert!(SecretVault::default().unlock(key, &[renamed]).is_err());
+  }
+
+  #[test]
+  fn plaintext_credentials_migrate_into_the_vault() {
+    let (db, _dir) = test_db();
+    // Rows written before the vault existed.
+    {
+      let conn = db.conn.lock();
+      conn
+        .execute(r#"INSERT INTO proxy_credentials(proxy_url, username, password) VALUES('http://p:1', 'u', 'pw')"#, [])
+        .unwrap();
+      conn.execute(r#"INSERT INTO settings(key, value) VALUES('local_api_token', 'tok')"#, []).unwrap();
+    }
+    let rule_id = db
+      .upsert_header_rule(None, "*", true, 0, &json!({"headers": {"Authorization": {"value": "Bearer abc"}, "Accept": "*/*"}}))
+      .unwrap();
+
+    // Locked: plaintext is still served.
+    assert_eq!(db.get_proxy_credentials("http://p:1").unwrap().unwrap().password.as_deref(), Some("pw"));
+    db.unlock_secrets(None).unwrap();
+
+    let raw: (Option<String>, Option<String>, String) = {
+      let conn = db.conn.lock();
+      (
+        conn.query_row(r#"SELECT password FROM proxy_credentials"#, [], |r| r.get(0)).unwrap(),
+        conn.query_row(r#"SELECT value FROM settings WHERE key='local_api_token'"#, [], |r| r.get(0)).optional().unwrap(),
+        conn.query_row(r#"SELECT headers_json FROM header_rules"#, [], |r| r.get(0)).unwrap(),
+      )
+    };
+    assert_eq!(raw.0, None);
+    assert_eq!(raw.1, None);
+    let name = format!("header_rule_{rule_id}_authorization");
+    assert!(raw.2.contains(&format!("${{secret:{name}}}")) && raw.2.contains("*/*"), "{}", raw.2);
+
+    assert_eq!(db.get_proxy_credentials("http://p:1").unwrap().unwrap().password.as_deref(), Some("pw"));
+    assert_eq!(db.get_local_api_token().unwrap(), "tok");
+    assert_eq!(db.secret_names(), vec![name.clone()]);
+    assert_eq!(db.secrets().get(&name).as_deref(), Some("Bearer abc"));
+
+    // New passwords go straight to the vault.
+    db.set_proxy_credentials(&ProxyCredentials { proxy_url: "http://q:1".into(), username: "v".into(), password: Some("pw2".into()) })
+      .unwrap();
+    let stored: Option<String> =
+      db.conn.lock().query_row(r#"SELECT password FROM proxy_credentials WHERE proxy_url='http://q:1'"#, [], |r| r.get(0)).unwrap();
+    assert_eq!(stored, None);
+    assert_eq!(db.list_proxy_credentials().unwrap()[1].password.as_deref(), Some("pw2"));
+
+    // Locked again: a vault-held password is an error, not a login without one.
+    db.lock_secrets();
+    assert!(db.get_proxy_credentials("http://q:1").is_err());
+  }
+
+  #[test]
+  fn deleting_a_header_rule_deletes_its_sealed_values() {
+    let (db, _dir) = test_db();
+    db.unlock_secrets(None).unwrap();
+    let auth = json!({"Authorization": "Bearer abc"});
+    let gone = db.upsert_header_rule(None, "a.example", true, 0, &auth).unwrap();
+    let kept = db.upsert_header_rule(None, "b.example", true, 0, &auth).unwrap();
+    db.migrate_plaintext_secrets().unwrap();
+    assert_eq!(db.secret_names().len(), 2);
+
+    db.delete_header_rule(gone).unwrap();
+    assert_eq!(db.secret_names(), vec![format!("header_rule_{kept}_authorization")]);
+    assert!(db.list_secret_rows().unwrap().iter().all(|r| !r.name.starts_with(&format!("header_rule_{gone}_"))));
+  }
+
+  #[test]
+  fn inline_proxy_logins_migrate_out_of_stored_urls() {
+    let (db, _dir) = test_db();
+    db.conn
+      .lock()
+      .execute(r#"INSERT INTO settings(key, value) VALUES('global_proxy_url', 'http://gu:gp@global.lan:3128')"#, [])
+      .unwrap();
+    db.upsert_proxy_rule(None, "*.example.com", true, true, Some("socks5h://ru:rp@jump.lan:1080")).unwrap();
+    let pool: ProxyPool = serde_json::from_value(json!({
+      "id": 0, "name": "p", "enabled": true, "strategy": "round_robin", "allow_direct": false,
+      "rotate_per_segment": false, "health_check_url": null, "health_check_interval_secs": 0,
+      "members": ["http://mu:mp@member.lan:8080"]
+    }))
+    .unwrap();
+    db.upsert_proxy_pool(&pool).unwrap();
+    db.insert_download_skeleton("d1", "https://a.example.com/f", "/tmp", true, Some("http://gu:gp@global.lan:3128"))
+      .unwrap();
+
+    db.unlock_secrets(None).unwrap();
+    let rules = db.list_rules().unwrap();
+    assert_eq!(db.get_setting_raw("global_proxy_url").unwrap().as_deref(), Some("http://global.lan:3128"));
+    assert_eq!(rules.proxy_rules[0].proxy_url_override.as_deref(), Some("socks5h://jump.lan:1080"));
+    assert_eq!(rules.proxy_pools[0].members, vec!["http://member.lan:8080".to_string()]);
+    let forced = db.get_download("d1").unwrap().unwrap().forced_proxy_url;
+    assert_eq!(forced.as_deref(), Some("http://global.lan:3128"));
+    for (key, user, password) in
+      [("http://global.lan:3128", "gu", "gp"), ("socks5h://jump.lan:1080", "ru", "rp"), ("http://member.lan:8080", "mu", "mp")]
+    {
+      let creds = db.get_proxy_credentials(key).unwrap().unwrap();
+      assert_eq!((creds.username.as_str(), creds.password.as_deref()), (user, Some(password)));
+    }
+    let stored: i64 =
+      db.conn.lock().query_row(r#"SELECT COUNT(*) FROM proxy_credentials WHERE password IS NOT NULL"#, [], |r| r.get(0)).unwrap();
+    assert_eq!(stored, 0);
+  }
+
+  #[test]
+  fn cookie_values_are_sealed_and_follow_a_new_key() {
+    let (db, _dir) = test_db();
+    // A cookie saved before values were sealed.
+    db.conn
+      .lock()
+      .execute(
+        r#"INSERT INTO cookies(domain, path, name, value, host_only, secure, http_only, expires)
+           VALUES('example.com', '/', 'sid', 'abc123', 1, 1, 1, NULL)"#,
+        [],
+      )
+      .unwrap();
+    assert!(db.replace_cookies(&db.list_cookies().unwrap()).is_err(), "a locked vault must not write cookies");
+    db.unlock_secrets(None).unwrap();
+
+    let raw: (String, Vec<u8>) =
+      db.conn.lock().query_row(r#"SELECT value, ciphertext FROM cookies"#, [], |r| Ok((r.get(0)?, r.get(1)?))).unwrap();
+    assert_eq!(raw.0, "");
+    assert!(!raw.1.windows(6).any(|w| w == b"abc123"));
+    assert_eq!(db.list_cookies().unwrap()[0].value, "abc123");
+
+    db.set_master_passphrase_with(Some("correct horse"), 10).unwrap();
+    db.lock_secrets();
+    assert!(db.list_cookies().unwrap().is_empty());
+    db.unlock_secrets(Some("correct horse")).unwrap();
+    assert_eq!(db.list_cookies().unwrap()[0].value, "abc123");
+  }
+
+  #[test]
+  fn a_failed_rekey_keeps_the_old_key() {
+    let (db, _dir) = test_db();
+    db.unlock_secrets(None).unwrap();
+    db.set_secret("hf_token", "hf_abc").unwrap();
+    db.conn
+      .lock()
+      .execute_batch(r#"CREATE TRIGGER no_secrets BEFORE INSERT ON secrets BEGIN SELECT RAISE(ABORT, 'disk full'); END;"#)
+      .unwrap();
+    assert!(db.set_master_passphrase_with(Some("correct horse"), 10).is_err());
+    assert_eq!(db.secrets_status().unwrap().mode, "key_file");
+    assert!(db.key_file_path().exists());
+
+    // What is stored still opens with the key the vault holds, and with the key file.
+    let rows = db.list_secret_rows().unwrap();
+    let key = load_or_create_key_file(&db.key_file_path()).unwrap();
+    assert!(SecretVault::default().unlock(key, &rows).is_ok());
+    db.conn.lock().execute_batch(r#"DROP TRIGGER no_secrets;"#).unwrap();
+    db.set_secret("other", "x").unwrap();
+    db.lock_secrets();
+    db.unlock_secrets(None).unwrap();
+    assert_eq!(db.secrets().get("hf_token").as_deref(), Some("hf_abc"));
+    assert_eq!(db.secrets().get("other").as_deref(), Some("x"));
+  }
+
+  #[test]
+  fn a_new_key_left_aside_is_recovered_on_unlock() {
+    let (db, _dir) = test_db();
+    db.unlock_secrets(None).unwrap();
+    db.set_secret("hf_token", "hf_abc").unwrap();
+    db.set_master_passphrase_with(Some("correct horse"), 10).unwrap();
+    db.set_master_passphrase_with(None, 10).unwrap();
+    // As if the final move had failed after the rows were committed.
+    let pending = db.key_file_path().with_extension("key.new");
+    std::fs::rename(db.key_file_path(), &pending).unwrap();
+
+    db.lock_secrets();
+    db.unlock_secrets(None).unwrap();
+    assert_eq!(db.secrets().get("hf_token").as_deref(), Some("hf_abc"));
+    assert!(db.key_file_path().exists() && !pending.exists());
+
+    // A stale one that does not open the rows is dropped.
+    std::fs::write(&pending, random_bytes::<KEY_LEN>().unwrap()).unwrap();
+    db.lock_secrets();
+    db.unlock_secrets(None).unwrap();
+    assert_eq!(db.secrets().get("hf_token").as_deref(), Some("hf_abc"));
+    assert!(!pending.exists());
+  }
+
+  #[test]
+  fn passphrase_mode_locks_and_unlocks() {
+    let (db, _dir) = test_db();
+    db.unlock_secrets(None).unwrap();
+    db.set_secret("hf_token", "hf_abc").unwrap();
+    assert!(db.set_secret("@internal", "x").is_err());
+    assert!(db.key_file_path().exists());
+
+    assert!(db.set_master_passphrase_with(Some("short"), 10).is_err());
+    db.set_master_passphrase_with(Some("correct horse"), 10).unwrap();
+    assert!(!db.key_file_path().exists());
+    assert_eq!(db.secrets_status().unwrap().mode, "passphrase");
+
+    db.lock_secrets();
+    assert_eq!(db.secrets().get("hf_token"), None);
+    assert!(db.get_local_api_token().is_err(), "a migrated token must not be regenerated while locked");
+    assert!(db.set_secret("x", "y").is_err());
+    assert!(db.unlock_secrets(None).is_err());
+    assert_eq!(db.unlock_secrets(Some("wrong passphrase")).unwrap_err().to_string(), "wrong passphrase");
+    assert!(!db.secrets().is_unlocked());
+    db.unlock_secrets(Some("correct horse")).unwrap();
+    assert_eq!(db.secrets().get("hf_token").as_deref(), Some("hf_abc"));
+
+    // Back to a key file: unlocks without a passphrase again.
+    let token = db.get_local_api_token().unwrap();
+    db.set_master_passphrase_with(None, 10).unwrap();
+    db.lock_secrets();
+    db.unlock_secrets(None).unwrap();
+    assert_eq!(db.secrets_status().unwrap(), SecretsStatus { mode: "key_file".into(), unlocked: true });
+    assert_eq!(db.get_local_api_token().unwrap(), token);
+    assert_eq!(db.secret_names(), vec!["hf_token".to_string()]);
+  }
+}
@@ -0,0 +1,290 @@
+//! Cookie jar shared by every client the transport builds.
+//!
+//! reqwest consults the jar on each hop, so cookies set by a redirect or a probe are sent with
+//! the next request. The jar lives in memory; the engine writes it to SQLite when it changes,
+//! with values sealed by the secrets vault.
+//! A `Cookie` header from a header rule replaces the jar's cookies for that request.
+
+use crate::model::StoredCookie;
+use anyhow::bail;
+use parking_lot::RwLock;
+use reqwest::header::HeaderValue;
+use std::sync::atomic::{AtomicBool, Ordering};
+use url::Url;
+
+#[derive(Default)]
+pub struct CookieJar {
+  cookies: RwLock<Vec<StoredCookie>>,
+  dirty: AtomicBool,
+}
+
+fn now_unix() -> i64 {
+  time::OffsetDateTime::now_utc().unix_timestamp()
+}
+
+fn is_expired(c: &StoredCookie, now: i64) -> bool {
+  c.expires.is_some_and(|e| e <= now)
+}
+
+fn domain_matches(c: &StoredCookie, host: &str) -> bool {
+  if c.host_only {
+    return host == c.domain;
+  }
+  host == c.domain || host.strip_suffix(c.domain.as_str()).is_some_and(|rest| rest.ends_with('.'))
+}
+
+fn path_matches(cookie_path: &str, request_path: &str) -> bool {
+  match request_path.strip_prefix(cookie_path) {
+    Some(rest) => rest.is_empty() || cookie_path.ends_with('/') || rest.starts_with('/'),
+    None => false,
+  }
+}
+
+/// RFC 6265 default-path: the request path up to (not including) its last `/`.
+fn default_path(url: &Url) -> String {
+  match url.path().rfind('/') {
+    Some(0) | None => "/".to_string(),
+    Some(i) => url.path()[..i].to_string(),
+  }
+}
+
+impl CookieJar {
+  /// Replaces the jar with cookies loaded from the database.
+  pub fn load(&self, cookies: Vec<StoredCookie>) {
+    *self.cookies.write() = cookies;
+  }
+
+  /// Adds cookies loaded once the secrets vault unlocks; ones set since startup win.
+  pub fn restore(&self, stored: Vec<StoredCookie>) {
+    let mut cookies = self.cookies.write();
+    for c in stored {
+      if !cookies.iter().any(|o| o.domain == c.domain && o.path == c.path && o.name == c.name) {
+        cookies.push(c);
+      }
+    }
+  }
+
+  /// Unexpired cookies, ordered by domain, path and name.
+  pub fn snapshot(&self) -> Vec<StoredCookie> {
+    let now = now_unix();
+    let mut out: Vec<StoredCookie> = self.cookies.read().iter().filter(|c| !is_expired(c, now)).cloned().collect();
+    out.sort_by(|a, b| (&a.domain, &a.path, &a.name).cmp(&(&b.domain, &b.path, &b.name)));
+    out
+  }
+
+  /// The cookies to persist, if anything changed since the last call.
+  pub fn take_dirty(&self) -> Option<Vec<StoredCookie>> {
+    self.dirty.swap(false, Ordering::AcqRel).then(|| self.snapshot())
+  }
+
+  /// Adds or replaces cookies (same domain, path and name); expired ones delete their match.
+  fn merge(&self, incoming: Vec<StoredCookie>) {
+    let now = now_unix();
+    let mut cookies = self.cookies.write();
+    for c in incoming {
+      cookies.retain(|o| !(o.domain == c.domain && o.path == c.path && o.name == c.name));
+      if !is_expired(&c, now) {
+        cookies.push(c);
+      }
+    }
+    cookies.retain(|c| !is_expired(c, now));
+    self.dirty.store(true, Ordering::Release);
+  }
+
+  /// Merges a Netscape `cookies.txt` and returns how many cookies it held.
+  pub fn import_netscape(&self, text: &str) -> anyhow::Result<usize> {
+    let cookies = parse_netscape(text)?;
+    let n = cookies.len();
+    self.merge(cookies);
+    Ok(n)
+  }
+
+  pub fn export_netscape(&self) -> String {
+    to_netscape(&self.snapshot())
+  }
+
+  /// The `Cookie` header value for `url`, longest paths first.
+  fn header_for(&self, url: &Url) -> Option<String> {
+    let host = url.host_str()?.to_ascii_lowercase();
+    let https = url.scheme() == "https";
+    let now = now_unix();
+    let cookies = self.cookies.read();
+    let mut matching: Vec<&StoredCookie> = cookies
+      .iter()
+      .filter(|c| !is_expired(c, now) && (https || !c.secure) && domain_matches(c, &host) && path_matches(&c.path, url.path()))
+      .collect();
+    if matching.is_empty() {
+      return None;
+    }
+    matching.sort_by_key(|c| std::cmp::Reverse(c.path.len()));
+    Some(matching.iter().map(|c| format!("{}={}", c.name, c.value)).collect::<Vec<_>>().join("; "))
+  }
+
+  /// Parses one `Set-Cookie` received from `url`; `None` if it is malformed or not allowed for
+  /// that host.
+  fn parse_set_cookie(raw: &str, url: &Url) -> Option<StoredCookie> {
+    let parsed = cookie::Cookie::parse(raw).ok()?;
+    let host = url.host_str()?.to_ascii_lowercase();
+    let is_ip = matches!(url.host(), Some(url::Host::Ipv4(_) | url::Host::Ipv6(_)));
+    let secure = parsed.secure().unwrap_or(false);
+    if secure && url.scheme() != "https" {
+      return None;
+    }
+    let (domain, host_only) = match parsed.domain().map(|d| d.trim_start_matches('.').to_ascii_lowercase()) {
+      Some(d) if !d.is_empty() && d != host => {
+        // A domain cookie must cover the sender, and not a bare TLD like `com`.
+        if is_ip || !d.contains('.') || !host.ends_with(&format!(".{d}")) {
+          return None;
+        }
+        (d, false)
+      }
+      _ => (host, true),
+    };
+    let path = parsed.path().filter(|p| p.starts_with('/')).map(str::to_string).unwrap_or_else(|| default_path(url));
+    let expires = match (parsed.max_age(), parsed.expires_datetime()) {
+      (Some(age), _) => Some(now_unix() + age.whole_seconds()),
+      (None, Some(at)) => Some(at.unix_timestamp()),
+      (None, None) => None,
+    };
+    Some(StoredCookie {
+      domain,
+      host_only,
+      path,
+      secure,
+      http_only: parsed.http_only().unwrap_or(false),
+      expires,
+      name: parsed.name().to_string(),
+      value: parsed.value().to_string(),
+    })
+  }
+}
+
+impl reqwest::cookie::CookieStore for CookieJar {
+  fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+    let incoming: Vec<StoredCookie> = cookie_headers
+      .filter_map(|v| v.to_str().ok())
+      .filter_map(|raw| Self::parse_set_cookie(raw, url))
+      .collect();
+    if !incoming.is_empty() {
+      self.merge(incoming);
+    }
+  }
+
+  fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+    HeaderValue::from_str(&self.header_for(url)?).ok()
+  }
+}
+
+/// Parses the Netscape/curl `cookies.txt` format: tab-separated domain, include-subdomains,
+/// path, secure, expiry (unix seconds, 0 for session), name and value. `#HttpOnly_` prefixes
+/// are understood; other `#` lines are comments.
+fn parse_netscape(text: &str) -> anyhow::Result<Vec<StoredCookie>> {
+  let mut out = Vec::new();
+  for (i, line) in text.lines().enumerate() {
+    let line = line.trim_end_matches('\r');
+    let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+      Some(rest) => (rest, true),
+      None => (line, false),
+    };
+    if line.trim().is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() < 6 || fields.len() > 7 {
+      bail!("line {}: expected 7 tab-separated fields", i + 1);
+    }
+    let flag = |s: &str| s.eq_ignore_ascii_case("TRUE");
+    let Ok(expiry) = fields[4].trim().parse::<i64>() else {
+      bail!("line {}: invalid expiry {:?}", i + 1, fields[4]);
+    };
+    let domain = fields[0].trim().to_ascii_lowercase();
+    let path = fields[2].trim();
+    out.push(StoredCookie {
+      host_only: !flag(fields[1]) && !domain.starts_with('.'),
+      domain: domain.trim_start_matches('.').to_string(),
+      path: if path.is_empty() { "/".to_string() } else { path.to_string() },
+      secure: flag(fields[3]),
+      http_only,
+      expires: (expiry > 0).then_some(expiry),
+      name: fields[5].to_string(),
+      value: fields.get(6).copied().unwrap_or_default().to_string(),
+    });
+  }
+  Ok(out)
+}
+
+fn to_netscape(cookies: &[StoredCookie]) -> String {
+  let mut out = String::from("# Netscape HTTP Cookie File\n");
+  for c in cookies {
+    let bool_str = |b: bool| if b { "TRUE" } else { "FALSE" };
+    out.push_str(&format!(
+      "{}{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+      if c.http_only { "#HttpOnly_" } else { "" },
+      if c.host_only { "" } else { "." },
+      c.domain,
+      bool_str(!c.host_only),
+      c.path,
+      bool_str(c.secure),
+      c.expires.unwrap_or(0),
+      c.name,
+      c.value,
+    ));
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use reqwest::cookie::CookieStore;
+
+  fn url(s: &str) -> Url {
+    Url::parse(s).unwrap()
+  }
+
+  #[test]
+  fn set_cookie_respects_domain_path_expiry_and_secure() {
+    let jar = CookieJar::default();
+    let headers = [
+      HeaderValue::from_static("sid=1; Path=/; HttpOnly"),
+      HeaderValue::from_static("wide=2; Domain=.example.com; Path=/files"),
+      HeaderValue::from_static("tls=3; Secure"),
+      HeaderValue::from_static("other=4; Domain=evil.com"),
+      HeaderValue::from_static("tld=5; Domain=com"),
+    ];
+    jar.set_cookies(&mut headers.iter(), &url("https://dl.example.com/files/a.zip"));
+
+    let header = |u: &str| jar.cookies(&url(u)).map(|v| v.to_str().unwrap().to_string());
+    assert_eq!(header("https://dl.example.com/files/b.zip").as_deref(), Some("wide=2; tls=3; sid=1"));
+    assert_eq!(header("http://dl.example.com/").as_deref(), Some("sid=1"));
+    assert_eq!(header("https://cdn.example.com/files/x").as_deref(), Some("wide=2"));
+    assert_eq!(header("https://cdn.example.com/filesystem"), None);
+    assert_eq!(header("https://evil.com/"), None);
+
+    let expire = [HeaderValue::from_static("sid=gone; Path=/; Max-Age=0")];
+    jar.set_cookies(&mut expire.iter(), &url("https://dl.example.com/"));
+    assert_eq!(header("http://dl.example.com/"), None);
+    assert!(jar.take_dirty().is_some());
+    assert!(jar.take_dirty().is_none());
+  }
+
+  #[test]
+  fn netscape_round_trip() {
+    let text = "# Netscape HTTP Cookie File\n\
+      .example.com\tTRUE\t/\tTRUE\t4102444800\tsession\tabc\n\
+      #HttpOnly_files.example.org\tFALSE\t/dl\tFALSE\t0\ttoken\tx=y\n\
+      \n";
+    let jar = CookieJar::default();
+    assert_eq!(jar.import_netscape(text).unwrap(), 2);
+    let cookies = jar.snapshot();
+    assert!(!cookies[0].host_only && cookies[0].secure && cookies[0].expires == Some(4102444800));
+    assert!(cookies[1].host_only && cookies[1].http_only && cookies[1].expires.is_none());
+    assert_eq!(cookies[1].value, "x=y");
+    assert_eq!(jar.cookies(&url("https://www.example.com/a")).unwrap(), "session=abc");
+    assert_eq!(jar.cookies(&url("http://sub.files.example.org/dl/x")), None);
+
+    assert_eq!(parse_netscape(&jar.export_netscape()).unwrap(), cookies);
+    assert!(parse_netscape("example.com\tTRUE\t/\n").is_err());
+    assert!(parse_netscape("example.com\tTRUE\t/\tFALSE\tsoon\ta\tb\n").is_err());
+  }
+}
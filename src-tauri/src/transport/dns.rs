@@ -0,0 +1,283 @@
+//! Name resolution for transport profiles: static host overrides (like curl's `--resolve`),
+//! DNS-over-HTTPS, the system resolver, and the address-family preference on top.
+//!
+//! Only names we connect to ourselves go through here; with an HTTP or `socks5h` proxy the
+//! proxy resolves the target and only the proxy's own name is looked up locally.
+
+use super::doh;
+use super::profile::IpPreference;
+use anyhow::{anyhow, bail, Context};
+use dashmap::DashMap;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// DoH answers are kept for their TTL, within these bounds: very short TTLs would mean a
+/// lookup per segment, very long ones outlive a host's move.
+const MIN_TTL: Duration = Duration::from_secs(30);
+const MAX_TTL: Duration = Duration::from_secs(3600);
+/// NXDOMAIN and address-less answers are remembered briefly too.
+const NEGATIVE_TTL: Duration = Duration::from_secs(30);
+const DOH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a profile resolves names. Part of `ClientProfile`, so it must stay hashable.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct DnsOptions {
+  /// Host (lowercase) to the addresses it is pinned to, sorted by host.
+  pub overrides: Vec<(String, Vec<IpAddr>)>,
+  pub doh_url: Option<String>,
+  pub ip_preference: IpPreference,
+}
+
+impl DnsOptions {
+  /// Whether reqwest's default resolver would do the same job.
+  pub fn is_default(&self) -> bool {
+    self.overrides.is_empty() && self.doh_url.is_none() && self.ip_preference == IpPreference::Auto
+  }
+}
+
+/// Parses overrides, one per line or separated by spaces: `host:addr[,addr...]`, or curl's
+/// `host:port:addr[,addr...]`. A port is accepted for familiarity but the pin applies to every
+/// port, since resolution happens before the port is known. IPv6 addresses go in brackets.
+pub fn parse_overrides(text: &str) -> anyhow::Result<Vec<(String, Vec<IpAddr>)>> {
+  let mut out: Vec<(String, Vec<IpAddr>)> = Vec::new();
+  for entry in text.split_whitespace() {
+    let (host, rest) = entry.split_once(':').ok_or_else(|| anyhow!("{entry}: expected host:address"))?;
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if host.is_empty() || host.parse::<IpAddr>().is_ok() {
+      bail!("{entry}: expected a host name before ':'");
+    }
+    let addrs = match rest.split_once(':') {
+      Some((port, addrs)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => addrs,
+      _ => rest,
+    };
+    let addrs = addrs
+      .split(',')
+      .map(|a| {
+        let a = a.trim_start_matches('[').trim_end_matches(']');
+        a.parse::<IpAddr>().with_context(|| format!("{entry}: {a:?} is not an IP address"))
+      })
+      .collect::<anyhow::Result<Vec<_>>>()?;
+    match out.iter_mut().find(|(h, _)| *h == host) {
+      Some((_, existing)) => existing.extend(addrs),
+      None => out.push((host, addrs)),
+    }
+  }
+  out.sort_by(|a, b| a.0.cmp(&b.0));
+  Ok(out)
+}
+
+/// Lookups over plain http:// could be read and rewritten on the way, so only tests (which
+/// serve DoH from a local plain-HTTP server) may use them.
+pub fn check_doh_url(raw: &str) -> anyhow::Result<()> {
+  let url = url::Url::parse(raw).with_context(|| format!("{raw} is not a URL"))?;
+  let secure = url.scheme() == "https" || (cfg!(test) && url.scheme() == "http");
+  if !secure || url.host_str().is_none() {
+    bail!("DoH endpoint must be an https:// URL");
+  }
+  Ok(())
+}
+
+/// Lookups shared by every client a `Transport` builds, cached per DoH endpoint and name.
+pub struct DnsCache {
+  /// Plain client for DoH requests; it resolves the endpoint's own name with the system
+  /// resolver, so write the endpoint with an IP address to avoid that lookup.
+  doh_client: reqwest::Client,
+  answers: DashMap<(String, String), (Instant, Vec<IpAddr>)>,
+}
+
+impl DnsCache {
+  pub fn new() -> anyhow::Result<Self> {
+    let doh_client = reqwest::Client::builder()
+      .timeout(DOH_TIMEOUT)
+      .build()
+      .context("failed to build DoH client")?;
+    Ok(Self { doh_client, answers: DashMap::new() })
+  }
+
+  /// IPv4 and IPv6 addresses for `name` from `endpoint`, from the cache while fresh.
+  pub async fn doh_lookup(&self, endpoint: &str, name: &str) -> anyhow::Result<Vec<IpAddr>> {
+    let key = (endpoint.to_string(), name.to_string());
+    if let Some(hit) = self.answers.get(&key) {
+      if hit.0 > Instant::now() {
+        return Ok(hit.1.clone());
+      }
+    }
+    let (v4, v6) = tokio::join!(
+      doh::query(&self.doh_client, endpoint, name, doh::TYPE_A),
+      doh::query(&self.doh_client, endpoint, name, doh::TYPE_AAAA),
+    );
+    // One family failing (some servers reject AAAA) is fine as long as the other answered.
+    let answers: Vec<doh::Answer> = match (v4, v6) {
+      (Err(e), Err(_)) => return Err(e),
+      (a, b) => a.into_iter().chain(b).collect(),
+    };
+    let addrs: Vec<IpAddr> = answers.iter().flat_map(|a| a.addrs.iter().copied()).collect();
+    let ttl = match answers.iter().filter(|a| !a.addrs.is_empty()).map(|a| a.ttl).min() {
+      Some(ttl) => ttl.clamp(MIN_TTL, MAX_TTL),
+      None => NEGATIVE_TTL,
+    };
+    self.answers.insert(key, (Instant::now() + ttl, addrs.clone()));
+    Ok(addrs)
+  }
+}
+
+/// The resolver installed on clients whose profile sets any `DnsOptions`, and used directly by
+/// the non-HTTP sources.
+#[derive(Clone)]
+pub struct ProfileResolver {
+  options: DnsOptions,
+  cache: Arc<DnsCache>,
+}
+
+impl ProfileResolver {
+  pub fn new(options: DnsOptions, cache: Arc<DnsCache>) -> Self {
+    Self { options, cache }
+  }
+
+  /// Addresses to try for `host:port`, in preference order. IP literals are used as they are.
+  pub(super) async fn socket_addrs(&self, host: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+      return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let addrs = self.lookup(host).await?.into_iter().map(|ip| SocketAddr::new(ip, port)).collect();
+    Ok(order_by_preference(addrs, self.options.ip_preference))
+  }
+
+  async fn lookup(&self, name: &str) -> anyhow::Result<Vec<IpAddr>> {
+    let host = name.trim_end_matches('.').to_ascii_lowercase();
+    if let Some((_, addrs)) = self.options.overrides.iter().find(|(h, _)| *h == host) {
+      return Ok(addrs.clone());
+    }
+    let addrs = match &self.options.doh_url {
+      Some(endpoint) => self.cache.doh_lookup(endpoint, &host).await?,
+      None => tokio::net::lookup_host((host.as_str(), 0)).await?.map(|a| a.ip()).collect(),
+    };
+    if addrs.is_empty() {
+      bail!("{host} has no addresses");
+    }
+    Ok(addrs)
+  }
+}
+
+impl Resolve for ProfileResolver {
+  fn resolve(&self, name: Name) -> Resolving {
+    let resolver = ProfileResolver::new(self.options.clone(), self.cache.clone());
+    Box::pin(async move {
+      let addrs = resolver.lookup(name.as_str()).await.map_err(Box::<dyn std::error::Error + Send + Sync>::from)?;
+      let addrs: Vec<SocketAddr> = addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)).collect();
+      let addrs: Addrs = Box::new(order_by_preference(addrs, resolver.options.ip_preference).into_iter());
+      Ok(addrs)
+    })
+  }
+}
+
+pub fn order_by_preference(mut addrs: Vec<SocketAddr>, preference: IpPreference) -> Vec<SocketAddr> {
+  match preference {
+    IpPreference::Auto => {}
+    // Stable sort keeps the resolver's order within each family.
+    IpPreference::Ipv4 => addrs.sort_by_key(|a| !a.is_ipv4()),
+    IpPreference::Ipv6 => addrs.sort_by_key(|a| !a.is_ipv6()),
+  }
+  addrs
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transport::{tests::serve, ClientProfile, Transport};
+  use axum::{extract::Query, routing::get, Router};
+  use base64::Engine;
+  use std::collections::HashMap;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  #[test]
+  fn overrides_accept_curl_syntax() {
+    let parsed = parse_overrides("cdn.example.com:443:192.0.2.1,[2001:db8::1]\n Files.Example.com:192.0.2.9 cdn.example.com:192.0.2.2").unwrap();
+    let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+    assert_eq!(
+      parsed,
+      vec![
+        ("cdn.example.com".to_string(), vec![ip("192.0.2.1"), ip("2001:db8::1"), ip("192.0.2.2")]),
+        ("files.example.com".to_string(), vec![ip("192.0.2.9")]),
+      ]
+    );
+    assert!(parse_overrides("example.com").is_err());
+    assert!(parse_overrides("example.com:443:not-an-ip").is_err());
+    assert!(parse_overrides("192.0.2.1:192.0.2.2").is_err());
+    assert!(parse_overrides("").unwrap().is_empty());
+  }
+
+  /// A stand-in DoH server that knows `names`, counting the queries it gets.
+  async fn doh_server(names: HashMap<String, IpAddr>) -> (String, Arc<AtomicUsize>) {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = hits.clone();
+    let app = Router::new().route(
+      "/dns-query",
+      get(move |Query(q): Query<HashMap<String, String>>| {
+        let names = names.clone();
+        let counter = counter.clone();
+        async move {
+          counter.fetch_add(1, Ordering::SeqCst);
+          let query = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(&q["dns"]).unwrap();
+          let mut name = Vec::new();
+          let mut pos = 12;
+          while query[pos] != 0 {
+            let len = query[pos] as usize;
+            name.push(String::from_utf8_lossy(&query[pos + 1..pos + 1 + len]).into_owned());
+            pos += len + 1;
+          }
+          let addrs: Vec<IpAddr> = names.get(&name.join(".")).into_iter().copied().collect();
+          ([("content-type", "application/dns-message")], doh::tests::respond(&query, &addrs, 120))
+        }
+      }),
+    );
+    (format!("{}/dns-query", serve(app).await), hits)
+  }
+
+  async fn file_server() -> u16 {
+    let base = serve(Router::new().route("/f", get(|| async { "found" }))).await;
+    url::Url::parse(&base).unwrap().port().unwrap()
+  }
+
+  async fn fetch(transport: &Transport, dns: DnsOptions, url: &str) -> Result<String, reqwest::Error> {
+    let profile = ClientProfile { dns, ..ClientProfile::default() };
+    let client = transport.client_for(&profile).unwrap();
+    client.get(url).send().await?.text().await
+  }
+
+  #[tokio::test]
+  async fn doh_answers_are_used_and_cached() {
+    let port = file_server().await;
+    let (endpoint, hits) = doh_server(HashMap::from([("files.internal.test".to_string(), "127.0.0.1".parse().unwrap())])).await;
+    let transport = Transport::new().unwrap();
+    let dns = DnsOptions { doh_url: Some(endpoint), ..Default::default() };
+
+    let url = format!("http://files.internal.test:{port}/f");
+    assert_eq!(fetch(&transport, dns.clone(), &url).await.unwrap(), "found");
+    assert_eq!(hits.load(Ordering::SeqCst), 2, "one A and one AAAA query");
+    // A fresh client (new connection pool) still answers from the cache.
+    let other = ClientProfile { dns: dns.clone(), user_agent: "other".into(), ..ClientProfile::default() };
+    let body = transport.client_for(&other).unwrap().get(&url).send().await.unwrap().text().await.unwrap();
+    assert_eq!(body, "found");
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    let err = fetch(&transport, dns, &format!("http://unknown.internal.test:{port}/f")).await.unwrap_err();
+    assert!(err.is_connect(), "{err:?}");
+  }
+
+  #[tokio::test]
+  async fn overrides_pin_hosts_before_doh() {
+    let port = file_server().await;
+    let (endpoint, hits) = doh_server(HashMap::new()).await;
+    let transport = Transport::new().unwrap();
+    let dns = DnsOptions {
+      overrides: parse_overrides("pinned.test:443:127.0.0.1").unwrap(),
+      doh_url: Some(endpoint),
+      ip_preference: IpPreference::Ipv4,
+    };
+    assert_eq!(fetch(&transport, dns, &format!("http://pinned.test:{port}/f")).await.unwrap(), "found");
+    assert_eq!(hits.load(Ordering::SeqCst), 0);
+  }
+}
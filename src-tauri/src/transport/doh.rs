@@ -0,0 +1,192 @@
+//! DNS-over-HTTPS (RFC 8484): just enough of the DNS wire format to ask for A/AAAA records
+//! and read the answers.
+
+use anyhow::{bail, Context};
+use base64::Engine;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// Addresses from one answer and how long they may be cached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Answer {
+  pub addrs: Vec<IpAddr>,
+  pub ttl: Duration,
+}
+
+/// A recursion-desired query. The ID is 0 so HTTP caches can share responses (RFC 8484 §4.1).
+pub fn encode_query(name: &str, qtype: u16) -> anyhow::Result<Vec<u8>> {
+  let mut out = vec![0, 0, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+  for label in name.trim_end_matches('.').split('.') {
+    if label.is_empty() || label.len() > 63 {
+      bail!("{name} is not a valid DNS name");
+    }
+    out.push(label.len() as u8);
+    out.extend_from_slice(label.as_bytes());
+  }
+  out.push(0);
+  out.extend_from_slice(&qtype.to_be_bytes());
+  out.extend_from_slice(&CLASS_IN.to_be_bytes());
+  Ok(out)
+}
+
+struct Reader<'a> {
+  buf: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+    let end = self.pos.checked_add(n).filter(|e| *e <= self.buf.len()).context("truncated DNS message")?;
+    let out = &self.buf[self.pos..end];
+    self.pos = end;
+    Ok(out)
+  }
+
+  fn u16(&mut self) -> anyhow::Result<u16> {
+    let b = self.take(2)?;
+    Ok(u16::from_be_bytes([b[0], b[1]]))
+  }
+
+  fn u32(&mut self) -> anyhow::Result<u32> {
+    let b = self.take(4)?;
+    Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+  }
+
+  /// Skips a (possibly compressed) name; answers are matched by type, not by owner name,
+  /// since a CNAME chain leaves the final records under another name.
+  fn skip_name(&mut self) -> anyhow::Result<()> {
+    loop {
+      let len = self.take(1)?[0];
+      match len {
+        0 => return Ok(()),
+        l if l & 0xC0 == 0xC0 => {
+          self.take(1)?;
+          return Ok(());
+        }
+        l => {
+          self.take(l as usize)?;
+        }
+      }
+    }
+  }
+}
+
+/// The `qtype` addresses in a response; NXDOMAIN and empty answers are an empty list.
+pub fn decode_answer(msg: &[u8], qtype: u16) -> anyhow::Result<Answer> {
+  let mut r = Reader { buf: msg, pos: 0 };
+  let _id = r.u16()?;
+  let flags = r.u16()?;
+  let qdcount = r.u16()?;
+  let ancount = r.u16()?;
+  r.take(4)?;
+  if flags & 0x8000 == 0 {
+    bail!("DNS message is not a response");
+  }
+  let rcode = flags & 0x000F;
+  if rcode != 0 && rcode != RCODE_NXDOMAIN {
+    bail!("DNS server answered with rcode {rcode}");
+  }
+  for _ in 0..qdcount {
+    r.skip_name()?;
+    r.take(4)?;
+  }
+  let mut addrs = Vec::new();
+  let mut ttl = u32::MAX;
+  for _ in 0..ancount {
+    r.skip_name()?;
+    let rtype = r.u16()?;
+    let class = r.u16()?;
+    let record_ttl = r.u32()?;
+    let len = r.u16()? as usize;
+    let data = r.take(len)?;
+    if class != CLASS_IN || rtype != qtype {
+      continue;
+    }
+    let addr = match (rtype, data.len()) {
+      (TYPE_A, 4) => IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3])),
+      (TYPE_AAAA, 16) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(data).expect("length checked"))),
+      _ => bail!("malformed address record"),
+    };
+    addrs.push(addr);
+    ttl = ttl.min(record_ttl);
+  }
+  let ttl = if addrs.is_empty() { 0 } else { ttl };
+  Ok(Answer { addrs, ttl: Duration::from_secs(ttl as u64) })
+}
+
+/// Asks `endpoint` (e.g. `https://cloudflare-dns.com/dns-query`) with a GET, which caches better
+/// than POST.
+pub async fn query(client: &reqwest::Client, endpoint: &str, name: &str, qtype: u16) -> anyhow::Result<Answer> {
+  let dns = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(encode_query(name, qtype)?);
+  let resp = client
+    .get(endpoint)
+    .query(&[("dns", dns)])
+    .header(reqwest::header::ACCEPT, "application/dns-message")
+    .send()
+    .await
+    .with_context(|| format!("DoH request to {endpoint} failed"))?;
+  if !resp.status().is_success() {
+    bail!("DoH server {endpoint} answered HTTP {}", resp.status().as_u16());
+  }
+  let body = resp.bytes().await.with_context(|| format!("DoH response from {endpoint} was cut off"))?;
+  decode_answer(&body, qtype)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+  use super::*;
+
+  /// Answers `query` the way a recursive server would, with `addrs` under a compressed pointer
+  /// to the question name; also used by the stand-in DoH server in `dns`.
+  pub fn respond(query: &[u8], addrs: &[IpAddr], ttl: u32) -> Vec<u8> {
+    let qtype = u16::from_be_bytes([query[query.len() - 4], query[query.len() - 3]]);
+    let records: Vec<Vec<u8>> = addrs
+      .iter()
+      .filter_map(|a| match (a, qtype) {
+        (IpAddr::V4(v4), TYPE_A) => Some(v4.octets().to_vec()),
+        (IpAddr::V6(v6), TYPE_AAAA) => Some(v6.octets().to_vec()),
+        _ => None,
+      })
+      .collect();
+    let mut out = query.to_vec();
+    out[2] = 0x81;
+    out[3] = 0x80;
+    out[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+    for data in records {
+      out.extend_from_slice(&[0xC0, 12]);
+      out.extend_from_slice(&qtype.to_be_bytes());
+      out.extend_from_slice(&CLASS_IN.to_be_bytes());
+      out.extend_from_slice(&ttl.to_be_bytes());
+      out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+      out.extend_from_slice(&data);
+    }
+    out
+  }
+
+  #[test]
+  fn query_and_answer_wire_format() {
+    let q = encode_query("dl.example.com.", TYPE_AAAA).unwrap();
+    assert_eq!(&q[12..], b"\x02dl\x07example\x03com\x00\x00\x1c\x00\x01");
+    assert!(encode_query("bad..name", TYPE_A).is_err());
+
+    let v6: IpAddr = "2001:db8::1".parse().unwrap();
+    let v4: IpAddr = "192.0.2.7".parse().unwrap();
+    let answer = decode_answer(&respond(&q, &[v4, v6], 300), TYPE_AAAA).unwrap();
+    assert_eq!(answer, Answer { addrs: vec![v6], ttl: Duration::from_secs(300) });
+
+    // NXDOMAIN is an empty answer; other failures and truncation are errors.
+    let mut nx = respond(&q, &[], 0);
+    nx[3] |= RCODE_NXDOMAIN as u8;
+    assert!(decode_answer(&nx, TYPE_AAAA).unwrap().addrs.is_empty());
+    let mut servfail = respond(&q, &[], 0);
+    servfail[3] |= 2;
+    assert!(decode_answer(&servfail, TYPE_AAAA).is_err());
+    let full = respond(&q, &[v6], 60);
+    assert!(decode_answer(&full[..full.len() - 3], TYPE_AAAA).is_err());
+  }
+}
@@ -0,0 +1,399 @@
+//! Header rules: every enabled rule matching a URL contributes, lowest priority first; equal
+//! priorities apply less specific patterns first, so `example.com` can override what `*` set.
+//!
+//! Values may contain `${secret:name}` (from the secrets store), `${env:NAME}`, `${host}`,
+//! `${url}` and `${date}` (now, as an HTTP date), resolved per request. A header whose value
+//! cannot be resolved is left out rather than sent with the placeholder.
+
+use super::profile::DEFAULT_USER_AGENT;
+use super::{best_pattern_match, pattern, Transport};
+use crate::model::{HeaderRule, RulesSnapshot};
+use crate::persistence::secrets::SecretVault;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, COOKIE, USER_AGENT};
+use std::collections::HashMap;
+use url::Url;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HeaderMode {
+  Override,
+  AddIfMissing,
+  Remove,
+  /// Joins onto an existing value (`, `, or `; ` for Cookie).
+  Append,
+}
+
+impl HeaderMode {
+  fn parse(v: &str) -> Option<Self> {
+    match v.trim().to_ascii_lowercase().as_str() {
+      "override" | "set" => Some(HeaderMode::Override),
+      "add_if_missing" | "add" => Some(HeaderMode::AddIfMissing),
+      "remove" | "delete" => Some(HeaderMode::Remove),
+      "append" => Some(HeaderMode::Append),
+      _ => None,
+    }
+  }
+}
+
+struct HeaderOp {
+  name: HeaderName,
+  value: String,
+  mode: HeaderMode,
+}
+
+/// A request's headers, what last set each one, and the rule values that could not be resolved.
+#[derive(Default)]
+struct ResolvedHeaders {
+  headers: HeaderMap,
+  sources: HashMap<HeaderName, String>,
+  skipped: Vec<PreviewHeader>,
+}
+
+/// One header of `preview_headers`, with what last set it.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct PreviewHeader {
+  pub name: String,
+  pub value: String,
+  pub source: String,
+}
+
+/// Supported shapes:
+/// - {"headers": {"User-Agent": {"value":"X", "mode":"override"}, "Referer": "Y"}}
+/// - {"User-Agent": "X", "Authorization": {"value":"...", "mode":"add_if_missing"}}
+/// - {"Cookie": {"mode":"remove"}}
+fn rule_ops(rule: &HeaderRule) -> Vec<HeaderOp> {
+  let v = &rule.headers_json;
+  let obj = if let Some(h) = v.get("headers") { h } else { v };
+  let Some(map) = obj.as_object() else { return vec![] };
+
+  let mut ops = Vec::new();
+  for (k, v) in map {
+    let Ok(name) = HeaderName::from_bytes(k.as_bytes()) else { continue };
+    let (value, mode) = if let Some(s) = v.as_str() {
+      (s.to_string(), Some(HeaderMode::Override))
+    } else if let Some(o) = v.as_object() {
+      let value = o.get("value").and_then(|v| v.as_str()).unwrap_or("").to_string();
+      let mode = o.get("mode").and_then(|v| v.as_str()).map_or(Some(HeaderMode::Override), HeaderMode::parse);
+      (value, mode)
+    } else {
+      continue;
+    };
+    let Some(mode) = mode else { continue };
+    if value.is_empty() && mode != HeaderMode::Remove {
+      continue;
+    }
+    ops.push(HeaderOp { name, value, mode });
+  }
+  ops
+}
+
+/// Resolves `${...}` in `template`. With `reveal_secrets` off (previews), secrets are masked.
+fn expand(template: &str, url: &Url, secrets: &SecretVault, reveal_secrets: bool) -> Result<String, String> {
+  let mut out = String::with_capacity(template.len());
+  let mut rest = template;
+  while let Some(start) = rest.find("${") {
+    out.push_str(&rest[..start]);
+    let var = &rest[start + 2..];
+    let end = var.find('}').ok_or_else(|| format!("unterminated ${{ in {template}"))?;
+    let (var, after) = (&var[..end], &var[end + 1..]);
+    let value = match var.split_once(':') {
+      Some(("secret", name)) => match secrets.get(name) {
+        Some(_) if !reveal_secrets => "••••••".to_string(),
+        Some(v) => v,
+        None if !secrets.is_unlocked() => return Err(format!("secrets are locked (needed for {name})")),
+        None => return Err(format!("secret {name} is not set")),
+      },
+      Some(("env", name)) => std::env::var(name).map_err(|_| format!("environment variable {name} is not set"))?,
+      _ => match var {
+        "host" => url.host_str().unwrap_or_default().to_string(),
+        "url" => url.as_str().to_string(),
+        "date" => httpdate::fmt_http_date(std::time::SystemTime::now()),
+        _ => return Err(format!("unknown variable ${{{var}}}")),
+      },
+    };
+    out.push_str(&value);
+    rest = after;
+  }
+  out.push_str(rest);
+  Ok(out)
+}
+
+/// Applies `op`; false if it left `headers` unchanged.
+fn apply_op(headers: &mut HeaderMap, op: HeaderOp) -> bool {
+  if op.mode == HeaderMode::Remove {
+    return headers.remove(&op.name).is_some();
+  }
+  let existing = headers.get(&op.name).and_then(|v| v.to_str().ok()).map(str::to_string);
+  let value = match (op.mode, existing) {
+    (HeaderMode::AddIfMissing, _) if headers.contains_key(&op.name) => return false,
+    (HeaderMode::Append, Some(existing)) => {
+      let sep = if op.name == COOKIE { "; " } else { ", " };
+      format!("{existing}{sep}{}", op.value)
+    }
+    _ => op.value,
+  };
+  let Ok(value) = HeaderValue::from_str(&value) else { return false };
+  headers.insert(op.name, value);
+  true
+}
+
+impl Transport {
+  /// Enabled rules matching `url`, in the order they apply.
+  fn matching_header_rules<'a>(rules: &'a RulesSnapshot, url: &Url) -> Vec<&'a HeaderRule> {
+    let mut matching: Vec<(&HeaderRule, pattern::Specificity)> = rules
+      .header_rules
+      .iter()
+      .filter(|r| r.enabled)
+      .filter_map(|r| {
+        let p = pattern::cached(&r.pattern)?;
+        p.matches(url).then(|| (r, p.specificity()))
+      })
+      .collect();
+    matching.sort_by_key(|(r, spec)| (r.priority, *spec, r.id));
+    matching.into_iter().map(|(r, _)| r).collect()
+  }
+
+  /// The User-Agent the client for `url` sends, and where it comes from.
+  pub(super) fn user_agent_for(rules: &RulesSnapshot, url: &Url) -> (String, String) {
+    let from_rule = best_pattern_match(&rules.transport_rules.iter().filter(|r| r.enabled), url)
+      .and_then(|r| r.user_agent.as_deref().filter(|ua| !ua.trim().is_empty()).map(|ua| (r, ua)));
+    match from_rule {
+      Some((r, ua)) => (ua.to_string(), format!("transport rule {}", r.pattern)),
+      None => (DEFAULT_USER_AGENT.to_string(), "default".to_string()),
+    }
+  }
+
+  pub fn apply_header_rules(&self, rules: &RulesSnapshot, headers: &mut HeaderMap, url: &Url) {
+    let mut resolved = ResolvedHeaders {
+      headers: std::mem::take(headers),
+      ..Default::default()
+    };
+    self.apply_rules(rules, url, true, &mut resolved);
+    *headers = resolved.headers;
+  }
+
+  /// Headers for a request to `url`: the client's User-Agent, then every matching rule.
+  /// `preview_headers` shows the same, with secrets masked.
+  pub fn request_headers(&self, rules: &RulesSnapshot, url: &Url) -> HeaderMap {
+    self.resolve_headers(rules, url, true).headers
+  }
+
+  /// The headers rules add to a request for `url`, starting from the client's User-Agent, and
+  /// which rule (or profile) each final value came from. Secret values are masked; headers
+  /// that would be skipped are listed last with an empty value and the reason.
+  pub fn preview_headers(&self, rules: &RulesSnapshot, url: &Url) -> Vec<PreviewHeader> {
+    let ResolvedHeaders { headers, sources, skipped } = self.resolve_headers(rules, url, false);
+    let mut out: Vec<PreviewHeader> = headers
+      .iter()
+      .map(|(name, value)| PreviewHeader {
+        name: name.to_string(),
+        value: String::from_utf8_lossy(value.as_bytes()).into_owned(),
+        source: sources.get(name).cloned().unwrap_or_default(),
+      })
+      .collect();
+    out.sort_by(|a, b| a.name.cmp(&b.name));
+    out.extend(skipped);
+    out
+  }
+
+  fn resolve_headers(&self, rules: &RulesSnapshot, url: &Url, reveal_secrets: bool) -> ResolvedHeaders {
+    let (user_agent, source) = Self::user_agent_for(rules, url);
+    let user_agent = HeaderValue::from_str(&user_agent).ok();
+    let mut resolved = ResolvedHeaders::default();
+    if let Some(ua) = user_agent.clone() {
+      resolved.headers.insert(USER_AGENT, ua);
+      resolved.sources.insert(USER_AGENT, source.clone());
+    }
+    self.apply_rules(rules, url, reveal_secrets, &mut resolved);
+    // reqwest adds the client's User-Agent to any request without one, so removing it only
+    // undoes what other rules set.
+    if let (false, Some(ua)) = (resolved.headers.contains_key(USER_AGENT), user_agent) {
+      resolved.headers.insert(USER_AGENT, ua);
+      resolved.sources.insert(USER_AGENT, format!("{source}; the client sends one even when a rule removes it"));
+    }
+    resolved
+  }
+
+  fn apply_rules(&self, rules: &RulesSnapshot, url: &Url, reveal_secrets: bool, out: &mut ResolvedHeaders) {
+    for rule in Self::matching_header_rules(rules, url) {
+      for mut op in rule_ops(rule) {
+        let name = op.name.clone();
+        match expand(&op.value, url, &self.secrets, reveal_secrets) {
+          Ok(value) => op.value = value,
+          Err(e) => {
+            if reveal_secrets {
+              tracing::warn!(pattern = %rule.pattern, header = %name, error = %e, "header rule value not resolved; header skipped");
+            }
+            out.skipped.push(PreviewHeader {
+              name: name.to_string(),
+              value: String::new(),
+              source: format!("header rule {}: not sent, {e}", rule.pattern),
+            });
+            continue;
+          }
+        }
+        if apply_op(&mut out.headers, op) {
+          out.sources.insert(name, format!("header rule {} (priority {})", rule.pattern, rule.priority));
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn rule(id: i64, pattern: &str, priority: i64, headers_json: serde_json::Value) -> HeaderRule {
+    HeaderRule {
+      id,
+      pattern: pattern.to_string(),
+      enabled: true,
+      priority,
+      headers_json,
+    }
+  }
+
+  fn rules(header_rules: Vec<HeaderRule>) -> RulesSnapshot {
+    let mut r = crate::transport::tests::rules_with(crate::transport::tests::local_rule());
+    r.transport_rules.clear();
+    r.header_rules = header_rules;
+    r
+  }
+
+  fn applied(rules: &RulesSnapshot, url: &str, start: &[(&'static str, &str)]) -> Vec<(String, String)> {
+    let mut headers = HeaderMap::new();
+    for (k, v) in start {
+      headers.insert(*k, HeaderValue::from_str(v).unwrap());
+    }
+    Transport::new().unwrap().apply_header_rules(rules, &mut headers, &Url::parse(url).unwrap());
+    let mut out: Vec<_> = headers.iter().map(|(k, v)| (k.to_string(), v.to_str().unwrap().to_string())).collect();
+    out.sort();
+    out
+  }
+
+  fn h(k: &str, v: &str) -> (String, String) {
+    (k.to_string(), v.to_string())
+  }
+
+  #[test]
+  fn all_matching_rules_merge_by_priority_then_specificity() {
+    let r = rules(vec![
+      rule(1, "*", 0, json!({"User-Agent": "Global/1", "Accept": "*/*"})),
+      rule(2, "*.example.com", 0, json!({"Authorization": "Bearer abc", "Accept": {"value": "application/json", "mode": "append"}})),
+      rule(3, "api.example.com", 0, json!({"User-Agent": "Api/2"})),
+      // Low priority: applies first, so the `*` rule still overrides its User-Agent.
+      rule(4, "api.example.com/v2/", -5, json!({"User-Agent": "Old/0", "X-Trace": "1"})),
+    ]);
+    assert_eq!(
+      applied(&r, "https://api.example.com/v2/x", &[]),
+      vec![
+        h("accept", "*/*, application/json"),
+        h("authorization", "Bearer abc"),
+        h("user-agent", "Api/2"),
+        h("x-trace", "1"),
+      ]
+    );
+    assert_eq!(applied(&r, "https://other.org/", &[]), vec![h("accept", "*/*"), h("user-agent", "Global/1")]);
+  }
+
+  #[test]
+  fn modes_add_remove_and_append() {
+    let r = rules(vec![
+      rule(1, "*", 0, json!({"headers": {"Referer": {"value": "https://r/", "mode": "add_if_missing"}, "Cookie": {"value": "b=2", "mode": "append"}}})),
+      rule(2, "example.com", 10, json!({"Range": {"mode": "remove"}, "Cookie": {"value": "c=3", "mode": "append"}, "X-Bad": {"value": "v", "mode": "bogus"}})),
+    ]);
+    assert_eq!(
+      applied(&r, "http://example.com/", &[("referer", "https://mine/"), ("cookie", "a=1"), ("range", "bytes=0-")]),
+      vec![h("cookie", "a=1; b=2; c=3"), h("referer", "https://mine/")]
+    );
+    assert_eq!(applied(&r, "http://other.org/", &[]), vec![h("cookie", "b=2"), h("referer", "https://r/")]);
+  }
+
+  #[test]
+  fn preview_names_the_rule_behind_each_header() {
+    let r = rules(vec![
+      rule(1, "*", 0, json!({"Accept-Language": "en"})),
+      rule(2, "example.com", 0, json!({"Accept-Language": "de", "User-Agent": "Mine"})),
+    ]);
+    let t = Transport::new().unwrap();
+    let preview = t.preview_headers(&r, &Url::parse("https://example.com/").unwrap());
+    assert_eq!(
+      preview,
+      vec![
+        PreviewHeader { name: "accept-language".into(), value: "de".into(), source: "header rule example.com (priority 0)".into() },
+        PreviewHeader { name: "user-agent".into(), value: "Mine".into(), source: "header rule example.com (priority 0)".into() },
+      ]
+    );
+    let preview = t.preview_headers(&r, &Url::parse("https://other.org/").unwrap());
+    assert_eq!(preview[1].source, "default");
+  }
+
+  #[tokio::test]
+  async fn preview_matches_what_a_request_sends_when_a_rule_removes_the_user_agent() {
+    use axum::{http::HeaderMap as Headers, routing::get, Router};
+
+    let app = Router::new().route(
+      "/echo",
+      get(|h: Headers| async move {
+        let mut seen: Vec<String> = h
+          .iter()
+          .filter(|(name, _)| *name == "user-agent" || name.as_str().starts_with("x-"))
+          .map(|(name, value)| format!("{name}: {}", value.to_str().unwrap()))
+          .collect();
+        seen.sort();
+        seen.join("\n")
+      }),
+    );
+    let url = Url::parse(&format!("{}/echo", crate::transport::tests::serve(app).await)).unwrap();
+    let r = rules(vec![
+      rule(1, "*", 0, json!({"User-Agent": "Rule/1", "X-Keep": "1"})),
+      rule(2, "127.0.0.1", 5, json!({"User-Agent": {"mode": "remove"}})),
+    ]);
+    let t = Transport::new().unwrap();
+    let client = t.client_for(&Transport::client_profile(&crate::transport::tests::settings(), &r, &url)).unwrap();
+    let sent = client.get(url.clone()).headers(t.request_headers(&r, &url)).send().await.unwrap().text().await.unwrap();
+
+    let preview = t.preview_headers(&r, &url);
+    let previewed: Vec<String> = preview.iter().map(|p| format!("{}: {}", p.name, p.value)).collect();
+    assert_eq!(sent, previewed.join("\n"));
+    assert_eq!(previewed, vec!["user-agent: Z-DMR/0.1".to_string(), "x-keep: 1".to_string()]);
+    assert!(preview[0].source.starts_with("default;"), "{}", preview[0].source);
+  }
+
+  #[test]
+  fn templates_resolve_per_request_and_mask_secrets_in_previews() {
+    let t = Transport::new().unwrap();
+    let url = Url::parse("https://huggingface.co/m/resolve/main/w.bin").unwrap();
+    let r = rules(vec![rule(
+      1,
+      "huggingface.co",
+      0,
+      json!({"Authorization": "Bearer ${secret:hf_token}", "X-For": "${host} ${url}", "X-Date": "${date}", "X-Env": "${env:ZDMR_TEST_ENV}"}),
+    )]);
+    let mut headers = HeaderMap::new();
+    t.apply_header_rules(&r, &mut headers, &url);
+    // Locked secrets and a missing variable: those headers are left out.
+    assert!(headers.get("authorization").is_none());
+    assert!(headers.get("x-env").is_none());
+    assert_eq!(headers["x-for"], "huggingface.co https://huggingface.co/m/resolve/main/w.bin");
+    assert!(httpdate::parse_http_date(headers["x-date"].to_str().unwrap()).is_ok());
+
+    t.secrets().unlock(crate::persistence::secrets::random_bytes().unwrap(), &[]).unwrap();
+    t.secrets().seal("hf_token", "hf_abc").unwrap();
+    std::env::set_var("ZDMR_TEST_ENV", "from-env");
+    let mut headers = HeaderMap::new();
+    t.apply_header_rules(&r, &mut headers, &url);
+    assert_eq!(headers["authorization"], "Bearer hf_abc");
+    assert_eq!(headers["x-env"], "from-env");
+
+    let preview = t.preview_headers(&r, &url);
+    let auth = preview.iter().find(|p| p.name == "authorization").unwrap();
+    assert_eq!(auth.value, "Bearer ••••••");
+
+    let secrets = t.secrets();
+    assert_eq!(expand("${nope}", &url, secrets, true), Err("unknown variable ${nope}".to_string()));
+    assert!(expand("${host", &url, secrets, true).is_err());
+    assert_eq!(expand("$5 ${secret:missing}", &url, secrets, true), Err("secret missing is not set".to_string()));
+  }
+}
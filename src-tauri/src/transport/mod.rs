@@ -1,119 +1,273 @@
-//! Transport layer: HTTP client, proxy selection, header rules, mirror resolution.
+//! Transport layer: HTTP client, proxy selection, header rules, mirror resolution, and the
+//! non-HTTP sources (FTP, SFTP). S3 rides on HTTP with signed requests.
+
+pub mod auth;
+pub mod cookies;
+pub mod dns;
+pub mod doh;
+pub mod ftp;
+pub mod headers;
+pub mod pac;
+pub mod pattern;
+pub mod pool;
+pub mod profile;
+pub mod proxy;
+pub mod route;
+pub mod s3;
+pub mod sftp;
+pub mod source;
+pub mod tls;
+
+pub use profile::{ClientProfile, HttpVersion, IpPreference, LocalAddress, Timeouts};
+pub use proxy::ProxyConfig;
+pub use route::ProxyRoute;
 
 use crate::model::{RulesSnapshot, SettingsSnapshot};
-use anyhow::Context;
+use crate::persistence::secrets::SecretVault;
 use dashmap::DashMap;
-use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use std::sync::Arc;
+use std::time::Duration;
 use url::Url;
 
+/// When a download is split into ranged segments, and how finely.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MultipartPolicy {
+  pub min_size_bytes: i64,
+  pub segment_size_bytes: i64,
+  pub max_segments: usize,
+}
+
+impl MultipartPolicy {
+  /// Multipart needs a known size above the threshold and room for at least two segments.
+  pub fn applies_to(&self, content_length: Option<i64>) -> bool {
+    self.max_segments >= 2 && content_length.is_some_and(|l| l >= self.min_size_bytes.max(2))
+  }
+}
+
+/// How a host lets us learn size/validators before downloading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeMethod {
+  Head,
+  // `GET` with `Range: bytes=0-0`, for hosts that reject or mishandle HEAD.
+  RangedGet,
+}
+
+/// A failed request, with timeouts told apart so the error message can say which one fired.
+#[derive(Debug, thiserror::Error)]
+pub enum SendError {
+  #[error("connect timeout: no connection within {}s", .0.as_secs())]
+  ConnectTimeout(Duration),
+  #[error("response timeout: no response headers within {}s", .0.as_secs())]
+  ResponseTimeout(Duration),
+  #[error("read idle timeout: no data for {}s", .0.as_secs())]
+  ReadIdleTimeout(Duration),
+  #[error(transparent)]
+  Request(reqwest::Error),
+}
+
+impl SendError {
+  /// Classifies a reqwest error against `timeouts`; the only timeout reqwest enforces is connect.
+  pub fn from_reqwest(err: reqwest::Error, timeouts: &Timeouts) -> Self {
+    if err.is_timeout() && err.is_connect() {
+      SendError::ConnectTimeout(timeouts.connect)
+    } else {
+      SendError::Request(err)
+    }
+  }
+
+  pub fn is_timeout(&self) -> bool {
+    match self {
+      SendError::Request(e) => e.is_timeout(),
+      _ => true,
+    }
+  }
+
+  pub fn is_connect(&self) -> bool {
+    matches!(self, SendError::Request(e) if e.is_connect())
+  }
+
+  /// The certificate or handshake problem, when the request failed during TLS.
+  pub fn tls_reason(&self) -> Option<String> {
+    match self {
+      SendError::Request(e) => tls::failure_reason(e),
+      _ => None,
+    }
+  }
+}
+
 #[derive(Clone)]
 pub struct Transport {
-  direct: reqwest::Client,
-  proxy_clients: Arc<DashMap<String, reqwest::Client>>,
+  clients: Arc<DashMap<ClientProfile, reqwest::Client>>,
+  probe_methods: Arc<DashMap<String, ProbeMethod>>,
+  pac: Arc<pac::PacResolver>,
+  pools: Arc<pool::ProxyPools>,
+  secrets: Arc<SecretVault>,
+  cookies: Arc<cookies::CookieJar>,
+  auth: Arc<auth::AuthCache>,
+  dns: Arc<dns::DnsCache>,
 }
 
 impl Transport {
   pub fn new() -> anyhow::Result<Self> {
+    let clients = DashMap::new();
+    let cookies = Arc::new(cookies::CookieJar::default());
+    let dns = Arc::new(dns::DnsCache::new()?);
+    let profile = ClientProfile::default();
+    clients.insert(profile.clone(), profile::build_client(&profile, &cookies, &dns)?);
     Ok(Self {
-      direct: build_client(None)?,
-      proxy_clients: Arc::new(DashMap::new()),
+      clients: Arc::new(clients),
+      probe_methods: Arc::new(DashMap::new()),
+      pac: Arc::new(pac::PacResolver::default()),
+      pools: Arc::new(pool::ProxyPools::default()),
+      secrets: Arc::new(SecretVault::default()),
+      cookies,
+      auth: Arc::new(auth::AuthCache::default()),
+      dns,
     })
   }
 
+  /// Shares the database's vault, so unlocking or locking it there applies to requests too.
+  pub fn with_secrets(mut self, secrets: Arc<SecretVault>) -> Self {
+    self.secrets = secrets;
+    self
+  }
+
+  /// Values for `${secret:...}` in header rules; locked (empty) until the app unlocks it.
+  pub fn secrets(&self) -> &SecretVault {
+    &self.secrets
+  }
+
+  /// Cookies sent with every request; the engine loads and persists them.
+  pub fn cookies(&self) -> &cookies::CookieJar {
+    &self.cookies
+  }
+
+  pub fn probe_method(&self, host: &str) -> ProbeMethod {
+    self
+      .probe_methods
+      .get(&host.to_ascii_lowercase())
+      .map(|m| *m)
+      .unwrap_or(ProbeMethod::Head)
+  }
+
+  pub fn remember_probe_method(&self, host: &str, method: ProbeMethod) {
+    self.probe_methods.insert(host.to_ascii_lowercase(), method);
+  }
+
   pub fn url_hostname(url: &str) -> Option<String> {
     Url::parse(url).ok().and_then(|u| u.host_str().map(|s| s.to_string()))
   }
 
-  pub fn client_for(&self, proxy_url: Option<&str>) -> anyhow::Result<reqwest::Client> {
-    if let Some(p) = proxy_url {
-      if let Some(existing) = self.proxy_clients.get(p) {
-        return Ok(existing.clone());
-      }
-      let client = build_client(Some(p))?;
-      self.proxy_clients.insert(p.to_string(), client.clone());
-      return Ok(client);
+  pub fn client_for(&self, profile: &ClientProfile) -> anyhow::Result<reqwest::Client> {
+    if let Some(existing) = self.clients.get(profile) {
+      return Ok(existing.clone());
     }
-    Ok(self.direct.clone())
+    let client = profile::build_client(profile, &self.cookies, &self.dns)?;
+    self.clients.insert(profile.clone(), client.clone());
+    Ok(client)
   }
 
-  pub fn effective_proxy_url(
-    settings: &SettingsSnapshot,
-    rules: &RulesSnapshot,
-    url: &Url,
-  ) -> Option<String> {
-    if !settings.global_proxy_enabled {
-      return None;
-    }
-    let global = settings.global_proxy_url.clone().filter(|s| !s.trim().is_empty())?;
-    let host = url.host_str()?.to_string();
-    let best = best_pattern_match(&rules.proxy_rules.iter().filter(|r| r.enabled), &host);
-    match best {
-      Some(rule) if rule.use_proxy => Some(rule.proxy_url_override.clone().unwrap_or(global)),
-      Some(_) => None,
-      None => None, // allowlist semantics: only proxied domains use proxy
+  /// Global settings, with any field set on the most specific transport rule taking precedence.
+  /// The proxy is chosen separately (`proxy_route`) and attached with `with_proxy`.
+  pub fn client_profile(settings: &SettingsSnapshot, rules: &RulesSnapshot, url: &Url) -> ClientProfile {
+    let rule = best_pattern_match(&rules.transport_rules.iter().filter(|r| r.enabled), url);
+    let defaults = ClientProfile::default();
+    let secs = |v: i64| Duration::from_secs(v.max(1) as u64);
+    ClientProfile {
+      proxy: None,
+      user_agent: Self::user_agent_for(rules, url).0,
+      http_version: rule
+        .and_then(|r| r.http_version.as_deref())
+        .and_then(HttpVersion::parse)
+        .unwrap_or(defaults.http_version),
+      max_redirects: rule
+        .and_then(|r| r.max_redirects)
+        .map(|n| n.clamp(0, 50) as usize)
+        .unwrap_or(defaults.max_redirects),
+      block_cross_host_redirects: rule.is_some_and(|r| r.block_cross_host_redirects),
+      dns: dns::DnsOptions {
+        // Invalid entries are refused when the rule is saved.
+        overrides: rule
+          .and_then(|r| r.dns_overrides.as_deref())
+          .and_then(|o| dns::parse_overrides(o).ok())
+          .unwrap_or_default(),
+        doh_url: rule.and_then(|r| r.doh_url.clone()),
+        ip_preference: rule
+          .and_then(|r| r.ip_preference.as_deref())
+          .and_then(IpPreference::parse)
+          .unwrap_or(defaults.dns.ip_preference),
+      },
+      decode_content: rule.is_some_and(|r| r.decode_content),
+      timeouts: Timeouts {
+        connect: secs(rule.and_then(|r| r.connect_timeout_secs).unwrap_or(settings.connect_timeout_secs)),
+        response: secs(rule.and_then(|r| r.response_timeout_secs).unwrap_or(settings.response_timeout_secs)),
+        read_idle: secs(rule.and_then(|r| r.read_idle_timeout_secs).unwrap_or(settings.read_idle_timeout_secs)),
+      },
+      tls: tls::TlsOptions {
+        ca_bundle: rule.and_then(|r| r.ca_bundle_path.clone()),
+        client_cert: rule.and_then(|r| r.client_cert_path.clone()),
+        client_key: rule.and_then(|r| r.client_key_path.clone()),
+        accept_invalid_certs: rule.is_some_and(|r| r.accept_invalid_certs),
+      },
+      local_address: Self::local_addresses(rules, url).into_iter().next(),
     }
   }
 
-  pub fn apply_header_rules(rules: &RulesSnapshot, headers: &mut HeaderMap, url: &Url) {
-    let host = match url.host_str() {
-      Some(h) => h,
-      None => return,
+  /// Every local address the most specific transport rule lists; the profile binds the first,
+  /// and multipart downloads spread their segments over all of them (`segment_profiles`).
+  pub fn local_addresses(rules: &RulesSnapshot, url: &Url) -> Vec<LocalAddress> {
+    best_pattern_match(&rules.transport_rules.iter().filter(|r| r.enabled), url)
+      .and_then(|r| r.local_address.as_deref())
+      // Invalid entries are refused when the rule is saved.
+      .and_then(|text| LocalAddress::parse_list(text).ok())
+      .unwrap_or_default()
+  }
+
+  /// One profile per segment slot: segments take turns over `proxies` (pool members, when the
+  /// pool rotates per segment) and over `locals`, so each link carries its share. An empty list
+  /// keeps what `base` has.
+  pub fn segment_profiles(base: &ClientProfile, proxies: &[Option<ProxyConfig>], locals: &[LocalAddress]) -> Vec<ClientProfile> {
+    let proxies = if proxies.is_empty() { vec![base.proxy.clone()] } else { proxies.to_vec() };
+    let locals: Vec<Option<LocalAddress>> = if locals.is_empty() {
+      vec![base.local_address.clone()]
+    } else {
+      locals.iter().cloned().map(Some).collect()
     };
+    (0..proxies.len().max(locals.len()))
+      .map(|i| {
+        base
+          .clone()
+          .with_proxy(proxies[i % proxies.len()].clone())
+          .with_local_address(locals[i % locals.len()].clone())
+      })
+      .collect()
+  }
 
-    let best = best_pattern_match(&rules.header_rules.iter().filter(|r| r.enabled), host);
-    let Some(rule) = best else { return };
-
-    // Supported shapes:
-    // - {"headers": {"User-Agent": {"value":"X", "mode":"override"}, "Referer": "Y"}}
-    // - {"User-Agent": "X", "Authorization": {"value":"...", "mode":"add_if_missing"}}
-    let v = &rule.headers_json;
-    let obj = if let Some(h) = v.get("headers") { h } else { v };
-    let Some(map) = obj.as_object() else { return };
-
-    for (k, v) in map {
-      let name = match HeaderName::from_bytes(k.as_bytes()) {
-        Ok(n) => n,
-        Err(_) => continue,
-      };
-
-      let (value, mode) = if let Some(s) = v.as_str() {
-        (s.to_string(), "override".to_string())
-      } else if let Some(o) = v.as_object() {
-        let value = o.get("value").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        let mode = o.get("mode").and_then(|v| v.as_str()).unwrap_or("override").to_string();
-        (value, mode)
-      } else {
-        continue;
-      };
-
-      if value.is_empty() {
-        continue;
-      }
-      let val = match HeaderValue::from_str(&value) {
-        Ok(v) => v,
-        Err(_) => continue,
-      };
-
-      match mode.as_str() {
-        "add_if_missing" | "add" => {
-          if !headers.contains_key(&name) {
-            headers.insert(name, val);
-          }
-        }
-        _ => {
-          headers.insert(name, val);
-        }
-      }
+  /// Sends `request`, bounding the wait for response headers by `timeouts.response`.
+  pub async fn send(request: reqwest::RequestBuilder, timeouts: &Timeouts) -> Result<reqwest::Response, SendError> {
+    Self::bounded(request.send(), timeouts).await
+  }
+
+  async fn execute(
+    client: &reqwest::Client,
+    request: reqwest::Request,
+    timeouts: &Timeouts,
+  ) -> Result<reqwest::Response, SendError> {
+    Self::bounded(client.execute(request), timeouts).await
+  }
+
+  async fn bounded(
+    send: impl std::future::Future<Output = reqwest::Result<reqwest::Response>>,
+    timeouts: &Timeouts,
+  ) -> Result<reqwest::Response, SendError> {
+    match tokio::time::timeout(timeouts.response, send).await {
+      Ok(resp) => resp.map_err(|e| SendError::from_reqwest(e, timeouts)),
+      Err(_) => Err(SendError::ResponseTimeout(timeouts.response)),
     }
   }
 
   pub fn mirror_candidates(rules: &RulesSnapshot, url: &Url) -> Vec<Url> {
-    let host = match url.host_str() {
-      Some(h) => h,
-      None => return vec![],
-    };
-    let best = best_pattern_match(&rules.mirror_rules.iter().filter(|r| r.enabled), host);
+    let best = best_pattern_match(&rules.mirror_rules.iter().filter(|r| r.enabled), url);
     let Some(rule) = best else { return vec![] };
     let Some(list) = rule.candidates_json.as_array() else { return vec![] };
 
@@ -128,59 +282,37 @@ impl Transport {
     }
     out
   }
-}
 
-fn build_client(proxy_url: Option<&str>) -> anyhow::Result<reqwest::Client> {
-  let mut b = reqwest::Client::builder()
-    .user_agent("Z-DMR/0.1")
-    .redirect(reqwest::redirect::Policy::limited(10))
-    .connect_timeout(std::time::Duration::from_secs(15))
-    .timeout(std::time::Duration::from_secs(60));
-  if let Some(p) = proxy_url {
-    let proxy = reqwest::Proxy::all(p).context("invalid proxy url")?;
-    b = b.proxy(proxy);
-  }
-  b.build().context("failed to build reqwest client")
-}
-
-fn pattern_specificity(pattern: &str) -> (u8, usize) {
-  // higher is more specific
-  if !pattern.contains('*') {
-    (2, pattern.len())
-  } else {
-    // "*.example.com" => suffix "example.com"
-    let suffix = pattern.trim_start_matches("*.").trim_start_matches('*');
-    (1, suffix.len())
-  }
-}
-
-fn pattern_matches(pattern: &str, host: &str) -> bool {
-  let p = pattern.trim().to_ascii_lowercase();
-  let h = host.trim().to_ascii_lowercase();
-  if p.is_empty() {
-    return false;
-  }
-  if !p.contains('*') {
-    return p == h;
-  }
-  if let Some(suffix) = p.strip_prefix("*.") {
-    return h == suffix || h.ends_with(&format!(".{suffix}"));
+  /// Global multipart settings, with any field set on the most specific segment rule taking precedence.
+  pub fn multipart_policy(settings: &SettingsSnapshot, rules: &RulesSnapshot, url: &Url) -> MultipartPolicy {
+    let rule = best_pattern_match(&rules.segment_rules.iter().filter(|r| r.enabled), url);
+    let min_size = rule.and_then(|r| r.min_size_bytes).unwrap_or(settings.multipart_min_size_bytes);
+    let segment_size = rule
+      .and_then(|r| r.segment_size_bytes)
+      .unwrap_or(settings.multipart_segment_size_bytes);
+    let max_segments = rule.and_then(|r| r.max_segments).unwrap_or(settings.multipart_max_segments);
+    MultipartPolicy {
+      min_size_bytes: min_size.max(0),
+      // Anything under 64KiB costs more in request overhead than it gains in parallelism.
+      segment_size_bytes: segment_size.max(64 * 1024),
+      max_segments: max_segments.clamp(1, 64) as usize,
+    }
   }
-  // Very small wildcard support: "*" means match all (not recommended, but deterministic).
-  p == "*"
 }
 
-fn best_pattern_match<'a, I, T>(rules: &I, host: &str) -> Option<&'a T>
+/// The enabled rule whose pattern (see `pattern`) matches `url` most specifically.
+fn best_pattern_match<'a, I, T>(rules: &I, url: &Url) -> Option<&'a T>
 where
   I: Iterator<Item = &'a T> + Clone,
   T: PatternRule,
 {
-  let mut best: Option<(&T, (u8, usize))> = None;
+  let mut best: Option<(&T, pattern::Specificity)> = None;
   for r in rules.clone() {
-    if !pattern_matches(r.pattern(), host) {
+    let Some(p) = pattern::cached(r.pattern()) else { continue };
+    if !p.matches(url) {
       continue;
     }
-    let spec = pattern_specificity(r.pattern());
+    let spec = p.specificity();
     if best.map(|(_, s)| spec > s).unwrap_or(true) {
       best = Some((r, spec));
     }
@@ -207,5 +339,372 @@ impl PatternRule for crate::model::MirrorRule {
     &self.pattern
   }
 }
+impl PatternRule for crate::model::SegmentRule {
+  fn pattern(&self) -> &str {
+    &self.pattern
+  }
+}
+impl PatternRule for crate::model::HttpCredential {
+  fn pattern(&self) -> &str {
+    &self.pattern
+  }
+}
+impl PatternRule for crate::model::TransportRule {
+  fn pattern(&self) -> &str {
+    &self.pattern
+  }
+}
+
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use axum::{body::Body, http::HeaderMap as AxumHeaders, routing::get, Router};
+  use futures_util::StreamExt;
+  use std::net::SocketAddr;
+
+  // gzip("hello, raw bytes\n" * 4)
+  const GZIPPED: &[u8] = &[
+    31, 139, 8, 0, 0, 0, 0, 0, 2, 255, 203, 72, 205, 201, 201, 215, 81, 40, 74, 44, 87, 72, 170, 44, 73, 45, 230,
+    202, 32, 93, 0, 0, 111, 102, 72, 130, 68, 0, 0, 0,
+  ];
+
+  pub(crate) fn settings() -> SettingsSnapshot {
+    serde_json::from_value(serde_json::json!({
+      "default_download_dir": "",
+      "bandwidth_limit_bps": null,
+      "minimize_to_tray": true,
+      "theme": "dark",
+      "skin": "modern",
+      "global_hotkey": "Ctrl+Shift+X",
+      "global_proxy_enabled": false,
+      "global_proxy_url": null,
+      "local_api_port": 17777
+    }))
+    .unwrap()
+  }
+
+  pub(crate) fn rules_with(transport_rule: crate::model::TransportRule) -> RulesSnapshot {
+    RulesSnapshot {
+      proxy_rules: vec![],
+      header_rules: vec![],
+      mirror_rules: vec![],
+      segment_rules: vec![],
+      transport_rules: vec![transport_rule],
+      proxy_pools: vec![],
+      http_credentials: vec![],
+    }
+  }
+
+  pub(crate) fn local_rule() -> crate::model::TransportRule {
+    crate::model::TransportRule {
+      id: 1,
+      pattern: "127.0.0.1".to_string(),
+      enabled: true,
+      decode_content: false,
+      connect_timeout_secs: None,
+      response_timeout_secs: None,
+      read_idle_timeout_secs: None,
+      user_agent: None,
+      http_version: None,
+      max_redirects: None,
+      block_cross_host_redirects: false,
+      ip_preference: None,
+      dns_overrides: None,
+      doh_url: None,
+      ca_bundle_path: None,
+      client_cert_path: None,
+      client_key_path: None,
+      accept_invalid_certs: false,
+      ssh_accept_new_host_keys: false,
+      local_address: None,
+    }
+  }
+
+  /// Serves `app` on a free loopback port, with the peer address available to handlers as
+  /// `ConnectInfo<SocketAddr>`; returns its base URL.
+  pub(crate) async fn serve(app: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      let _ = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await;
+    });
+    format!("http://{addr}")
+  }
+
+  /// A fresh directory under the system temp dir, removed with everything in it on drop.
+  pub(crate) struct TempDir(pub(crate) std::path::PathBuf);
+
+  impl TempDir {
+    pub(crate) fn new() -> Self {
+      let dir = std::env::temp_dir().join(format!("zdmr-{}", uuid::Uuid::new_v4()));
+      std::fs::create_dir_all(&dir).unwrap();
+      TempDir(dir)
+    }
+  }
+
+  impl std::ops::Deref for TempDir {
+    type Target = std::path::Path;
+    fn deref(&self) -> &std::path::Path {
+      &self.0
+    }
+  }
+
+  impl Drop for TempDir {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.0);
+    }
+  }
+
+  /// A database with the current schema in a fresh [`TempDir`], which goes away with it.
+  pub(crate) fn test_db() -> (crate::persistence::Db, TempDir) {
+    let dir = TempDir::new();
+    let db = crate::persistence::Db::open(dir.join("db.sqlite")).unwrap();
+    db.init_schema().unwrap();
+    (db, dir)
+  }
+
+  /// Serves a gzip-encoded body regardless of what the client asked for, echoing its Accept-Encoding.
+  async fn encoded_server() -> String {
+    let app = Router::new().route(
+      "/archive.tar.gz",
+      get(|req_headers: AxumHeaders| async move {
+        let seen = req_headers
+          .get("accept-encoding")
+          .and_then(|v| v.to_str().ok())
+          .unwrap_or("")
+          .to_string();
+        (
+          [("content-encoding", "gzip".to_string()), ("x-seen-accept-encoding", seen)],
+          GZIPPED,
+        )
+      }),
+    );
+    format!("{}/archive.tar.gz", serve(app).await)
+  }
+
+  #[tokio::test]
+  async fn downloads_are_byte_exact_by_default() {
+    let url = encoded_server().await;
+    let transport = Transport::new().unwrap();
+    let client = transport.client_for(&ClientProfile::default()).unwrap();
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.headers()["x-seen-accept-encoding"], "identity");
+    assert_eq!(resp.content_length(), Some(GZIPPED.len() as u64));
+    assert_eq!(resp.bytes().await.unwrap().as_ref(), GZIPPED);
+  }
+
+  #[tokio::test]
+  async fn decoding_is_opt_in_per_rule() {
+    let url = encoded_server().await;
+    let rules = rules_with(crate::model::TransportRule {
+      decode_content: true,
+      ..local_rule()
+    });
+    let profile = Transport::client_profile(&settings(), &rules, &Url::parse(&url).unwrap());
+    assert!(profile.decode_content);
+    let client = Transport::new().unwrap().client_for(&profile).unwrap();
+    let body = client.get(&url).send().await.unwrap().text().await.unwrap();
+    assert_eq!(body, "hello, raw bytes\n".repeat(4));
+  }
+
+  #[tokio::test]
+  async fn response_timeout_bounds_headers_not_body() {
+    let base = serve(
+      Router::new()
+        .route(
+          "/slow-headers",
+          get(|| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            "late"
+          }),
+        )
+        .route(
+          "/slow-body",
+          get(|| async {
+            let chunks = futures_util::stream::iter([b"x", b"y"]).then(|c| async move {
+              tokio::time::sleep(Duration::from_millis(1500)).await;
+              Ok::<_, std::io::Error>(bytes::Bytes::from_static(c))
+            });
+            Body::from_stream(chunks)
+          }),
+        ),
+    )
+    .await;
+    let rules = rules_with(crate::model::TransportRule {
+      response_timeout_secs: Some(1),
+      ..local_rule()
+    });
+    let profile = Transport::client_profile(&settings(), &rules, &Url::parse(&base).unwrap());
+    assert_eq!(profile.timeouts.connect, Duration::from_secs(crate::model::DEFAULT_CONNECT_TIMEOUT_SECS as u64));
+    let client = Transport::new().unwrap().client_for(&profile).unwrap();
+
+    let err = Transport::send(client.get(format!("{base}/slow-headers")), &profile.timeouts)
+      .await
+      .unwrap_err();
+    assert!(matches!(err, SendError::ResponseTimeout(_)));
+    assert_eq!(err.to_string(), "response timeout: no response headers within 1s");
+
+    // Headers arrive at once; a body that outlives the response timeout is not cut off.
+    let resp = Transport::send(client.get(format!("{base}/slow-body")), &profile.timeouts).await.unwrap();
+    assert_eq!(resp.bytes().await.unwrap().as_ref(), b"xy");
+  }
+
+  #[tokio::test]
+  async fn profile_sets_user_agent_and_blocks_cross_host_redirects() {
+    let (listener, port) = {
+      let l = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let port = l.local_addr().unwrap().port();
+      (l, port)
+    };
+    let app = Router::new()
+      .route(
+        "/ua",
+        get(|h: AxumHeaders| async move { h.get("user-agent").and_then(|v| v.to_str().ok()).unwrap_or("").to_string() }),
+      )
+      .route("/same-host", get(|| async { axum::response::Redirect::temporary("/ua") }))
+      .route(
+        "/other-host",
+        get(move || async move { axum::response::Redirect::temporary(&format!("http://localhost:{port}/ua")) }),
+      );
+    tokio::spawn(async move {
+      let _ = axum::serve(listener, app).await;
+    });
+    let base = format!("http://127.0.0.1:{port}");
+
+    let rules = rules_with(crate::model::TransportRule {
+      user_agent: Some("Fetcher/2.0".to_string()),
+      block_cross_host_redirects: true,
+      ..local_rule()
+    });
+    let profile = Transport::client_profile(&settings(), &rules, &Url::parse(&base).unwrap());
+    assert_eq!(profile.http_version, HttpVersion::Http1);
+    let transport = Transport::new().unwrap();
+    let client = transport.client_for(&profile).unwrap();
 
+    let ua = client.get(format!("{base}/same-host")).send().await.unwrap().text().await.unwrap();
+    assert_eq!(ua, "Fetcher/2.0");
+    let err = client.get(format!("{base}/other-host")).send().await.unwrap_err();
+    assert!(err.is_redirect(), "{err:?}");
 
+    // A different proxy is a different profile, hence a different client.
+    let proxied = profile.clone().with_proxy(Some(ProxyConfig::parse("http://127.0.0.1:9").unwrap()));
+    assert_ne!(proxied, profile);
+    assert!(transport.client_for(&proxied).is_ok());
+    assert_eq!(transport.clients.len(), 3);
+  }
+
+  #[tokio::test]
+  async fn cookies_set_during_redirects_are_sent_on_the_next_hop() {
+    let app = Router::new()
+      .route(
+        "/login",
+        get(|| async { ([("set-cookie", "session=abc; Path=/")], axum::response::Redirect::temporary("/file")) }),
+      )
+      .route(
+        "/file",
+        get(|h: AxumHeaders| async move { h.get("cookie").and_then(|v| v.to_str().ok()).unwrap_or("").to_string() }),
+      );
+    let base = serve(app).await;
+
+    let transport = Transport::new().unwrap();
+    let client = transport.client_for(&ClientProfile::default()).unwrap();
+    let cookie = client.get(format!("{base}/login")).send().await.unwrap().text().await.unwrap();
+    assert_eq!(cookie, "session=abc");
+    assert!(transport.cookies().export_netscape().contains("127.0.0.1\tFALSE\t/\tFALSE\t0\tsession\tabc"));
+  }
+
+  /// A server answering `/peer` with the caller's IP, and rules binding it to 127.0.0.2 and 127.0.0.3.
+  async fn peer_server() -> (String, RulesSnapshot) {
+    let app = Router::new().route(
+      "/peer",
+      get(|axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<SocketAddr>| async move { peer.ip().to_string() }),
+    );
+    let rules = rules_with(crate::model::TransportRule {
+      local_address: Some("127.0.0.2, 127.0.0.3".to_string()),
+      ..local_rule()
+    });
+    (serve(app).await, rules)
+  }
+
+  #[tokio::test]
+  async fn local_addresses_spread_over_segments() {
+    let (base, rules) = peer_server().await;
+    let url = Url::parse(&base).unwrap();
+    let locals = Transport::local_addresses(&rules, &url);
+    let profile = Transport::client_profile(&settings(), &rules, &url);
+    assert_eq!(profile.local_address, Some(locals[0].clone()));
+
+    let proxies = [None, Some(ProxyConfig::parse("http://127.0.0.1:9").unwrap()), None];
+    let slots = Transport::segment_profiles(&profile, &proxies, &locals);
+    assert_eq!(slots.len(), 3);
+    assert_eq!(slots[2].local_address, Some(locals[0].clone()));
+    assert!(slots[1].proxy.is_some() && slots[1].local_address == Some(locals[1].clone()));
+
+    assert_eq!(
+      LocalAddress::parse_list("eth1 [::1],eth1").unwrap(),
+      vec![LocalAddress::Interface("eth1".into()), LocalAddress::Ip("::1".parse().unwrap())]
+    );
+    assert!(LocalAddress::parse_list("eth/1").is_err());
+  }
+
+  #[tokio::test]
+  #[cfg_attr(
+    not(any(target_os = "linux", target_os = "windows")),
+    ignore = "loopback only carries 127.0.0.1 by default here"
+  )]
+  async fn local_addresses_bind_outgoing_connections() {
+    let (base, rules) = peer_server().await;
+    let url = Url::parse(&base).unwrap();
+    let locals = Transport::local_addresses(&rules, &url);
+    let profile = Transport::client_profile(&settings(), &rules, &url);
+    let transport = Transport::new().unwrap();
+    let mut seen = Vec::new();
+    for slot in Transport::segment_profiles(&profile, &[], &locals) {
+      let client = transport.client_for(&slot).unwrap();
+      seen.push(client.get(format!("{base}/peer")).send().await.unwrap().text().await.unwrap());
+    }
+    assert_eq!(seen, ["127.0.0.2", "127.0.0.3"]);
+  }
+
+  #[test]
+  fn ip_preference_orders_addresses() {
+    let v4: SocketAddr = "192.0.2.1:0".parse().unwrap();
+    let v6: SocketAddr = "[2001:db8::1]:0".parse().unwrap();
+    assert_eq!(dns::order_by_preference(vec![v6, v4], IpPreference::Ipv4), vec![v4, v6]);
+    assert_eq!(dns::order_by_preference(vec![v4, v6], IpPreference::Ipv6), vec![v6, v4]);
+    assert_eq!(dns::order_by_preference(vec![v6, v4], IpPreference::Auto), vec![v6, v4]);
+  }
+
+  #[test]
+  fn best_pattern_match_prefers_the_most_specific_rule() {
+    let rule = |id: i64, pattern: &str| crate::model::ProxyRule {
+      id,
+      pattern: pattern.to_string(),
+      enabled: true,
+      use_proxy: true,
+      proxy_url_override: None,
+    };
+    let rules = vec![
+      rule(1, "*"),
+      rule(2, "*.example.com"),
+      rule(3, "dl.example.com"),
+      rule(4, "dl.example.com/releases/"),
+      rule(5, "https://dl.example.com/releases/"),
+      rule(6, "*://*.example.com/*.iso"),
+      rule(7, r"re:/nightly/\d+/"),
+      rule(8, "re:("),
+      rule(9, "*.example.com"),
+    ];
+    let best = |u: &str| best_pattern_match(&rules.iter(), &Url::parse(u).unwrap()).map(|r| r.id);
+    assert_eq!(best("http://other.org/"), Some(1));
+    // Equal patterns: the first listed wins.
+    assert_eq!(best("http://www.example.com/"), Some(2));
+    assert_eq!(best("http://dl.example.com/"), Some(3));
+    assert_eq!(best("http://dl.example.com/releases/a.zip"), Some(4));
+    assert_eq!(best("https://dl.example.com/releases/a.zip"), Some(5));
+    assert_eq!(best("https://dl.example.com/releases/a.iso"), Some(6));
+    assert_eq!(best("https://dl.example.com/nightly/42/a.iso"), Some(7));
+  }
+}
@@ -0,0 +1,385 @@
+//! Proxy auto-config: runs `FindProxyForURL` from a PAC script (file path or URL) and turns the
+//! answer into a proxy URL. Scripts run in a fresh QuickJS runtime on a blocking thread with a
+//! time and memory budget; answers are cached per origin.
+
+use anyhow::Context;
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use rquickjs::{Context as JsContext, Function, Runtime};
+use std::net::{IpAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use url::Url;
+
+const SCRIPT_TTL: Duration = Duration::from_secs(30 * 60);
+const DECISION_TTL: Duration = Duration::from_secs(5 * 60);
+const EVAL_BUDGET: Duration = Duration::from_secs(2);
+const MEMORY_LIMIT: usize = 32 * 1024 * 1024;
+
+/// The standard PAC helpers (after Mozilla's `pac_utils.js`). `dnsResolve` and `myIpAddress`
+/// are backed by Rust functions installed before this runs.
+const PAC_UTILS: &str = r#"
+function dnsResolve(host) { var r = __dnsResolve(String(host)); return r === undefined ? null : r; }
+function myIpAddress() { return __myIpAddress(); }
+function isPlainHostName(host) { return host.indexOf('.') < 0; }
+function dnsDomainIs(host, domain) {
+  return host.length >= domain.length && host.substring(host.length - domain.length) == domain;
+}
+function localHostOrDomainIs(host, hostdom) { return host == hostdom || hostdom.lastIndexOf(host + '.', 0) == 0; }
+function isResolvable(host) { return dnsResolve(host) !== null; }
+function dnsDomainLevels(host) { return host.split('.').length - 1; }
+function convert_addr(ipchars) {
+  var b = ipchars.split('.');
+  return ((b[0] & 0xff) << 24) | ((b[1] & 0xff) << 16) | ((b[2] & 0xff) << 8) | (b[3] & 0xff);
+}
+function isInNet(ipaddr, pattern, maskstr) {
+  if (!/^\d+\.\d+\.\d+\.\d+$/.test(ipaddr)) {
+    ipaddr = dnsResolve(ipaddr);
+    if (ipaddr === null) return false;
+  }
+  var mask = convert_addr(maskstr);
+  return (convert_addr(ipaddr) & mask) == (convert_addr(pattern) & mask);
+}
+function shExpMatch(str, shexp) {
+  var re = shexp.replace(/[.+^${}()|[\]\\]/g, '\\$&').replace(/\*/g, '.*').replace(/\?/g, '.');
+  return new RegExp('^' + re + '$').test(str);
+}
+function __args(args) {
+  var a = Array.prototype.slice.call(args), gmt = a.length > 0 && a[a.length - 1] === 'GMT';
+  if (gmt) a.pop();
+  var d = new Date();
+  return {
+    a: a,
+    day: gmt ? d.getUTCDate() : d.getDate(),
+    month: gmt ? d.getUTCMonth() : d.getMonth(),
+    year: gmt ? d.getUTCFullYear() : d.getFullYear(),
+    wday: gmt ? d.getUTCDay() : d.getDay(),
+    secs: gmt ? d.getUTCHours() * 3600 + d.getUTCMinutes() * 60 + d.getUTCSeconds()
+              : d.getHours() * 3600 + d.getMinutes() * 60 + d.getSeconds()
+  };
+}
+function __inRange(lo, cur, hi) { return lo <= hi ? cur >= lo && cur <= hi : cur >= lo || cur <= hi; }
+function weekdayRange() {
+  var t = __args(arguments), days = 'SUNMONTUEWEDTHUFRISAT';
+  var w1 = days.indexOf(t.a[0]), w2 = t.a.length > 1 ? days.indexOf(t.a[1]) : w1;
+  if (w1 < 0 || w2 < 0 || w1 % 3 || w2 % 3) return false;
+  return __inRange(w1 / 3, t.wday, w2 / 3);
+}
+function timeRange() {
+  var t = __args(arguments), a = t.a;
+  switch (a.length) {
+    case 1: return Math.floor(t.secs / 3600) == a[0];
+    case 2: return __inRange(a[0] * 3600, t.secs, a[1] * 3600 - 1);
+    case 4: return __inRange(a[0] * 3600 + a[1] * 60, t.secs, a[2] * 3600 + a[3] * 60 - 1);
+    case 6: return __inRange(a[0] * 3600 + a[1] * 60 + a[2], t.secs, a[3] * 3600 + a[4] * 60 + a[5]);
+    default: return false;
+  }
+}
+function dateRange() {
+  var t = __args(arguments), a = t.a, months = 'JANFEBMARAPRMAYJUNJULAUGSEPOCTNOVDEC';
+  if (a.length == 0 || a.length > 6) return false;
+  function kind(v) { return typeof v == 'string' ? 'm' : v > 31 ? 'y' : 'd'; }
+  function parse(list) {
+    var r = {};
+    for (var i = 0; i < list.length; i++) {
+      var v = list[i], k = kind(v);
+      r[k] = k == 'm' ? months.indexOf(v) / 3 : v;
+    }
+    return r;
+  }
+  function key(x, f) {
+    return (f.y !== undefined ? x.y : 0) * 10000 + (f.m !== undefined ? x.m : 0) * 100 + (f.d !== undefined ? x.d : 0);
+  }
+  var now = { d: t.day, m: t.month, y: t.year }, half = a.length / 2;
+  var kinds = a.map(kind).join('');
+  if (a.length % 2 == 0 && kinds.substring(0, half) == kinds.substring(half)) {
+    var lo = parse(a.slice(0, half)), hi = parse(a.slice(half));
+    return __inRange(key(lo, lo), key(now, lo), key(hi, lo));
+  }
+  var one = parse(a);
+  return key(one, one) == key(now, one);
+}
+"#;
+
+/// One entry of a `FindProxyForURL` answer such as `"PROXY a:3128; SOCKS5 b:1080; DIRECT"`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacEntry {
+  Direct,
+  /// As a proxy URL: `PROXY` → `http://`, `HTTPS` → `https://`, `SOCKS`/`SOCKS4` → `socks4://`,
+  /// `SOCKS5` → `socks5h://` (names resolved by the proxy, as browsers do).
+  Proxy(String),
+}
+
+/// Unknown entries are skipped; an answer with nothing usable means DIRECT.
+pub fn parse_result(answer: &str) -> Vec<PacEntry> {
+  let mut out = Vec::new();
+  for part in answer.split(';') {
+    let mut it = part.split_whitespace();
+    let Some(kind) = it.next() else { continue };
+    let kind = kind.to_ascii_uppercase();
+    if kind == "DIRECT" {
+      out.push(PacEntry::Direct);
+      continue;
+    }
+    let Some(addr) = it.next() else { continue };
+    let scheme = match kind.as_str() {
+      "PROXY" | "HTTP" => "http",
+      "HTTPS" => "https",
+      "SOCKS" | "SOCKS4" => "socks4",
+      "SOCKS5" => "socks5h",
+      _ => continue,
+    };
+    out.push(PacEntry::Proxy(format!("{scheme}://{addr}")));
+  }
+  if out.is_empty() {
+    out.push(PacEntry::Direct);
+  }
+  out
+}
+
+/// Looks up a host for `dnsResolve` and `isInNet`; the system resolver outside tests.
+pub type HostResolver = fn(&str) -> Option<String>;
+
+/// Runs `FindProxyForURL(url, host)` and returns its raw answer. Blocking: DNS helpers resolve
+/// synchronously, so call this from `spawn_blocking`.
+pub fn evaluate(script: &str, url: &str, host: &str) -> anyhow::Result<String> {
+  evaluate_with(script, url, host, dns_resolve)
+}
+
+fn evaluate_with(script: &str, url: &str, host: &str, resolve: HostResolver) -> anyhow::Result<String> {
+  let rt = Runtime::new().context("failed to start PAC runtime")?;
+  rt.set_memory_limit(MEMORY_LIMIT);
+  let started = Instant::now();
+  rt.set_interrupt_handler(Some(Box::new(move || started.elapsed() > EVAL_BUDGET)));
+  let ctx = JsContext::full(&rt).context("failed to start PAC runtime")?;
+  ctx.with(|ctx| -> anyhow::Result<String> {
+    let js_err = |e: rquickjs::Error, ctx: &rquickjs::Ctx<'_>| {
+      let detail = ctx.catch().as_exception().map(|ex| ex.to_string()).unwrap_or_else(|| e.to_string());
+      anyhow::anyhow!("PAC script error: {detail}")
+    };
+    let globals = ctx.globals();
+    let resolve = Function::new(ctx.clone(), move |host: String| resolve(&host)).map_err(|e| js_err(e, &ctx))?;
+    let my_ip = Function::new(ctx.clone(), my_ip_address).map_err(|e| js_err(e, &ctx))?;
+    globals.set("__dnsResolve", resolve).map_err(|e| js_err(e, &ctx))?;
+    globals.set("__myIpAddress", my_ip).map_err(|e| js_err(e, &ctx))?;
+    ctx.eval::<(), _>(PAC_UTILS).map_err(|e| js_err(e, &ctx))?;
+    ctx.eval::<(), _>(script).map_err(|e| js_err(e, &ctx))?;
+    let find: Function = globals
+      .get("FindProxyForURL")
+      .map_err(|_| anyhow::anyhow!("PAC script does not define FindProxyForURL"))?;
+    let answer: rquickjs::Value = find.call((url, host)).map_err(|e| js_err(e, &ctx))?;
+    Ok(answer.as_string().and_then(|s| s.to_string().ok()).unwrap_or_default())
+  })
+}
+
+fn dns_resolve(host: &str) -> Option<String> {
+  let addrs = (host, 0).to_socket_addrs().ok()?.map(|a| a.ip()).collect::<Vec<_>>();
+  // PAC scripts compare dotted quads, so prefer IPv4.
+  addrs.iter().find(|ip| ip.is_ipv4()).or(addrs.first()).map(IpAddr::to_string)
+}
+
+fn my_ip_address() -> String {
+  // Connecting a UDP socket sends nothing; it only picks the outbound interface.
+  UdpSocket::bind("0.0.0.0:0")
+    .and_then(|s| s.connect("192.0.2.1:9").map(|_| s))
+    .and_then(|s| s.local_addr())
+    .map(|a| a.ip().to_string())
+    .unwrap_or_else(|_| "127.0.0.1".to_string())
+}
+
+/// Result of evaluating the PAC script for one origin.
+#[derive(Debug, Clone)]
+pub struct PacDecision {
+  pub answer: String,
+  pub entries: Vec<PacEntry>,
+}
+
+struct LoadedScript {
+  source: String,
+  text: Arc<str>,
+  loaded_at: Instant,
+}
+
+/// Loads the configured script (re-fetched every `SCRIPT_TTL` or when the source changes) and
+/// caches decisions per origin. Scripts see `scheme://host:port/` rather than the full URL, as
+/// browsers do for https, so one answer holds for every path on the origin.
+pub struct PacResolver {
+  script: Mutex<Option<LoadedScript>>,
+  decisions: DashMap<String, (PacDecision, Instant)>,
+  resolve: HostResolver,
+}
+
+impl Default for PacResolver {
+  fn default() -> Self {
+    Self {
+      script: Mutex::default(),
+      decisions: DashMap::new(),
+      resolve: dns_resolve,
+    }
+  }
+}
+
+impl PacResolver {
+  pub async fn decide(&self, client: &reqwest::Client, source: &str, url: &Url) -> anyhow::Result<PacDecision> {
+    let host = url.host_str().context("url has no host")?.to_string();
+    let origin = format!("{}://{}:{}/", url.scheme(), host, url.port_or_known_default().unwrap_or(0));
+    let script = self.script(client, source).await?;
+    if let Some(hit) = self.decisions.get(&origin) {
+      if hit.1.elapsed() < DECISION_TTL {
+        return Ok(hit.0.clone());
+      }
+    }
+    let (pac_url, resolve) = (origin.clone(), self.resolve);
+    let answer = tokio::task::spawn_blocking(move || evaluate_with(&script, &pac_url, &host, resolve))
+      .await
+      .context("PAC evaluation panicked")??;
+    let decision = PacDecision {
+      entries: parse_result(&answer),
+      answer,
+    };
+    self.decisions.insert(origin, (decision.clone(), Instant::now()));
+    Ok(decision)
+  }
+
+  async fn script(&self, client: &reqwest::Client, source: &str) -> anyhow::Result<Arc<str>> {
+    {
+      let cur = self.script.lock();
+      if let Some(s) = cur.as_ref().filter(|s| s.source == source && s.loaded_at.elapsed() < SCRIPT_TTL) {
+        return Ok(s.text.clone());
+      }
+    }
+    let text: Arc<str> = load_script(client, source).await?.into();
+    self.decisions.clear();
+    *self.script.lock() = Some(LoadedScript {
+      source: source.to_string(),
+      text: text.clone(),
+      loaded_at: Instant::now(),
+    });
+    Ok(text)
+  }
+}
+
+async fn load_script(client: &reqwest::Client, source: &str) -> anyhow::Result<String> {
+  let source = source.trim();
+  match Url::parse(source) {
+    Ok(u) if u.scheme() == "http" || u.scheme() == "https" => {
+      let resp = client
+        .get(u)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .with_context(|| format!("failed to fetch PAC file {source}"))?;
+      resp.text().await.context("failed to read PAC file")
+    }
+    Ok(u) if u.scheme() == "file" => {
+      let path = u.to_file_path().map_err(|_| anyhow::anyhow!("invalid PAC file url {source}"))?;
+      tokio::fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("failed to read PAC file {}", path.display()))
+    }
+    _ => tokio::fs::read_to_string(source)
+      .await
+      .with_context(|| format!("failed to read PAC file {source}")),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use axum::{routing::get, Router};
+
+  const SCRIPT: &str = r#"
+    function FindProxyForURL(url, host) {
+      if (isPlainHostName(host) || dnsDomainIs(host, ".corp.local")) return "DIRECT";
+      if (isInNet(host, "10.0.0.0", "255.0.0.0")) return "DIRECT";
+      if (shExpMatch(host, "*.video.example")) return "SOCKS5 socks.corp:1080";
+      if (url.substring(0, 6) == "https:") return "HTTPS secure.corp:443; DIRECT";
+      return "PROXY proxy.corp:3128; DIRECT";
+    }
+  "#;
+
+  /// Stands in for DNS so the tests do not depend on the network: `*.corp.local` and `db.internal`
+  /// are in 10/8, everything else is a documentation address.
+  fn stub_resolve(host: &str) -> Option<String> {
+    match host {
+      "nowhere.invalid" => None,
+      h if h.ends_with(".corp.local") || h == "db.internal" => Some("10.4.5.6".to_string()),
+      _ => Some("203.0.113.7".to_string()),
+    }
+  }
+
+  #[test]
+  fn answers_are_parsed_into_proxy_urls() {
+    assert_eq!(
+      parse_result("PROXY a:3128; socks b:1080;SOCKS5 c:1080 ; DIRECT"),
+      vec![
+        PacEntry::Proxy("http://a:3128".to_string()),
+        PacEntry::Proxy("socks4://b:1080".to_string()),
+        PacEntry::Proxy("socks5h://c:1080".to_string()),
+        PacEntry::Direct,
+      ]
+    );
+    assert_eq!(parse_result(""), vec![PacEntry::Direct]);
+    assert_eq!(parse_result("QUIC q:443"), vec![PacEntry::Direct]);
+  }
+
+  #[test]
+  fn pac_helpers_drive_the_answer() {
+    let eval = |url: &str, host: &str| evaluate_with(SCRIPT, url, host, stub_resolve).unwrap();
+    assert_eq!(eval("http://intranet/", "intranet"), "DIRECT");
+    assert_eq!(eval("http://db.internal/", "db.internal"), "DIRECT");
+    assert_eq!(eval("http://wiki.corp.local/", "wiki.corp.local"), "DIRECT");
+    assert_eq!(eval("http://10.1.2.3/", "10.1.2.3"), "DIRECT");
+    assert_eq!(eval("http://cdn.video.example/", "cdn.video.example"), "SOCKS5 socks.corp:1080");
+    assert_eq!(eval("https://example.com:443/", "example.com"), "HTTPS secure.corp:443; DIRECT");
+    assert_eq!(eval("http://example.com:80/", "example.com"), "PROXY proxy.corp:3128; DIRECT");
+    let always = "function FindProxyForURL(u, h) {
+      return weekdayRange('SUN', 'SAT') && timeRange(0, 24) && dateRange(1, 31) ? 'DIRECT' : 'PROXY x:1';
+    }";
+    assert_eq!(evaluate_with(always, "http://a/", "a", stub_resolve).unwrap(), "DIRECT");
+    let resolvable = "function FindProxyForURL(u, h) { return isResolvable(h) ? dnsResolve(h) : 'DIRECT'; }";
+    assert_eq!(evaluate_with(resolvable, "http://a.example/", "a.example", stub_resolve).unwrap(), "203.0.113.7");
+    assert_eq!(evaluate_with(resolvable, "http://nowhere.invalid/", "nowhere.invalid", stub_resolve).unwrap(), "DIRECT");
+  }
+
+  #[test]
+  fn broken_and_runaway_scripts_fail_cleanly() {
+    assert!(evaluate("function FindProxyForURL(", "http://a/", "a").is_err());
+    assert!(evaluate("var x = 1;", "http://a/", "a").unwrap_err().to_string().contains("FindProxyForURL"));
+    let err = evaluate("function FindProxyForURL(u, h) { while (true) {} }", "http://a/", "a").unwrap_err();
+    assert!(err.to_string().contains("PAC script error"), "{err:#}");
+  }
+
+  #[tokio::test]
+  async fn decisions_are_cached_per_origin() {
+    let hits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let hits2 = hits.clone();
+    let app = Router::new().route(
+      "/proxy.pac",
+      get(move || {
+        hits2.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        async { SCRIPT }
+      }),
+    );
+    let base = crate::transport::tests::serve(app).await;
+
+    let resolver = PacResolver { resolve: stub_resolve, ..PacResolver::default() };
+    let client = reqwest::Client::new();
+    let source = format!("{base}/proxy.pac");
+    let a = resolver.decide(&client, &source, &Url::parse("http://example.com/a.iso").unwrap()).await.unwrap();
+    let b = resolver.decide(&client, &source, &Url::parse("http://example.com/b.iso").unwrap()).await.unwrap();
+    assert_eq!(a.entries[0], PacEntry::Proxy("http://proxy.corp:3128".to_string()));
+    assert_eq!(b.answer, a.answer);
+    assert_eq!(resolver.decisions.len(), 1);
+    assert_eq!(hits.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    let path = std::env::temp_dir().join(format!("zdmr-{}.pac", uuid::Uuid::new_v4()));
+    std::fs::write(&path, "function FindProxyForURL(u, h) { return 'DIRECT'; }").unwrap();
+    let c = resolver
+      .decide(&client, path.to_str().unwrap(), &Url::parse("http://example.com/a.iso").unwrap())
+      .await
+      .unwrap();
+    assert_eq!(c.entries, vec![PacEntry::Direct]);
+    let _ = std::fs::remove_file(path);
+  }
+}
@@ -0,0 +1,356 @@
+//! Rule patterns: which URLs a proxy/header/mirror/segment/transport rule applies to.
+//!
+//! Structured patterns, `[scheme://]host[:port][/path-prefix]`, where host is one of:
+//! - `example.com` — exactly that host (IDN names may be written in Unicode or punycode)
+//! - `*.example.com` — the host and any subdomain
+//! - `*` — every host
+//! - `10.0.0.0/8`, `[fd00::]/8` — IP-address hosts inside the range (names are not resolved)
+//!
+//! e.g. `https://*.example.com/releases/`, `*:8443`, `10.0.0.0/8:22`, `example.com/api`.
+//!
+//! Anything else with `*`, `?`, `[..]` or `{a,b}` is a glob over the whole URL (`*` crosses
+//! `/`; without a scheme any scheme matches, without a path any path does), e.g.
+//! `https://cdn?.example.com/*.iso`. `re:<regex>` is a regex searched in the whole URL.
+//!
+//! When several patterns match, the most specific one wins:
+//! 1. regexes, then globs (more literal characters first), then structured patterns;
+//! 2. structured: exact host > IP range (longer prefix first) > `*.suffix` (longer first) > `*`;
+//! 3. then the longer path prefix, then having a scheme, then having a port;
+//! 4. remaining ties go to the rule listed first.
+
+use anyhow::{anyhow, bail, Context};
+use globset::{GlobBuilder, GlobMatcher};
+use parking_lot::Mutex;
+use regex::Regex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, OnceLock};
+use url::{Host, Position, Url};
+
+const GLOB_META: &[char] = &['*', '?', '[', ']', '{', '}'];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostMatch {
+  Any,
+  Exact(String),
+  /// `*.suffix`: the suffix itself or any subdomain.
+  Suffix(String),
+  Cidr(IpAddr, u8),
+}
+
+#[derive(Debug, Clone)]
+enum Kind {
+  Regex(Regex),
+  Glob { matcher: GlobMatcher, literals: usize },
+  Parts {
+    scheme: Option<String>,
+    host: HostMatch,
+    port: Option<u16>,
+    path: Option<String>,
+  },
+}
+
+#[derive(Debug, Clone)]
+pub struct Pattern {
+  kind: Kind,
+}
+
+/// Ordering key for `best_pattern_match`; greater is more specific (see the module docs).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Specificity {
+  kind: u8,
+  host: u8,
+  len: usize,
+  path_len: usize,
+  scheme: bool,
+  port: bool,
+}
+
+impl Pattern {
+  pub fn parse(raw: &str) -> anyhow::Result<Self> {
+    let raw = raw.trim();
+    if raw.is_empty() {
+      bail!("pattern is empty");
+    }
+    if let Some(re) = raw.strip_prefix("re:") {
+      return Ok(Self { kind: Kind::Regex(Regex::new(re)?) });
+    }
+    let (scheme, rest) = match raw.split_once("://") {
+      Some((s, r)) => (Some(s.to_ascii_lowercase()), r),
+      None => (None, raw),
+    };
+    let (authority, path) = split_path(rest);
+    let (host, port) = split_port(authority);
+    let bracketed = host.starts_with('[');
+    let host_has_meta = !bracketed && host.trim_start_matches("*.").contains(GLOB_META) && host != "*";
+    if scheme.as_deref().is_some_and(|s| s.contains(GLOB_META)) || path.is_some_and(|p| p.contains(GLOB_META)) || host_has_meta {
+      return Self::glob(scheme.as_deref(), authority, path);
+    }
+
+    let host = host.trim_start_matches('[').replace(']', "");
+    let host = if host == "*" {
+      HostMatch::Any
+    } else if let Some(suffix) = host.strip_prefix("*.") {
+      HostMatch::Suffix(normalize_domain(suffix))
+    } else if let Some((addr, len)) = host.split_once('/') {
+      let addr: IpAddr = addr.parse().with_context(|| format!("{addr} is not an IP address"))?;
+      let max = if addr.is_ipv4() { 32 } else { 128 };
+      let len: u8 = len.parse().ok().filter(|l| *l <= max).ok_or_else(|| anyhow!("bad prefix length /{len}"))?;
+      HostMatch::Cidr(addr, len)
+    } else if host.is_empty() {
+      bail!("pattern has no host");
+    } else {
+      HostMatch::Exact(normalize_domain(&host))
+    };
+    let path = match path {
+      // Normalized the way URL paths are, so `/my files/` matches `/my%20files/x`.
+      Some(p) => Some(Url::parse(&format!("http://h{p}"))?.path().to_string()),
+      None => None,
+    };
+    Ok(Self {
+      kind: Kind::Parts { scheme, host, port, path },
+    })
+  }
+
+  fn glob(scheme: Option<&str>, authority: &str, path: Option<&str>) -> anyhow::Result<Self> {
+    let authority: Vec<String> = authority
+      .split('.')
+      .map(|label| if label.contains(GLOB_META) || label.contains(':') { label.to_ascii_lowercase() } else { normalize_domain(label) })
+      .collect();
+    let glob = format!("{}://{}{}", scheme.unwrap_or("*"), authority.join("."), path.unwrap_or("/*"));
+    let matcher = GlobBuilder::new(&glob).literal_separator(false).build()?.compile_matcher();
+    let literals = glob.chars().filter(|c| !GLOB_META.contains(c)).count();
+    Ok(Self {
+      kind: Kind::Glob { matcher, literals },
+    })
+  }
+
+  pub fn matches(&self, url: &Url) -> bool {
+    let (scheme, host, port, path) = match &self.kind {
+      Kind::Regex(re) => return re.is_match(&url[..Position::AfterQuery]),
+      Kind::Glob { matcher, .. } => return matcher.is_match(&url[..Position::AfterQuery]),
+      Kind::Parts { scheme, host, port, path } => (scheme, host, port, path),
+    };
+    if scheme.as_deref().is_some_and(|s| s != url.scheme()) {
+      return false;
+    }
+    if port.is_some_and(|p| url.port_or_known_default() != Some(p)) {
+      return false;
+    }
+    if path.as_deref().is_some_and(|p| !url.path().starts_with(p)) {
+      return false;
+    }
+    let Some(url_host) = url.host() else { return false };
+    // Non-special schemes (sftp://, s3://) leave IP addresses and IDNs as opaque names.
+    let (ip, name) = match url_host {
+      Host::Ipv4(ip) => (Some(IpAddr::V4(ip)), None),
+      Host::Ipv6(ip) => (Some(IpAddr::V6(ip)), None),
+      Host::Domain(d) => match d.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => (Some(ip), None),
+        Err(_) => (None, Some(normalize_domain(d))),
+      },
+    };
+    match host {
+      HostMatch::Any => true,
+      HostMatch::Exact(h) => match (ip, h.parse::<IpAddr>()) {
+        (Some(ip), Ok(p)) => ip == p,
+        _ => name.as_deref() == Some(h.as_str()),
+      },
+      HostMatch::Suffix(suffix) => name.is_some_and(|d| d == *suffix || d.ends_with(&format!(".{suffix}"))),
+      HostMatch::Cidr(net, len) => ip.is_some_and(|ip| in_cidr(ip, *net, *len)),
+    }
+  }
+
+  /// A structured pattern naming no particular host (`*`, `https://*`, `*:8443`), which a
+  /// setting meant as a per-host exception must not use.
+  pub fn is_any_host(&self) -> bool {
+    matches!(&self.kind, Kind::Parts { host: HostMatch::Any, .. })
+  }
+
+  pub fn specificity(&self) -> Specificity {
+    let mut s = Specificity {
+      kind: 0,
+      host: 0,
+      len: 0,
+      path_len: 0,
+      scheme: false,
+      port: false,
+    };
+    match &self.kind {
+      Kind::Regex(_) => s.kind = 2,
+      Kind::Glob { literals, .. } => {
+        s.kind = 1;
+        s.len = *literals;
+      }
+      Kind::Parts { scheme, host, port, path } => {
+        (s.host, s.len) = match host {
+          HostMatch::Exact(h) => (3, h.len()),
+          HostMatch::Cidr(_, len) => (2, *len as usize),
+          HostMatch::Suffix(h) => (1, h.len()),
+          HostMatch::Any => (0, 0),
+        };
+        s.path_len = path.as_ref().map_or(0, |p| p.len());
+        s.scheme = scheme.is_some();
+        s.port = port.is_some();
+      }
+    }
+    s
+  }
+}
+
+/// Parsed patterns by source text; rules are matched on every request, and regexes and globs
+/// are not free to compile. Invalid patterns are cached as `None` and never match.
+pub fn cached(raw: &str) -> Option<Arc<Pattern>> {
+  static CACHE: OnceLock<Mutex<HashMap<String, Option<Arc<Pattern>>>>> = OnceLock::new();
+  let mut cache = CACHE.get_or_init(Default::default).lock();
+  if let Some(p) = cache.get(raw) {
+    return p.clone();
+  }
+  if cache.len() >= 1024 {
+    cache.clear();
+  }
+  let p = Pattern::parse(raw).ok().map(Arc::new);
+  cache.insert(raw.to_string(), p.clone());
+  p
+}
+
+/// Lowercase punycode, so `Bücher.example` and `xn--bcher-kva.example` compare equal.
+fn normalize_domain(d: &str) -> String {
+  let d = urlencoding::decode(d).map(|d| d.into_owned()).unwrap_or_else(|_| d.to_string());
+  if d.is_ascii() {
+    return d.to_ascii_lowercase();
+  }
+  match Host::parse(&d) {
+    Ok(Host::Domain(ascii)) => ascii,
+    _ => d.to_lowercase(),
+  }
+}
+
+/// `host:port/path` → (`host:port`, `/path`). A `/` right after an IP address followed by
+/// digits is a CIDR prefix length, not a path: `10.0.0.0/8:22/x` → (`10.0.0.0/8:22`, `/x`).
+fn split_path(rest: &str) -> (&str, Option<&str>) {
+  let Some(slash) = rest.find('/') else { return (rest, None) };
+  let before = rest[..slash].trim_start_matches('[').trim_end_matches(']');
+  let after = &rest[slash + 1..];
+  let digits = after.bytes().take_while(u8::is_ascii_digit).count();
+  let mut end = slash;
+  if digits > 0 && before.parse::<IpAddr>().is_ok() {
+    end = slash + 1 + digits;
+    if let Some(port) = rest[end..].strip_prefix(':') {
+      end += 1 + port.bytes().take_while(u8::is_ascii_digit).count();
+    }
+  }
+  match &rest[end..] {
+    "" => (rest, None),
+    path => (&rest[..end], Some(path)),
+  }
+}
+
+/// `host:443` → (`host`, 443). A bare IPv6 address has colons of its own, so it only takes a
+/// port when bracketed.
+fn split_port(p: &str) -> (&str, Option<u16>) {
+  if let Some((host, port)) = p.rsplit_once(':') {
+    let bracketed = host.starts_with('[') && (host.ends_with(']') || host.contains("]/"));
+    if (bracketed || !host.contains(':')) && !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) {
+      if let Ok(port) = port.parse() {
+        return (host, Some(port));
+      }
+    }
+  }
+  (p, None)
+}
+
+fn in_cidr(ip: IpAddr, net: IpAddr, len: u8) -> bool {
+  match (ip, net) {
+    (IpAddr::V4(ip), IpAddr::V4(net)) => {
+      let mask = if len == 0 { 0 } else { u32::MAX << (32 - len as u32) };
+      u32::from(ip) & mask == u32::from(net) & mask
+    }
+    (IpAddr::V6(ip), IpAddr::V6(net)) => {
+      let mask = if len == 0 { 0 } else { u128::MAX << (128 - len as u32) };
+      u128::from(ip) & mask == u128::from(net) & mask
+    }
+    _ => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn m(pattern: &str, url: &str) -> bool {
+    Pattern::parse(pattern).unwrap().matches(&Url::parse(url).unwrap())
+  }
+
+  #[test]
+  fn hosts_wildcards_and_ports() {
+    assert!(m("Example.com", "http://example.COM/x"));
+    assert!(!m("example.com", "http://a.example.com/"));
+    assert!(m("*.example.com", "http://example.com/"));
+    assert!(m("*.example.com", "http://a.b.example.com/"));
+    assert!(!m("*.example.com", "http://badexample.com/"));
+    assert!(m("*.example.com:8443", "https://a.example.com:8443/"));
+    assert!(!m("*.example.com:8443", "https://a.example.com/"));
+    assert!(m("*:443", "https://anything.org/"));
+    assert!(m("*", "ftp://h/"));
+    assert!(Pattern::parse("").is_err());
+  }
+
+  #[test]
+  fn cidr_ranges_match_ip_hosts_only() {
+    assert!(m("10.0.0.0/8", "http://10.20.30.40/"));
+    assert!(!m("10.0.0.0/8", "http://11.0.0.1/"));
+    assert!(!m("10.0.0.0/8", "http://ten.corp/"));
+    assert!(m("192.168.1.0/24:22", "sftp://192.168.1.9:22/"));
+    assert!(m("fd00::/8", "http://[fd12::1]/"));
+    assert!(m("[fd00::]/8:443", "https://[fd12::1]/"));
+    assert!(!m("[fd00::]/8:443", "http://[fd12::1]/"));
+    assert!(m("::1", "http://[::1]:8080/"));
+    assert!(m("0.0.0.0/0", "http://1.2.3.4/"));
+    assert!(m("10.0.0.0/8:80/dl/", "http://10.1.1.1/dl/a"));
+    assert!(Pattern::parse("10.0.0.0/33").is_err());
+  }
+
+  #[test]
+  fn schemes_paths_and_idn() {
+    assert!(m("https://*.example.com", "https://a.example.com/x"));
+    assert!(!m("https://*.example.com", "http://a.example.com/x"));
+    assert!(m("example.com/releases/", "https://example.com/releases/v1.zip"));
+    assert!(!m("example.com/releases/", "https://example.com/nightly/v1.zip"));
+    assert!(m("example.com/my files/", "https://example.com/my%20files/a"));
+    assert!(m("bücher.example", "https://xn--bcher-kva.example/"));
+    assert!(m("*.XN--BCHER-KVA.example", "https://www.bücher.example/"));
+    assert!(m("https://bücher.example/*.pdf", "https://bücher.example/a/b.pdf"));
+  }
+
+  #[test]
+  fn globs_and_regexes_see_the_whole_url() {
+    assert!(m("https://cdn?.example.com/*.iso", "https://cdn2.example.com/os/x.iso"));
+    assert!(!m("https://cdn?.example.com/*.iso", "https://cdn2.example.com/os/x.img"));
+    assert!(m("*.example.com/*.iso", "ftp://a.example.com/x.iso"));
+    assert!(m("mirror-[0-9].example.org", "http://mirror-3.example.org/any/path?q=1"));
+    assert!(m("*://{a,b}.example.org:8080/*", "http://b.example.org:8080/x"));
+    assert!(m(r"re:^https://[^/]+/v\d+/.*\.tar\.gz$", "https://dl.example.org/v2/pkg.tar.gz"));
+    assert!(!m(r"re:^https://[^/]+/v\d+/", "https://dl.example.org/latest/pkg.tar.gz"));
+    assert!(m("re:token=", "https://a.example/x?token=1"));
+    assert!(Pattern::parse("re:(").is_err());
+    assert!(Pattern::parse("https://example.com/[a-").is_err());
+  }
+
+  #[test]
+  fn specificity_orders_patterns() {
+    let s = |p: &str| Pattern::parse(p).unwrap().specificity();
+    assert!(s("re:.") > s("https://a.example.com/*.iso"));
+    assert!(s("https://a.example.com/*.iso") > s("*/*.iso"));
+    assert!(s("*/*.iso") > s("https://a.example.com:8443/releases/"));
+    assert!(s("a.example.com") > s("10.0.0.0/8"));
+    assert!(s("10.1.0.0/16") > s("10.0.0.0/8"));
+    assert!(s("10.0.0.0/8") > s("*.a.example.com"));
+    assert!(s("*.a.example.com") > s("*.example.com"));
+    assert!(s("*.example.com") > s("*"));
+    assert!(s("example.com/a/b") > s("example.com/a"));
+    assert!(s("example.com/a") > s("https://example.com"));
+    assert!(s("https://example.com") > s("example.com:443"));
+    assert!(s("example.com:8443") > s("example.com"));
+  }
+}
@@ -0,0 +1,303 @@
+//! Proxy pools: named sets of proxies, referenced as `pool://<name>` wherever a proxy URL is
+//! accepted. Health is tracked per member URL (shared between pools) from periodic checks and
+//! from real connection failures.
+
+use super::proxy::{self, ProxyConfig};
+use super::{ClientProfile, Transport};
+use crate::model::{ProxyCredentials, ProxyPool};
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+use url::Url;
+
+pub const POOL_SCHEME: &str = "pool://";
+/// A member that failed is skipped for this long unless a health check clears it sooner.
+const DOWN_COOLDOWN: Duration = Duration::from_secs(60);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `pool://corp` → `corp`.
+pub fn pool_name(proxy_url: &str) -> Option<&str> {
+  let name = proxy_url.trim().strip_prefix(POOL_SCHEME)?.trim_end_matches('/');
+  (!name.is_empty()).then_some(name)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PoolStrategy {
+  #[default]
+  RoundRobin,
+  LeastLatency,
+}
+
+impl PoolStrategy {
+  pub fn parse(v: &str) -> Option<Self> {
+    match v.trim().to_ascii_lowercase().as_str() {
+      "round_robin" | "roundrobin" => Some(PoolStrategy::RoundRobin),
+      "least_latency" | "latency" => Some(PoolStrategy::LeastLatency),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+struct MemberHealth {
+  latency: Option<Duration>,
+  down_since: Option<Instant>,
+  last_error: Option<String>,
+  checked: bool,
+}
+
+/// One member as shown in the UI; `healthy` is `None` until it has been checked or used.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct MemberStatus {
+  pub proxy_url: String,
+  pub healthy: Option<bool>,
+  pub latency_ms: Option<u64>,
+  pub last_error: Option<String>,
+}
+
+#[derive(Default)]
+pub struct ProxyPools {
+  health: DashMap<String, MemberHealth>,
+  cursors: DashMap<i64, usize>,
+  last_check: DashMap<i64, Instant>,
+  /// The pools as last read from the database, for the periodic health checks; dropped
+  /// whenever a pool is saved or deleted.
+  configured: Mutex<Option<Vec<ProxyPool>>>,
+}
+
+impl ProxyPools {
+  /// Members to try, in order: usable ones by the pool's strategy, then DIRECT (`None`) if the
+  /// pool allows it. With every member down and no DIRECT fallback, the down members are tried
+  /// anyway rather than failing without a connection attempt.
+  pub fn candidates(&self, pool: &ProxyPool) -> Vec<Option<String>> {
+    let members = pool.members.iter().map(|m| m.trim()).filter(|m| !m.is_empty());
+    let (mut usable, down): (Vec<&str>, Vec<&str>) = members.partition(|m| self.usable(m));
+    match PoolStrategy::parse(&pool.strategy).unwrap_or_default() {
+      PoolStrategy::RoundRobin if !usable.is_empty() => {
+        let mut cursor = self.cursors.entry(pool.id).or_insert(0);
+        let start = *cursor % usable.len();
+        *cursor = cursor.wrapping_add(1);
+        usable.rotate_left(start);
+      }
+      PoolStrategy::RoundRobin => {}
+      PoolStrategy::LeastLatency => {
+        // Stable: unmeasured members keep their configured order, after measured ones.
+        usable.sort_by_key(|m| self.health.get(*m).and_then(|h| h.latency).unwrap_or(Duration::MAX));
+      }
+    }
+    let mut out: Vec<Option<String>> = usable.into_iter().map(|m| Some(m.to_string())).collect();
+    if pool.allow_direct {
+      out.push(None);
+    } else if out.is_empty() {
+      out.extend(down.into_iter().map(|m| Some(m.to_string())));
+    }
+    out
+  }
+
+  pub fn mark_down(&self, member: &str, error: &str) {
+    let mut h = self.health.entry(member.trim().to_string()).or_default();
+    h.down_since = Some(Instant::now());
+    h.last_error = Some(error.to_string());
+    h.checked = true;
+  }
+
+  /// `latency` is only updated when measured (health checks); a successful download keeps it.
+  pub fn mark_up(&self, member: &str, latency: Option<Duration>) {
+    let mut h = self.health.entry(member.trim().to_string()).or_default();
+    h.down_since = None;
+    h.last_error = None;
+    h.checked = true;
+    if latency.is_some() {
+      h.latency = latency;
+    }
+  }
+
+  pub fn status(&self, pool: &ProxyPool) -> Vec<MemberStatus> {
+    pool
+      .members
+      .iter()
+      .map(|m| {
+        let h = self.health.get(m.trim()).map(|h| h.clone()).unwrap_or_default();
+        MemberStatus {
+          proxy_url: m.clone(),
+          healthy: h.checked.then_some(h.down_since.is_none()),
+          latency_ms: h.latency.map(|l| l.as_millis() as u64),
+          last_error: h.last_error,
+        }
+      })
+      .collect()
+  }
+
+  pub fn configured(&self) -> Option<Vec<ProxyPool>> {
+    self.configured.lock().clone()
+  }
+
+  pub fn set_configured(&self, pools: Vec<ProxyPool>) {
+    *self.configured.lock() = Some(pools);
+  }
+
+  pub fn forget_configured(&self) {
+    *self.configured.lock() = None;
+  }
+
+  fn usable(&self, member: &str) -> bool {
+    self
+      .health
+      .get(member)
+      .and_then(|h| h.down_since)
+      .map_or(true, |since| since.elapsed() >= DOWN_COOLDOWN)
+  }
+}
+
+impl Transport {
+  pub fn pools(&self) -> &ProxyPools {
+    &self.pools
+  }
+
+  /// Checks every member of `pool` now. `creds` are the stored proxy credentials.
+  pub async fn check_pool(&self, pool: &ProxyPool, creds: &[ProxyCredentials]) -> Vec<MemberStatus> {
+    self.pools.last_check.insert(pool.id, Instant::now());
+    let checks = pool.members.iter().map(|m| async move {
+      let started = Instant::now();
+      match self.check_member(pool, m, creds).await {
+        Ok(()) => self.pools.mark_up(m, Some(started.elapsed())),
+        Err(e) => self.pools.mark_down(m, &format!("{e:#}")),
+      }
+    });
+    futures_util::future::join_all(checks).await;
+    self.pools.status(pool)
+  }
+
+  /// Checks the enabled pools whose interval has elapsed.
+  pub async fn check_due_pools(&self, pools: &[ProxyPool], creds: &[ProxyCredentials]) {
+    for pool in pools.iter().filter(|p| p.enabled && p.health_check_interval_secs > 0) {
+      let interval = Duration::from_secs(pool.health_check_interval_secs as u64);
+      let due = self.pools.last_check.get(&pool.id).map_or(true, |t| t.elapsed() >= interval);
+      if due {
+        self.check_pool(pool, creds).await;
+      }
+    }
+  }
+
+  async fn check_member(&self, pool: &ProxyPool, member: &str, creds: &[ProxyCredentials]) -> anyhow::Result<()> {
+    let cfg = ProxyConfig::parse(member)?;
+    let stored = creds.iter().find(|c| c.proxy_url == cfg.key()).cloned();
+    let cfg = cfg.with_credentials(stored);
+    let Some(target) = pool.health_check_url.as_deref().filter(|u| !u.trim().is_empty()) else {
+      // No URL to fetch: a TCP connect to the proxy itself.
+      let u = Url::parse(&cfg.url)?;
+      let host = u.host_str().ok_or_else(|| anyhow::anyhow!("proxy url has no host"))?;
+      let port = u.port().or_else(|| proxy::default_port(u.scheme())).unwrap_or(1080);
+      tokio::time::timeout(CHECK_TIMEOUT, tokio::net::TcpStream::connect((host, port)))
+        .await
+        .map_err(|_| anyhow::anyhow!("connect timed out"))??;
+      return Ok(());
+    };
+    let mut profile = ClientProfile::default().with_proxy(Some(cfg));
+    profile.timeouts.connect = CHECK_TIMEOUT;
+    profile.timeouts.response = CHECK_TIMEOUT;
+    let client = self.client_for(&profile)?;
+    let resp = Transport::send(client.get(target.trim()), &profile.timeouts).await?;
+    let status = resp.status();
+    if !(status.is_success() || status.is_redirection()) {
+      anyhow::bail!("health check got HTTP {}", status.as_u16());
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transport::proxy::stand_in;
+
+  fn pool(strategy: &str, allow_direct: bool, members: &[&str]) -> ProxyPool {
+    ProxyPool {
+      id: 7,
+      name: "corp".to_string(),
+      enabled: true,
+      strategy: strategy.to_string(),
+      allow_direct,
+      rotate_per_segment: false,
+      health_check_url: None,
+      health_check_interval_secs: 60,
+      members: members.iter().map(|m| m.to_string()).collect(),
+    }
+  }
+
+  fn firsts(pools: &ProxyPools, p: &ProxyPool, n: usize) -> Vec<Option<String>> {
+    (0..n).map(|_| pools.candidates(p)[0].clone()).collect()
+  }
+
+  #[test]
+  fn pool_urls_name_pools() {
+    assert_eq!(pool_name("pool://corp/"), Some("corp"));
+    assert_eq!(pool_name("pool://"), None);
+    assert_eq!(pool_name("http://corp"), None);
+  }
+
+  #[test]
+  fn round_robin_rotates_and_skips_down_members() {
+    let pools = ProxyPools::default();
+    let p = pool("round_robin", false, &["http://a:1", "http://b:1", "http://c:1"]);
+    let s = |v: &str| Some(v.to_string());
+    assert_eq!(firsts(&pools, &p, 4), vec![s("http://a:1"), s("http://b:1"), s("http://c:1"), s("http://a:1")]);
+
+    pools.mark_down("http://b:1", "connection refused");
+    let order = pools.candidates(&p);
+    assert_eq!(order.len(), 2);
+    assert!(!order.contains(&s("http://b:1")));
+    assert_eq!(pools.status(&p)[1].healthy, Some(false));
+    assert_eq!(pools.status(&p)[0].healthy, None);
+
+    pools.mark_down("http://a:1", "x");
+    pools.mark_down("http://c:1", "x");
+    // Nothing usable and no DIRECT: try everyone rather than nothing.
+    assert_eq!(pools.candidates(&p).len(), 3);
+    let direct = pool("round_robin", true, &["http://a:1", "http://b:1", "http://c:1"]);
+    assert_eq!(pools.candidates(&direct), vec![None]);
+  }
+
+  #[test]
+  fn least_latency_prefers_the_fastest_measured_member() {
+    let pools = ProxyPools::default();
+    let p = pool("least_latency", true, &["http://slow:1", "http://new:1", "http://fast:1"]);
+    pools.mark_up("http://slow:1", Some(Duration::from_millis(300)));
+    pools.mark_up("http://fast:1", Some(Duration::from_millis(20)));
+    let order = pools.candidates(&p);
+    assert_eq!(
+      order,
+      vec![Some("http://fast:1".to_string()), Some("http://slow:1".to_string()), Some("http://new:1".to_string()), None]
+    );
+    // A later success without a measurement keeps the known latency.
+    pools.mark_up("http://fast:1", None);
+    assert_eq!(pools.status(&p)[2].latency_ms, Some(20));
+  }
+
+  #[tokio::test]
+  async fn health_checks_mark_members() {
+    let (live, _) = stand_in::socks5(None).await;
+    let dead = {
+      let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+      l.local_addr().unwrap().port()
+    };
+    let t = Transport::new().unwrap();
+    let p = pool("round_robin", false, &[&format!("socks5://127.0.0.1:{live}"), &format!("socks5://127.0.0.1:{dead}")]);
+    let status = t.check_pool(&p, &[]).await;
+    assert_eq!(status[0].healthy, Some(true), "{status:?}");
+    assert_eq!(status[1].healthy, Some(false), "{status:?}");
+    assert_eq!(t.pools().candidates(&p), vec![Some(p.members[0].clone())]);
+
+    // With a URL, the check goes through the proxy; the stand-in HTTP proxy wants credentials.
+    let http = stand_in::http_proxy("Basic dXNlcjpwdw==").await;
+    let mut p = pool("round_robin", false, &[&format!("http://127.0.0.1:{http}")]);
+    p.health_check_url = Some("http://example.invalid/generate_204".to_string());
+    assert_eq!(t.check_pool(&p, &[]).await[0].healthy, Some(false));
+    let creds = ProxyCredentials {
+      proxy_url: format!("http://127.0.0.1:{http}"),
+      username: "user".to_string(),
+      password: Some("pw".to_string()),
+    };
+    assert_eq!(t.check_pool(&p, &[creds]).await[0].healthy, Some(true));
+  }
+}
@@ -0,0 +1,239 @@
+//! Transport profiles: everything reqwest fixes when a client is built, resolved per host from
+//! the global settings and the most specific transport rule.
+
+use super::cookies::CookieJar;
+use super::dns::{DnsCache, DnsOptions, ProfileResolver};
+use super::proxy::ProxyConfig;
+use super::tls::{self, TlsOptions};
+use crate::model::{DEFAULT_CONNECT_TIMEOUT_SECS, DEFAULT_READ_IDLE_TIMEOUT_SECS, DEFAULT_RESPONSE_TIMEOUT_SECS};
+use anyhow::Context;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT_ENCODING};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEFAULT_USER_AGENT: &str = "Z-DMR/0.1";
+pub const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+/// There is deliberately no whole-request timeout: a healthy body may stream for hours.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Timeouts {
+  pub connect: Duration,
+  /// From sending the request until response headers arrive (includes connecting).
+  pub response: Duration,
+  /// Longest gap between two chunks of the body; enforced by the download loops' stall
+  /// watchdog rather than by reqwest, whose read timeout would also cut the header wait short.
+  pub read_idle: Duration,
+}
+
+impl Default for Timeouts {
+  fn default() -> Self {
+    Self {
+      connect: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS as u64),
+      response: Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS as u64),
+      read_idle: Duration::from_secs(DEFAULT_READ_IDLE_TIMEOUT_SECS as u64),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum HttpVersion {
+  /// HTTP/1.1 only: one connection per segment, which is what multipart relies on.
+  #[default]
+  Http1,
+  /// Offer `h2` via ALPN and use it when the server agrees.
+  Http2,
+}
+
+impl HttpVersion {
+  pub fn parse(v: &str) -> Option<Self> {
+    match v.trim().to_ascii_lowercase().as_str() {
+      "http1" | "http/1.1" | "1.1" => Some(HttpVersion::Http1),
+      "http2" | "h2" | "2" => Some(HttpVersion::Http2),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum IpPreference {
+  /// Whatever order the system resolver returns.
+  #[default]
+  Auto,
+  Ipv4,
+  Ipv6,
+}
+
+impl IpPreference {
+  pub fn parse(v: &str) -> Option<Self> {
+    match v.trim().to_ascii_lowercase().as_str() {
+      "ipv4" | "v4" | "4" => Some(IpPreference::Ipv4),
+      "ipv6" | "v6" | "6" => Some(IpPreference::Ipv6),
+      "auto" | "" => Some(IpPreference::Auto),
+      _ => None,
+    }
+  }
+}
+
+/// Where outgoing connections originate, for multi-homed machines.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LocalAddress {
+  /// Bind to this source address; only connections of the same IP family are bound.
+  Ip(IpAddr),
+  /// Bind to a network interface by name (`SO_BINDTODEVICE`), where the OS supports it.
+  Interface(String),
+}
+
+impl LocalAddress {
+  /// Addresses or interface names separated by commas or whitespace, e.g. `192.168.1.20, eth1`.
+  pub fn parse_list(text: &str) -> anyhow::Result<Vec<Self>> {
+    let mut out = Vec::new();
+    for item in text.split(|c: char| c == ',' || c.is_whitespace()).filter(|s| !s.is_empty()) {
+      let item = item.trim_start_matches('[').trim_end_matches(']');
+      let local = match item.parse::<IpAddr>() {
+        Ok(ip) => LocalAddress::Ip(ip),
+        Err(_) if item.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b)) => {
+          LocalAddress::Interface(item.to_string())
+        }
+        Err(_) => anyhow::bail!("{item:?} is neither an IP address nor an interface name"),
+      };
+      if !out.contains(&local) {
+        out.push(local);
+      }
+    }
+    Ok(out)
+  }
+}
+
+/// Client-level behavior chosen per host by transport rules. `Transport` caches one client per
+/// distinct profile, proxy included, since reqwest fixes all of this at build time.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClientProfile {
+  pub proxy: Option<ProxyConfig>,
+  pub user_agent: String,
+  pub http_version: HttpVersion,
+  pub max_redirects: usize,
+  /// Refuse redirects that leave the original host (e.g. a download page bouncing to a tracker).
+  pub block_cross_host_redirects: bool,
+  /// Overrides, DoH and address-family preference.
+  pub dns: DnsOptions,
+  /// Let reqwest undo `Content-Encoding`. Off by default: downloads are byte-exact.
+  pub decode_content: bool,
+  pub timeouts: Timeouts,
+  pub tls: TlsOptions,
+  pub local_address: Option<LocalAddress>,
+}
+
+impl Default for ClientProfile {
+  fn default() -> Self {
+    Self {
+      proxy: None,
+      user_agent: DEFAULT_USER_AGENT.to_string(),
+      http_version: HttpVersion::default(),
+      max_redirects: DEFAULT_MAX_REDIRECTS,
+      block_cross_host_redirects: false,
+      dns: DnsOptions::default(),
+      decode_content: false,
+      timeouts: Timeouts::default(),
+      tls: TlsOptions::default(),
+      local_address: None,
+    }
+  }
+}
+
+impl ClientProfile {
+  pub fn with_proxy(mut self, proxy: Option<ProxyConfig>) -> Self {
+    self.proxy = proxy;
+    self
+  }
+
+  pub fn with_local_address(mut self, local_address: Option<LocalAddress>) -> Self {
+    self.local_address = local_address;
+    self
+  }
+}
+
+pub(super) fn build_client(
+  profile: &ClientProfile,
+  cookies: &Arc<CookieJar>,
+  dns: &Arc<DnsCache>,
+) -> anyhow::Result<reqwest::Client> {
+  let mut b = reqwest::Client::builder()
+    .user_agent(profile.user_agent.as_str())
+    .cookie_provider(cookies.clone())
+    .redirect(redirect_policy(profile.max_redirects, profile.block_cross_host_redirects))
+    .connect_timeout(profile.timeouts.connect);
+  if !profile.decode_content {
+    // A `.tar.gz` served with `Content-Encoding: gzip` must land on disk as the .tar.gz,
+    // with Content-Length and range offsets referring to the bytes we write.
+    let mut defaults = HeaderMap::new();
+    defaults.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+    b = b.no_gzip().no_brotli().no_deflate().default_headers(defaults);
+  }
+  if profile.http_version == HttpVersion::Http1 {
+    b = b.http1_only();
+  }
+  if !profile.dns.is_default() {
+    b = b.dns_resolver(Arc::new(ProfileResolver::new(profile.dns.clone(), dns.clone())));
+  }
+  if let Some(p) = &profile.proxy {
+    b = b.proxy(p.to_reqwest()?);
+  }
+  match &profile.local_address {
+    Some(LocalAddress::Ip(ip)) => b = b.local_address(*ip),
+    Some(LocalAddress::Interface(name)) => b = bind_interface(b, name)?,
+    None => {}
+  }
+  b = tls::configure(b, &profile.tls)?;
+  b.build().context("failed to build reqwest client")
+}
+
+#[cfg(any(
+  target_os = "android",
+  target_os = "fuchsia",
+  target_os = "illumos",
+  target_os = "ios",
+  target_os = "linux",
+  target_os = "macos",
+  target_os = "solaris",
+  target_os = "tvos",
+  target_os = "visionos",
+  target_os = "watchos",
+))]
+fn bind_interface(b: reqwest::ClientBuilder, name: &str) -> anyhow::Result<reqwest::ClientBuilder> {
+  Ok(b.interface(name))
+}
+
+#[cfg(not(any(
+  target_os = "android",
+  target_os = "fuchsia",
+  target_os = "illumos",
+  target_os = "ios",
+  target_os = "linux",
+  target_os = "macos",
+  target_os = "solaris",
+  target_os = "tvos",
+  target_os = "visionos",
+  target_os = "watchos",
+)))]
+fn bind_interface(_b: reqwest::ClientBuilder, name: &str) -> anyhow::Result<reqwest::ClientBuilder> {
+  anyhow::bail!("binding to interface {name} is not supported on this system; use its IP address")
+}
+
+fn redirect_policy(max_redirects: usize, block_cross_host: bool) -> reqwest::redirect::Policy {
+  if !block_cross_host {
+    return reqwest::redirect::Policy::limited(max_redirects);
+  }
+  reqwest::redirect::Policy::custom(move |attempt| {
+    if attempt.previous().len() > max_redirects {
+      return attempt.error(format!("too many redirects (max {max_redirects})"));
+    }
+    let origin = attempt.previous().first().and_then(|u| u.host_str()).map(str::to_ascii_lowercase);
+    let next = attempt.url().host_str().map(str::to_ascii_lowercase);
+    if origin != next {
+      let to = next.unwrap_or_default();
+      return attempt.error(format!("cross-host redirect to {to} blocked by transport rule"));
+    }
+    attempt.follow()
+  })
+}
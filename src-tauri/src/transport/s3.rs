@@ -0,0 +1,557 @@
+//! Amazon S3 and S3-compatible storage (MinIO and friends).
+//!
+//! An "aws-sigv4" server login holds an access key id (user), secret key (password), region
+//! (realm) and optional endpoint. `s3://bucket/key` resolves to that endpoint, and every request
+//! to it is signed with AWS Signature Version 4 as it is sent. Nothing signed is stored, so
+//! resumes and segments never carry a stale signature. Presigned `https://` URLs are re-signed
+//! the same way when a login for their endpoint holds the same key, or once they have expired.
+
+use super::{best_pattern_match, SendError, Timeouts, Transport};
+use crate::model::{HttpCredential, RulesSnapshot};
+use anyhow::Context;
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use ring::{digest, hmac};
+use time::OffsetDateTime;
+use url::Url;
+
+pub const SCHEME: &str = "aws-sigv4";
+const SERVICE: &str = "s3";
+const DEFAULT_REGION: &str = "us-east-1";
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+/// Query parameters of a presigned URL; dropped before signing with headers.
+const PRESIGN_PARAMS: [&str; 7] = [
+  "x-amz-algorithm",
+  "x-amz-credential",
+  "x-amz-date",
+  "x-amz-expires",
+  "x-amz-signedheaders",
+  "x-amz-signature",
+  "x-amz-security-token",
+];
+
+/// Where a login's buckets live.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Endpoint {
+  /// A custom endpoint addressed path-style (`http://localhost:9000/bucket/key`); `None` for
+  /// AWS, addressed virtual-hosted style (`https://bucket.s3.region.amazonaws.com/key`).
+  custom: Option<Url>,
+  region: String,
+}
+
+impl Endpoint {
+  fn of(cred: &HttpCredential) -> anyhow::Result<Self> {
+    let region = cred
+      .realm
+      .as_deref()
+      .map(str::trim)
+      .filter(|r| !r.is_empty())
+      .unwrap_or(DEFAULT_REGION)
+      .to_string();
+    let custom = match cred.endpoint.as_deref().map(str::trim).filter(|e| !e.is_empty()) {
+      Some(e) => Some(parse_endpoint(e)?),
+      None => None,
+    };
+    Ok(Self { custom, region })
+  }
+
+  fn aws_host(&self) -> String {
+    format!("s3.{}.amazonaws.com", self.region)
+  }
+
+  /// `encoded_key` is percent-encoded as in a URL path, without the leading slash; empty for
+  /// the bucket itself.
+  fn object_url(&self, bucket: &str, encoded_key: &str) -> anyhow::Result<Url> {
+    let key = if encoded_key.is_empty() { String::new() } else { format!("/{encoded_key}") };
+    let raw = match &self.custom {
+      Some(base) => format!("{}/{bucket}{key}", base.as_str().trim_end_matches('/')),
+      // Dotted bucket names break the wildcard certificate, so they go path-style.
+      None if bucket.contains('.') => format!("https://{}/{bucket}{key}", self.aws_host()),
+      None => format!("https://{bucket}.{}/{encoded_key}", self.aws_host()),
+    };
+    Url::parse(&raw).context("invalid S3 object URL")
+  }
+
+  /// The bucket and (encoded) key that `url` addresses through this endpoint.
+  fn locate(&self, url: &Url) -> Option<(String, String)> {
+    let host = url.host_str()?.to_ascii_lowercase();
+    let path = url.path().trim_start_matches('/');
+    let path_style = |rest: &str| {
+      let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+      (!bucket.is_empty()).then(|| (bucket.to_string(), key.to_string()))
+    };
+    match &self.custom {
+      Some(base) => {
+        let same_origin = url.scheme() == base.scheme()
+          && Some(host.as_str()) == base.host_str()
+          && url.port_or_known_default() == base.port_or_known_default();
+        let base_path = base.path().trim_matches('/');
+        let rest = if base_path.is_empty() { Some(path) } else { path.strip_prefix(base_path)?.strip_prefix('/') };
+        if same_origin {
+          path_style(rest?)
+        } else {
+          None
+        }
+      }
+      None => {
+        let aws = self.aws_host();
+        if host == aws || host == "s3.amazonaws.com" {
+          return path_style(path);
+        }
+        let bucket = host.strip_suffix(&format!(".{aws}")).or_else(|| host.strip_suffix(".s3.amazonaws.com"))?;
+        Some((bucket.to_string(), path.to_string()))
+      }
+    }
+  }
+}
+
+/// An endpoint as entered for a login: `http(s)://host[:port][/base]`.
+pub fn parse_endpoint(text: &str) -> anyhow::Result<Url> {
+  let url = Url::parse(text).with_context(|| format!("invalid S3 endpoint {text}"))?;
+  if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+    anyhow::bail!("S3 endpoint {text} must be an http:// or https:// address");
+  }
+  if url.query().is_some() {
+    anyhow::bail!("S3 endpoint {text} must not have a query");
+  }
+  Ok(url)
+}
+
+fn logins(creds: &[HttpCredential]) -> impl Iterator<Item = &HttpCredential> + Clone {
+  creds.iter().filter(|c| c.enabled && c.scheme == SCHEME && c.password.is_some())
+}
+
+/// Whether `url` names a prefix (`s3://bucket/` or `s3://bucket/dir/`) rather than an object.
+pub fn is_prefix(url: &Url) -> bool {
+  url.scheme() == "s3" && (url.path().is_empty() || url.path().ends_with('/'))
+}
+
+/// The endpoint URL for `s3://bucket/key`, through the most specific login matching it.
+pub fn object_url(creds: &[HttpCredential], url: &Url) -> anyhow::Result<Url> {
+  let bucket = url.host_str().filter(|b| !b.is_empty()).context("S3 URL has no bucket")?;
+  let cred = best_pattern_match(&logins(creds), url)
+    .with_context(|| format!("no AWS SigV4 login matches s3://{bucket}; add one under Server logins"))?;
+  Endpoint::of(cred)?.object_url(bucket, url.path().trim_start_matches('/'))
+}
+
+/// The login to sign a request to `url` with, and the region to sign for.
+fn signer_for<'a>(creds: &'a [HttpCredential], url: &Url, now: OffsetDateTime) -> Option<(&'a HttpCredential, String)> {
+  let at_endpoint = logins(creds).filter(|c| Endpoint::of(c).ok().and_then(|e| e.locate(url)).is_some());
+  let (bucket, key) = at_endpoint.clone().find_map(|c| Endpoint::of(c).ok()?.locate(url))?;
+  let view = Url::parse(&format!("s3://{bucket}/{key}")).ok()?;
+  let cred = best_pattern_match(&at_endpoint, &view)?;
+  match presigned(url) {
+    Some(p) if p.access_key != cred.username && !p.expired(now) => None,
+    Some(p) if !p.region.is_empty() => Some((cred, p.region)),
+    _ => Endpoint::of(cred).ok().map(|e| (cred, e.region)),
+  }
+}
+
+/// What a presigned URL's query says about its signature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Presigned {
+  access_key: String,
+  region: String,
+  /// `X-Amz-Date` plus `X-Amz-Expires`.
+  expires: Option<OffsetDateTime>,
+}
+
+impl Presigned {
+  fn expired(&self, now: OffsetDateTime) -> bool {
+    self.expires.is_some_and(|at| at <= now)
+  }
+}
+
+fn presigned(url: &Url) -> Option<Presigned> {
+  let param = |name: &str| {
+    raw_query_pairs(url)
+      .find(|(k, _)| k.eq_ignore_ascii_case(name))
+      .map(|(_, v)| urlencoding::decode(v).map(|v| v.into_owned()).unwrap_or_else(|_| v.to_string()))
+  };
+  let credential = param("x-amz-credential")?;
+  let mut scope = credential.split('/');
+  let access_key = scope.next()?.to_string();
+  let region = scope.nth(1).unwrap_or_default().to_string();
+  let signed_at = param("x-amz-date").and_then(|d| parse_amz_date(&d));
+  let lifetime = param("x-amz-expires").and_then(|s| s.parse::<i64>().ok());
+  let expires = signed_at.zip(lifetime).map(|(at, secs)| at + time::Duration::seconds(secs));
+  Some(Presigned { access_key, region, expires })
+}
+
+/// When a presigned `url` without a login to re-sign it stopped being valid.
+pub fn expired_presign(creds: &[HttpCredential], url: &Url, now: OffsetDateTime) -> Option<OffsetDateTime> {
+  let p = presigned(url)?;
+  if !p.expired(now) || signer_for(creds, url, now).is_some() {
+    return None;
+  }
+  p.expires
+}
+
+/// `20130524T000000Z`.
+fn parse_amz_date(s: &str) -> Option<OffsetDateTime> {
+  let b = s.as_bytes();
+  if b.len() != 16 || b[8] != b'T' || b[15] != b'Z' {
+    return None;
+  }
+  let num = |r: std::ops::Range<usize>| s.get(r)?.parse::<u32>().ok();
+  let month = time::Month::try_from(num(4..6)? as u8).ok()?;
+  let date = time::Date::from_calendar_date(num(0..4)? as i32, month, num(6..8)? as u8).ok()?;
+  let clock = time::Time::from_hms(num(9..11)? as u8, num(11..13)? as u8, num(13..15)? as u8).ok()?;
+  Some(date.with_time(clock).assume_utc())
+}
+
+fn amz_date(now: OffsetDateTime) -> String {
+  let f = time::macros::format_description!("[year][month][day]T[hour][minute][second]Z");
+  now.format(&f).unwrap_or_default()
+}
+
+/// `k=v` pairs of the query as written (still percent-encoded).
+fn raw_query_pairs(url: &Url) -> impl Iterator<Item = (&str, &str)> {
+  url
+    .query()
+    .unwrap_or_default()
+    .split('&')
+    .filter(|p| !p.is_empty())
+    .map(|p| p.split_once('=').unwrap_or((p, "")))
+}
+
+fn strip_presign_params(url: &mut Url) {
+  let kept: Vec<String> = url
+    .query()
+    .unwrap_or_default()
+    .split('&')
+    .filter(|p| !p.is_empty())
+    .filter(|p| {
+      let name = p.split_once('=').map_or(*p, |(k, _)| k);
+      !PRESIGN_PARAMS.iter().any(|n| name.eq_ignore_ascii_case(n))
+    })
+    .map(str::to_string)
+    .collect();
+  let query = kept.join("&");
+  url.set_query((!query.is_empty()).then_some(query.as_str()));
+}
+
+/// SigV4's URI encoding: everything but `A-Za-z0-9-_.~` (and `/` in paths).
+fn uri_encode(raw: &str) -> String {
+  let decoded = urlencoding::decode(raw).map(|v| v.into_owned()).unwrap_or_else(|_| raw.to_string());
+  urlencoding::encode(&decoded).into_owned()
+}
+
+fn canonical_query(url: &Url) -> String {
+  let mut pairs: Vec<(String, String)> = raw_query_pairs(url).map(|(k, v)| (uri_encode(k), uri_encode(v))).collect();
+  pairs.sort();
+  pairs.iter().map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>().join("&")
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+  hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data.as_bytes()).as_ref().to_vec()
+}
+
+/// The SigV4 signature for a request; `headers` are the signed ones, lowercase and sorted.
+#[allow(clippy::too_many_arguments)]
+fn signature(
+  method: &str,
+  url: &Url,
+  headers: &[(&str, &str)],
+  payload_hash: &str,
+  region: &str,
+  service: &str,
+  secret: &str,
+  amz_date: &str,
+) -> String {
+  let path = url.path().split('/').map(uri_encode).collect::<Vec<_>>().join("/");
+  let canonical_headers: String = headers.iter().map(|(k, v)| format!("{k}:{}\n", v.trim())).collect();
+  let signed_headers = headers.iter().map(|(k, _)| *k).collect::<Vec<_>>().join(";");
+  let canonical_request = format!(
+    "{method}\n{path}\n{}\n{canonical_headers}\n{signed_headers}\n{payload_hash}",
+    canonical_query(url)
+  );
+  let date = &amz_date[..8];
+  let scope = format!("{date}/{region}/{service}/aws4_request");
+  let string_to_sign = format!(
+    "{ALGORITHM}\n{amz_date}\n{scope}\n{}",
+    hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+  );
+  let mut key = hmac_sha256(format!("AWS4{secret}").as_bytes(), date);
+  for part in [region, service, "aws4_request"] {
+    key = hmac_sha256(&key, part);
+  }
+  hex(&hmac_sha256(&key, &string_to_sign))
+}
+
+/// Signs `req` in place with header authentication, replacing any presigned query.
+fn sign(req: &mut reqwest::Request, cred: &HttpCredential, region: &str, now: OffsetDateTime) {
+  strip_presign_params(req.url_mut());
+  let url = req.url().clone();
+  let host = match url.port() {
+    Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+    None => url.host_str().unwrap_or_default().to_string(),
+  };
+  let amz_date = amz_date(now);
+  let headers = [("host", host.as_str()), ("x-amz-content-sha256", UNSIGNED_PAYLOAD), ("x-amz-date", amz_date.as_str())];
+  let secret = cred.password.as_deref().unwrap_or_default();
+  let sig = signature(req.method().as_str(), &url, &headers, UNSIGNED_PAYLOAD, region, SERVICE, secret, &amz_date);
+  let authorization = format!(
+    "{ALGORITHM} Credential={}/{}/{region}/{SERVICE}/aws4_request, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={sig}",
+    cred.username,
+    &amz_date[..8],
+  );
+  let h = req.headers_mut();
+  h.insert("x-amz-content-sha256", HeaderValue::from_static(UNSIGNED_PAYLOAD));
+  if let Ok(v) = HeaderValue::from_str(&amz_date) {
+    h.insert("x-amz-date", v);
+  }
+  if let Ok(v) = HeaderValue::from_str(&authorization) {
+    h.insert(AUTHORIZATION, v);
+  }
+}
+
+/// Signs `req` if it goes to the endpoint of an "aws-sigv4" login; `false` leaves it alone.
+pub(super) fn sign_for_endpoint(creds: &[HttpCredential], req: &mut reqwest::Request) -> bool {
+  let now = OffsetDateTime::now_utc();
+  let Some((cred, region)) = signer_for(creds, req.url(), now) else { return false };
+  sign(req, cred, &region, now);
+  true
+}
+
+/// One object found under a prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Object {
+  /// `s3://bucket/key`.
+  pub url: Url,
+  /// The key below the listed prefix, e.g. `2024/jan.csv` under `s3://bucket/data/`.
+  pub relative: String,
+  pub size: Option<i64>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListBucketResult {
+  #[serde(default)]
+  contents: Vec<ListedObject>,
+  #[serde(default)]
+  is_truncated: bool,
+  next_continuation_token: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListedObject {
+  key: String,
+  size: Option<i64>,
+}
+
+fn parse_listing(xml: &str) -> anyhow::Result<ListBucketResult> {
+  quick_xml::de::from_str(xml).context("unreadable ListObjectsV2 response")
+}
+
+impl Transport {
+  /// Every object under the prefix `url` (`s3://bucket/dir/`), following continuation tokens.
+  /// Folder placeholders (keys ending in `/`) are skipped.
+  pub async fn list_s3_prefix(
+    &self,
+    rules: &RulesSnapshot,
+    client: &reqwest::Client,
+    timeouts: &Timeouts,
+    url: &Url,
+  ) -> anyhow::Result<Vec<S3Object>> {
+    let bucket = url.host_str().filter(|b| !b.is_empty()).context("S3 URL has no bucket")?.to_string();
+    let prefix = urlencoding::decode(url.path().trim_start_matches('/'))
+      .context("S3 prefix is not valid UTF-8")?
+      .into_owned();
+    let mut list_url = object_url(&rules.http_credentials, &Url::parse(&format!("s3://{bucket}"))?)?;
+    let mut out = Vec::new();
+    let mut token: Option<String> = None;
+    loop {
+      let mut query = format!("list-type=2&prefix={}", urlencoding::encode(&prefix));
+      if let Some(t) = &token {
+        query.push_str(&format!("&continuation-token={}", urlencoding::encode(t)));
+      }
+      list_url.set_query(Some(&query));
+      let resp = self
+        .send_with_auth(rules, client.get(list_url.clone()), timeouts)
+        .await
+        .map_err(|e: SendError| anyhow::Error::new(e).context(format!("listing s3://{bucket}/{prefix} failed")))?;
+      let status = resp.status();
+      let body = resp.text().await.context("listing response was cut off")?;
+      if !status.is_success() {
+        anyhow::bail!("listing s3://{bucket}/{prefix} failed: HTTP {status}: {}", body.trim());
+      }
+      let page = parse_listing(&body)?;
+      for obj in page.contents.into_iter().filter(|o| !o.key.ends_with('/')) {
+        let encoded = obj.key.split('/').map(|s| urlencoding::encode(s).into_owned()).collect::<Vec<_>>().join("/");
+        out.push(S3Object {
+          url: Url::parse(&format!("s3://{bucket}/{encoded}"))?,
+          relative: obj.key.strip_prefix(&prefix).unwrap_or(&obj.key).to_string(),
+          size: obj.size,
+        });
+      }
+      match page.next_continuation_token.filter(|t| page.is_truncated && !t.is_empty()) {
+        Some(t) => token = Some(t),
+        None => break,
+      }
+    }
+    Ok(out)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use axum::{extract::Query, http::HeaderMap, routing::get, Router};
+  use std::collections::HashMap;
+
+  fn login(id: i64, pattern: &str, key: &str, region: Option<&str>, endpoint: Option<&str>) -> HttpCredential {
+    HttpCredential {
+      id,
+      pattern: pattern.to_string(),
+      realm: region.map(str::to_string),
+      scheme: SCHEME.to_string(),
+      username: key.to_string(),
+      enabled: true,
+      password: Some("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string()),
+      key_path: None,
+      endpoint: endpoint.map(str::to_string),
+    }
+  }
+
+  fn url(s: &str) -> Url {
+    Url::parse(s).unwrap()
+  }
+
+  #[test]
+  fn signature_matches_the_aws_test_suite() {
+    // "get-vanilla" from the AWS SigV4 test suite.
+    let sig = signature(
+      "GET",
+      &url("https://example.amazonaws.com/"),
+      &[("host", "example.amazonaws.com"), ("x-amz-date", "20150830T123600Z")],
+      "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+      "us-east-1",
+      "service",
+      "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+      "20150830T123600Z",
+    );
+    assert_eq!(sig, "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31");
+  }
+
+  #[test]
+  fn s3_urls_resolve_through_the_matching_login() {
+    let creds = vec![
+      login(1, "*", "AKAWS", Some("eu-west-1"), None),
+      login(2, "s3://datasets", "AKMINIO", None, Some("http://localhost:9000")),
+    ];
+    assert_eq!(
+      object_url(&creds, &url("s3://logs/2024/a%20b.gz")).unwrap().as_str(),
+      "https://logs.s3.eu-west-1.amazonaws.com/2024/a%20b.gz"
+    );
+    assert_eq!(
+      object_url(&creds, &url("s3://my.bucket/f")).unwrap().as_str(),
+      "https://s3.eu-west-1.amazonaws.com/my.bucket/f"
+    );
+    let minio = object_url(&creds, &url("s3://datasets/train.parquet")).unwrap();
+    assert_eq!(minio.as_str(), "http://localhost:9000/datasets/train.parquet");
+    assert!(object_url(&[], &url("s3://logs/a")).is_err());
+
+    // Requests to an endpoint are signed by the login that owns the bucket there.
+    let now = OffsetDateTime::now_utc();
+    assert_eq!(signer_for(&creds, &minio, now).map(|(c, r)| (c.id, r)), Some((2, "us-east-1".to_string())));
+    assert_eq!(signer_for(&creds, &url("https://logs.s3.eu-west-1.amazonaws.com/a"), now).map(|(c, _)| c.id), Some(1));
+    assert!(signer_for(&creds, &url("https://example.com/a"), now).is_none());
+    assert!(is_prefix(&url("s3://datasets/")) && is_prefix(&url("s3://datasets/2024/")));
+    assert!(!is_prefix(&url("s3://datasets/train.parquet")));
+  }
+
+  #[test]
+  fn presigned_urls_are_re_signed_only_with_their_own_key_or_once_expired() {
+    let creds = vec![login(1, "*", "AKMINE", None, Some("http://localhost:9000"))];
+    let signed_at = parse_amz_date("20240101T000000Z").unwrap();
+    let presigned = |key: &str| {
+      url(&format!(
+        "http://localhost:9000/b/k?X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Credential={key}%2F20240101%2Feu-central-1%2Fs3%2Faws4_request&X-Amz-Date=20240101T000000Z&X-Amz-Expires=3600&X-Amz-SignedHeaders=host&X-Amz-Signature=abc&versionId=7"
+      ))
+    };
+    let fresh = signed_at + time::Duration::minutes(5);
+    let late = signed_at + time::Duration::hours(2);
+    // Someone else's link still works as it is; an expired one is worth a try with our key.
+    assert!(signer_for(&creds, &presigned("AKOTHER"), fresh).is_none());
+    assert_eq!(signer_for(&creds, &presigned("AKOTHER"), late).map(|(_, r)| r), Some("eu-central-1".to_string()));
+    assert_eq!(signer_for(&creds, &presigned("AKMINE"), fresh).map(|(c, _)| c.id), Some(1));
+
+    assert_eq!(expired_presign(&[], &presigned("AKOTHER"), late), Some(signed_at + time::Duration::hours(1)));
+    assert_eq!(expired_presign(&[], &presigned("AKOTHER"), fresh), None);
+    assert_eq!(expired_presign(&creds, &presigned("AKOTHER"), late), None);
+
+    let mut stripped = presigned("AKMINE");
+    strip_presign_params(&mut stripped);
+    assert_eq!(stripped.as_str(), "http://localhost:9000/b/k?versionId=7");
+  }
+
+  #[test]
+  fn listings_parse_with_escaped_keys() {
+    let page = parse_listing(
+      r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Name>datasets</Name><Prefix>raw/</Prefix><KeyCount>2</KeyCount><IsTruncated>true</IsTruncated>
+  <NextContinuationToken>1ueGcxLPRx1Tr/XYExHnhbYLgveDs2J/wm36Hy4vbOwM=</NextContinuationToken>
+  <Contents><Key>raw/a&amp;b.csv</Key><Size>10</Size><ETag>"x"</ETag></Contents>
+  <Contents><Key>raw/sub/</Key><Size>0</Size></Contents>
+</ListBucketResult>"#,
+    )
+    .unwrap();
+    assert!(page.is_truncated);
+    assert_eq!(page.contents.iter().map(|c| c.key.as_str()).collect::<Vec<_>>(), ["raw/a&b.csv", "raw/sub/"]);
+    assert_eq!(page.next_continuation_token.as_deref(), Some("1ueGcxLPRx1Tr/XYExHnhbYLgveDs2J/wm36Hy4vbOwM="));
+  }
+
+  #[tokio::test]
+  async fn lists_a_prefix_page_by_page_with_signed_requests() {
+    let app = Router::new().route(
+      "/datasets",
+      get(|Query(q): Query<HashMap<String, String>>, headers: HeaderMap| async move {
+        let auth = headers.get("authorization").and_then(|v| v.to_str().ok()).unwrap_or_default();
+        if !auth.starts_with("AWS4-HMAC-SHA256 Credential=AKMINIO/") || !headers.contains_key("x-amz-date") {
+          return (axum::http::StatusCode::FORBIDDEN, "<Error><Code>AccessDenied</Code></Error>".to_string());
+        }
+        assert_eq!(q.get("list-type").map(String::as_str), Some("2"));
+        assert_eq!(q.get("prefix").map(String::as_str), Some("raw/"));
+        let body = match q.get("continuation-token").map(String::as_str) {
+          None => "<ListBucketResult><IsTruncated>true</IsTruncated><NextContinuationToken>p2</NextContinuationToken>\
+                   <Contents><Key>raw/a.csv</Key><Size>3</Size></Contents><Contents><Key>raw/sub/</Key></Contents></ListBucketResult>",
+          Some("p2") => "<ListBucketResult><IsTruncated>false</IsTruncated>\
+                   <Contents><Key>raw/sub/b c.csv</Key><Size>5</Size></Contents></ListBucketResult>",
+          Some(other) => panic!("unexpected token {other}"),
+        };
+        (axum::http::StatusCode::OK, body.to_string())
+      }),
+    );
+    let endpoint = crate::transport::tests::serve(app).await;
+
+    let transport = Transport::new().unwrap();
+    let mut rules = crate::transport::tests::rules_with(crate::transport::tests::local_rule());
+    rules.transport_rules.clear();
+    rules.http_credentials = vec![login(1, "s3://datasets", "AKMINIO", None, Some(&endpoint))];
+    let client = transport.client_for(&Default::default()).unwrap();
+    let objects = transport
+      .list_s3_prefix(&rules, &client, &Timeouts::default(), &url("s3://datasets/raw/"))
+      .await
+      .unwrap();
+    assert_eq!(
+      objects.iter().map(|o| (o.url.as_str(), o.relative.as_str(), o.size)).collect::<Vec<_>>(),
+      [("s3://datasets/raw/a.csv", "a.csv", Some(3)), ("s3://datasets/raw/sub/b%20c.csv", "sub/b c.csv", Some(5))]
+    );
+
+    // A refused connection stays recognizable, so the engine can try the next proxy.
+    let dead = format!("http://{}", std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap());
+    rules.http_credentials = vec![login(1, "s3://datasets", "AKMINIO", None, Some(&dead))];
+    let err = transport
+      .list_s3_prefix(&rules, &client, &Timeouts::default(), &url("s3://datasets/raw/"))
+      .await
+      .unwrap_err();
+    assert!(err.downcast_ref::<SendError>().is_some_and(SendError::is_connect));
+  }
+}
@@ -0,0 +1,164 @@
+//! Non-HTTP download sources. HTTP keeps its own richer path (probe methods, validators,
+//! proxies, auth challenges); everything else answers the same two questions through
+//! [`Source`]: what is there, and give me these bytes.
+
+use super::dns::ProfileResolver;
+use super::{auth, best_pattern_match, ftp, sftp, LocalAddress, Transport};
+use crate::model::{RulesSnapshot, SettingsSnapshot};
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use futures_util::stream::BoxStream;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
+use url::Url;
+
+/// Body bytes in order, starting at the offset passed to [`Source::open`].
+pub type ByteStream = BoxStream<'static, Result<Bytes, SourceError>>;
+
+/// What a probe learned about the remote file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceInfo {
+  pub size: Option<i64>,
+  /// Opaque modification stamp; a different value on resume means the file changed.
+  pub modified: Option<String>,
+  /// Reads may start at an offset.
+  pub ranges: bool,
+  /// The server accepts more than one transfer at a time from us.
+  pub parallel: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SourceError {
+  #[error("connect failed: {0}")]
+  Connect(io::Error),
+  #[error("connect timeout: no connection within {}s", .0.as_secs())]
+  ConnectTimeout(Duration),
+  #[error("response timeout: no reply within {}s", .0.as_secs())]
+  ResponseTimeout(Duration),
+  #[error("read idle timeout: no data for {}s", .0.as_secs())]
+  ReadIdleTimeout(Duration),
+  #[error("TLS handshake failed: {0}")]
+  Tls(String),
+  #[error("SSH host key rejected: {0}")]
+  HostKey(String),
+  /// A permanent refusal (FTP 5xx, SSH: bad login, no such file, permission denied).
+  #[error("server refused: {0}")]
+  Refused(String),
+  /// A transient refusal (FTP 4xx).
+  #[error("server busy: {0}")]
+  Busy(String),
+  #[error("too many connections: {0}")]
+  TooManyConnections(String),
+  #[error("server cannot resume: {0}")]
+  NoResume(String),
+  #[error("connection lost: {0}")]
+  Io(io::Error),
+  #[error("protocol error: {0}")]
+  Protocol(String),
+}
+
+impl SourceError {
+  /// Worth reopening the transfer after a backoff.
+  pub fn is_transient(&self) -> bool {
+    matches!(
+      self,
+      SourceError::Connect(_)
+        | SourceError::ConnectTimeout(_)
+        | SourceError::ResponseTimeout(_)
+        | SourceError::ReadIdleTimeout(_)
+        | SourceError::Busy(_)
+        | SourceError::TooManyConnections(_)
+        | SourceError::Io(_)
+    )
+  }
+}
+
+/// A downloadable file behind some protocol. Every `open` is independent, so segments can read
+/// different ranges at once when [`SourceInfo::parallel`] allows it.
+pub trait Source: Send + Sync {
+  fn probe(&self) -> BoxFuture<'_, Result<SourceInfo, SourceError>>;
+
+  /// Bytes from `start` through `end` inclusive, or to the end of the file.
+  fn open(&self, start: u64, end: Option<u64>) -> BoxFuture<'_, Result<ByteStream, SourceError>>;
+}
+
+/// Whether the engine fetches `url` through a [`Source`] rather than as HTTP.
+pub fn handles(url: &Url) -> bool {
+  matches!(url.scheme(), "ftp" | "ftps" | "sftp" | "scp")
+}
+
+/// Connects to `addr` from `local` (when it is of the same IP family, or an interface).
+pub(super) async fn connect_addr(addr: SocketAddr, local: Option<&LocalAddress>, timeout: Duration) -> Result<TcpStream, SourceError> {
+  let connect = async {
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    match local {
+      Some(LocalAddress::Ip(ip)) if ip.is_ipv4() == addr.is_ipv4() => socket.bind(SocketAddr::new(*ip, 0))?,
+      Some(LocalAddress::Interface(name)) => bind_device(&socket, name)?,
+      _ => {}
+    }
+    socket.connect(addr).await
+  };
+  match tokio::time::timeout(timeout, connect).await {
+    Ok(conn) => conn.map_err(SourceError::Connect),
+    Err(_) => Err(SourceError::ConnectTimeout(timeout)),
+  }
+}
+
+/// Resolves `host` like the profile's HTTP clients would (overrides, DoH, family preference)
+/// and connects to the first address that answers.
+pub(super) async fn connect_host(
+  resolver: &ProfileResolver,
+  host: &str,
+  port: u16,
+  local: Option<&LocalAddress>,
+  timeout: Duration,
+) -> Result<TcpStream, SourceError> {
+  let addrs = resolver
+    .socket_addrs(host, port)
+    .await
+    .map_err(|e| SourceError::Connect(io::Error::new(io::ErrorKind::NotFound, format!("{e:#}"))))?;
+  let mut last_err = None;
+  for addr in addrs {
+    match connect_addr(addr, local, timeout).await {
+      Ok(conn) => return Ok(conn),
+      Err(e) => last_err = Some(e),
+    }
+  }
+  Err(last_err.unwrap_or_else(|| {
+    SourceError::Connect(io::Error::new(io::ErrorKind::NotFound, format!("{host} has no addresses")))
+  }))
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &TcpSocket, name: &str) -> io::Result<()> {
+  socket.bind_device(Some(name.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &TcpSocket, name: &str) -> io::Result<()> {
+  Err(io::Error::new(
+    io::ErrorKind::Unsupported,
+    format!("binding to interface {name} is not supported for this protocol on this system; use its IP address"),
+  ))
+}
+
+impl Transport {
+  /// The source for a non-HTTP `url`, configured from the transport rule that matches it
+  /// (timeouts, TLS trust, DNS, local address, SSH host keys) and its login from the URL or the server logins.
+  pub fn source_for(&self, settings: &SettingsSnapshot, rules: &RulesSnapshot, url: &Url) -> anyhow::Result<Arc<dyn Source>> {
+    let profile = Self::client_profile(settings, rules, url);
+    let login = auth::login_for(&rules.http_credentials, url);
+    match url.scheme() {
+      "ftp" | "ftps" => Ok(Arc::new(ftp::FtpSource::new(url, login, &profile, &self.dns)?)),
+      "sftp" | "scp" => {
+        let rule = best_pattern_match(&rules.transport_rules.iter().filter(|r| r.enabled), url);
+        let accept_new = rule.is_some_and(|r| r.ssh_accept_new_host_keys);
+        Ok(Arc::new(sftp::SftpSource::new(url, &rules.http_credentials, &profile, &self.dns, accept_new)?))
+      }
+      other => anyhow::bail!("unsupported scheme {other}://"),
+    }
+  }
+}
@@ -0,0 +1,296 @@
+//! Per-profile TLS trust: extra CA bundles, client certificates for mTLS and the per-host
+//! "accept invalid certificates" escape hatch, plus a readable reason for handshake failures.
+
+use anyhow::Context;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use std::error::Error as StdError;
+use std::sync::Arc;
+
+/// TLS settings from a transport rule. Files are read whenever a client is built, so a renewed
+/// certificate takes effect on the next restart without touching the rule.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct TlsOptions {
+  pub ca_bundle: Option<String>,
+  pub client_cert: Option<String>,
+  /// Defaults to looking for the key in `client_cert`.
+  pub client_key: Option<String>,
+  pub accept_invalid_certs: bool,
+}
+
+fn read(path: &str, what: &str) -> anyhow::Result<Vec<u8>> {
+  std::fs::read(path).with_context(|| format!("failed to read {what} {path}"))
+}
+
+pub(super) fn configure(mut b: reqwest::ClientBuilder, tls: &TlsOptions) -> anyhow::Result<reqwest::ClientBuilder> {
+  if let Some(path) = &tls.ca_bundle {
+    let certs = reqwest::Certificate::from_pem_bundle(&read(path, "CA bundle")?)
+      .with_context(|| format!("{path} is not a PEM certificate bundle"))?;
+    if certs.is_empty() {
+      anyhow::bail!("{path} holds no PEM certificates");
+    }
+    for cert in certs {
+      b = b.add_root_certificate(cert);
+    }
+  }
+  if let Some(cert_path) = &tls.client_cert {
+    // reqwest wants the chain and the private key in one PEM buffer.
+    let mut pem = read(cert_path, "client certificate")?;
+    if let Some(key_path) = &tls.client_key {
+      pem.push(b'\n');
+      pem.extend(read(key_path, "client key")?);
+    }
+    let identity = reqwest::Identity::from_pem(&pem)
+      .with_context(|| format!("{cert_path} is not a usable PEM certificate and private key"))?;
+    b = b.identity(identity);
+  }
+  if tls.accept_invalid_certs {
+    b = b.danger_accept_invalid_certs(true);
+  }
+  Ok(b)
+}
+
+/// The same trust as `configure`, for protocols we speak over rustls ourselves (FTPS).
+pub fn rustls_config(tls: &TlsOptions) -> anyhow::Result<Arc<rustls::ClientConfig>> {
+  let provider = Arc::new(rustls::crypto::ring::default_provider());
+  let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+    .with_safe_default_protocol_versions()
+    .context("no usable TLS versions")?;
+  let builder = if tls.accept_invalid_certs {
+    builder.dangerous().with_custom_certificate_verifier(Arc::new(AcceptAnyCert(provider)))
+  } else {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(path) = &tls.ca_bundle {
+      let certs = CertificateDer::pem_slice_iter(&read(path, "CA bundle")?)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("{path} is not a PEM certificate bundle"))?;
+      let (added, _) = roots.add_parsable_certificates(certs);
+      if added == 0 {
+        anyhow::bail!("{path} holds no PEM certificates");
+      }
+    }
+    builder.with_root_certificates(roots)
+  };
+  let config = match &tls.client_cert {
+    Some(cert_path) => {
+      let cert_pem = read(cert_path, "client certificate")?;
+      let key_pem = match &tls.client_key {
+        Some(key_path) => read(key_path, "client key")?,
+        None => cert_pem.clone(),
+      };
+      let chain = CertificateDer::pem_slice_iter(&cert_pem)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("{cert_path} is not a PEM certificate"))?;
+      let key = PrivateKeyDer::from_pem_slice(&key_pem).context("no usable PEM private key for the client certificate")?;
+      builder.with_client_auth_cert(chain, key).context("client certificate and key do not match")?
+    }
+    None => builder.with_no_client_auth(),
+  };
+  Ok(Arc::new(config))
+}
+
+/// `accept_invalid_certs` for rustls: signatures are still checked so the handshake is sound,
+/// but the chain and name are not.
+#[derive(Debug)]
+struct AcceptAnyCert(Arc<rustls::crypto::CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCert {
+  fn verify_server_cert(
+    &self,
+    _end_entity: &CertificateDer<'_>,
+    _intermediates: &[CertificateDer<'_>],
+    _server_name: &ServerName<'_>,
+    _ocsp_response: &[u8],
+    _now: UnixTime,
+  ) -> Result<ServerCertVerified, rustls::Error> {
+    Ok(ServerCertVerified::assertion())
+  }
+
+  fn verify_tls12_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+  }
+
+  fn verify_tls13_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+    self.0.signature_verification_algorithms.supported_schemes()
+  }
+}
+
+/// Why a request failed in the TLS handshake, if it did: the certificate validation error or
+/// the alert the server sent. reqwest buries rustls' error a few sources deep, inside nested
+/// `io::Error`s whose `source()` skips the error they wrap.
+pub fn failure_reason(err: &(dyn StdError + 'static)) -> Option<String> {
+  let mut next = Some(err);
+  while let Some(e) = next {
+    if let Some(r) = e.downcast_ref::<rustls::Error>() {
+      return Some(describe(r));
+    }
+    next = match e.downcast_ref::<std::io::Error>().and_then(|io| io.get_ref()) {
+      Some(inner) => Some(inner as &(dyn StdError + 'static)),
+      None => e.source(),
+    };
+  }
+  None
+}
+
+fn describe(err: &rustls::Error) -> String {
+  use rustls::CertificateError as C;
+  let cert = match err {
+    rustls::Error::InvalidCertificate(cert) => cert,
+    rustls::Error::AlertReceived(rustls::AlertDescription::CertificateRequired) => {
+      return "server requires a client certificate".into();
+    }
+    _ => return err.to_string(),
+  };
+  match cert {
+    C::UnknownIssuer => "certificate is not signed by a trusted CA (add the issuer's CA bundle to a transport rule)".into(),
+    C::Expired => "certificate has expired".into(),
+    C::NotValidYet => "certificate is not valid yet".into(),
+    C::NotValidForName => "certificate does not cover this host name".into(),
+    C::Revoked => "certificate has been revoked".into(),
+    C::BadSignature => "certificate signature is invalid".into(),
+    other => format!("invalid certificate: {other:?}"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transport::tests::TempDir;
+  use crate::transport::{ClientProfile, SendError, Transport};
+  use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+  struct Pki {
+    ca: rcgen::Certificate,
+    ca_key: KeyPair,
+  }
+
+  impl Pki {
+    fn new() -> Self {
+      let ca_key = KeyPair::generate().unwrap();
+      let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+      params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+      let ca = params.self_signed(&ca_key).unwrap();
+      Self { ca, ca_key }
+    }
+
+    fn issue(&self, name: &str, purpose: ExtendedKeyUsagePurpose) -> (rcgen::Certificate, KeyPair) {
+      let key = KeyPair::generate().unwrap();
+      let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+      params.extended_key_usages = vec![purpose];
+      (params.signed_by(&key, &self.ca, &self.ca_key).unwrap(), key)
+    }
+  }
+
+  /// An HTTPS server on 127.0.0.1 answering every request with "ok"; returns its base URL.
+  async fn serve_tls(config: rustls::ServerConfig) -> String {
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      while let Ok((tcp, _)) = listener.accept().await {
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+          let Ok(mut tls) = acceptor.accept(tcp).await else { return };
+          let mut buf = [0u8; 4096];
+          let _ = tls.read(&mut buf).await;
+          let _ = tls.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").await;
+          let _ = tls.shutdown().await;
+        });
+      }
+    });
+    format!("https://{addr}/")
+  }
+
+  fn server_config(pki: &Pki, client_ca: Option<&rcgen::Certificate>) -> rustls::ServerConfig {
+    let (cert, key) = pki.issue("127.0.0.1", ExtendedKeyUsagePurpose::ServerAuth);
+    let chain = vec![cert.der().clone()];
+    let key = rustls::pki_types::PrivateKeyDer::try_from(key.serialize_der()).unwrap();
+    let builder = rustls::ServerConfig::builder();
+    let builder = match client_ca {
+      Some(ca) => {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        builder.with_client_cert_verifier(rustls::server::WebPkiClientVerifier::builder(Arc::new(roots)).build().unwrap())
+      }
+      None => builder.with_no_client_auth(),
+    };
+    builder.with_single_cert(chain, key).unwrap()
+  }
+
+  fn write_temp(dir: &TempDir, contents: &str) -> String {
+    let path = dir.join(format!("{}.pem", uuid::Uuid::new_v4()));
+    std::fs::write(&path, contents).unwrap();
+    path.to_string_lossy().into_owned()
+  }
+
+  async fn get(tls: TlsOptions, url: &str) -> Result<String, SendError> {
+    let profile = ClientProfile { tls, ..ClientProfile::default() };
+    let client = Transport::new().unwrap().client_for(&profile).unwrap();
+    let resp = Transport::send(client.get(url), &profile.timeouts).await?;
+    Ok(resp.text().await.unwrap())
+  }
+
+  #[tokio::test]
+  async fn private_ca_is_trusted_only_when_configured() {
+    let pki = Pki::new();
+    let dir = TempDir::new();
+    let url = serve_tls(server_config(&pki, None)).await;
+
+    let err = get(TlsOptions::default(), &url).await.unwrap_err();
+    let reason = err.tls_reason().expect("a TLS failure");
+    assert!(reason.contains("not signed by a trusted CA"), "{reason}");
+
+    let ca_bundle = Some(write_temp(&dir, &pki.ca.pem()));
+    assert_eq!(get(TlsOptions { ca_bundle, ..Default::default() }, &url).await.unwrap(), "ok");
+    assert_eq!(get(TlsOptions { accept_invalid_certs: true, ..Default::default() }, &url).await.unwrap(), "ok");
+
+    let not_pem = Some(write_temp(&dir, "hello"));
+    let profile = ClientProfile {
+      tls: TlsOptions { ca_bundle: not_pem, ..Default::default() },
+      ..ClientProfile::default()
+    };
+    assert!(Transport::new().unwrap().client_for(&profile).is_err());
+  }
+
+  #[tokio::test]
+  async fn client_certificate_satisfies_mtls() {
+    let pki = Pki::new();
+    let dir = TempDir::new();
+    let url = serve_tls(server_config(&pki, Some(&pki.ca))).await;
+    let ca_bundle = Some(write_temp(&dir, &pki.ca.pem()));
+
+    let err = get(TlsOptions { ca_bundle: ca_bundle.clone(), ..Default::default() }, &url).await.unwrap_err();
+    // TLS 1.3 servers reject the missing certificate after the client's handshake is done, so
+    // the alert can lose the race with a connection reset.
+    if let Some(reason) = err.tls_reason() {
+      assert_eq!(reason, "server requires a client certificate");
+    }
+
+    let (cert, key) = pki.issue("downloader", ExtendedKeyUsagePurpose::ClientAuth);
+    let tls = TlsOptions {
+      ca_bundle,
+      client_cert: Some(write_temp(&dir, &cert.pem())),
+      client_key: Some(write_temp(&dir, &key.serialize_pem())),
+      accept_invalid_certs: false,
+    };
+    assert_eq!(get(tls, &url).await.unwrap(), "ok");
+  }
+}
@@ -1,8 +1,14 @@
 use crate::{
   app_state::AppState,
   engine::EngineCommand,
-  model::{AddDownloadsRequest, NewBatchRequest, RulesSnapshot, SettingsSnapshot},
-  transport::Transport,
+  model::{AddDownloadsRequest, DashRepresentation, HttpCredential, NewBatchRequest, ProxyCredentials, ProxyPool, RulesSnapshot, SettingsSnapshot, TransportRule},
+  persistence::{secrets::SecretsStatus, Db},
+  transport::{
+    auth, dns,
+    headers::PreviewHeader,
+    pool::{self, MemberStatus},
+    pattern, proxy, LocalAddress, ProxyRoute, Transport,
+  },
 };
 use tauri::{AppHandle, Manager};
 use tauri_plugin_shell::ShellExt;
@@ -89,6 +95,7 @@ pub async fn cmd_add_downloads(state: tauri::State<'_, AppState>, req: AddDownlo
       batch_id: None,
       forced_proxy: false,
       forced_proxy_url: None,
+      dash: req.dash,
     })
     .await
     .map_err(|e| e.to_string())
@@ -121,6 +128,7 @@ pub async fn cmd_add_batch(state: tauri::State<'_, AppState>, req: NewBatchReque
       batch_id: Some(batch_id.clone()),
       forced_proxy: force_proxy,
       forced_proxy_url,
+      dash: req.dash,
     })
     .await
     .map_err(|e| e.to_string())?;
@@ -340,8 +348,9 @@ pub fn cmd_get_settings(state: tauri::State<AppState>) -> Result<SettingsSnapsho
 }
 
 #[tauri::command]
-pub async fn cmd_set_settings(app: AppHandle, state: tauri::State<'_, AppState>, s: SettingsSnapshot) -> Result<(), String> {
+pub async fn cmd_set_settings(app: AppHandle, state: tauri::State<'_, AppState>, mut s: SettingsSnapshot) -> Result<(), String> {
   let prev = state.settings.get_snapshot().map_err(|e| e.to_string())?;
+  s.global_proxy_url = store_inline_proxy_credentials(&state.db, s.global_proxy_url.take())?;
   state.settings.set_snapshot(&s).map_err(|e| e.to_string())?;
   state
     .engine
@@ -383,12 +392,174 @@ pub fn cmd_upsert_proxy_rule(
   use_proxy: bool,
   proxy_url_override: Option<String>,
 ) -> Result<i64, String> {
+  check_pattern(&pattern)?;
+  let proxy_url_override = store_inline_proxy_credentials(&state.db, proxy_url_override)?;
   state
     .db
     .upsert_proxy_rule(id, &pattern, enabled, use_proxy, proxy_url_override.as_deref())
     .map_err(|e| e.to_string())
 }
 
+/// Rejects patterns that would never match (bad regex, glob or CIDR) when a rule is saved.
+fn check_pattern(raw: &str) -> Result<(), String> {
+  pattern::Pattern::parse(raw).map(|_| ()).map_err(|e| format!("Invalid pattern {raw}: {e:#}"))
+}
+
+/// The video and audio representations a DASH manifest offers, so the add dialog can pick them.
+#[tauri::command]
+pub async fn cmd_probe_dash(state: tauri::State<'_, AppState>, url: String) -> Result<Vec<DashRepresentation>, String> {
+  let url = url::Url::parse(url.trim()).map_err(|e| format!("Invalid URL: {e}"))?;
+  crate::engine::probe_dash(&state.db, &state.settings, state.engine.transport(), &url)
+    .await
+    .map_err(|e| format!("{e:#}"))
+}
+
+/// Which proxy `url` would go through with the saved settings and rules, and why.
+#[tauri::command]
+pub async fn cmd_test_proxy_route(state: tauri::State<'_, AppState>, url: String) -> Result<ProxyRoute, String> {
+  let url = url::Url::parse(url.trim()).map_err(|e| format!("Invalid URL: {e}"))?;
+  let settings = state.settings.get_snapshot().map_err(|e| e.to_string())?;
+  let rules = state.db.list_rules().map_err(|e| e.to_string())?;
+  Ok(state.engine.transport().proxy_route(&settings, &rules, &url).await)
+}
+
+fn store_inline_proxy_credentials(db: &Db, raw: Option<String>) -> Result<Option<String>, String> {
+  let Some(raw) = raw.filter(|v| !v.trim().is_empty()) else {
+    return Ok(None);
+  };
+  db.store_inline_proxy_credentials(&raw).map(Some).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn cmd_upsert_proxy_pool(state: tauri::State<AppState>, pool: ProxyPool) -> Result<i64, String> {
+  if pool.name.trim().is_empty() {
+    return Err("Pool name is empty".to_string());
+  }
+  let mut members = Vec::new();
+  for m in &pool.members {
+    if let Some(bare) = store_inline_proxy_credentials(&state.db, Some(m.clone()))? {
+      if pool::pool_name(&bare).is_some() {
+        return Err("A pool cannot contain another pool".to_string());
+      }
+      members.push(bare);
+    }
+  }
+  let pool = ProxyPool {
+    name: pool.name.trim().to_string(),
+    members,
+    ..pool
+  };
+  let id = state.db.upsert_proxy_pool(&pool).map_err(|e| e.to_string())?;
+  state.engine.transport().pools().forget_configured();
+  Ok(id)
+}
+
+#[tauri::command]
+pub fn cmd_delete_proxy_pool(state: tauri::State<AppState>, id: i64) -> Result<(), String> {
+  state.db.delete_proxy_pool(id).map_err(|e| e.to_string())?;
+  state.engine.transport().pools().forget_configured();
+  Ok(())
+}
+
+/// Runs a health check on every member now and returns their status.
+#[tauri::command]
+pub async fn cmd_check_proxy_pool(state: tauri::State<'_, AppState>, id: i64) -> Result<Vec<MemberStatus>, String> {
+  let rules = state.db.list_rules().map_err(|e| e.to_string())?;
+  let pool = rules
+    .proxy_pools
+    .iter()
+    .find(|p| p.id == id)
+    .ok_or_else(|| "Proxy pool not found".to_string())?;
+  let creds = state.db.list_proxy_credentials().map_err(|e| e.to_string())?;
+  Ok(state.engine.transport().check_pool(pool, &creds).await)
+}
+
+#[tauri::command]
+pub fn cmd_list_proxy_credentials(state: tauri::State<AppState>) -> Result<Vec<ProxyCredentials>, String> {
+  state.db.list_proxy_credentials().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn cmd_set_proxy_credentials(state: tauri::State<AppState>, creds: ProxyCredentials) -> Result<(), String> {
+  let key = proxy::credential_key(&creds.proxy_url).ok_or_else(|| "invalid proxy url".to_string())?;
+  state
+    .db
+    .set_proxy_credentials(&ProxyCredentials { proxy_url: key, ..creds })
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn cmd_delete_proxy_credentials(state: tauri::State<AppState>, proxy_url: String) -> Result<(), String> {
+  let key = proxy::credential_key(&proxy_url).unwrap_or(proxy_url);
+  state.db.delete_proxy_credentials(&key).map_err(|e| e.to_string())
+}
+
+/// Saves a server login; a `None` password keeps the stored one.
+#[tauri::command]
+pub fn cmd_upsert_http_credential(state: tauri::State<AppState>, cred: HttpCredential) -> Result<i64, String> {
+  check_pattern(&cred.pattern)?;
+  if !auth::SCHEMES.contains(&cred.scheme.as_str()) {
+    return Err(format!("Unknown auth scheme {}", cred.scheme));
+  }
+  if let Some(path) = cred.key_path.as_deref().filter(|p| !p.is_empty()) {
+    if !std::path::Path::new(path).is_file() {
+      return Err(format!("Key file {path} does not exist"));
+    }
+  }
+  if let Some(endpoint) = cred.endpoint.as_deref().filter(|e| !e.is_empty()) {
+    crate::transport::s3::parse_endpoint(endpoint).map_err(|e| e.to_string())?;
+  }
+  state.db.upsert_http_credential(&cred).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn cmd_delete_http_credential(state: tauri::State<AppState>, id: i64) -> Result<(), String> {
+  state.db.delete_http_credential(id).map_err(|e| e.to_string())
+}
+
+/// Adds logins from a `.netrc` file (updating ones with the same host and user); `default`
+/// becomes a `*` credential. Returns how many were imported.
+#[tauri::command]
+pub fn cmd_import_netrc(state: tauri::State<AppState>, text: String) -> Result<usize, String> {
+  let entries = auth::parse_netrc(&text);
+  let existing = state.db.list_rules().map_err(|e| e.to_string())?.http_credentials;
+  for e in &entries {
+    let pattern = e.host.clone().unwrap_or_else(|| "*".to_string());
+    let id = existing
+      .iter()
+      .find(|c| c.pattern == pattern && c.username == e.login && c.realm.is_none())
+      .map_or(0, |c| c.id);
+    let cred = HttpCredential {
+      id,
+      pattern,
+      realm: None,
+      scheme: "auto".to_string(),
+      username: e.login.clone(),
+      enabled: true,
+      password: Some(e.password.clone()),
+      key_path: None,
+      endpoint: None,
+    };
+    state.db.upsert_http_credential(&cred).map_err(|e| e.to_string())?;
+  }
+  Ok(entries.len())
+}
+
+/// Merges a Netscape `cookies.txt` into the cookie jar; returns how many cookies it held.
+#[tauri::command]
+pub fn cmd_import_cookies(state: tauri::State<AppState>, text: String) -> Result<usize, String> {
+  let transport = state.engine.transport();
+  let n = transport.cookies().import_netscape(&text).map_err(|e| e.to_string())?;
+  crate::engine::save_cookies(&state.db, transport).map_err(|e| e.to_string())?;
+  Ok(n)
+}
+
+/// The cookie jar in Netscape `cookies.txt` format.
+#[tauri::command]
+pub fn cmd_export_cookies(state: tauri::State<AppState>) -> Result<String, String> {
+  Ok(state.engine.transport().cookies().export_netscape())
+}
+
 #[tauri::command]
 pub fn cmd_delete_proxy_rule(state: tauri::State<AppState>, id: i64) -> Result<(), String> {
   state.db.delete_proxy_rule(id).map_err(|e| e.to_string())
@@ -400,11 +571,70 @@ pub fn cmd_upsert_header_rule(
   id: Option<i64>,
   pattern: String,
   enabled: bool,
+  priority: Option<i64>,
   headers_json: serde_json::Value,
 ) -> Result<i64, String> {
+  check_pattern(&pattern)?;
+  let id = state
+    .db
+    .upsert_header_rule(id, &pattern, enabled, priority.unwrap_or(0), &headers_json)
+    .map_err(|e| e.to_string())?;
+  // Literal tokens in the new rule move to the vault (no-op while it is locked).
+  state.db.migrate_plaintext_secrets().map_err(|e| e.to_string())?;
+  Ok(id)
+}
+
+/// The headers the saved rules would send to `url`, and where each comes from.
+#[tauri::command]
+pub fn cmd_preview_headers(state: tauri::State<AppState>, url: String) -> Result<Vec<PreviewHeader>, String> {
+  let url = url::Url::parse(url.trim()).map_err(|e| format!("Invalid URL: {e}"))?;
+  let rules = state.db.list_rules().map_err(|e| e.to_string())?;
+  Ok(state.engine.transport().preview_headers(&rules, &url))
+}
+
+/// Names of stored secrets; values never leave the backend.
+#[tauri::command]
+pub fn cmd_list_secrets(state: tauri::State<AppState>) -> Result<Vec<String>, String> {
+  Ok(state.db.secret_names())
+}
+
+#[tauri::command]
+pub fn cmd_set_secret(state: tauri::State<AppState>, name: String, value: String) -> Result<(), String> {
+  state.db.set_secret(name.trim(), &value).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn cmd_delete_secret(state: tauri::State<AppState>, name: String) -> Result<(), String> {
+  state.db.delete_secret(&name).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn cmd_secrets_status(state: tauri::State<AppState>) -> Result<SecretsStatus, String> {
+  state.db.secrets_status().map_err(|e| e.to_string())
+}
+
+/// `passphrase` is only needed when a master passphrase is set.
+#[tauri::command]
+pub fn cmd_unlock_secrets(state: tauri::State<AppState>, passphrase: Option<String>) -> Result<(), String> {
+  state.db.unlock_secrets(passphrase.as_deref()).map_err(|e| e.to_string())?;
+  // Sealed cookies could not be read at startup while the vault was locked.
+  let cookies = state.db.list_cookies().map_err(|e| e.to_string())?;
+  state.engine.transport().cookies().restore(cookies);
+  Ok(())
+}
+
+#[tauri::command]
+pub fn cmd_lock_secrets(state: tauri::State<AppState>) -> Result<(), String> {
+  state.db.lock_secrets();
+  Ok(())
+}
+
+/// Sets or changes the master passphrase; `None` goes back to an automatic key file.
+#[tauri::command]
+pub fn cmd_set_master_passphrase(state: tauri::State<AppState>, passphrase: Option<String>) -> Result<(), String> {
   state
     .db
-    .upsert_header_rule(id, &pattern, enabled, &headers_json)
+    .set_master_passphrase(passphrase.as_deref().filter(|p| !p.is_empty()))
     .map_err(|e| e.to_string())
 }
 
@@ -421,6 +651,7 @@ pub fn cmd_upsert_mirror_rule(
   enabled: bool,
   candidates_json: serde_json::Value,
 ) -> Result<i64, String> {
+  check_pattern(&pattern)?;
   state
     .db
     .upsert_mirror_rule(id, &pattern, enabled, &candidates_json)
@@ -432,6 +663,52 @@ pub fn cmd_delete_mirror_rule(state: tauri::State<AppState>, id: i64) -> Result<
   state.db.delete_mirror_rule(id).map_err(|e| e.to_string())
 }
 
+#[tauri::command]
+pub fn cmd_upsert_segment_rule(
+  state: tauri::State<AppState>,
+  id: Option<i64>,
+  pattern: String,
+  enabled: bool,
+  min_size_bytes: Option<i64>,
+  segment_size_bytes: Option<i64>,
+  max_segments: Option<i64>,
+) -> Result<i64, String> {
+  check_pattern(&pattern)?;
+  state
+    .db
+    .upsert_segment_rule(id, &pattern, enabled, min_size_bytes, segment_size_bytes, max_segments)
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn cmd_delete_segment_rule(state: tauri::State<AppState>, id: i64) -> Result<(), String> {
+  state.db.delete_segment_rule(id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn cmd_upsert_transport_rule(state: tauri::State<AppState>, rule: TransportRule) -> Result<i64, String> {
+  check_pattern(&rule.pattern)?;
+  if rule.accept_invalid_certs && pattern::Pattern::parse(&rule.pattern).is_ok_and(|p| p.is_any_host()) {
+    return Err("accepting invalid certificates needs a pattern naming the host, not *".to_string());
+  }
+  if let Some(overrides) = &rule.dns_overrides {
+    dns::parse_overrides(overrides).map_err(|e| format!("Invalid DNS override: {e:#}"))?;
+  }
+  if let Some(local) = &rule.local_address {
+    LocalAddress::parse_list(local).map_err(|e| format!("Invalid local address: {e:#}"))?;
+  }
+  if let Some(doh) = rule.doh_url.as_deref().map(str::trim).filter(|u| !u.is_empty()) {
+    dns::check_doh_url(doh).map_err(|e| format!("{e:#}"))?;
+  }
+  // Transport rules carry many optional overrides, so they travel as one object (id <= 0 inserts).
+  state.db.upsert_transport_rule(&rule).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn cmd_delete_transport_rule(state: tauri::State<AppState>, id: i64) -> Result<(), String> {
+  state.db.delete_transport_rule(id).map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 pub async fn cmd_add_domain_to_proxy_and_retry(state: tauri::State<'_, AppState>, download_id: String, url: String) -> Result<(), String> {
   let host = Transport::url_hostname(&url).ok_or_else(|| "could not parse hostname".to_string())?;
@@ -1,12 +1,13 @@
-import { useEffect, useMemo, useRef, useState } from 'react'
+import { Fragment, useEffect, useMemo, useRef, useState } from 'react'
 import { invoke } from '@tauri-apps/api/core'
 import { listen } from '@tauri-apps/api/event'
 import './App.css'
 import nyanCatUrl from './assets/nyan_cat.png'
-import type { AddDownloadsRequest, DownloadProgressUpdate, DownloadRecord, NewBatchRequest, RulesSnapshot, SettingsSnapshot, UpdateCheckResult } from './types'
+import type { AddDownloadsRequest, DashChoice, DashRepresentation, DownloadProgressUpdate, DownloadRecord, HttpCredential, NewBatchRequest, ProxyCredentials, ProxyMemberStatus, PreviewHeader, ProxyPool, ProxyRoute, RulesSnapshot, SecretsStatus, SegmentRule, SettingsSnapshot, TransportRule, UpdateCheckResult } from './types'
 
 const EVENT_PROGRESS_BATCH = 'zdmr://progress_batch'
 const EVENT_DOWNLOADS_CHANGED = 'zdmr://downloads_changed'
+const MIB = 1024 * 1024
 
 function parseUrlsFromText(text: string): string[] {
   const parts = text
@@ -17,7 +18,7 @@ function parseUrlsFromText(text: string): string[] {
   for (const p of parts) {
     try {
       const u = new URL(p)
-      if (u.protocol === 'http:' || u.protocol === 'https:') out.push(u.toString())
+      if (['http:', 'https:', 'ftp:', 'ftps:', 'sftp:', 'scp:', 's3:'].includes(u.protocol)) out.push(u.toString())
     } catch {
       // ignore
     }
@@ -267,7 +268,7 @@ export default function App() {
                 }}
               >
                 <div className="rowTop">
-                  <div className="filename">
+                  <div className="filename" title={d.proxy_route ?? undefined}>
                     {d.final_filename ?? '(resolving...)'}
                     {d.forced_proxy ? <span className="pill">Proxied</span> : null}
                   </div>
@@ -338,8 +339,8 @@ export default function App() {
           defaultDir={settings.default_download_dir}
           canUseProxy={!!settings.global_proxy_url && settings.global_proxy_url.trim().length > 0}
           onClose={() => setBatchOpen(false)}
-          onStart={(urls, destDir, downloadThroughProxy) => {
-            const req: NewBatchRequest = { name: null, dest_dir: destDir, raw_url_list: urls.join('\n'), urls, download_through_proxy: downloadThroughProxy }
+          onStart={(urls, destDir, downloadThroughProxy, dash) => {
+            const req: NewBatchRequest = { name: null, dest_dir: destDir, raw_url_list: urls.join('\n'), urls, download_through_proxy: downloadThroughProxy, dash }
             invoke<string>('cmd_add_batch', { req })
               .then(() => refreshDownloads())
               .catch((e) => window.alert(String(e)))
@@ -452,10 +453,17 @@ function ContextMenu() {
   )
 }
 
-function BatchModal(props: { defaultDir: string; canUseProxy: boolean; onClose: () => void; onStart: (urls: string[], destDir: string, downloadThroughProxy: boolean) => void }) {
+function BatchModal(props: { defaultDir: string; canUseProxy: boolean; onClose: () => void; onStart: (urls: string[], destDir: string, downloadThroughProxy: boolean, dash: DashChoice) => void }) {
   const [dest, setDest] = useState(props.defaultDir)
   const [text, setText] = useState('')
   const [useProxy, setUseProxy] = useState(false)
+  // Representations offered by the single URL's DASH manifest, once probed.
+  const [reps, setReps] = useState<DashRepresentation[] | null>(null)
+  const [repsError, setRepsError] = useState<string | null>(null)
+  const [dash, setDash] = useState<DashChoice>({ video_id: null, audio_id: null })
+  const urls = parseUrlsFromText(text.replace(/\r/g, '\n')).filter((u) => u.includes('://'))
+  const repLabel = (r: DashRepresentation) =>
+    [r.height ? `${r.height}p` : null, `${Math.round(r.bandwidth / 1000)} kbps`, r.lang, r.mime].filter(Boolean).join(' · ')
   return (
     <div className="modalBackdrop" onMouseDown={props.onClose}>
       <div className="modal" onMouseDown={(e) => e.stopPropagation()}>
@@ -466,8 +474,56 @@ function BatchModal(props: { defaultDir: string; canUseProxy: boolean; onClose:
         </label>
         <label className="field">
           <div className="label">URLs (newline-separated)</div>
-          <textarea value={text} onChange={(e) => setText(e.target.value)} rows={10} />
+          <textarea
+            value={text}
+            onChange={(e) => {
+              setText(e.target.value)
+              setReps(null)
+              setRepsError(null)
+              setDash({ video_id: null, audio_id: null })
+            }}
+            rows={10}
+          />
         </label>
+        {urls.length === 1 && (
+          <div className="field">
+            <div className="label">DASH quality</div>
+            {reps === null ? (
+              <button
+                className="btn"
+                onClick={async () => {
+                  try {
+                    setReps(await invoke<DashRepresentation[]>('cmd_probe_dash', { url: urls[0] }))
+                    setRepsError(null)
+                  } catch (e) {
+                    setRepsError(String(e))
+                  }
+                }}
+              >
+                Choose quality
+              </button>
+            ) : (
+              <div className="rowInline">
+                {(['video', 'audio'] as const).map((kind) => {
+                  const key = kind === 'video' ? 'video_id' : 'audio_id'
+                  return (
+                    <select key={kind} value={dash[key] ?? ''} onChange={(e) => setDash({ ...dash, [key]: e.target.value || null })}>
+                      <option value="">{kind === 'video' ? 'Video' : 'Audio'}: from settings</option>
+                      {reps
+                        .filter((r) => r.kind === kind)
+                        .map((r) => (
+                          <option key={r.id} value={r.id}>
+                            {repLabel(r)}
+                          </option>
+                        ))}
+                    </select>
+                  )
+                })}
+              </div>
+            )}
+            {repsError && <div className="hint">{repsError}</div>}
+          </div>
+        )}
         <label className="field">
           <div className="rowInline">
             <input
@@ -480,6 +536,7 @@ function BatchModal(props: { defaultDir: string; canUseProxy: boolean; onClose:
           </div>
           {!props.canUseProxy && <div className="hint">Set a proxy address in Settings first.</div>}
         </label>
+
         <div className="modalActions">
           <button className="btn" onClick={props.onClose}>
             Cancel
@@ -487,9 +544,8 @@ function BatchModal(props: { defaultDir: string; canUseProxy: boolean; onClose:
           <button
             className="btn primary"
             onClick={() => {
-              const urls = parseUrlsFromText(text.replace(/\r/g, '\n')).filter((u) => u.includes('://'))
               if (urls.length === 0) return
-              props.onStart(urls, dest, useProxy)
+              props.onStart(urls, dest, useProxy, dash)
             }}
           >
             Start
@@ -508,8 +564,32 @@ function SettingsModal(props: {
 }) {
   const [s, setS] = useState<SettingsSnapshot>(props.settings)
   const [r, setR] = useState<RulesSnapshot>(props.rules)
+  const [creds, setCreds] = useState<ProxyCredentials[]>([])
+  const [poolStatus, setPoolStatus] = useState<Record<number, ProxyMemberStatus[]>>({})
+  const [routeUrl, setRouteUrl] = useState('')
+  const [routeResult, setRouteResult] = useState<string | null>(null)
+  const [headerUrl, setHeaderUrl] = useState('')
+  const [headerPreview, setHeaderPreview] = useState<PreviewHeader[] | null>(null)
+  // Stored secrets come back as names only; `value` is what the user typed since.
+  const [secrets, setSecrets] = useState<{ name: string; value: string; saved: boolean }[]>([])
+  const [vault, setVault] = useState<SecretsStatus | null>(null)
+  const [passphrase, setPassphrase] = useState('')
+  const [cookiesText, setCookiesText] = useState('')
+  const [cookiesResult, setCookiesResult] = useState<string | null>(null)
+  const [netrcText, setNetrcText] = useState('')
   const [updateBusy, setUpdateBusy] = useState(false)
 
+  useEffect(() => {
+    invoke<ProxyCredentials[]>('cmd_list_proxy_credentials').then(setCreds).catch(() => {})
+    reloadSecrets().catch(() => {})
+  }, [])
+
+  const reloadSecrets = async () => {
+    setVault(await invoke<SecretsStatus>('cmd_secrets_status'))
+    const names = await invoke<string[]>('cmd_list_secrets')
+    setSecrets(names.map((name) => ({ name, value: '', saved: true })))
+  }
+
   return (
     <div className="modalBackdrop" onMouseDown={props.onClose}>
       <div className="modal wide" onMouseDown={(e) => e.stopPropagation()}>
@@ -592,16 +672,145 @@ function SettingsModal(props: {
                 <span>Enable</span>
               </label>
               <input
-                placeholder="http://127.0.0.1:7890"
+                placeholder="http://127.0.0.1:7890 or socks5h://127.0.0.1:1080"
                 value={s.global_proxy_url ?? ''}
                 onChange={(e) => setS({ ...s, global_proxy_url: e.target.value })}
               />
             </div>
-            <div className="hint">Proxy rules are allowlist-based: only matching domains use the proxy.</div>
+            <div className="hint">
+              Supports http, https, socks4, socks4a, socks5 and socks5h
+              (socks5h resolves names on the proxy). A user:password@ in the address is moved to Proxy credentials on save.
+            </div>
+          </div>
+
+          <div className="field">
+            <div className="label">Proxy mode</div>
+            <div className="rowInline">
+              <select value={s.proxy_mode} onChange={(e) => setS({ ...s, proxy_mode: e.target.value as SettingsSnapshot['proxy_mode'] })}>
+                <option value="allowlist">Proxy rules (allowlist)</option>
+                <option value="denylist">Everything except rules (denylist)</option>
+                <option value="all">Everything</option>
+                <option value="none">Nothing</option>
+                <option value="pac">PAC file</option>
+              </select>
+              <input
+                placeholder="/path/to/proxy.pac or http://wpad.corp/wpad.dat"
+                disabled={s.proxy_mode !== 'pac'}
+                value={s.pac_source ?? ''}
+                onChange={(e) => setS({ ...s, pac_source: e.target.value })}
+              />
+            </div>
+            <div className="hint">
+              Allowlist proxies only hosts with a matching “use proxy” rule; denylist proxies everything except hosts with a matching rule
+              that turns it off. In PAC mode the script picks DIRECT, PROXY or SOCKS per host. Hover a download’s name to see the route it
+              took.
+            </div>
+            <div className="rowInline">
+              <input placeholder="https://example.com/file.zip" value={routeUrl} onChange={(e) => setRouteUrl(e.target.value)} />
+              <button
+                className="btn"
+                disabled={!routeUrl.trim()}
+                onClick={async () => {
+                  try {
+                    const r = await invoke<ProxyRoute>('cmd_test_proxy_route', { url: routeUrl });
+                    setRouteResult(r.explanation);
+                  } catch (e) {
+                    setRouteResult(String(e));
+                  }
+                }}
+              >
+                Test route
+              </button>
+            </div>
+            {routeResult && <div className="hint">{routeResult} (uses saved settings)</div>}
+          </div>
+
+          <div className="field">
+            <div className="label">Multipart downloads</div>
+            <div className="rowInline">
+              <input
+                title="Minimum size (MiB)"
+                value={Math.floor(s.multipart_min_size_bytes / MIB)}
+                onChange={(e) => setS({ ...s, multipart_min_size_bytes: Math.max(0, parseInt(e.target.value || '0', 10)) * MIB })}
+              />
+              <input
+                title="Segment size (MiB)"
+                value={Math.floor(s.multipart_segment_size_bytes / MIB)}
+                onChange={(e) => setS({ ...s, multipart_segment_size_bytes: Math.max(1, parseInt(e.target.value || '1', 10)) * MIB })}
+              />
+              <input
+                title="Max segments"
+                value={s.multipart_max_segments}
+                onChange={(e) => setS({ ...s, multipart_max_segments: Math.max(1, parseInt(e.target.value || '1', 10)) })}
+              />
+            </div>
+            <div className="hint">Minimum size (MiB), segment size (MiB), max segments. Segment rules override these per host.</div>
+          </div>
+
+          <div className="field">
+            <div className="label">Timeouts (seconds)</div>
+            <div className="rowInline">
+              <input
+                title="Connect"
+                value={s.connect_timeout_secs}
+                onChange={(e) => setS({ ...s, connect_timeout_secs: Math.max(1, parseInt(e.target.value || '1', 10)) })}
+              />
+              <input
+                title="Response headers"
+                value={s.response_timeout_secs}
+                onChange={(e) => setS({ ...s, response_timeout_secs: Math.max(1, parseInt(e.target.value || '1', 10)) })}
+              />
+              <input
+                title="Read idle"
+                value={s.read_idle_timeout_secs}
+                onChange={(e) => setS({ ...s, read_idle_timeout_secs: Math.max(1, parseInt(e.target.value || '1', 10)) })}
+              />
+            </div>
+            <div className="hint">Connect, response headers, and idle time between reads. There is no limit on total download time.</div>
+          </div>
+
+          <label className="field">
+            <div className="label">Stream quality (HLS / DASH)</div>
+            <input
+              placeholder="highest"
+              value={s.stream_variant}
+              onChange={(e) => setS({ ...s, stream_variant: e.target.value })}
+            />
+            <div className="hint">
+              Which variant of a multi-quality .m3u8 playlist, or video track of an .mpd manifest, to save: highest, lowest, a height cap
+              like 720p, or a bitrate cap like 3000k.
+            </div>
+          </label>
+
+          <div className="field">
+            <div className="label">Stream audio (DASH)</div>
+            <div className="rowInline">
+              <input
+                title="Audio track"
+                placeholder="highest"
+                value={s.stream_audio}
+                onChange={(e) => setS({ ...s, stream_audio: e.target.value })}
+              />
+              <input
+                title="Language"
+                placeholder="any language"
+                value={s.stream_audio_language ?? ''}
+                onChange={(e) => setS({ ...s, stream_audio_language: e.target.value || null })}
+              />
+            </div>
+            <div className="hint">
+              Audio track of an .mpd manifest: highest, lowest, a bitrate cap like 128k, or none; and a preferred language like en. Video and
+              audio are saved as separate track files next to a simple concatenation of both.
+            </div>
           </div>
         </div>
 
         <div className="sectionTitle">Proxy rules</div>
+        <div className="hint">
+          Patterns in every rule table: example.com, *.example.com, 10.0.0.0/8 or fd00::/8, optionally with a scheme, :port or path prefix
+          (https://example.com:8443/releases/); a glob over the whole URL (https://cdn?.example.com/*.iso); or re: followed by a regex.
+          The most specific match wins: regex, then glob, then exact host, IP range, *.domain, *.
+        </div>
         <div className="table">
           <div className="thead">
             <div>Pattern</div>
@@ -679,17 +888,193 @@ function SettingsModal(props: {
           Add proxy rule
         </button>
 
+        <div className="sectionTitle">Proxy credentials</div>
+        <div className="table">
+          <div className="thead" style={{ gridTemplateColumns: '1fr 1fr 1fr 80px 80px' }}>
+            <div>Proxy</div>
+            <div>Username</div>
+            <div>Password</div>
+            <div />
+            <div />
+          </div>
+          {creds.map((c, i) => {
+            const update = (patch: Partial<ProxyCredentials>) => setCreds(creds.map((x, j) => (j === i ? { ...x, ...patch } : x)))
+            return (
+              <div key={i} className="trow" style={{ gridTemplateColumns: '1fr 1fr 1fr 80px 80px' }}>
+                <input placeholder="socks5h://proxy.lan:1080" value={c.proxy_url} onChange={(e) => update({ proxy_url: e.target.value })} />
+                <input value={c.username} onChange={(e) => update({ username: e.target.value })} />
+                <input
+                  type="password"
+                  placeholder="(unchanged)"
+                  value={c.password ?? ''}
+                  onChange={(e) => update({ password: e.target.value === '' ? null : e.target.value })}
+                />
+                <button
+                  className="btn"
+                  onClick={async () => {
+                    await invoke('cmd_delete_proxy_credentials', { proxyUrl: c.proxy_url })
+                    setCreds(await invoke<ProxyCredentials[]>('cmd_list_proxy_credentials'))
+                  }}
+                >
+                  Delete
+                </button>
+                <button
+                  className="btn primary"
+                  onClick={async () => {
+                    try {
+                      await invoke('cmd_set_proxy_credentials', { creds: c })
+                      setCreds(await invoke<ProxyCredentials[]>('cmd_list_proxy_credentials'))
+                    } catch (e) {
+                      window.alert(String(e))
+                    }
+                  }}
+                >
+                  Save
+                </button>
+              </div>
+            )
+          })}
+        </div>
+        <button className="btn" onClick={() => setCreds([{ proxy_url: '', username: '', password: null }, ...creds])}>
+          Add proxy credentials
+        </button>
+
+        <div className="sectionTitle">Proxy pools</div>
+        <div className="table">
+          <div className="thead" style={{ gridTemplateColumns: '120px 70px 130px 70px 80px 2fr 1fr 70px 80px 80px 80px' }}>
+            <div>Name</div>
+            <div>Enabled</div>
+            <div>Selection</div>
+            <div>DIRECT fallback</div>
+            <div>Rotate segments</div>
+            <div>Members (one per line)</div>
+            <div>Health check URL</div>
+            <div>Every s</div>
+            <div />
+            <div />
+            <div />
+          </div>
+          {(r.proxy_pools ?? []).map((pp) => {
+            const update = (patch: Partial<ProxyPool>) =>
+              setR({ ...r, proxy_pools: r.proxy_pools.map((x) => (x.id === pp.id ? { ...x, ...patch } : x)) })
+            const status = poolStatus[pp.id]
+            return (
+              <div key={pp.id}>
+                <div className="trow" style={{ gridTemplateColumns: '120px 70px 130px 70px 80px 2fr 1fr 70px 80px 80px 80px' }}>
+                  <input value={pp.name} onChange={(e) => update({ name: e.target.value })} />
+                  <input type="checkbox" checked={pp.enabled} onChange={(e) => update({ enabled: e.target.checked })} />
+                  <select value={pp.strategy} onChange={(e) => update({ strategy: e.target.value as ProxyPool['strategy'] })}>
+                    <option value="round_robin">Round robin</option>
+                    <option value="least_latency">Least latency</option>
+                  </select>
+                  <input type="checkbox" checked={pp.allow_direct} onChange={(e) => update({ allow_direct: e.target.checked })} />
+                  <input type="checkbox" checked={pp.rotate_per_segment} onChange={(e) => update({ rotate_per_segment: e.target.checked })} />
+                  <textarea
+                    rows={2}
+                    value={pp.members.join('\n')}
+                    onChange={(e) => update({ members: e.target.value.split('\n') })}
+                  />
+                  <input
+                    placeholder="(TCP connect)"
+                    value={pp.health_check_url ?? ''}
+                    onChange={(e) => update({ health_check_url: e.target.value.trim() === '' ? null : e.target.value })}
+                  />
+                  <input
+                    value={pp.health_check_interval_secs}
+                    onChange={(e) => update({ health_check_interval_secs: Math.max(0, parseInt(e.target.value || '0', 10)) })}
+                  />
+                  <button
+                    className="btn"
+                    disabled={pp.id < 0}
+                    onClick={async () => {
+                      const st = await invoke<ProxyMemberStatus[]>('cmd_check_proxy_pool', { id: pp.id })
+                      setPoolStatus({ ...poolStatus, [pp.id]: st })
+                    }}
+                  >
+                    Check
+                  </button>
+                  <button
+                    className="btn"
+                    onClick={async () => {
+                      if (pp.id < 0) {
+                        setR({ ...r, proxy_pools: r.proxy_pools.filter((x) => x.id !== pp.id) })
+                      } else {
+                        await invoke('cmd_delete_proxy_pool', { id: pp.id })
+                        const rr = await invoke<RulesSnapshot>('cmd_list_rules')
+                        setR(rr)
+                      }
+                    }}
+                  >
+                    Delete
+                  </button>
+                  <button
+                    className="btn primary"
+                    onClick={async () => {
+                      try {
+                        await invoke('cmd_upsert_proxy_pool', {
+                          pool: { ...pp, members: pp.members.map((m) => m.trim()).filter((m) => m.length > 0) },
+                        })
+                        const rr = await invoke<RulesSnapshot>('cmd_list_rules')
+                        setR(rr)
+                      } catch (e) {
+                        window.alert(String(e))
+                      }
+                    }}
+                  >
+                    Save
+                  </button>
+                </div>
+                {status ? (
+                  <div className="hint">
+                    {status
+                      .map((m) =>
+                        `${m.proxy_url}: ${m.healthy === null ? 'unchecked' : m.healthy ? `up${m.latency_ms != null ? ` ${m.latency_ms} ms` : ''}` : `down (${m.last_error ?? 'error'})`}`,
+                      )
+                      .join(' · ')}
+                  </div>
+                ) : null}
+              </div>
+            )
+          })}
+        </div>
+        <button
+          className="btn"
+          onClick={() =>
+            setR({
+              ...r,
+              proxy_pools: [
+                {
+                  id: -Date.now(),
+                  name: 'pool',
+                  enabled: true,
+                  strategy: 'round_robin',
+                  allow_direct: false,
+                  rotate_per_segment: false,
+                  health_check_url: null,
+                  health_check_interval_secs: 60,
+                  members: [],
+                },
+                ...(r.proxy_pools ?? []),
+              ],
+            })
+          }
+        >
+          Add proxy pool
+        </button>
+        <div className="hint">Use a pool anywhere a proxy address is accepted as pool://name. Unreachable members are skipped until they pass a health check.</div>
+
         <div className="sectionTitle">Header rules</div>
         <div className="table">
-          <div className="thead" style={{ gridTemplateColumns: '1fr 80px 1fr 80px 80px' }}>
+          <div className="thead" style={{ gridTemplateColumns: '1fr 80px 80px 1fr 80px 80px' }}>
             <div>Pattern</div>
             <div>Enabled</div>
+            <div>Priority</div>
             <div>headers_json</div>
             <div />
             <div />
           </div>
           {r.header_rules.map((hr) => (
-            <div key={hr.id} className="trow" style={{ gridTemplateColumns: '1fr 80px 1fr 80px 80px' }}>
+            <div key={hr.id} className="trow" style={{ gridTemplateColumns: '1fr 80px 80px 1fr 80px 80px' }}>
               <input
                 value={hr.pattern}
                 onChange={(e) => setR({ ...r, header_rules: r.header_rules.map((x) => (x.id === hr.id ? { ...x, pattern: e.target.value } : x)) })}
@@ -699,6 +1084,13 @@ function SettingsModal(props: {
                 checked={hr.enabled}
                 onChange={(e) => setR({ ...r, header_rules: r.header_rules.map((x) => (x.id === hr.id ? { ...x, enabled: e.target.checked } : x)) })}
               />
+              <input
+                title="Matching rules apply from lowest to highest priority; later ones win"
+                value={hr.priority}
+                onChange={(e) =>
+                  setR({ ...r, header_rules: r.header_rules.map((x) => (x.id === hr.id ? { ...x, priority: parseInt(e.target.value || '0', 10) || 0 } : x)) })
+                }
+              />
               <textarea
                 className="json"
                 value={JSON.stringify(hr.headers_json, null, 2)}
@@ -733,6 +1125,7 @@ function SettingsModal(props: {
                     id: hr.id > 0 ? hr.id : null,
                     pattern: hr.pattern,
                     enabled: hr.enabled,
+                    priority: hr.priority,
                     headers_json: hr.headers_json,
                   })
                   const rr = await invoke<RulesSnapshot>('cmd_list_rules')
@@ -750,7 +1143,7 @@ function SettingsModal(props: {
             setR({
               ...r,
               header_rules: [
-                { id: -Date.now(), pattern: 'example.com', enabled: true, headers_json: { headers: { 'User-Agent': { value: 'Z-DMR', mode: 'override' } } } },
+                { id: -Date.now(), pattern: 'example.com', enabled: true, priority: 0, headers_json: { headers: { 'User-Agent': { value: 'Z-DMR', mode: 'override' } } } },
                 ...r.header_rules,
               ],
             })
@@ -758,6 +1151,205 @@ function SettingsModal(props: {
         >
           Add header rule
         </button>
+        <div className="hint">
+          Every matching rule applies, lowest priority first (equal priorities: less specific pattern first). Modes: override, add_if_missing,
+          remove, append.
+        </div>
+        <div className="rowInline">
+          <input placeholder="https://example.com/file.zip" value={headerUrl} onChange={(e) => setHeaderUrl(e.target.value)} />
+          <button
+            className="btn"
+            disabled={!headerUrl.trim()}
+            onClick={async () => {
+              try {
+                setHeaderPreview(await invoke<PreviewHeader[]>('cmd_preview_headers', { url: headerUrl }))
+              } catch (e) {
+                setHeaderPreview([{ name: 'error', value: String(e), source: '' }])
+              }
+            }}
+          >
+            Preview headers
+          </button>
+        </div>
+        {headerPreview && (
+          <div className="hint">
+            {headerPreview.length === 0
+              ? 'No headers added.'
+              : headerPreview.map((p) => (
+                  <div key={p.name}>
+                    {p.name}: {p.value} {p.source && <span>({p.source})</span>}
+                  </div>
+                ))}
+          </div>
+        )}
+
+        <div className="sectionTitle">Secrets</div>
+        {vault && (
+          <div className="rowInline">
+            <span>
+              {vault.unlocked ? 'Unlocked' : 'Locked'} ({vault.mode === 'passphrase' ? 'master passphrase' : 'key file'})
+            </span>
+            <input
+              type="password"
+              placeholder={vault.unlocked ? 'New master passphrase' : 'Master passphrase'}
+              value={passphrase}
+              onChange={(e) => setPassphrase(e.target.value)}
+            />
+            {vault.unlocked ? (
+              <>
+                <button
+                  className="btn"
+                  disabled={!passphrase}
+                  onClick={async () => {
+                    try {
+                      await invoke('cmd_set_master_passphrase', { passphrase })
+                      setPassphrase('')
+                      await reloadSecrets()
+                    } catch (e) {
+                      window.alert(String(e))
+                    }
+                  }}
+                >
+                  Set passphrase
+                </button>
+                {vault.mode === 'passphrase' && (
+                  <>
+                    <button
+                      className="btn"
+                      onClick={async () => {
+                        await invoke('cmd_set_master_passphrase', { passphrase: null })
+                        await reloadSecrets()
+                      }}
+                    >
+                      Remove passphrase
+                    </button>
+                    <button
+                      className="btn"
+                      onClick={async () => {
+                        await invoke('cmd_lock_secrets')
+                        await reloadSecrets()
+                      }}
+                    >
+                      Lock
+                    </button>
+                  </>
+                )}
+              </>
+            ) : (
+              <button
+                className="btn primary"
+                disabled={vault.mode === 'passphrase' && !passphrase}
+                onClick={async () => {
+                  try {
+                    await invoke('cmd_unlock_secrets', { passphrase })
+                    setPassphrase('')
+                    await reloadSecrets()
+                  } catch (e) {
+                    window.alert(String(e))
+                  }
+                }}
+              >
+                Unlock
+              </button>
+            )}
+          </div>
+        )}
+        <div className="table">
+          <div className="thead" style={{ gridTemplateColumns: '1fr 1fr 80px 80px' }}>
+            <div>Name</div>
+            <div>Value</div>
+            <div />
+            <div />
+          </div>
+          {secrets.map((sc, i) => {
+            const update = (patch: Partial<typeof sc>) => setSecrets(secrets.map((x, j) => (j === i ? { ...x, ...patch } : x)))
+            return (
+              <div key={i} className="trow" style={{ gridTemplateColumns: '1fr 1fr 80px 80px' }}>
+                <input value={sc.name} disabled={sc.saved} placeholder="hf_token" onChange={(e) => update({ name: e.target.value })} />
+                <input
+                  type="password"
+                  placeholder={sc.saved ? '(unchanged)' : ''}
+                  value={sc.value}
+                  onChange={(e) => update({ value: e.target.value })}
+                />
+                <button
+                  className="btn"
+                  onClick={async () => {
+                    if (sc.saved) {
+                      await invoke('cmd_delete_secret', { name: sc.name })
+                      await reloadSecrets()
+                    } else {
+                      setSecrets(secrets.filter((_, j) => j !== i))
+                    }
+                  }}
+                >
+                  Delete
+                </button>
+                <button
+                  className="btn primary"
+                  disabled={!sc.value}
+                  onClick={async () => {
+                    try {
+                      await invoke('cmd_set_secret', { name: sc.name, value: sc.value })
+                      await reloadSecrets()
+                    } catch (e) {
+                      window.alert(String(e))
+                    }
+                  }}
+                >
+                  Save
+                </button>
+              </div>
+            )
+          })}
+        </div>
+        <button className="btn" onClick={() => setSecrets([{ name: '', value: '', saved: false }, ...secrets])}>
+          Add secret
+        </button>
+        <div className="hint">
+          Header values can use $&#123;secret:name&#125;, $&#123;env:NAME&#125;, $&#123;host&#125;, $&#123;url&#125; and $&#123;date&#125;. Secrets are
+          stored encrypted, separately from rules,
+          together with proxy passwords and the local API token. With a master passphrase they stay locked (and downloads that
+          need them fail) until unlocked here.
+        </div>
+
+        <div className="sectionTitle">Cookies</div>
+        <textarea
+          className="json"
+          rows={4}
+          placeholder="Paste a Netscape cookies.txt exported from your browser"
+          value={cookiesText}
+          onChange={(e) => setCookiesText(e.target.value)}
+        />
+        <div className="rowInline">
+          <button
+            className="btn primary"
+            disabled={!cookiesText.trim()}
+            onClick={async () => {
+              try {
+                const n = await invoke<number>('cmd_import_cookies', { text: cookiesText })
+                setCookiesResult(`Imported ${n} cookies`)
+                setCookiesText('')
+              } catch (e) {
+                setCookiesResult(String(e))
+              }
+            }}
+          >
+            Import
+          </button>
+          <button
+            className="btn"
+            onClick={async () => {
+              setCookiesText(await invoke<string>('cmd_export_cookies'))
+              setCookiesResult(null)
+            }}
+          >
+            Export
+          </button>
+        </div>
+        <div className="hint">
+          {cookiesResult ?? 'Cookies are sent to matching hosts and updated from Set-Cookie; a Cookie header rule replaces them for that request.'}
+        </div>
 
         <div className="sectionTitle">Mirror rules</div>
         <div className="table">
@@ -836,6 +1428,409 @@ function SettingsModal(props: {
           Add mirror rule
         </button>
 
+        <div className="sectionTitle">Segment rules</div>
+        <div className="table">
+          <div className="thead" style={{ gridTemplateColumns: '1fr 80px 100px 100px 100px 80px 80px' }}>
+            <div>Pattern</div>
+            <div>Enabled</div>
+            <div>Min MiB</div>
+            <div>Segment MiB</div>
+            <div>Max segs</div>
+            <div />
+            <div />
+          </div>
+          {r.segment_rules.map((sr) => {
+            const update = (patch: Partial<SegmentRule>) =>
+              setR({ ...r, segment_rules: r.segment_rules.map((x) => (x.id === sr.id ? { ...x, ...patch } : x)) })
+            // Empty means "use the global setting".
+            const mib = (v: string) => (v.trim() === '' ? null : Math.max(0, parseInt(v, 10) || 0) * MIB)
+            return (
+              <div key={sr.id} className="trow" style={{ gridTemplateColumns: '1fr 80px 100px 100px 100px 80px 80px' }}>
+                <input value={sr.pattern} onChange={(e) => update({ pattern: e.target.value })} />
+                <input type="checkbox" checked={sr.enabled} onChange={(e) => update({ enabled: e.target.checked })} />
+                <input
+                  placeholder="global"
+                  value={sr.min_size_bytes == null ? '' : Math.floor(sr.min_size_bytes / MIB)}
+                  onChange={(e) => update({ min_size_bytes: mib(e.target.value) })}
+                />
+                <input
+                  placeholder="global"
+                  value={sr.segment_size_bytes == null ? '' : Math.floor(sr.segment_size_bytes / MIB)}
+                  onChange={(e) => update({ segment_size_bytes: mib(e.target.value) })}
+                />
+                <input
+                  placeholder="global"
+                  value={sr.max_segments ?? ''}
+                  onChange={(e) => update({ max_segments: e.target.value.trim() === '' ? null : Math.max(1, parseInt(e.target.value, 10) || 1) })}
+                />
+                <button
+                  className="btn"
+                  onClick={async () => {
+                    if (sr.id < 0) {
+                      setR({ ...r, segment_rules: r.segment_rules.filter((x) => x.id !== sr.id) })
+                    } else {
+                      await invoke('cmd_delete_segment_rule', { id: sr.id })
+                      const rr = await invoke<RulesSnapshot>('cmd_list_rules')
+                      setR(rr)
+                    }
+                  }}
+                >
+                  Delete
+                </button>
+                <button
+                  className="btn primary"
+                  onClick={async () => {
+                    await invoke('cmd_upsert_segment_rule', {
+                      id: sr.id > 0 ? sr.id : null,
+                      pattern: sr.pattern,
+                      enabled: sr.enabled,
+                      min_size_bytes: sr.min_size_bytes,
+                      segment_size_bytes: sr.segment_size_bytes,
+                      max_segments: sr.max_segments,
+                    })
+                    const rr = await invoke<RulesSnapshot>('cmd_list_rules')
+                    setR(rr)
+                  }}
+                >
+                  Save
+                </button>
+              </div>
+            )
+          })}
+        </div>
+        <button
+          className="btn"
+          onClick={() =>
+            setR({
+              ...r,
+              segment_rules: [
+                { id: -Date.now(), pattern: 'example.com', enabled: true, min_size_bytes: null, segment_size_bytes: null, max_segments: 4 },
+                ...r.segment_rules,
+              ],
+            })
+          }
+        >
+          Add segment rule
+        </button>
+
+        <div className="sectionTitle">Server logins</div>
+        <div className="table">
+          <div className="thead" style={{ gridTemplateColumns: '1fr 120px 100px 1fr 1fr 1fr 70px 80px 80px' }}>
+            <div>Pattern</div>
+            <div>Realm / region</div>
+            <div>Scheme</div>
+            <div>User / access key</div>
+            <div>Password / token</div>
+            <div>Key file / endpoint</div>
+            <div>Enabled</div>
+            <div />
+            <div />
+          </div>
+          {(r.http_credentials ?? []).map((hc) => {
+            const update = (patch: Partial<HttpCredential>) =>
+              setR({ ...r, http_credentials: r.http_credentials.map((x) => (x.id === hc.id ? { ...x, ...patch } : x)) })
+            return (
+              <div key={hc.id} className="trow" style={{ gridTemplateColumns: '1fr 120px 100px 1fr 1fr 1fr 70px 80px 80px' }}>
+                <input value={hc.pattern} onChange={(e) => update({ pattern: e.target.value })} />
+                <input placeholder={hc.scheme === 'aws-sigv4' ? 'us-east-1' : 'any'} value={hc.realm ?? ''} onChange={(e) => update({ realm: e.target.value || null })} />
+                <select value={hc.scheme} onChange={(e) => update({ scheme: e.target.value as HttpCredential['scheme'] })}>
+                  <option value="auto">Auto</option>
+                  <option value="basic">Basic</option>
+                  <option value="digest">Digest</option>
+                  <option value="bearer">Bearer</option>
+                  <option value="ssh-key">SSH key</option>
+                  <option value="aws-sigv4">AWS SigV4 (S3)</option>
+                </select>
+                <input value={hc.username} disabled={hc.scheme === 'bearer'} onChange={(e) => update({ username: e.target.value })} />
+                <input
+                  type="password"
+                  placeholder={hc.id > 0 ? '(unchanged)' : hc.scheme === 'ssh-key' ? 'passphrase, or the key itself' : ''}
+                  value={hc.password ?? ''}
+                  onChange={(e) => update({ password: e.target.value })}
+                />
+                {hc.scheme === 'aws-sigv4' ? (
+                  <input
+                    placeholder="AWS, or http://localhost:9000"
+                    value={hc.endpoint ?? ''}
+                    onChange={(e) => update({ endpoint: e.target.value || null })}
+                  />
+                ) : (
+                  <input
+                    placeholder="/home/me/.ssh/id_ed25519"
+                    disabled={hc.scheme !== 'ssh-key'}
+                    value={hc.key_path ?? ''}
+                    onChange={(e) => update({ key_path: e.target.value || null })}
+                  />
+                )}
+                <input type="checkbox" checked={hc.enabled} onChange={(e) => update({ enabled: e.target.checked })} />
+                <button
+                  className="btn"
+                  onClick={async () => {
+                    if (hc.id < 0) {
+                      setR({ ...r, http_credentials: r.http_credentials.filter((x) => x.id !== hc.id) })
+                    } else {
+                      await invoke('cmd_delete_http_credential', { id: hc.id })
+                      setR(await invoke<RulesSnapshot>('cmd_list_rules'))
+                    }
+                  }}
+                >
+                  Delete
+                </button>
+                <button
+                  className="btn primary"
+                  onClick={async () => {
+                    try {
+                      await invoke('cmd_upsert_http_credential', {
+                        cred: { ...hc, id: hc.id > 0 ? hc.id : 0, password: hc.password || null },
+                      })
+                      setR(await invoke<RulesSnapshot>('cmd_list_rules'))
+                    } catch (e) {
+                      window.alert(String(e))
+                    }
+                  }}
+                >
+                  Save
+                </button>
+              </div>
+            )
+          })}
+        </div>
+        <div className="rowInline">
+          <button
+            className="btn"
+            onClick={() =>
+              setR({
+                ...r,
+                http_credentials: [
+                  { id: -Date.now(), pattern: 'files.example.com', realm: null, scheme: 'auto', username: '', enabled: true, key_path: null, endpoint: null },
+                  ...(r.http_credentials ?? []),
+                ],
+              })
+            }
+          >
+            Add login
+          </button>
+          <textarea rows={2} placeholder="Paste a .netrc to import its logins" value={netrcText} onChange={(e) => setNetrcText(e.target.value)} />
+          <button
+            className="btn"
+            disabled={!netrcText.trim()}
+            onClick={async () => {
+              try {
+                const n = await invoke<number>('cmd_import_netrc', { text: netrcText })
+                setNetrcText('')
+                setR(await invoke<RulesSnapshot>('cmd_list_rules'))
+                window.alert(`Imported ${n} logins`)
+              } catch (e) {
+                window.alert(String(e))
+              }
+            }}
+          >
+            Import .netrc
+          </button>
+        </div>
+        <div className="hint">
+          Used when a server answers 401: Digest is preferred over Basic, and an empty realm answers any realm. Bearer tokens are sent
+          up front. Passwords are stored in the secrets vault.
+        </div>
+
+        <div className="sectionTitle">Transport rules</div>
+        <div className="table">
+          <div className="thead" style={{ gridTemplateColumns: '1fr 70px 1fr 100px 80px 80px 100px 90px 70px 70px 70px 80px 80px' }}>
+            <div>Pattern</div>
+            <div>Enabled</div>
+            <div>User agent</div>
+            <div>HTTP</div>
+            <div>Redirects</div>
+            <div>Same host only</div>
+            <div>IP</div>
+            <div>Decode content</div>
+            <div>Connect s</div>
+            <div>Response s</div>
+            <div>Idle s</div>
+            <div />
+            <div />
+          </div>
+          {r.transport_rules.map((tr) => {
+            const update = (patch: Partial<TransportRule>) =>
+              setR({ ...r, transport_rules: r.transport_rules.map((x) => (x.id === tr.id ? { ...x, ...patch } : x)) })
+            // Empty means "use the global setting".
+            const secs = (v: string) => (v.trim() === '' ? null : Math.max(1, parseInt(v, 10) || 1))
+            // Paths and DNS fields are trimmed by the backend; empty means "not set".
+            const path = (v: string) => (v.trim() === '' ? null : v)
+            return (
+              <Fragment key={tr.id}>
+                <div className="trow" style={{ gridTemplateColumns: '1fr 70px 1fr 100px 80px 80px 100px 90px 70px 70px 70px 80px 80px' }}>
+                  <input value={tr.pattern} onChange={(e) => update({ pattern: e.target.value })} />
+                  <input type="checkbox" checked={tr.enabled} onChange={(e) => update({ enabled: e.target.checked })} />
+                  <input
+                    placeholder="Z-DMR/0.1"
+                    value={tr.user_agent ?? ''}
+                    onChange={(e) => update({ user_agent: e.target.value.trim() === '' ? null : e.target.value })}
+                  />
+                  <select
+                    value={tr.http_version ?? 'http1'}
+                    onChange={(e) => update({ http_version: e.target.value as TransportRule['http_version'] })}
+                  >
+                    <option value="http1">HTTP/1.1</option>
+                    <option value="http2">HTTP/2</option>
+                  </select>
+                  <input
+                    placeholder="10"
+                    value={tr.max_redirects ?? ''}
+                    onChange={(e) => update({ max_redirects: e.target.value.trim() === '' ? null : Math.max(0, parseInt(e.target.value, 10) || 0) })}
+                  />
+                  <input
+                    type="checkbox"
+                    checked={tr.block_cross_host_redirects}
+                    onChange={(e) => update({ block_cross_host_redirects: e.target.checked })}
+                  />
+                  <select
+                    value={tr.ip_preference ?? ''}
+                    onChange={(e) => update({ ip_preference: e.target.value === '' ? null : (e.target.value as TransportRule['ip_preference']) })}
+                  >
+                    <option value="">Auto</option>
+                    <option value="ipv4">IPv4</option>
+                    <option value="ipv6">IPv6</option>
+                  </select>
+                  <input type="checkbox" checked={tr.decode_content} onChange={(e) => update({ decode_content: e.target.checked })} />
+                  <input
+                    placeholder="global"
+                    value={tr.connect_timeout_secs ?? ''}
+                    onChange={(e) => update({ connect_timeout_secs: secs(e.target.value) })}
+                  />
+                  <input
+                    placeholder="global"
+                    value={tr.response_timeout_secs ?? ''}
+                    onChange={(e) => update({ response_timeout_secs: secs(e.target.value) })}
+                  />
+                  <input
+                    placeholder="global"
+                    value={tr.read_idle_timeout_secs ?? ''}
+                    onChange={(e) => update({ read_idle_timeout_secs: secs(e.target.value) })}
+                  />
+                  <button
+                    className="btn"
+                    onClick={async () => {
+                      if (tr.id < 0) {
+                        setR({ ...r, transport_rules: r.transport_rules.filter((x) => x.id !== tr.id) })
+                      } else {
+                        await invoke('cmd_delete_transport_rule', { id: tr.id })
+                        const rr = await invoke<RulesSnapshot>('cmd_list_rules')
+                        setR(rr)
+                      }
+                    }}
+                  >
+                    Delete
+                  </button>
+                  <button
+                    className="btn primary"
+                    onClick={async () => {
+                      try {
+                        await invoke('cmd_upsert_transport_rule', { rule: tr })
+                        const rr = await invoke<RulesSnapshot>('cmd_list_rules')
+                        setR(rr)
+                      } catch (e) {
+                        window.alert(String(e))
+                      }
+                    }}
+                  >
+                    Save
+                  </button>
+                </div>
+                <div className="rowInline">
+                  <input
+                    placeholder="Local address(es) or interface"
+                    value={tr.local_address ?? ''}
+                    onChange={(e) => update({ local_address: path(e.target.value) })}
+                  />
+                  <input
+                    placeholder="DNS overrides: host:addr[,addr] …"
+                    value={tr.dns_overrides ?? ''}
+                    onChange={(e) => update({ dns_overrides: path(e.target.value) })}
+                  />
+                  <input
+                    placeholder="DoH endpoint (https://…/dns-query)"
+                    value={tr.doh_url ?? ''}
+                    onChange={(e) => update({ doh_url: path(e.target.value) })}
+                  />
+                  <input
+                    placeholder="CA bundle (.pem)"
+                    value={tr.ca_bundle_path ?? ''}
+                    onChange={(e) => update({ ca_bundle_path: path(e.target.value) })}
+                  />
+                  <input
+                    placeholder="Client certificate (.pem)"
+                    value={tr.client_cert_path ?? ''}
+                    onChange={(e) => update({ client_cert_path: path(e.target.value) })}
+                  />
+                  <input
+                    placeholder="Client key (.pem)"
+                    value={tr.client_key_path ?? ''}
+                    onChange={(e) => update({ client_key_path: path(e.target.value) })}
+                  />
+                  <label className="rowInline">
+                    <input
+                      type="checkbox"
+                      checked={tr.accept_invalid_certs}
+                      onChange={(e) => update({ accept_invalid_certs: e.target.checked })}
+                    />
+                    <span>Accept invalid certificates (insecure)</span>
+                  </label>
+                  <label className="rowInline">
+                    <input
+                      type="checkbox"
+                      checked={tr.ssh_accept_new_host_keys}
+                      onChange={(e) => update({ ssh_accept_new_host_keys: e.target.checked })}
+                    />
+                    <span>Trust new SSH host keys on first use (changed keys are still refused)</span>
+                  </label>
+                </div>
+              </Fragment>
+            )
+          })}
+        </div>
+        <button
+          className="btn"
+          onClick={() =>
+            setR({
+              ...r,
+              transport_rules: [
+                {
+                  id: -Date.now(),
+                  pattern: 'example.com',
+                  enabled: true,
+                  decode_content: false,
+                  connect_timeout_secs: null,
+                  response_timeout_secs: null,
+                  read_idle_timeout_secs: null,
+                  user_agent: null,
+                  http_version: null,
+                  max_redirects: null,
+                  block_cross_host_redirects: false,
+                  ip_preference: null,
+                  dns_overrides: null,
+                  doh_url: null,
+                  ca_bundle_path: null,
+                  client_cert_path: null,
+                  client_key_path: null,
+                  accept_invalid_certs: false,
+                  ssh_accept_new_host_keys: false,
+                  local_address: null,
+                },
+                ...r.transport_rules,
+              ],
+            })
+          }
+        >
+          Add transport rule
+        </button>
+        <div className="hint">Downloads are saved byte-exact; enable “Decode content” only for hosts whose gzip/brotli encoding should be undone.</div>
+        <div className="hint">
+          Several local addresses (or interface names) spread the segments of a multipart download across them. DNS overrides pin hosts to addresses like curl --resolve (separate entries with spaces); other names go to the DoH endpoint
+          when set. A CA bundle is trusted in addition to the built-in roots. The client key may live in the certificate file. Accepting invalid
+          certificates needs a pattern naming the host.
+        </div>
+
         <div className="modalActions">
           <button
             className="btn"
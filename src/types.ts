@@ -7,6 +7,7 @@ export type ErrorCode =
   | 'HTTP_4XX'
   | 'HTTP_5XX'
   | 'TIMEOUT'
+  | 'RATE_LIMITED'
   | 'RANGE_UNSUPPORTED'
   | 'DISK_FULL'
   | 'REMOTE_CHANGED'
@@ -38,6 +39,9 @@ export interface DownloadRecord {
   supports_ranges: boolean | null
   mirror_used: string | null
   batch_id: string | null
+  proxy_route?: string | null
+  dash_video_id?: string | null
+  dash_audio_id?: string | null
 }
 
 export interface DownloadProgressUpdate {
@@ -63,6 +67,17 @@ export interface SettingsSnapshot {
   global_proxy_enabled: boolean
   global_proxy_url: string | null
   local_api_port: number
+  multipart_min_size_bytes: number
+  multipart_segment_size_bytes: number
+  multipart_max_segments: number
+  connect_timeout_secs: number
+  response_timeout_secs: number
+  read_idle_timeout_secs: number
+  proxy_mode: 'allowlist' | 'denylist' | 'all' | 'none' | 'pac'
+  pac_source: string | null
+  stream_variant: string
+  stream_audio: string
+  stream_audio_language: string | null
 }
 
 export interface ProxyRule {
@@ -73,13 +88,57 @@ export interface ProxyRule {
   proxy_url_override: string | null
 }
 
+/** Stored apart from proxy URLs; the password is write-only and never returned. */
+export interface ProxyCredentials {
+  proxy_url: string
+  username: string
+  password?: string | null
+}
+
+/** Referenced as `pool://<name>` wherever a proxy URL is accepted. */
+export interface ProxyPool {
+  id: number
+  name: string
+  enabled: boolean
+  strategy: 'round_robin' | 'least_latency'
+  allow_direct: boolean
+  rotate_per_segment: boolean
+  health_check_url: string | null
+  health_check_interval_secs: number
+  members: string[]
+}
+
+export interface ProxyMemberStatus {
+  proxy_url: string
+  healthy: boolean | null
+  latency_ms: number | null
+  last_error: string | null
+}
+
+export interface ProxyRoute {
+  proxy_url: string | null
+  explanation: string
+}
+
 export interface HeaderRule {
   id: number
   pattern: string
   enabled: boolean
+  priority: number
   headers_json: unknown
 }
 
+export interface SecretsStatus {
+  mode: 'key_file' | 'passphrase'
+  unlocked: boolean
+}
+
+export interface PreviewHeader {
+  name: string
+  value: string
+  source: string
+}
+
 export interface MirrorRule {
   id: number
   pattern: string
@@ -87,15 +146,81 @@ export interface MirrorRule {
   candidates_json: unknown
 }
 
+export interface HttpCredential {
+  id: number
+  pattern: string
+  realm: string | null
+  scheme: 'auto' | 'basic' | 'digest' | 'bearer' | 'ssh-key' | 'aws-sigv4'
+  username: string
+  enabled: boolean
+  // Write-only: never returned by the backend. For ssh-key: the key's passphrase, or the key itself without a key file.
+  password?: string
+  key_path?: string | null
+  // aws-sigv4 only: S3-compatible endpoint; empty for AWS.
+  endpoint?: string | null
+}
+
+export interface SegmentRule {
+  id: number
+  pattern: string
+  enabled: boolean
+  min_size_bytes: number | null
+  segment_size_bytes: number | null
+  max_segments: number | null
+}
+
+export interface TransportRule {
+  id: number
+  pattern: string
+  enabled: boolean
+  decode_content: boolean
+  connect_timeout_secs: number | null
+  response_timeout_secs: number | null
+  read_idle_timeout_secs: number | null
+  user_agent: string | null
+  http_version: 'http1' | 'http2' | null
+  max_redirects: number | null
+  block_cross_host_redirects: boolean
+  ip_preference: 'ipv4' | 'ipv6' | null
+  dns_overrides: string | null
+  doh_url: string | null
+  ca_bundle_path: string | null
+  client_cert_path: string | null
+  client_key_path: string | null
+  accept_invalid_certs: boolean
+  ssh_accept_new_host_keys: boolean
+  local_address: string | null
+}
+
 export interface RulesSnapshot {
   proxy_rules: ProxyRule[]
   header_rules: HeaderRule[]
   mirror_rules: MirrorRule[]
+  segment_rules: SegmentRule[]
+  transport_rules: TransportRule[]
+  proxy_pools: ProxyPool[]
+  http_credentials: HttpCredential[]
 }
 
 export interface AddDownloadsRequest {
   urls: string[]
   dest_dir?: string | null
+  dash?: DashChoice
+}
+
+/** DASH representation ids for a download; a null track follows the stream settings. */
+export interface DashChoice {
+  video_id: string | null
+  audio_id: string | null
+}
+
+export interface DashRepresentation {
+  id: string
+  kind: 'video' | 'audio'
+  mime: string
+  bandwidth: number
+  height: number | null
+  lang: string | null
 }
 
 export interface NewBatchRequest {
@@ -104,6 +229,7 @@ export interface NewBatchRequest {
   raw_url_list: string | null
   urls: string[]
   download_through_proxy?: boolean | null
+  dash?: DashChoice
 }
 
 export interface UpdateCheckResult {